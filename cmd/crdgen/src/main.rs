@@ -1,7 +1,9 @@
+use kaniop_account_policy::crd::KanidmAccountPolicy;
 use kaniop_group::crd::KanidmGroup;
 use kaniop_oauth2::crd::KanidmOAuth2Client;
 use kaniop_operator::kanidm::crd::Kanidm;
 use kaniop_person::crd::KanidmPersonAccount;
+use kaniop_stack::crd::KanidmStack;
 
 use kube::CustomResourceExt;
 
@@ -11,8 +13,61 @@ fn main() {
         KanidmGroup::crd(),
         KanidmOAuth2Client::crd(),
         KanidmPersonAccount::crd(),
+        KanidmStack::crd(),
+        KanidmAccountPolicy::crd(),
     ] {
         // safe unwrap: we know CRD is serializable
         print!("---\n{}\n", serde_yaml::to_string(&crd).unwrap());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+
+    fn printer_columns(crd: CustomResourceDefinition) -> Vec<String> {
+        crd.spec
+            .versions
+            .into_iter()
+            .flat_map(|v| v.additional_printer_columns.unwrap_or_default())
+            .map(|c| c.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_kanidm_printer_columns() {
+        let columns = printer_columns(Kanidm::crd());
+        for column in ["Replicas", "Available", "Domain", "Secret", "Image", "Age"] {
+            assert!(columns.contains(&column.to_string()), "missing {column}");
+        }
+    }
+
+    #[test]
+    fn test_oauth2_printer_columns() {
+        let columns = printer_columns(KanidmOAuth2Client::crd());
+        for column in ["Kanidm", "Public", "Secret", "Exists", "Updated", "Ready"] {
+            assert!(columns.contains(&column.to_string()), "missing {column}");
+        }
+    }
+
+    #[test]
+    fn test_short_names_and_categories() {
+        for (crd, short_names) in [
+            (Kanidm::crd(), vec!["idm", "km"]),
+            (KanidmGroup::crd(), vec!["kg"]),
+            (KanidmOAuth2Client::crd(), vec!["oauth2", "kmoauth2"]),
+            (KanidmPersonAccount::crd(), vec!["person"]),
+            (KanidmStack::crd(), vec!["kstack"]),
+            (KanidmAccountPolicy::crd(), vec!["kap"]),
+        ] {
+            let names = crd.spec.names;
+            assert_eq!(
+                names.short_names,
+                Some(short_names.into_iter().map(String::from).collect())
+            );
+            assert_eq!(names.categories, Some(vec!["kaniop".to_string()]));
+        }
+    }
+}
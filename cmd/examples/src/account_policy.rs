@@ -0,0 +1,34 @@
+use kaniop_account_policy::crd::{
+    KanidmAccountPolicy, KanidmAccountPolicySpec, KanidmCredentialType,
+};
+use kaniop_group::crd::KanidmGroup;
+use kaniop_operator::{crd::KanidmRef, kanidm::crd::Kanidm};
+
+use kube::{api::ObjectMeta, ResourceExt};
+use schemars::{gen::SchemaGenerator, schema::RootSchema};
+
+pub fn example(kanidm: &Kanidm, group: &KanidmGroup) -> KanidmAccountPolicy {
+    KanidmAccountPolicy {
+        metadata: ObjectMeta {
+            name: Some("my-account-policy".to_string()),
+            namespace: Some("default".to_string()),
+            ..Default::default()
+        },
+        spec: KanidmAccountPolicySpec {
+            kanidm_ref: KanidmRef {
+                name: kanidm.name_any(),
+                namespace: kanidm.namespace(),
+            },
+            group: group.name_any(),
+            auth_session_expiry: Some(86400),
+            privilege_expiry: Some(900),
+            password_minimum_length: Some(12),
+            credential_type_minimum: Some(KanidmCredentialType::Mfa),
+        },
+        status: Default::default(),
+    }
+}
+
+pub fn schema(gen: &SchemaGenerator) -> RootSchema {
+    gen.clone().into_root_schema_for::<KanidmAccountPolicy>()
+}
@@ -25,6 +25,7 @@ pub fn example(kanidm: &Kanidm, person: &KanidmPersonAccount) -> KanidmGroup {
                 format!("alias-{name}@{}", kanidm.spec.domain),
             ]),
             posix_attributes: Some(Default::default()),
+            external_source: None,
         },
         status: Default::default(),
     }
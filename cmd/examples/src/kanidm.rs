@@ -8,15 +8,17 @@ use k8s_openapi::{
             PodAntiAffinity, ResourceRequirements, SecretKeySelector, Toleration,
             TopologySpreadConstraint, VolumeResourceRequirements,
         },
+        networking::v1::{IngressBackend, IngressServiceBackend, ServiceBackendPort},
     },
     apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
 };
 use kaniop_operator::kanidm::{
     crd::{
-        ExternalReplicationNode, Kanidm, KanidmIngress, KanidmLogLevel, KanidmServerRole,
-        KanidmService, KanidmSpec, KanidmStorage, ReplicaGroup, ReplicationType,
+        ExternalReplicationNode, Kanidm, KanidmClientTimeouts, KanidmExternal, KanidmIngress,
+        KanidmLogLevel, KanidmServerRole, KanidmService, KanidmSpec, KanidmStorage,
+        KanidmSystemPolicy, ReplicaGroup, ReplicationType, SecretRef,
     },
-    reconcile::{statefulset::REPLICA_GROUP_LABEL, CLUSTER_LABEL},
+    reconcile::LabelKeys,
 };
 
 use kube::api::ObjectMeta;
@@ -33,6 +35,11 @@ pub fn example() -> Kanidm {
         },
         spec: KanidmSpec {
             domain: format!("{name}.localhost"),
+            domain_display_name: Some("My IdM".to_string()),
+            system_policy: Some(KanidmSystemPolicy {
+                password_minimum_length: Some(12),
+                password_badlist: Some(vec!["password123456".to_string()]),
+            }),
             replica_groups: vec![ReplicaGroup {
                 name: replica_group_name.to_string(),
                 replicas: 1,
@@ -62,9 +69,9 @@ pub fn example() -> Kanidm {
                             PodAffinityTerm {
                                 label_selector: Some(LabelSelector {
                                     match_labels: Some(BTreeMap::from([
-                                        (CLUSTER_LABEL.to_string(), name.to_string()),
+                                        (LabelKeys::get().cluster.clone(), name.to_string()),
                                         (
-                                            REPLICA_GROUP_LABEL.to_string(),
+                                            LabelKeys::get().replica_group.clone(),
                                             replica_group_name.to_string(),
                                         ),
                                     ])),
@@ -89,9 +96,9 @@ pub fn example() -> Kanidm {
                     when_unsatisfiable: "DoNotSchedule".to_string(),
                     label_selector: Some(LabelSelector {
                         match_labels: Some(BTreeMap::from([
-                            (CLUSTER_LABEL.to_string(), name.to_string()),
+                            (LabelKeys::get().cluster.clone(), name.to_string()),
                             (
-                                REPLICA_GROUP_LABEL.to_string(),
+                                LabelKeys::get().replica_group.clone(),
                                 replica_group_name.to_string(),
                             ),
                         ])),
@@ -99,6 +106,7 @@ pub fn example() -> Kanidm {
                     }),
                     ..Default::default()
                 }]),
+                extra_config: Some("log_level = \"info\"\n".to_string()),
             }],
             external_replication_nodes: vec![ExternalReplicationNode {
                 name: "my-idm-external".to_string(),
@@ -112,6 +120,10 @@ pub fn example() -> Kanidm {
                 _type: ReplicationType::MutualPull,
                 automatic_refresh: true,
             }],
+            auto_restart_on_cert_renewal: true,
+            replica_cert_renew_before_days: Some(30),
+            validate_config: true,
+            extra_config: Some("trust_x_forward_for = true\n".to_string()),
             image: "kanidm/server:latest".to_string(),
             log_level: KanidmLogLevel::Info,
             port_name: "https".to_string(),
@@ -140,6 +152,9 @@ pub fn example() -> Kanidm {
                     }),
                     status: None,
                 }),
+                size: Some(Quantity("100Mi".to_string())),
+                storage_class_name: Some("standard".to_string()),
+                access_modes: Some(vec!["ReadWriteOnce".to_string()]),
             }),
             ldap_port_name: Some("ldap".to_string()),
             tls_secret_name: Some("my-idm-tls".to_string()),
@@ -149,6 +164,8 @@ pub fn example() -> Kanidm {
                     "tcp".to_string(),
                 )])),
                 type_: Some("ClusterIP".to_string()),
+                port_name: Some("https".to_string()),
+                port: Some(8443),
             }),
             ingress: Some(KanidmIngress {
                 annotations: Some(BTreeMap::from([(
@@ -156,6 +173,17 @@ pub fn example() -> Kanidm {
                     "HTTPS".to_string(),
                 )])),
                 ingress_class_name: Some("nginx".to_string()),
+                default_backend: Some(IngressBackend {
+                    service: Some(IngressServiceBackend {
+                        name: "default-http-backend".to_string(),
+                        port: Some(ServiceBackendPort {
+                            number: Some(80),
+                            ..ServiceBackendPort::default()
+                        }),
+                    }),
+                    ..IngressBackend::default()
+                }),
+                tls_passthrough: false,
                 tls_secret_name: Some("my-idm-tls".to_string()),
             }),
             volumes: Some(vec![]),
@@ -166,6 +194,20 @@ pub fn example() -> Kanidm {
                 },
             ),
             security_context: Some(Default::default()),
+            container_security_context: Some(Default::default()),
+            runtime_class_name: Some("gvisor".to_string()),
+            node_selector: Some(BTreeMap::from([(
+                "kubernetes.io/os".to_string(),
+                "linux".to_string(),
+            )])),
+            tolerations: Some(vec![Toleration {
+                key: Some("dedicated".to_string()),
+                operator: Some("Equal".to_string()),
+                value: Some("idm".to_string()),
+                effect: Some("NoSchedule".to_string()),
+                ..Default::default()
+            }]),
+            priority_class_name: Some("high-priority".to_string()),
             dns_config: Some(Default::default()),
             dns_policy: Some(Default::default()),
             containers: Some(vec![]),
@@ -173,6 +215,23 @@ pub fn example() -> Kanidm {
             min_ready_seconds: Some(0),
             host_aliases: Some(vec![]),
             host_network: Some(false),
+            operator_credentials: Some(SecretRef {
+                name: format!("{name}-operator-credentials"),
+            }),
+            external: Some(KanidmExternal {
+                url: format!("https://{name}.example.com"),
+                credentials_secret: SecretRef {
+                    name: format!("{name}-operator-credentials"),
+                },
+            }),
+            ca_secret: Some(SecretRef {
+                name: format!("{name}-ca"),
+            }),
+            insecure_skip_verify: false,
+            client_timeouts: Some(KanidmClientTimeouts {
+                connect: 5,
+                request: 30,
+            }),
         },
         status: Default::default(),
     }
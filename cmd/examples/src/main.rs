@@ -1,3 +1,4 @@
+mod account_policy;
 mod group;
 mod kanidm;
 mod oauth2;
@@ -12,11 +13,18 @@ fn main() {
     let person = person::example(&kanidm);
     let group = group::example(&kanidm, &person);
     let oauth2 = oauth2::example();
+    let account_policy = account_policy::example(&kanidm, &group);
 
     let settings = SchemaSettings::default().with(|s| {
         s.inline_subschemas = true;
     });
     let gen = settings.into_generator();
+    write_to_file(
+        &account_policy,
+        &account_policy::schema(&gen),
+        "examples/account-policy.yaml",
+    )
+    .unwrap();
     write_to_file(&group, &group::schema(&gen), "examples/group.yaml").unwrap();
     write_to_file(&kanidm, &kanidm::schema(&gen), "examples/kanidm.yaml").unwrap();
     write_to_file(&oauth2, &oauth2::schema(&gen), "examples/oauth2.yaml").unwrap();
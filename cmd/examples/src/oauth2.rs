@@ -1,6 +1,6 @@
 use kaniop_oauth2::crd::{
     KanidmClaimMap, KanidmClaimMapJoinStrategy, KanidmClaimsValuesMap, KanidmOAuth2Client,
-    KanidmOAuth2ClientSpec, KanidmScopeMap,
+    KanidmOAuth2ClientSpec, KanidmOAuth2SecretFormat, KanidmScopeMap,
 };
 use kaniop_operator::crd::KanidmRef;
 
@@ -34,6 +34,7 @@ pub fn example() -> KanidmOAuth2Client {
                     "email".to_string(),
                 ],
             }])),
+            require_openid: true,
             sup_scope_map: Some(BTreeSet::from([KanidmScopeMap {
                 group: "my-service-admins".to_string(),
                 scopes: vec!["admin".to_string()],
@@ -51,6 +52,10 @@ pub fn example() -> KanidmOAuth2Client {
             allow_localhost_redirect: Some(false),
             allow_insecure_client_disable_pkce: Some(false),
             jwt_legacy_crypto_enable: Some(false),
+            device_flow_enable: Some(false),
+            secret_type: Some("Opaque".to_string()),
+            secret_format: KanidmOAuth2SecretFormat::Raw,
+            expose_secret_in_status: false,
         },
         status: Default::default(),
     }
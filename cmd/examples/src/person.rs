@@ -35,6 +35,8 @@ pub fn example(kanidm: &Kanidm) -> KanidmPersonAccount {
                 gidnumber: Some(1000),
                 loginshell: Some("/bin/bash".to_string()),
             }),
+            groups: None,
+            manage_groups: false,
         },
         status: Default::default(),
     }
@@ -1,22 +1,59 @@
-use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace, Secret, Service};
+use k8s_openapi::api::networking::v1::Ingress;
+use kaniop_account_policy::crd::KanidmAccountPolicy;
+use kaniop_group::crd::KanidmGroup;
 use kaniop_k8s_util::client::new_client_with_metrics;
+use kaniop_oauth2::crd::KanidmOAuth2Client;
+use kaniop_operator::controller::check::{CheckReport, OWN_VERBS, RECONCILE_VERBS, WATCH_VERBS};
 use kaniop_operator::controller::{
-    check_api_queryable, create_subscriber, State as KaniopState, SUBSCRIBE_BUFFER_SIZE,
+    check_api_queryable, create_subscriber, list_store, BufferSizes, DefaultNamespaceSelector,
+    DeletionGrace, ReconcileErrors, State as KaniopState, DEFAULT_EXEC_TIMEOUT,
+    MAX_CLEANUP_ATTEMPTS, MAX_CONCURRENT_KANIDM_REQUESTS, RELOAD_BUFFER_SIZE,
+    SUBSCRIBE_BUFFER_SIZE,
 };
 use kaniop_operator::kanidm::crd::Kanidm;
-use kaniop_operator::telemetry;
+use kaniop_operator::kanidm::reconcile::{LabelKeys, DEFAULT_LABEL_PREFIX};
+use kaniop_operator::telemetry::{self, LogFilterHandle, SampleRatioHandle};
+use kaniop_person::crd::KanidmPersonAccount;
+use kaniop_stack::crd::KanidmStack;
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::{get, Router};
-use axum::Json;
-use clap::{crate_authors, crate_description, crate_version, Parser};
-use kube::Config;
+use axum::{Extension, Json};
+use clap::builder::{PossibleValuesParser, RangedU64ValueParser};
+use clap::{crate_authors, crate_description, crate_version, Parser, Subcommand};
+use kube::api::{Api, ListParams};
+use kube::{Client, Config};
 use prometheus_client::registry::Registry;
 use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 
+const CONTROLLERS: [&str; 6] = [
+    kaniop_account_policy::controller::CONTROLLER_ID,
+    kaniop_group::controller::CONTROLLER_ID,
+    kaniop_operator::kanidm::controller::CONTROLLER_ID,
+    kaniop_oauth2::controller::CONTROLLER_ID,
+    kaniop_person::controller::CONTROLLER_ID,
+    kaniop_stack::controller::CONTROLLER_ID,
+];
+
+/// Controllers that can be disabled. The Kanidm controller watches the namespaces and Kanidms
+/// every other controller depends on, so it always runs.
+const OPTIONAL_CONTROLLERS: [&str; 5] = [
+    kaniop_account_policy::controller::CONTROLLER_ID,
+    kaniop_group::controller::CONTROLLER_ID,
+    kaniop_oauth2::controller::CONTROLLER_ID,
+    kaniop_person::controller::CONTROLLER_ID,
+    kaniop_stack::controller::CONTROLLER_ID,
+];
+
 async fn metrics(State(state): State<KaniopState>) -> impl IntoResponse {
     match state.metrics() {
         Ok(metrics) => (
@@ -39,6 +76,56 @@ async fn health() -> impl IntoResponse {
     Json("healthy")
 }
 
+async fn debug_stores(State(state): State<KaniopState>) -> impl IntoResponse {
+    Json(state.stores())
+}
+
+async fn get_log_filter(Extension(handle): Extension<LogFilterHandle>) -> impl IntoResponse {
+    match handle.get() {
+        Ok(log_filter) => (StatusCode::OK, log_filter).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn set_log_filter(
+    Extension(handle): Extension<LogFilterHandle>,
+    log_filter: String,
+) -> impl IntoResponse {
+    let log_filter = log_filter.trim();
+    match handle.set(log_filter) {
+        Ok(()) => {
+            tracing::info!(msg = "log filter updated", log_filter);
+            (StatusCode::OK, log_filter.to_string()).into_response()
+        }
+        Err(e @ telemetry::Error::InvalidLogFilter(_)) => {
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn get_sample_ratio(Extension(handle): Extension<SampleRatioHandle>) -> impl IntoResponse {
+    (StatusCode::OK, handle.get().to_string())
+}
+
+async fn set_sample_ratio(
+    Extension(handle): Extension<SampleRatioHandle>,
+    sample_ratio: String,
+) -> impl IntoResponse {
+    let result = sample_ratio
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|ratio| handle.set(ratio).map_err(|e| e.to_string()));
+    match result {
+        Ok(()) => {
+            tracing::info!(msg = "sample ratio updated", sample_ratio = handle.get());
+            (StatusCode::OK, handle.get().to_string()).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(
     name="kaniop",
@@ -47,6 +134,9 @@ async fn health() -> impl IntoResponse {
     author = crate_authors!("\n"),
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Listen on given port
     #[arg(short, long, default_value_t = 8080, env)]
     port: u16,
@@ -73,13 +163,226 @@ struct Args {
     /// of traces are sampled.
     #[arg(short, long, default_value_t = 0.1, env)]
     sample_ratio: f64,
+
+    /// Enable debug endpoints.
+    ///
+    /// Exposes `/debug/stores` with the size and object keys of every operator cache. Objects
+    /// content, such as secret data, is never exposed.
+    ///
+    /// Exposes `/debug/log-filter` to get the logging filter.
+    ///
+    /// Exposes `/debug/sample-ratio` to get the tracing sampling ratio.
+    #[arg(long, default_value_t = false, env)]
+    enable_debug_endpoints: bool,
+
+    /// Enable debug endpoints that change the operator at runtime. Requires
+    /// `--enable-debug-endpoints`.
+    ///
+    /// A `PUT` request to `/debug/log-filter` replaces the logging filter with the directives
+    /// sent in its body, e.g. "info,kube=debug".
+    ///
+    /// A `PUT` request to `/debug/sample-ratio` replaces the tracing sampling ratio with the one
+    /// sent in its body, between 0.0 and 1.0.
+    ///
+    /// The endpoints are not authenticated: only enable them when the metrics port is not
+    /// reachable by untrusted clients.
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "enable_debug_endpoints",
+        env
+    )]
+    enable_debug_write_endpoints: bool,
+
+    /// Buffer size of the watch event subscribers shared between reflectors and controllers.
+    #[arg(
+        long,
+        default_value_t = SUBSCRIBE_BUFFER_SIZE,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..),
+        env
+    )]
+    subscribe_buffer_size: usize,
+
+    /// Buffer size of the channel triggering the reconcile of all objects when a managed
+    /// resource is deleted. Triggers are coalesced with pending ones when it is full.
+    #[arg(long, default_value_t = RELOAD_BUFFER_SIZE, env)]
+    reload_buffer_size: usize,
+
+    /// Failed finalizer cleanup attempts of an object before publishing a warning event.
+    #[arg(long, default_value_t = MAX_CLEANUP_ATTEMPTS, env)]
+    max_cleanup_attempts: u32,
+
+    /// Remove the finalizer of an object once `--max-cleanup-attempts` is reached.
+    ///
+    /// Unblocks deletions, e.g. namespaces stuck in Terminating when Kanidm is unreachable, at
+    /// the cost of orphaning the object in Kanidm.
+    #[arg(long, default_value_t = false, env)]
+    force_finalizer_removal: bool,
+
+    /// Maximum concurrent requests sent to each Kanidm, shared by all controllers.
+    #[arg(
+        long,
+        default_value_t = MAX_CONCURRENT_KANIDM_REQUESTS,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..),
+        env
+    )]
+    max_concurrent_kanidm_requests: usize,
+
+    /// Path to a PEM encoded CA certificate trusted when connecting to Kanidm.
+    ///
+    /// Useful when Kanidm uses an internal CA. Kanidms defining `caSecret` use their own CA.
+    #[arg(long, env)]
+    ca_bundle: Option<PathBuf>,
+
+    /// Seconds to wait for a command executed in a Kanidm pod before giving up.
+    ///
+    /// Keeps a stuck pod from blocking the Kanidm reconcile indefinitely.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_EXEC_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        env
+    )]
+    exec_timeout: u64,
+
+    /// Seconds between full reconciles of each OAuth2 client, which apply every attribute to
+    /// Kanidm regardless of the status. `0` disables them.
+    ///
+    /// Corrects changes made outside the operator that the status does not detect. Objects are
+    /// reconciled every 5 minutes when nothing changes, so shorter intervals behave as 5 minutes.
+    #[arg(long, default_value_t = 3600, env)]
+    full_reconcile_interval: u64,
+
+    /// Prefix of the label keys identifying the Kanidm cluster and replica group of the managed
+    /// resources, e.g. `<prefix>/cluster`.
+    ///
+    /// Useful when other tools claim the default keys. It has to be a DNS subdomain. Changing it
+    /// relabels the managed resources, but the selector of the StatefulSets is immutable: the
+    /// Kanidm reconcile fails until they are deleted, so the operator recreates them.
+    #[arg(
+        long,
+        default_value = DEFAULT_LABEL_PREFIX,
+        value_parser = LabelKeys::parse_prefix,
+        env
+    )]
+    label_prefix: String,
+
+    /// Roll out the Kanidm pods when their server TLS Secret changes, e.g. after a cert-manager
+    /// renewal. The StatefulSets replace the pods one by one.
+    ///
+    /// Only Secrets of type `kubernetes.io/tls` are detected. Any change to the Secret rolls out
+    /// the pods, including label or annotation updates.
+    #[arg(long, default_value_t = false, env)]
+    rollout_on_tls_secret_change: bool,
+
+    /// Namespaces watched for KanidmOAuth2Clients of a Kanidm without
+    /// `oauth2ClientNamespaceSelector`.
+    ///
+    /// The selector of the Kanidm always takes precedence: an empty one matches every namespace
+    /// and a non-empty one the namespaces it selects.
+    #[arg(long, value_enum, default_value_t = DefaultNamespaceSelector::KanidmNamespace, env)]
+    default_namespace_selector: DefaultNamespaceSelector,
+
+    /// URL prefix KanidmGroups are allowed to read external members from. Repeat it to allow
+    /// several prefixes.
+    ///
+    /// External sources with a `url` are rejected unless it starts with one of the prefixes, so
+    /// the operator cannot be used to reach arbitrary endpoints. A prefix without a path only
+    /// matches URLs of that exact origin.
+    #[arg(
+        long = "external-source-allowed-url",
+        value_name = "URL_PREFIX",
+        value_delimiter = ',',
+        env = "EXTERNAL_SOURCE_ALLOWED_URLS"
+    )]
+    external_source_allowed_urls: Vec<String>,
+
+    /// Reconcile every object once and exit, instead of running the controllers.
+    ///
+    /// Exits with an error if any object fails to reconcile. Useful to validate a cluster in CI.
+    #[arg(long, default_value_t = false, env)]
+    once: bool,
+
+    /// Controller to skip, by its ID. Repeat it to disable several controllers.
+    ///
+    /// Useful for debugging or staged rollouts. The kanidm controller cannot be disabled.
+    #[arg(
+        long = "disable-controller",
+        value_name = "CONTROLLER_ID",
+        value_parser = PossibleValuesParser::new(OPTIONAL_CONTROLLERS),
+        value_delimiter = ',',
+        env = "DISABLE_CONTROLLERS"
+    )]
+    disable_controllers: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the operator prerequisites and exit, instead of running the controllers.
+    ///
+    /// Validates API access and RBAC permissions for every watched kind, the installed CRD
+    /// versions and the connectivity with every Kanidm. Exits with an error if any check fails.
+    Check,
+}
+
+impl Args {
+    fn buffer_sizes(&self) -> BufferSizes {
+        BufferSizes {
+            subscribe: self.subscribe_buffer_size,
+            reload: self.reload_buffer_size,
+        }
+    }
+
+    fn deletion_grace(&self) -> DeletionGrace {
+        DeletionGrace {
+            max_cleanup_attempts: self.max_cleanup_attempts,
+            force_finalizer_removal: self.force_finalizer_removal,
+        }
+    }
+
+    fn full_reconcile_interval(&self) -> Option<Duration> {
+        (self.full_reconcile_interval > 0)
+            .then(|| Duration::from_secs(self.full_reconcile_interval))
+    }
+
+    fn enabled_controllers(&self) -> Vec<&'static str> {
+        CONTROLLERS
+            .into_iter()
+            .filter(|id| !self.disable_controllers.iter().any(|d| d == id))
+            .collect()
+    }
+}
+
+/// Run `controller` only if its ID is in `enabled`. Futures are lazy, so a disabled controller
+/// never starts its watchers.
+async fn run_enabled<F>(enabled: &[&str], controller_id: &str, controller: F)
+where
+    F: Future<Output = ()>,
+{
+    if enabled.contains(&controller_id) {
+        controller.await
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Args = Args::parse();
+    LabelKeys::init(&args.label_prefix)
+        .map_err(|_| anyhow::anyhow!("label prefix already initialized"))?;
+    let buffer_sizes = args.buffer_sizes();
+    let deletion_grace = args.deletion_grace();
+    let full_reconcile_interval = args.full_reconcile_interval();
+    let controllers = args.enabled_controllers();
+    let ca_bundle = args
+        .ca_bundle
+        .as_ref()
+        .map(|path| {
+            std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("failed to read CA bundle {}: {e}", path.display()))
+        })
+        .transpose()?;
 
-    telemetry::init(
+    let telemetry_handles = telemetry::init(
         &args.log_filter,
         args.log_format,
         args.tracing_url.as_deref(),
@@ -90,24 +393,57 @@ async fn main() -> anyhow::Result<()> {
     let mut registry = Registry::with_prefix("kaniop");
     let config = Config::infer().await?;
     let client = new_client_with_metrics(config, &mut registry).await?;
-    let controllers = [
-        kaniop_group::controller::CONTROLLER_ID,
-        kaniop_operator::kanidm::controller::CONTROLLER_ID,
-        kaniop_oauth2::controller::CONTROLLER_ID,
-        kaniop_person::controller::CONTROLLER_ID,
-    ];
+    if !args.disable_controllers.is_empty() {
+        tracing::info!(msg = "controllers disabled", disabled = ?args.disable_controllers);
+    }
+
+    if let Some(Command::Check) = args.command {
+        return check(client, ca_bundle).await;
+    }
+
+    if args.once {
+        let state = KaniopState::new(
+            registry,
+            &controllers,
+            list_store(
+                &Api::<Namespace>::all(client.clone()),
+                &ListParams::default(),
+            )
+            .await?,
+            list_store(&Api::<Kanidm>::all(client.clone()), &ListParams::default()).await?,
+            buffer_sizes,
+            deletion_grace,
+            args.max_concurrent_kanidm_requests,
+        )
+        .with_ca_bundle(ca_bundle)
+        .with_exec_timeout(Duration::from_secs(args.exec_timeout))
+        .with_full_reconcile_interval(full_reconcile_interval)
+        .with_tls_secret_rollout(args.rollout_on_tls_secret_change)
+        .with_default_namespace_selector(args.default_namespace_selector)
+        .with_external_source_allowed_urls(args.external_source_allowed_urls);
+        return run_once(state, client, &controllers).await;
+    }
 
     let namespace = check_api_queryable::<Namespace>(client.clone()).await;
-    let namespace_r = create_subscriber::<Namespace>(SUBSCRIBE_BUFFER_SIZE);
+    let namespace_r = create_subscriber::<Namespace>(buffer_sizes.subscribe);
     let kanidm = check_api_queryable::<Kanidm>(client.clone()).await;
-    let kanidm_r = create_subscriber::<Kanidm>(SUBSCRIBE_BUFFER_SIZE);
+    let kanidm_r = create_subscriber::<Kanidm>(buffer_sizes.subscribe);
 
     let state = KaniopState::new(
         registry,
         &controllers,
         namespace_r.store.clone(),
         kanidm_r.store.clone(),
-    );
+        buffer_sizes,
+        deletion_grace,
+        args.max_concurrent_kanidm_requests,
+    )
+    .with_ca_bundle(ca_bundle)
+    .with_exec_timeout(Duration::from_secs(args.exec_timeout))
+    .with_full_reconcile_interval(full_reconcile_interval)
+    .with_tls_secret_rollout(args.rollout_on_tls_secret_change)
+    .with_default_namespace_selector(args.default_namespace_selector)
+    .with_external_source_allowed_urls(args.external_source_allowed_urls);
 
     let kanidm_c = kaniop_operator::kanidm::controller::run(
         state.clone(),
@@ -118,22 +454,165 @@ async fn main() -> anyhow::Result<()> {
         kanidm_r,
     );
 
-    let group_c = kaniop_group::controller::run(state.clone(), client.clone());
-    let oauth2_c = kaniop_oauth2::controller::run(state.clone(), client.clone());
-    let person_c = kaniop_person::controller::run(state.clone(), client);
+    let account_policy_c = run_enabled(
+        &controllers,
+        kaniop_account_policy::controller::CONTROLLER_ID,
+        kaniop_account_policy::controller::run(state.clone(), client.clone()),
+    );
+    let group_c = run_enabled(
+        &controllers,
+        kaniop_group::controller::CONTROLLER_ID,
+        kaniop_group::controller::run(state.clone(), client.clone()),
+    );
+    let oauth2_c = run_enabled(
+        &controllers,
+        kaniop_oauth2::controller::CONTROLLER_ID,
+        kaniop_oauth2::controller::run(state.clone(), client.clone()),
+    );
+    let person_c = run_enabled(
+        &controllers,
+        kaniop_person::controller::CONTROLLER_ID,
+        kaniop_person::controller::run(state.clone(), client.clone()),
+    );
+    let stack_c = run_enabled(
+        &controllers,
+        kaniop_stack::controller::CONTROLLER_ID,
+        kaniop_stack::controller::run(state.clone(), client),
+    );
 
-    let app = Router::new()
+    let router = Router::new()
         .route("/metrics", get(metrics))
-        .route("/health", get(health))
-        .with_state(state.clone());
+        .route("/health", get(health));
+    let log_filter_route = match args.enable_debug_write_endpoints {
+        true => get(get_log_filter).put(set_log_filter),
+        false => get(get_log_filter),
+    };
+    let sample_ratio_route = match args.enable_debug_write_endpoints {
+        true => get(get_sample_ratio).put(set_sample_ratio),
+        false => get(get_sample_ratio),
+    };
+    let router = match args.enable_debug_endpoints {
+        true => router
+            .route("/debug/stores", get(debug_stores))
+            .route("/debug/log-filter", log_filter_route)
+            .route("/debug/sample-ratio", sample_ratio_route)
+            .layer(Extension(telemetry_handles.log_filter))
+            .layer(Extension(telemetry_handles.sample_ratio)),
+        false => router,
+    };
+    let app = router.with_state(state.clone());
 
     let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
     let server = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal());
 
-    tokio::join!(group_c, kanidm_c, oauth2_c, person_c, server).4?;
+    tokio::join!(
+        account_policy_c,
+        group_c,
+        kanidm_c,
+        oauth2_c,
+        person_c,
+        stack_c,
+        server
+    )
+    .6?;
     Ok(())
 }
 
+/// Reconcile every object of the `enabled` controllers once, stacks first because they create
+/// the other resources, and then Kanidms because the rest depend on them.
+async fn run_once(state: KaniopState, client: Client, enabled: &[&str]) -> anyhow::Result<()> {
+    let mut errors = ReconcileErrors::new();
+    if enabled.contains(&kaniop_stack::controller::CONTROLLER_ID) {
+        errors.extend(kaniop_stack::controller::run_once(state.clone(), client.clone()).await?);
+    }
+    errors.extend(
+        kaniop_operator::kanidm::controller::run_once(state.clone(), client.clone()).await?,
+    );
+    if enabled.contains(&kaniop_group::controller::CONTROLLER_ID) {
+        errors.extend(kaniop_group::controller::run_once(state.clone(), client.clone()).await?);
+    }
+    if enabled.contains(&kaniop_account_policy::controller::CONTROLLER_ID) {
+        errors.extend(
+            kaniop_account_policy::controller::run_once(state.clone(), client.clone()).await?,
+        );
+    }
+    if enabled.contains(&kaniop_person::controller::CONTROLLER_ID) {
+        errors.extend(kaniop_person::controller::run_once(state.clone(), client.clone()).await?);
+    }
+    if enabled.contains(&kaniop_oauth2::controller::CONTROLLER_ID) {
+        errors.extend(kaniop_oauth2::controller::run_once(state, client).await?);
+    }
+    match errors.len() {
+        0 => Ok(()),
+        failed => {
+            let objects = errors
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            anyhow::bail!("{failed} objects failed to reconcile: {objects}")
+        }
+    }
+}
+
+/// Run every operator prerequisite check, printing the report.
+async fn check(client: Client, ca_bundle: Option<Vec<u8>>) -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+    report.check_crd::<Kanidm>(client.clone()).await;
+    report.check_crd::<KanidmGroup>(client.clone()).await;
+    report.check_crd::<KanidmOAuth2Client>(client.clone()).await;
+    report
+        .check_crd::<KanidmPersonAccount>(client.clone())
+        .await;
+    report.check_crd::<KanidmStack>(client.clone()).await;
+    report
+        .check_crd::<KanidmAccountPolicy>(client.clone())
+        .await;
+    report
+        .check_resource::<Namespace>(client.clone(), WATCH_VERBS)
+        .await;
+    report
+        .check_resource::<Kanidm>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<KanidmGroup>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<KanidmOAuth2Client>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<KanidmPersonAccount>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<KanidmStack>(client.clone(), RECONCILE_VERBS)
+        .await;
+    report
+        .check_resource::<KanidmAccountPolicy>(client.clone(), RECONCILE_VERBS)
+        .await;
+    report
+        .check_resource::<StatefulSet>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<Service>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<Ingress>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<Secret>(client.clone(), OWN_VERBS)
+        .await;
+    report
+        .check_resource::<ConfigMap>(client.clone(), OWN_VERBS)
+        .await;
+    report.check_kanidms(client, ca_bundle).await;
+
+    println!("{report}");
+    match report.is_ok() {
+        true => Ok(()),
+        false => anyhow::bail!("{} checks failed", report.failed().len()),
+    }
+}
+
 async fn shutdown_signal() {
     let mut sigterm =
         signal(SignalKind::terminate()).expect("failed to install SIGTERM signal handler");
@@ -143,3 +622,184 @@ async fn shutdown_signal() {
         _ = sigterm.recv() => {},
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_buffer_sizes_default() {
+        let args = Args::try_parse_from(["kaniop"]).unwrap();
+        assert_eq!(args.buffer_sizes(), BufferSizes::default());
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let args = Args::try_parse_from([
+            "kaniop",
+            "--subscribe-buffer-size",
+            "1024",
+            "--reload-buffer-size",
+            "64",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.buffer_sizes(),
+            BufferSizes {
+                subscribe: 1024,
+                reload: 64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_command() {
+        let args = Args::try_parse_from(["kaniop", "check"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Check)));
+        assert!(Args::try_parse_from(["kaniop"]).unwrap().command.is_none());
+    }
+
+    #[test]
+    fn test_max_concurrent_kanidm_requests_not_zero() {
+        assert!(Args::try_parse_from(["kaniop", "--max-concurrent-kanidm-requests", "0"]).is_err());
+        let args =
+            Args::try_parse_from(["kaniop", "--max-concurrent-kanidm-requests", "1"]).unwrap();
+        assert_eq!(args.max_concurrent_kanidm_requests, 1);
+    }
+
+    #[test]
+    fn test_subscribe_buffer_size_not_zero() {
+        assert!(Args::try_parse_from(["kaniop", "--subscribe-buffer-size", "0"]).is_err());
+        let args = Args::try_parse_from(["kaniop", "--subscribe-buffer-size", "1"]).unwrap();
+        assert_eq!(args.buffer_sizes().subscribe, 1);
+    }
+
+    #[test]
+    fn test_exec_timeout_not_zero() {
+        assert!(Args::try_parse_from(["kaniop", "--exec-timeout", "0"]).is_err());
+        let args = Args::try_parse_from(["kaniop", "--exec-timeout", "1"]).unwrap();
+        assert_eq!(args.exec_timeout, 1);
+    }
+
+    #[test]
+    fn test_debug_write_endpoints_require_debug_endpoints() {
+        assert!(Args::try_parse_from(["kaniop", "--enable-debug-write-endpoints"]).is_err());
+        let args = Args::try_parse_from([
+            "kaniop",
+            "--enable-debug-endpoints",
+            "--enable-debug-write-endpoints",
+        ])
+        .unwrap();
+        assert!(args.enable_debug_write_endpoints);
+        assert!(
+            !Args::try_parse_from(["kaniop"])
+                .unwrap()
+                .enable_debug_write_endpoints
+        );
+    }
+
+    #[test]
+    fn test_deletion_grace() {
+        let args = Args::try_parse_from([
+            "kaniop",
+            "--max-cleanup-attempts",
+            "3",
+            "--force-finalizer-removal",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.deletion_grace(),
+            DeletionGrace {
+                max_cleanup_attempts: 3,
+                force_finalizer_removal: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_label_prefix_validation() {
+        let args = Args::try_parse_from(["kaniop"]).unwrap();
+        assert_eq!(args.label_prefix, DEFAULT_LABEL_PREFIX);
+        let args =
+            Args::try_parse_from(["kaniop", "--label-prefix", "kanidm.example.com"]).unwrap();
+        assert_eq!(args.label_prefix, "kanidm.example.com");
+
+        for prefix in [
+            "",
+            "Kanidm.example.com",
+            "kanidm..example.com",
+            "-kanidm.example.com",
+            "kanidm/cluster",
+        ] {
+            assert!(
+                Args::try_parse_from(["kaniop", "--label-prefix", prefix]).is_err(),
+                "{prefix}"
+            );
+        }
+        assert!(
+            Args::try_parse_from(["kaniop", "--label-prefix", "a".repeat(64).as_str()]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_full_reconcile_interval() {
+        let args = Args::try_parse_from(["kaniop"]).unwrap();
+        assert_eq!(
+            args.full_reconcile_interval(),
+            Some(Duration::from_secs(3600))
+        );
+        let args = Args::try_parse_from(["kaniop", "--full-reconcile-interval", "0"]).unwrap();
+        assert_eq!(args.full_reconcile_interval(), None);
+    }
+
+    #[test]
+    fn test_enabled_controllers() {
+        let args = Args::try_parse_from(["kaniop"]).unwrap();
+        assert_eq!(args.enabled_controllers(), CONTROLLERS);
+
+        let args = Args::try_parse_from([
+            "kaniop",
+            "--disable-controller",
+            "oauth2",
+            "--disable-controller",
+            "person-account",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.enabled_controllers(),
+            vec!["account-policy", "group", "kanidm", "stack"]
+        );
+    }
+
+    #[test]
+    fn test_disable_controller_validation() {
+        assert!(Args::try_parse_from(["kaniop", "--disable-controller", "unknown"]).is_err());
+        assert!(Args::try_parse_from(["kaniop", "--disable-controller", "kanidm"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_enabled_skips_disabled_controller() {
+        let args = Args::try_parse_from(["kaniop", "--disable-controller", "group"]).unwrap();
+        let controllers = args.enabled_controllers();
+        let spawned = AtomicBool::new(false);
+        let controller = async { spawned.store(true, Ordering::SeqCst) };
+        run_enabled(
+            &controllers,
+            kaniop_group::controller::CONTROLLER_ID,
+            controller,
+        )
+        .await;
+        assert!(!spawned.load(Ordering::SeqCst));
+
+        let controller = async { spawned.store(true, Ordering::SeqCst) };
+        run_enabled(
+            &controllers,
+            kaniop_person::controller::CONTROLLER_ID,
+            controller,
+        )
+        .await;
+        assert!(spawned.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::crd::KanidmAccountPolicy;
+use crate::reconcile::reconcile_account_policy;
+
+use kaniop_operator::backoff_reconciler;
+use kaniop_operator::controller::{
+    check_api_queryable, error_policy, reconcile_once, reconcile_watcher, ControllerId,
+    ReconcileErrors, State,
+};
+use kaniop_operator::error::Result;
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+use kube::api::Api;
+use kube::client::Client;
+use kube::runtime::controller::{self, Controller};
+use kube::runtime::reflector;
+use tokio::time::Duration;
+use tracing::info;
+
+pub const CONTROLLER_ID: ControllerId = "account-policy";
+
+/// Initialize Kanidm account policy controller and shared state
+pub async fn run(state: State, client: Client) {
+    let policy = check_api_queryable::<KanidmAccountPolicy>(client.clone()).await;
+
+    let ctx = Arc::new(state.to_context(client, CONTROLLER_ID));
+
+    info!(msg = format!("starting {CONTROLLER_ID} controller"));
+    // TODO: watcher::Config::default().streaming_lists() when stabilized in K8s
+    // https://kubernetes.io/docs/reference/using-api/api-concepts/#streaming-lists
+    let (policy_store, policy_writer) = reflector::store();
+    let policy_watcher = reconcile_watcher(policy, policy_writer, ctx.metrics.clone());
+    let policy_controller = Controller::for_stream(policy_watcher, policy_store)
+        // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
+        .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
+        .shutdown_on_signal()
+        .run(
+            backoff_reconciler!(reconcile_account_policy),
+            error_policy,
+            ctx.clone(),
+        )
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    ctx.metrics.ready_set(1);
+    tokio::join!(policy_controller);
+}
+
+/// Reconcile every account policy once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let ctx = Arc::new(state.to_context(client.clone(), CONTROLLER_ID));
+    reconcile_once(&Api::<KanidmAccountPolicy>::all(client), |policy| {
+        reconcile_account_policy(policy, ctx.clone())
+    })
+    .await
+}
@@ -0,0 +1,141 @@
+use kaniop_operator::crd::{KanidmRef, KanidmResource, ReadyStatus};
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+use kube::{CustomResource, ResourceExt};
+#[cfg(feature = "schemars")]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Account policies restrict the authentication of the members of a group. When an account is
+/// member of several groups with a policy, Kanidm applies the most restrictive value of each
+/// attribute.
+/// More info:
+/// https://github.com/kubernetes/community/blob/master/contributors/devel/sig-architecture/api-conventions.md#spec-and-status
+#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[kube(
+    group = "kaniop.rs",
+    version = "v1beta1",
+    kind = "KanidmAccountPolicy",
+    plural = "kanidmaccountpolicies",
+    singular = "kanidmaccountpolicy",
+    shortname = "kap",
+    category = "kaniop",
+    namespaced,
+    status = "KanidmAccountPolicyStatus",
+    doc = r#"The Kanidm account policy custom resource definition (CRD) defines the account policy
+    of a group in Kanidm. This resource has to be in the same namespace as the Kanidm cluster."#,
+    printcolumn = r#"{"name":"Kanidm","type":"string","jsonPath":".status.kanidmRef"}"#,
+    printcolumn = r#"{"name":"Group","type":"string","jsonPath":".spec.group"}"#,
+    printcolumn = r#"{"name":"Ready","type":"boolean","jsonPath":".status.ready"}"#,
+    derive = "Default"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmAccountPolicySpec {
+    pub kanidm_ref: KanidmRef,
+
+    /// Name of the Kanidm group the policy is attached to, e.g. one managed by a KanidmGroup.
+    /// The group has to exist before the policy is applied.
+    pub group: String,
+
+    /// Maximum number of seconds of an authentication session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_session_expiry: Option<u32>,
+
+    /// Number of seconds an authentication session keeps its privileges, after which it has to
+    /// re-authenticate to make changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privilege_expiry: Option<u32>,
+
+    /// Minimum length of the passwords. Kanidm rejects values below its own minimum.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_minimum_length: Option<u32>,
+
+    /// Minimum security strength of the credentials used to authenticate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential_type_minimum: Option<KanidmCredentialType>,
+}
+
+impl KanidmResource for KanidmAccountPolicy {
+    #[inline]
+    fn kanidm_name(&self) -> String {
+        self.spec.kanidm_ref.name.clone()
+    }
+
+    #[inline]
+    fn kanidm_namespace(&self) -> String {
+        // safe unwrap: account policy is namespaced scoped
+        self.namespace().unwrap()
+    }
+}
+
+impl ReadyStatus for KanidmAccountPolicy {
+    #[inline]
+    fn ready(&self) -> Option<bool> {
+        self.status.as_ref().map(|status| status.ready)
+    }
+}
+
+/// Credential types ordered from the weakest to the strongest.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum KanidmCredentialType {
+    Any,
+    Mfa,
+    Passkey,
+    AttestedPasskey,
+}
+
+impl KanidmCredentialType {
+    /// Value of the credential type in Kanidm.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KanidmCredentialType::Any => "any",
+            KanidmCredentialType::Mfa => "mfa",
+            KanidmCredentialType::Passkey => "passkey",
+            KanidmCredentialType::AttestedPasskey => "attested_passkey",
+        }
+    }
+}
+
+/// Most recent observed status of the Kanidm account policy. Read-only.
+/// More info:
+/// https://github.com/kubernetes/community/blob/master/contributors/devel/sig-architecture/api-conventions.md#spec-and-status
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmAccountPolicyStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<Condition>>,
+
+    /// The group exists with the policy enabled and all its attributes match.
+    pub ready: bool,
+
+    /// Attributes of the group set by the policy. They are removed from the group when they are
+    /// removed from the policy or the policy is deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_attributes: Option<Vec<String>>,
+
+    pub kanidm_ref: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KanidmCredentialType;
+
+    #[test]
+    fn test_credential_type_as_str_matches_serialization() {
+        for credential_type in [
+            KanidmCredentialType::Any,
+            KanidmCredentialType::Mfa,
+            KanidmCredentialType::Passkey,
+            KanidmCredentialType::AttestedPasskey,
+        ] {
+            assert_eq!(
+                serde_json::to_value(&credential_type).unwrap(),
+                credential_type.as_str()
+            );
+        }
+    }
+}
@@ -0,0 +1,5 @@
+#[cfg(feature = "client")]
+pub mod controller;
+pub mod crd;
+#[cfg(feature = "client")]
+pub mod reconcile;
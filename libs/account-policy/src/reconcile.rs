@@ -0,0 +1,724 @@
+use crate::crd::{KanidmAccountPolicy, KanidmAccountPolicyStatus};
+
+use kaniop_k8s_util::resources::is_status_unchanged;
+use kaniop_k8s_util::types::get_first_cloned;
+use kaniop_operator::controller::kanidm::KanidmResource;
+use kaniop_operator::controller::{
+    context::{out_of_sync_conditions, Context, SystemClientContext},
+    DEFAULT_RECONCILE_INTERVAL,
+};
+use kaniop_operator::error::{Error, Result};
+use kaniop_operator::telemetry;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::TryFutureExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use k8s_openapi::chrono::Utc;
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::{
+    ATTR_AUTH_PASSWORD_MINIMUM_LENGTH, ATTR_AUTH_SESSION_EXPIRY, ATTR_CLASS,
+    ATTR_CREDENTIAL_TYPE_MINIMUM, ATTR_PRIVILEGE_EXPIRY,
+};
+use kanidm_proto::v1::Entry;
+use kube::api::{Api, Patch, PatchParams};
+use kube::runtime::controller::Action;
+use kube::runtime::events::{Event, EventType};
+use kube::runtime::finalizer::{finalizer, Event as Finalizer};
+use kube::ResourceExt;
+use tracing::{debug, field, info, instrument, trace, Span};
+
+pub static ACCOUNT_POLICY_OPERATOR_NAME: &str = "kanidmaccountpolicies.kaniop.rs";
+pub static ACCOUNT_POLICY_FINALIZER: &str = "kanidms.kaniop.rs/account-policy";
+
+const ACCOUNT_POLICY_CLASS: &str = "account_policy";
+
+const TYPE_GROUP_EXISTS: &str = "GroupExists";
+const TYPE_ENABLED: &str = "Enabled";
+const TYPE_AUTH_SESSION_EXPIRY_UPDATED: &str = "AuthSessionExpiryUpdated";
+const TYPE_PRIVILEGE_EXPIRY_UPDATED: &str = "PrivilegeExpiryUpdated";
+const TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED: &str = "PasswordMinimumLengthUpdated";
+const TYPE_CREDENTIAL_TYPE_MINIMUM_UPDATED: &str = "CredentialTypeMinimumUpdated";
+const REASON_ATTRIBUTE_MATCH: &str = "AttributeMatch";
+const REASON_ATTRIBUTE_NOT_MATCH: &str = "AttributeNotMatch";
+const CONDITION_TRUE: &str = "True";
+const CONDITION_FALSE: &str = "False";
+
+/// Attributes of the group that a policy can set.
+const POLICY_ATTRIBUTES: [&str; 4] = [
+    ATTR_AUTH_SESSION_EXPIRY,
+    ATTR_PRIVILEGE_EXPIRY,
+    ATTR_AUTH_PASSWORD_MINIMUM_LENGTH,
+    ATTR_CREDENTIAL_TYPE_MINIMUM,
+];
+
+/// Conditions that reconcile fixes when they are false
+const SYNC_CONDITIONS: [&str; 5] = [
+    TYPE_ENABLED,
+    TYPE_AUTH_SESSION_EXPIRY_UPDATED,
+    TYPE_PRIVILEGE_EXPIRY_UPDATED,
+    TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED,
+    TYPE_CREDENTIAL_TYPE_MINIMUM_UPDATED,
+];
+
+#[instrument(skip(ctx, policy))]
+pub async fn reconcile_account_policy(
+    policy: Arc<KanidmAccountPolicy>,
+    ctx: Arc<Context<KanidmAccountPolicy>>,
+) -> Result<Action> {
+    let trace_id = telemetry::get_trace_id();
+    Span::current().record("trace_id", field::display(&trace_id));
+    let _timer = ctx.metrics.reconcile_count_and_measure(&trace_id);
+    info!(msg = "reconciling account policy");
+
+    // safe unwrap: account policy is namespaced scoped
+    let namespace = policy.get_namespace();
+    let policy_api: Api<KanidmAccountPolicy> = Api::namespaced(ctx.client.clone(), &namespace);
+    let (kanidm_client, status) = match policy.client_and_status(ctx.clone()).await {
+        Ok(client_and_status) => client_and_status,
+        // a deleted or unreachable Kanidm must not block the deletion forever
+        Err(e) if policy.metadata.deletion_timestamp.is_some() => {
+            return ctx
+                .cleanup_unavailable(&policy_api, ACCOUNT_POLICY_FINALIZER, policy, e)
+                .await
+                .map_err(|e| {
+                    Error::FinalizerError(
+                        "failed on account policy finalizer".to_string(),
+                        Box::new(e),
+                    )
+                });
+        }
+        Err(e) => return Err(e),
+    };
+    finalizer(
+        &policy_api,
+        ACCOUNT_POLICY_FINALIZER,
+        policy,
+        |event| async {
+            match event {
+                Finalizer::Apply(p) => p.reconcile(kanidm_client, status, ctx).await,
+                Finalizer::Cleanup(p) => {
+                    let result = p.cleanup(kanidm_client, status).await;
+                    ctx.cleanup_with_grace(&p, result).await
+                }
+            }
+        },
+    )
+    .await
+    .map_err(|e| {
+        Error::FinalizerError(
+            "failed on account policy finalizer".to_string(),
+            Box::new(e),
+        )
+    })
+}
+
+impl KanidmAccountPolicy {
+    #[inline]
+    fn get_namespace(&self) -> String {
+        // safe unwrap: account policy is namespaced scoped
+        self.namespace().unwrap()
+    }
+
+    async fn client_and_status(
+        &self,
+        ctx: Arc<Context<KanidmAccountPolicy>>,
+    ) -> Result<(Arc<KanidmClient>, KanidmAccountPolicyStatus)> {
+        // account policies can only be modified by system administrators
+        let kanidm_client = ctx.get_system_client(self).await?;
+        let status = self
+            .update_status(kanidm_client.clone(), ctx.clone())
+            .await
+            .map_err(|e| {
+                debug!(msg = "failed to reconcile status", %e);
+                ctx.metrics.status_update_errors_inc();
+                e
+            })?;
+        Ok((kanidm_client, status))
+    }
+
+    #[inline]
+    async fn reconcile(
+        &self,
+        kanidm_client: Arc<KanidmClient>,
+        status: KanidmAccountPolicyStatus,
+        ctx: Arc<Context<KanidmAccountPolicy>>,
+    ) -> Result<Action> {
+        match self.internal_reconcile(kanidm_client, status).await {
+            Ok(action) => Ok(action),
+            Err(e) => match e {
+                Error::KanidmClientError(_, _) => {
+                    ctx.publish_event(
+                        self,
+                        Event {
+                            type_: EventType::Warning,
+                            reason: "KanidmError".to_string(),
+                            note: Some(format!("{e:?}")),
+                            action: "KanidmRequest".to_string(),
+                            secondary: None,
+                        },
+                    )
+                    .await?;
+                    Err(e)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    #[inline]
+    async fn internal_reconcile(
+        &self,
+        kanidm_client: Arc<KanidmClient>,
+        status: KanidmAccountPolicyStatus,
+    ) -> Result<Action> {
+        let group = &self.spec.group;
+        if is_policy_false(TYPE_GROUP_EXISTS, status.clone()) {
+            return Err(Error::MissingData(format!(
+                "group {group} not found in {namespace}/{kanidm}",
+                namespace = self.kanidm_namespace(),
+                kanidm = self.kanidm_name(),
+            )));
+        }
+
+        let mut require_status_update = false;
+        if is_policy_false(TYPE_ENABLED, status.clone()) {
+            debug!(msg = "enable account policy");
+            kanidm_client
+                .group_account_policy_enable(group)
+                .await
+                .map_err(|e| self.client_error("enable account policy", e))?;
+            require_status_update = true;
+        }
+
+        if let Some(expiry) = self
+            .spec
+            .auth_session_expiry
+            .filter(|_| is_policy_false(TYPE_AUTH_SESSION_EXPIRY_UPDATED, status.clone()))
+        {
+            debug!(msg = format!("update {ATTR_AUTH_SESSION_EXPIRY} attribute"));
+            kanidm_client
+                .group_account_policy_authsession_expiry_set(group, expiry)
+                .await
+                .map_err(|e| self.client_error(&format!("update {ATTR_AUTH_SESSION_EXPIRY}"), e))?;
+            require_status_update = true;
+        }
+
+        if let Some(expiry) = self
+            .spec
+            .privilege_expiry
+            .filter(|_| is_policy_false(TYPE_PRIVILEGE_EXPIRY_UPDATED, status.clone()))
+        {
+            debug!(msg = format!("update {ATTR_PRIVILEGE_EXPIRY} attribute"));
+            kanidm_client
+                .group_account_policy_privilege_expiry_set(group, expiry)
+                .await
+                .map_err(|e| self.client_error(&format!("update {ATTR_PRIVILEGE_EXPIRY}"), e))?;
+            require_status_update = true;
+        }
+
+        if let Some(length) = self
+            .spec
+            .password_minimum_length
+            .filter(|_| is_policy_false(TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED, status.clone()))
+        {
+            debug!(msg = format!("update {ATTR_AUTH_PASSWORD_MINIMUM_LENGTH} attribute"));
+            kanidm_client
+                .group_account_policy_password_minimum_length_set(group, length)
+                .await
+                .map_err(|e| {
+                    self.client_error(&format!("update {ATTR_AUTH_PASSWORD_MINIMUM_LENGTH}"), e)
+                })?;
+            require_status_update = true;
+        }
+
+        if let Some(credential_type) = self
+            .spec
+            .credential_type_minimum
+            .as_ref()
+            .filter(|_| is_policy_false(TYPE_CREDENTIAL_TYPE_MINIMUM_UPDATED, status.clone()))
+        {
+            debug!(msg = format!("update {ATTR_CREDENTIAL_TYPE_MINIMUM} attribute"));
+            kanidm_client
+                .group_account_policy_credential_type_minimum_set(group, credential_type.as_str())
+                .await
+                .map_err(|e| {
+                    self.client_error(&format!("update {ATTR_CREDENTIAL_TYPE_MINIMUM}"), e)
+                })?;
+            require_status_update = true;
+        }
+
+        for attr in self.removed_attributes(&status) {
+            self.purge_attribute(&kanidm_client, attr).await?;
+            require_status_update = true;
+        }
+
+        if require_status_update {
+            trace!(msg = "status update required, requeueing in 500ms");
+            Ok(Action::requeue(Duration::from_millis(500)))
+        } else {
+            Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL))
+        }
+    }
+
+    /// Remove the attributes managed or applied by the policy from the group. The group itself
+    /// and its `account_policy` class are kept, so Kanidm falls back to the defaults.
+    async fn cleanup(
+        &self,
+        kanidm_client: Arc<KanidmClient>,
+        status: KanidmAccountPolicyStatus,
+    ) -> Result<Action> {
+        if is_policy(TYPE_GROUP_EXISTS, status.clone()) {
+            for attr in self.cleanup_attributes(&status) {
+                self.purge_attribute(&kanidm_client, attr).await?;
+            }
+        }
+        Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL))
+    }
+
+    async fn purge_attribute(&self, kanidm_client: &KanidmClient, attr: &str) -> Result<()> {
+        debug!(msg = format!("purge {attr} attribute"));
+        kanidm_client
+            .idm_group_purge_attr(&self.spec.group, attr)
+            .await
+            .map_err(|e| self.client_error(&format!("purge {attr}"), e))
+    }
+
+    /// Attributes of the group defined in the policy.
+    fn managed_attributes(&self) -> Vec<&'static str> {
+        [
+            (
+                self.spec.auth_session_expiry.is_some(),
+                ATTR_AUTH_SESSION_EXPIRY,
+            ),
+            (self.spec.privilege_expiry.is_some(), ATTR_PRIVILEGE_EXPIRY),
+            (
+                self.spec.password_minimum_length.is_some(),
+                ATTR_AUTH_PASSWORD_MINIMUM_LENGTH,
+            ),
+            (
+                self.spec.credential_type_minimum.is_some(),
+                ATTR_CREDENTIAL_TYPE_MINIMUM,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(defined, attr)| defined.then_some(attr))
+        .collect()
+    }
+
+    /// Attributes of the group set by the policy: the ones defined in it and the ones applied
+    /// before, while the group still has them.
+    fn applied_attributes(&self, group: &Entry) -> Vec<String> {
+        let managed = self.managed_attributes();
+        let previous = self
+            .status
+            .as_ref()
+            .and_then(|status| status.applied_attributes.as_ref());
+        POLICY_ATTRIBUTES
+            .into_iter()
+            .filter(|attr| {
+                managed.contains(attr)
+                    || previous.is_some_and(|applied| applied.iter().any(|a| a == attr))
+            })
+            .filter(|attr| group.attrs.contains_key(*attr))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Attributes applied by the policy that are not defined in it anymore.
+    fn removed_attributes(&self, status: &KanidmAccountPolicyStatus) -> Vec<&'static str> {
+        let managed = self.managed_attributes();
+        POLICY_ATTRIBUTES
+            .into_iter()
+            .filter(|attr| !managed.contains(attr) && is_applied(attr, status))
+            .collect()
+    }
+
+    /// Attributes removed from the group when the policy is deleted.
+    fn cleanup_attributes(&self, status: &KanidmAccountPolicyStatus) -> Vec<&'static str> {
+        let managed = self.managed_attributes();
+        POLICY_ATTRIBUTES
+            .into_iter()
+            .filter(|attr| managed.contains(attr) || is_applied(attr, status))
+            .collect()
+    }
+
+    fn client_error(&self, action: &str, e: kanidm_client::ClientError) -> Error {
+        Error::KanidmClientError(
+            format!(
+                "failed to {action} for group {group} from {namespace}/{kanidm}",
+                group = self.spec.group,
+                namespace = self.kanidm_namespace(),
+                kanidm = self.kanidm_name(),
+            ),
+            Box::new(e),
+        )
+    }
+
+    async fn update_status(
+        &self,
+        kanidm_client: Arc<KanidmClient>,
+        ctx: Arc<Context<KanidmAccountPolicy>>,
+    ) -> Result<KanidmAccountPolicyStatus> {
+        let namespace = self.get_namespace();
+        let name = self.name_any();
+        let current_group = kanidm_client
+            .idm_group_get(&self.spec.group)
+            .map_err(|e| self.client_error("get group", e))
+            .await?;
+
+        let mut status = self.generate_status(current_group);
+        if let Some(stalled) = ctx
+            .stalled_condition(
+                self,
+                out_of_sync_conditions(status.conditions.as_deref(), &SYNC_CONDITIONS),
+            )
+            .await
+        {
+            status.conditions.get_or_insert_with(Vec::new).push(stalled);
+        }
+        if is_status_unchanged(self.status.as_ref(), &status) {
+            trace!(msg = "status unchanged, skipping patch");
+            return Ok(status);
+        }
+        let status_patch = Patch::Apply(KanidmAccountPolicy {
+            status: Some(status.clone()),
+            ..KanidmAccountPolicy::default()
+        });
+        debug!(msg = "updating status");
+        trace!(msg = format!("status patch {:?}", status_patch));
+        let patch = PatchParams::apply(ACCOUNT_POLICY_OPERATOR_NAME).force();
+        let policy_api = Api::<KanidmAccountPolicy>::namespaced(ctx.client.clone(), &namespace);
+        let _o = policy_api
+            .patch_status(&name, &patch, &status_patch)
+            .await
+            .map_err(|e| {
+                Error::KubeError(
+                    format!("failed to patch KanidmAccountPolicy/status {namespace}/{name}"),
+                    e,
+                )
+            })?;
+        Ok(status)
+    }
+
+    fn generate_status(&self, group: Option<Entry>) -> KanidmAccountPolicyStatus {
+        let now = Utc::now();
+        let condition = |type_: &str, status: &str, reason: &str, message: String| Condition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            reason: reason.to_string(),
+            message,
+            last_transition_time: Time(now),
+            observed_generation: self.metadata.generation,
+        };
+        let Some(g) = group else {
+            return KanidmAccountPolicyStatus {
+                conditions: Some(vec![condition(
+                    TYPE_GROUP_EXISTS,
+                    CONDITION_FALSE,
+                    "NotExists",
+                    "Group is not present.".to_string(),
+                )]),
+                ready: false,
+                applied_attributes: None,
+                kanidm_ref: self.kanidm_ref(),
+            };
+        };
+
+        let group_exists_condition = condition(
+            TYPE_GROUP_EXISTS,
+            CONDITION_TRUE,
+            "Exists",
+            "Group exists.".to_string(),
+        );
+        let enabled_condition = match g
+            .attrs
+            .get(ATTR_CLASS)
+            .is_some_and(|classes| classes.iter().any(|c| c == ACCOUNT_POLICY_CLASS))
+        {
+            true => condition(
+                TYPE_ENABLED,
+                CONDITION_TRUE,
+                "Enabled",
+                "Group has the account policy enabled.".to_string(),
+            ),
+            false => condition(
+                TYPE_ENABLED,
+                CONDITION_FALSE,
+                "NotEnabled",
+                "Group has the account policy disabled.".to_string(),
+            ),
+        };
+        let attribute_condition = |type_: &str, attr: &str, desired: Option<String>| {
+            desired.map(
+                |desired| match get_first_cloned(&g, attr).as_ref() == Some(&desired) {
+                    true => condition(
+                        type_,
+                        CONDITION_TRUE,
+                        REASON_ATTRIBUTE_MATCH,
+                        format!("Group exists with desired {attr} attribute."),
+                    ),
+                    false => condition(
+                        type_,
+                        CONDITION_FALSE,
+                        REASON_ATTRIBUTE_NOT_MATCH,
+                        format!("Group exists with different {attr} attribute."),
+                    ),
+                },
+            )
+        };
+        let conditions = vec![group_exists_condition, enabled_condition]
+            .into_iter()
+            .chain(attribute_condition(
+                TYPE_AUTH_SESSION_EXPIRY_UPDATED,
+                ATTR_AUTH_SESSION_EXPIRY,
+                self.spec.auth_session_expiry.map(|v| v.to_string()),
+            ))
+            .chain(attribute_condition(
+                TYPE_PRIVILEGE_EXPIRY_UPDATED,
+                ATTR_PRIVILEGE_EXPIRY,
+                self.spec.privilege_expiry.map(|v| v.to_string()),
+            ))
+            .chain(attribute_condition(
+                TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED,
+                ATTR_AUTH_PASSWORD_MINIMUM_LENGTH,
+                self.spec.password_minimum_length.map(|v| v.to_string()),
+            ))
+            .chain(attribute_condition(
+                TYPE_CREDENTIAL_TYPE_MINIMUM_UPDATED,
+                ATTR_CREDENTIAL_TYPE_MINIMUM,
+                self.spec
+                    .credential_type_minimum
+                    .as_ref()
+                    .map(|c| c.as_str().to_string()),
+            ))
+            .collect::<Vec<_>>();
+        let applied_attributes = self.applied_attributes(&g);
+        KanidmAccountPolicyStatus {
+            ready: conditions.iter().all(|c| c.status == CONDITION_TRUE),
+            conditions: Some(conditions),
+            applied_attributes: (!applied_attributes.is_empty()).then_some(applied_attributes),
+            kanidm_ref: self.kanidm_ref(),
+        }
+    }
+}
+
+fn is_applied(attr: &str, status: &KanidmAccountPolicyStatus) -> bool {
+    status
+        .applied_attributes
+        .as_ref()
+        .is_some_and(|applied| applied.iter().any(|a| a == attr))
+}
+
+pub fn is_policy(type_: &str, status: KanidmAccountPolicyStatus) -> bool {
+    status
+        .conditions
+        .unwrap_or_default()
+        .iter()
+        .any(|c| c.type_ == type_ && c.status == CONDITION_TRUE)
+}
+
+pub fn is_policy_false(type_: &str, status: KanidmAccountPolicyStatus) -> bool {
+    status
+        .conditions
+        .unwrap_or_default()
+        .iter()
+        .any(|c| c.type_ == type_ && c.status == CONDITION_FALSE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::crd::{KanidmAccountPolicySpec, KanidmCredentialType};
+
+    use kaniop_operator::crd::KanidmRef;
+
+    use std::collections::BTreeMap;
+
+    use kube::api::ObjectMeta;
+
+    fn policy(spec: KanidmAccountPolicySpec) -> KanidmAccountPolicy {
+        KanidmAccountPolicy {
+            metadata: ObjectMeta {
+                name: Some("admins-policy".to_string()),
+                namespace: Some("default".to_string()),
+                generation: Some(1),
+                ..ObjectMeta::default()
+            },
+            spec: KanidmAccountPolicySpec {
+                kanidm_ref: KanidmRef {
+                    name: "idm".to_string(),
+                    namespace: None,
+                },
+                group: "admins".to_string(),
+                ..spec
+            },
+            status: None,
+        }
+    }
+
+    fn group(attrs: &[(&str, &str)]) -> Entry {
+        let mut entry = Entry {
+            attrs: BTreeMap::new(),
+        };
+        for (attr, value) in attrs {
+            entry
+                .attrs
+                .entry(attr.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        entry
+    }
+
+    fn condition_status(status: &KanidmAccountPolicyStatus, type_: &str) -> Option<String> {
+        status
+            .conditions
+            .as_ref()
+            .and_then(|c| c.iter().find(|c| c.type_ == type_))
+            .map(|c| c.status.clone())
+    }
+
+    #[test]
+    fn test_generate_status_group_not_exists() {
+        let status = policy(KanidmAccountPolicySpec::default()).generate_status(None);
+        assert!(!status.ready);
+        assert!(is_policy_false(TYPE_GROUP_EXISTS, status.clone()));
+        assert_eq!(status.kanidm_ref, "default/idm");
+    }
+
+    #[test]
+    fn test_generate_status_create() {
+        let policy = policy(KanidmAccountPolicySpec {
+            auth_session_expiry: Some(3600),
+            credential_type_minimum: Some(KanidmCredentialType::Mfa),
+            ..KanidmAccountPolicySpec::default()
+        });
+        let status = policy.generate_status(Some(group(&[(ATTR_CLASS, "group")])));
+        assert!(!status.ready);
+        assert!(is_policy(TYPE_GROUP_EXISTS, status.clone()));
+        assert!(is_policy_false(TYPE_ENABLED, status.clone()));
+        assert!(is_policy_false(
+            TYPE_AUTH_SESSION_EXPIRY_UPDATED,
+            status.clone()
+        ));
+        assert!(is_policy_false(
+            TYPE_CREDENTIAL_TYPE_MINIMUM_UPDATED,
+            status.clone()
+        ));
+        // attributes not defined in the policy are not managed
+        assert_eq!(
+            condition_status(&status, TYPE_PRIVILEGE_EXPIRY_UPDATED),
+            None
+        );
+        assert_eq!(
+            condition_status(&status, TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED),
+            None
+        );
+    }
+
+    #[test]
+    fn test_generate_status_update() {
+        let policy = policy(KanidmAccountPolicySpec {
+            auth_session_expiry: Some(3600),
+            password_minimum_length: Some(16),
+            ..KanidmAccountPolicySpec::default()
+        });
+        let status = policy.generate_status(Some(group(&[
+            (ATTR_CLASS, "group"),
+            (ATTR_CLASS, ACCOUNT_POLICY_CLASS),
+            (ATTR_AUTH_SESSION_EXPIRY, "3600"),
+            (ATTR_AUTH_PASSWORD_MINIMUM_LENGTH, "12"),
+        ])));
+        assert!(!status.ready);
+        assert!(is_policy(TYPE_ENABLED, status.clone()));
+        assert!(is_policy(TYPE_AUTH_SESSION_EXPIRY_UPDATED, status.clone()));
+        assert!(is_policy_false(
+            TYPE_PASSWORD_MINIMUM_LENGTH_UPDATED,
+            status.clone()
+        ));
+    }
+
+    #[test]
+    fn test_generate_status_applied() {
+        let policy = policy(KanidmAccountPolicySpec {
+            privilege_expiry: Some(900),
+            credential_type_minimum: Some(KanidmCredentialType::AttestedPasskey),
+            ..KanidmAccountPolicySpec::default()
+        });
+        let status = policy.generate_status(Some(group(&[
+            (ATTR_CLASS, ACCOUNT_POLICY_CLASS),
+            (ATTR_PRIVILEGE_EXPIRY, "900"),
+            (ATTR_CREDENTIAL_TYPE_MINIMUM, "attested_passkey"),
+        ])));
+        assert!(status.ready);
+    }
+
+    #[test]
+    fn test_managed_attributes() {
+        assert!(policy(KanidmAccountPolicySpec::default())
+            .managed_attributes()
+            .is_empty());
+        let policy = policy(KanidmAccountPolicySpec {
+            auth_session_expiry: Some(3600),
+            password_minimum_length: Some(16),
+            ..KanidmAccountPolicySpec::default()
+        });
+        assert_eq!(
+            policy.managed_attributes(),
+            vec![ATTR_AUTH_SESSION_EXPIRY, ATTR_AUTH_PASSWORD_MINIMUM_LENGTH]
+        );
+    }
+
+    #[test]
+    fn test_removed_attributes_purged() {
+        let mut policy = policy(KanidmAccountPolicySpec {
+            auth_session_expiry: Some(3600),
+            ..KanidmAccountPolicySpec::default()
+        });
+        // password minimum length was removed from the policy after being applied
+        policy.status = Some(KanidmAccountPolicyStatus {
+            applied_attributes: Some(vec![
+                ATTR_AUTH_SESSION_EXPIRY.to_string(),
+                ATTR_AUTH_PASSWORD_MINIMUM_LENGTH.to_string(),
+            ]),
+            ..KanidmAccountPolicyStatus::default()
+        });
+        let status = policy.generate_status(Some(group(&[
+            (ATTR_CLASS, ACCOUNT_POLICY_CLASS),
+            (ATTR_AUTH_SESSION_EXPIRY, "3600"),
+            (ATTR_AUTH_PASSWORD_MINIMUM_LENGTH, "16"),
+            (ATTR_PRIVILEGE_EXPIRY, "900"),
+        ])));
+        assert!(status.ready);
+        assert_eq!(
+            status.applied_attributes,
+            Some(vec![
+                ATTR_AUTH_SESSION_EXPIRY.to_string(),
+                ATTR_AUTH_PASSWORD_MINIMUM_LENGTH.to_string(),
+            ])
+        );
+        assert_eq!(
+            policy.removed_attributes(&status),
+            vec![ATTR_AUTH_PASSWORD_MINIMUM_LENGTH]
+        );
+        assert_eq!(
+            policy.cleanup_attributes(&status),
+            vec![ATTR_AUTH_SESSION_EXPIRY, ATTR_AUTH_PASSWORD_MINIMUM_LENGTH]
+        );
+
+        // once purged, the attribute is not applied anymore
+        policy.status = Some(status);
+        let status = policy.generate_status(Some(group(&[
+            (ATTR_CLASS, ACCOUNT_POLICY_CLASS),
+            (ATTR_AUTH_SESSION_EXPIRY, "3600"),
+        ])));
+        assert_eq!(
+            status.applied_attributes,
+            Some(vec![ATTR_AUTH_SESSION_EXPIRY.to_string()])
+        );
+        assert!(policy.removed_attributes(&status).is_empty());
+    }
+}
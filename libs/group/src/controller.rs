@@ -2,14 +2,19 @@ use crate::crd::KanidmGroup;
 use crate::reconcile::reconcile_group;
 
 use kaniop_operator::backoff_reconciler;
-use kaniop_operator::controller::{check_api_queryable, error_policy, ControllerId, State};
+use kaniop_operator::controller::{
+    check_api_queryable, error_policy, reconcile_once, reconcile_watcher, ControllerId,
+    ReconcileErrors, State,
+};
+use kaniop_operator::error::Result;
 
 use std::sync::Arc;
 
 use futures::StreamExt;
+use kube::api::Api;
 use kube::client::Client;
 use kube::runtime::controller::{self, Controller};
-use kube::runtime::watcher;
+use kube::runtime::reflector;
 use tokio::time::Duration;
 use tracing::info;
 
@@ -24,7 +29,9 @@ pub async fn run(state: State, client: Client) {
     info!(msg = format!("starting {CONTROLLER_ID} controller"));
     // TODO: watcher::Config::default().streaming_lists() when stabilized in K8s
     // https://kubernetes.io/docs/reference/using-api/api-concepts/#streaming-lists
-    let group_controller = Controller::new(group, watcher::Config::default().any_semantic())
+    let (group_store, group_writer) = reflector::store();
+    let group_watcher = reconcile_watcher(group, group_writer, ctx.metrics.clone());
+    let group_controller = Controller::for_stream(group_watcher, group_store)
         // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
         .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
         .shutdown_on_signal()
@@ -39,3 +46,12 @@ pub async fn run(state: State, client: Client) {
     ctx.metrics.ready_set(1);
     tokio::join!(group_controller);
 }
+
+/// Reconcile every group once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let ctx = Arc::new(state.to_context(client.clone(), CONTROLLER_ID));
+    reconcile_once(&Api::<KanidmGroup>::all(client), |group| {
+        reconcile_group(group, ctx.clone())
+    })
+    .await
+}
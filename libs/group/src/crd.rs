@@ -1,9 +1,9 @@
 use kanidm_proto::{constants::ATTR_GIDNUMBER, v1::Entry};
 use kaniop_k8s_util::types::get_first_cloned;
-use kaniop_operator::controller::kanidm::KanidmResource;
-use kaniop_operator::crd::KanidmRef;
+use kaniop_operator::crd::{KanidmRef, KanidmResource, ReadyStatus};
 
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+use k8s_openapi::api::core::v1::ConfigMapKeySelector;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use kube::{CustomResource, ResourceExt};
 #[cfg(feature = "schemars")]
 use schemars::JsonSchema;
@@ -21,6 +21,7 @@ use serde::{Deserialize, Serialize};
     plural = "groups",
     singular = "kanidmgroup",
     shortname = "kg",
+    category = "kaniop",
     namespaced,
     status = "KanidmGroupStatus",
     doc = r#"The Kanidm group custom resource definition (CRD) defines a group in Kanidm.
@@ -51,6 +52,11 @@ pub struct KanidmGroupSpec {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub members: Option<Vec<String>>,
 
+    /// External source of the group members. When defined, members are fetched from it on every
+    /// reconcile and synchronized into Kanidm, ignoring `members`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_source: Option<KanidmGroupExternalSource>,
+
     /// POSIX attributes for the group account. When specified, the operator will activate them.
     /// If omitted, the operator retains the attributes in the database but ceases to manage them.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -70,6 +76,31 @@ impl KanidmResource for KanidmGroup {
     }
 }
 
+impl ReadyStatus for KanidmGroup {
+    #[inline]
+    fn ready(&self) -> Option<bool> {
+        self.status.as_ref().map(|status| status.ready)
+    }
+}
+
+/// External system where the group members are maintained. Exactly one of `configMapRef` or
+/// `url` has to be defined.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmGroupExternalSource {
+    /// Key of a ConfigMap, in the group namespace, containing a member name or SPN per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_map_ref: Option<ConfigMapKeySelector>,
+
+    /// URL of a SCIM Group resource. Members are read from the `value` of its `members`.
+    /// It has to start with one of the prefixes allowed by the operator
+    /// `--external-source-allowed-url` option.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
 /// Kanidm has features that enable its accounts and groups to be consumed on POSIX-like machines,
 /// such as Linux, FreeBSD or others. Both service accounts and person accounts can be used on POSIX
 /// systems.
@@ -114,10 +145,78 @@ pub struct KanidmGroupStatus {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub conditions: Option<Vec<Condition>>,
 
+    /// The group exists and all its conditions, except `PosixInitialized`, are true.
     pub ready: bool,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub gid: Option<u32>,
 
+    /// Last time the members of the external source were synchronized into Kanidm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_source_sync_time: Option<Time>,
+
     pub kanidm_ref: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::KanidmGroupPosixAttributes;
+
+    use kanidm_proto::{constants::ATTR_GIDNUMBER, v1::Entry};
+
+    #[test]
+    fn test_posix_attributes_eq_set_gidnumber() {
+        let desired = KanidmGroupPosixAttributes {
+            gidnumber: Some(1000),
+        };
+
+        assert_eq!(
+            desired,
+            KanidmGroupPosixAttributes {
+                gidnumber: Some(1000)
+            }
+        );
+        assert_ne!(
+            desired,
+            KanidmGroupPosixAttributes {
+                gidnumber: Some(2000)
+            }
+        );
+        assert_ne!(desired, KanidmGroupPosixAttributes { gidnumber: None });
+    }
+
+    #[test]
+    fn test_posix_attributes_eq_unmanaged_gidnumber() {
+        let desired = KanidmGroupPosixAttributes { gidnumber: None };
+
+        assert_eq!(
+            desired,
+            KanidmGroupPosixAttributes {
+                gidnumber: Some(1000)
+            }
+        );
+        assert_eq!(desired, KanidmGroupPosixAttributes { gidnumber: None });
+    }
+
+    #[test]
+    fn test_posix_attributes_from_entry() {
+        let mut entry = Entry::default();
+        assert_eq!(
+            KanidmGroupPosixAttributes::from(entry.clone()).gidnumber,
+            None
+        );
+
+        entry
+            .attrs
+            .insert(ATTR_GIDNUMBER.to_string(), vec!["1000".to_string()]);
+        assert_eq!(
+            KanidmGroupPosixAttributes::from(entry.clone()).gidnumber,
+            Some(1000)
+        );
+
+        entry
+            .attrs
+            .insert(ATTR_GIDNUMBER.to_string(), vec!["invalid".to_string()]);
+        assert_eq!(KanidmGroupPosixAttributes::from(entry).gidnumber, None);
+    }
+}
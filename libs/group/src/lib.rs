@@ -1,4 +1,8 @@
+#[cfg(feature = "client")]
 pub mod controller;
 #[rustfmt::skip]
 pub mod crd;
+#[cfg(feature = "client")]
 pub mod reconcile;
+#[cfg(feature = "client")]
+pub mod source;
@@ -1,9 +1,11 @@
 use crate::crd::{KanidmGroup, KanidmGroupPosixAttributes, KanidmGroupStatus};
+use crate::source::members_diff;
 
+use kaniop_k8s_util::resources::is_status_unchanged;
 use kaniop_k8s_util::types::{compare_names, get_first_cloned};
-use kaniop_operator::controller::kanidm::KanidmResource;
+use kaniop_operator::controller::kanidm::{KanidmApiLimiter, KanidmResource};
 use kaniop_operator::controller::{
-    context::{Context, IdmClientContext},
+    context::{out_of_sync_conditions, Context, IdmClientContext},
     DEFAULT_RECONCILE_INTERVAL,
 };
 use kaniop_operator::error::{Error, Result};
@@ -22,8 +24,8 @@ use kube::api::{Api, Patch, PatchParams};
 use kube::runtime::controller::Action;
 use kube::runtime::events::{Event, EventType};
 use kube::runtime::finalizer::{finalizer, Event as Finalizer};
-use kube::{Resource, ResourceExt};
-use tracing::{debug, field, info, instrument, trace, warn, Span};
+use kube::ResourceExt;
+use tracing::{debug, field, info, instrument, trace, Span};
 
 pub static GROUP_OPERATOR_NAME: &str = "kanidmgroups.kaniop.rs";
 pub static GROUP_FINALIZER: &str = "kanidms.kaniop.rs/group";
@@ -34,6 +36,7 @@ const TYPE_MANAGED_UPDATED: &str = "ManagedUpdated";
 const TYPE_MEMBERS_UPDATED: &str = "MembersUpdated";
 const TYPE_POSIX_INITIALIZED: &str = "PosixInitialized";
 const TYPE_POSIX_UPDATED: &str = "PosixUpdated";
+const TYPE_SOURCE_SYNCED: &str = "SourceSynced";
 const REASON_ATTRIBUTE_MATCH: &str = "AttributeMatch";
 const REASON_ATTRIBUTE_NOT_MATCH: &str = "AttributeNotMatch";
 const REASON_ATTRIBUTES_MATCH: &str = "AttributesMatch";
@@ -41,6 +44,15 @@ const REASON_ATTRIBUTES_NOT_MATCH: &str = "AttributesNotMatch";
 const CONDITION_TRUE: &str = "True";
 const CONDITION_FALSE: &str = "False";
 
+/// Conditions that reconcile fixes when they are false
+const SYNC_CONDITIONS: [&str; 5] = [
+    TYPE_EXISTS,
+    TYPE_MAIL_UPDATED,
+    TYPE_MEMBERS_UPDATED,
+    TYPE_SOURCE_SYNCED,
+    TYPE_POSIX_UPDATED,
+];
+
 #[instrument(skip(ctx, group))]
 pub async fn reconcile_group(
     group: Arc<KanidmGroup>,
@@ -53,20 +65,43 @@ pub async fn reconcile_group(
 
     // safe unwrap: group is namespaced scoped
     let namespace = group.get_namespace();
-    let kanidm_client = ctx.get_idm_client(&group).await?;
-    let status = group
-        .update_status(kanidm_client.clone(), ctx.clone())
-        .await
-        .map_err(|e| {
-            debug!(msg = "failed to reconcile status", %e);
-            ctx.metrics.status_update_errors_inc();
-            e
-        })?;
+    // members are not needed to delete the group
+    let source_members = match group.spec.external_source.as_ref() {
+        Some(source) if group.metadata.deletion_timestamp.is_none() => {
+            Some(source.fetch_members(&ctx, &namespace).await)
+        }
+        _ => None,
+    };
     let persons_api: Api<KanidmGroup> = Api::namespaced(ctx.client.clone(), &namespace);
+    let (kanidm_client, status) = match group
+        .client_and_status(ctx.clone(), source_members.as_ref())
+        .await
+    {
+        Ok(client_and_status) => client_and_status,
+        // a deleted or unreachable Kanidm must not block the deletion forever
+        Err(e) if group.metadata.deletion_timestamp.is_some() => {
+            return ctx
+                .cleanup_unavailable(&persons_api, GROUP_FINALIZER, group, e)
+                .await
+                .map_err(|e| {
+                    Error::FinalizerError("failed on group finalizer".to_string(), Box::new(e))
+                });
+        }
+        Err(e) => return Err(e),
+    };
     finalizer(&persons_api, GROUP_FINALIZER, group, |event| async {
         match event {
-            Finalizer::Apply(p) => p.reconcile(kanidm_client, status, ctx).await,
-            Finalizer::Cleanup(p) => p.cleanup(kanidm_client, status).await,
+            Finalizer::Apply(p) => {
+                p.reconcile(kanidm_client, status, ctx, source_members.as_ref())
+                    .await
+            }
+            Finalizer::Cleanup(p) => {
+                let result = ctx
+                    .kanidm_api_limiter(&p)
+                    .run(p.cleanup(kanidm_client, status))
+                    .await;
+                ctx.cleanup_with_grace(&p, result).await
+            }
         }
     })
     .await
@@ -80,33 +115,54 @@ impl KanidmGroup {
         self.namespace().unwrap()
     }
 
+    async fn client_and_status(
+        &self,
+        ctx: Arc<Context<KanidmGroup>>,
+        source_members: Option<&Result<Vec<String>>>,
+    ) -> Result<(Arc<KanidmClient>, KanidmGroupStatus)> {
+        let kanidm_client = ctx.get_idm_client(self).await?;
+        let status = self
+            .update_status(kanidm_client.clone(), ctx.clone(), source_members)
+            .await
+            .map_err(|e| {
+                debug!(msg = "failed to reconcile status", %e);
+                ctx.metrics.status_update_errors_inc();
+                e
+            })?;
+        Ok((kanidm_client, status))
+    }
+
     #[inline]
     async fn reconcile(
         &self,
         kanidm_client: Arc<KanidmClient>,
         status: KanidmGroupStatus,
         ctx: Arc<Context<KanidmGroup>>,
+        source_members: Option<&Result<Vec<String>>>,
     ) -> Result<Action> {
-        match self.internal_reconcile(kanidm_client, status).await {
+        match self
+            .internal_reconcile(
+                kanidm_client,
+                status,
+                &ctx.kanidm_api_limiter(self),
+                source_members,
+            )
+            .await
+        {
             Ok(action) => Ok(action),
             Err(e) => match e {
                 Error::KanidmClientError(_, _) => {
-                    ctx.recorder
-                        .publish(
-                            &Event {
-                                type_: EventType::Warning,
-                                reason: "KanidmError".to_string(),
-                                note: Some(format!("{e:?}")),
-                                action: "KanidmRequest".to_string(),
-                                secondary: None,
-                            },
-                            &self.object_ref(&()),
-                        )
-                        .await
-                        .map_err(|e| {
-                            warn!(msg = "failed to publish KanidmError event", %e);
-                            Error::KubeError("failed to publish event".to_string(), e)
-                        })?;
+                    ctx.publish_event(
+                        self,
+                        Event {
+                            type_: EventType::Warning,
+                            reason: "KanidmError".to_string(),
+                            note: Some(format!("{e:?}")),
+                            action: "KanidmRequest".to_string(),
+                            secondary: None,
+                        },
+                    )
+                    .await?;
                     Err(e)
                 }
                 _ => Err(e),
@@ -119,11 +175,14 @@ impl KanidmGroup {
         &self,
         kanidm_client: Arc<KanidmClient>,
         status: KanidmGroupStatus,
+        limiter: &KanidmApiLimiter,
+        source_members: Option<&Result<Vec<String>>>,
     ) -> Result<Action> {
         let name = &self.name_any();
+        // each stage sends a single request to Kanidm
         let mut require_status_update = false;
         if is_group_false(TYPE_EXISTS, status.clone()) {
-            self.create(&kanidm_client, name).await?;
+            limiter.run(self.create(&kanidm_client, name)).await?;
             require_status_update = true;
         }
 
@@ -135,23 +194,42 @@ impl KanidmGroup {
         // }
 
         if is_group_false(TYPE_MAIL_UPDATED, status.clone()) {
-            self.update_mail(&kanidm_client, name).await?;
+            limiter.run(self.update_mail(&kanidm_client, name)).await?;
             require_status_update = true;
         }
 
         if is_group_false(TYPE_MEMBERS_UPDATED, status.clone()) {
-            self.update_members(&kanidm_client, name).await?;
+            limiter
+                .run(self.update_members(&kanidm_client, name))
+                .await?;
             require_status_update = true;
         }
 
+        if let Some(Ok(members)) = source_members {
+            if is_group_false(TYPE_SOURCE_SYNCED, status.clone()) {
+                limiter
+                    .run(self.sync_source_members(&kanidm_client, name, members))
+                    .await?;
+                require_status_update = true;
+            }
+        }
+
         if is_group_false(TYPE_POSIX_UPDATED, status.clone())
             || (is_group_false(TYPE_POSIX_INITIALIZED, status.clone())
                 && is_group(TYPE_POSIX_UPDATED, status.clone()))
         {
-            self.update_posix_attributes(&kanidm_client, name).await?;
+            limiter
+                .run(self.update_posix_attributes(&kanidm_client, name))
+                .await?;
             require_status_update = true;
         }
 
+        if let Some(Err(e)) = source_members {
+            return Err(Error::MissingData(format!(
+                "failed to sync members from external source: {e}"
+            )));
+        }
+
         if require_status_update {
             trace!(msg = "status update required, requeueing in 500ms");
             Ok(Action::requeue(Duration::from_millis(500)))
@@ -271,6 +349,32 @@ impl KanidmGroup {
         Ok(())
     }
 
+    async fn sync_source_members(
+        &self,
+        kanidm_client: &KanidmClient,
+        name: &str,
+        members: &[String],
+    ) -> Result<()> {
+        debug!(msg = format!("sync {ATTR_MEMBER} attribute from external source"));
+        let members = members.iter().map(|m| m.as_str()).collect::<Vec<&str>>();
+
+        match members.is_empty() {
+            true => kanidm_client.idm_group_purge_members(name).await,
+            false => kanidm_client.idm_group_set_members(name, &members).await,
+        }
+        .map_err(|e| {
+            Error::KanidmClientError(
+                format!(
+                    "failed to sync {ATTR_MEMBER} for {name} from {namespace}/{kanidm}",
+                    namespace = self.kanidm_namespace(),
+                    kanidm = self.kanidm_name(),
+                ),
+                Box::new(e),
+            )
+        })?;
+        Ok(())
+    }
+
     async fn update_posix_attributes(
         &self,
         kanidm_client: &KanidmClient,
@@ -327,12 +431,14 @@ impl KanidmGroup {
         &self,
         kanidm_client: Arc<KanidmClient>,
         ctx: Arc<Context<KanidmGroup>>,
+        source_members: Option<&Result<Vec<String>>>,
     ) -> Result<KanidmGroupStatus> {
         // safe unwrap: person is namespaced scoped
         let namespace = self.get_namespace();
         let name = self.name_any();
-        let current_group = kanidm_client
-            .idm_group_get(&name)
+        let current_group = ctx
+            .kanidm_api_limiter(self)
+            .run(kanidm_client.idm_group_get(&name))
             .map_err(|e| {
                 Error::KanidmClientError(
                     format!(
@@ -345,7 +451,20 @@ impl KanidmGroup {
             })
             .await?;
 
-        let status = self.generate_status(current_group)?;
+        let mut status = self.generate_status(current_group, source_members)?;
+        if let Some(stalled) = ctx
+            .stalled_condition(
+                self,
+                out_of_sync_conditions(status.conditions.as_deref(), &SYNC_CONDITIONS),
+            )
+            .await
+        {
+            status.conditions.get_or_insert_with(Vec::new).push(stalled);
+        }
+        if is_status_unchanged(self.status.as_ref(), &status) {
+            trace!(msg = "status unchanged, skipping patch");
+            return Ok(status);
+        }
         let status_patch = Patch::Apply(KanidmGroup {
             status: Some(status.clone()),
             ..KanidmGroup::default()
@@ -366,7 +485,11 @@ impl KanidmGroup {
         Ok(status)
     }
 
-    fn generate_status(&self, group: Option<Entry>) -> Result<KanidmGroupStatus> {
+    fn generate_status(
+        &self,
+        group: Option<Entry>,
+        source_members: Option<&Result<Vec<String>>>,
+    ) -> Result<KanidmGroupStatus> {
         let now = Utc::now();
         match group {
             Some(g) => {
@@ -426,29 +549,78 @@ impl KanidmGroup {
                     }
                 });
 
-                let members_condition = self.spec.members.as_ref().map(|members| {
-                    if compare_names(members, g.attrs.get(ATTR_MEMBER).unwrap_or(&Vec::new())) {
-                        Condition {
-                            type_: TYPE_MEMBERS_UPDATED.to_string(),
-                            status: CONDITION_TRUE.to_string(),
-                            reason: REASON_ATTRIBUTES_MATCH.to_string(),
-                            message: format!("Group exists with desired {ATTR_MEMBER} attributes."),
-                            last_transition_time: Time(now),
-                            observed_generation: self.metadata.generation,
+                // members are ignored when they come from an external source
+                let members_condition = self
+                    .spec
+                    .members
+                    .as_ref()
+                    .filter(|_| self.spec.external_source.is_none())
+                    .map(|members| {
+                        if compare_names(members, g.attrs.get(ATTR_MEMBER).unwrap_or(&Vec::new())) {
+                            Condition {
+                                type_: TYPE_MEMBERS_UPDATED.to_string(),
+                                status: CONDITION_TRUE.to_string(),
+                                reason: REASON_ATTRIBUTES_MATCH.to_string(),
+                                message: format!(
+                                    "Group exists with desired {ATTR_MEMBER} attributes."
+                                ),
+                                last_transition_time: Time(now),
+                                observed_generation: self.metadata.generation,
+                            }
+                        } else {
+                            Condition {
+                                type_: TYPE_MEMBERS_UPDATED.to_string(),
+                                status: CONDITION_FALSE.to_string(),
+                                reason: REASON_ATTRIBUTES_NOT_MATCH.to_string(),
+                                message: format!(
+                                    "Group exists with different {ATTR_MEMBER} attributes."
+                                ),
+                                last_transition_time: Time(now),
+                                observed_generation: self.metadata.generation,
+                            }
                         }
-                    } else {
-                        Condition {
-                            type_: TYPE_MEMBERS_UPDATED.to_string(),
-                            status: CONDITION_FALSE.to_string(),
-                            reason: REASON_ATTRIBUTES_NOT_MATCH.to_string(),
-                            message: format!(
-                                "Group exists with different {ATTR_MEMBER} attributes."
-                            ),
-                            last_transition_time: Time(now),
-                            observed_generation: self.metadata.generation,
+                    });
+                let source_condition = source_members.map(|source_members| match source_members {
+                    Ok(members) => {
+                        let (to_add, to_remove) = members_diff(
+                            members,
+                            g.attrs.get(ATTR_MEMBER).unwrap_or(&Vec::new()),
+                        );
+                        if to_add.is_empty() && to_remove.is_empty() {
+                            Condition {
+                                type_: TYPE_SOURCE_SYNCED.to_string(),
+                                status: CONDITION_TRUE.to_string(),
+                                reason: REASON_ATTRIBUTES_MATCH.to_string(),
+                                message: "Group members are synced with the external source."
+                                    .to_string(),
+                                last_transition_time: Time(now),
+                                observed_generation: self.metadata.generation,
+                            }
+                        } else {
+                            Condition {
+                                type_: TYPE_SOURCE_SYNCED.to_string(),
+                                status: CONDITION_FALSE.to_string(),
+                                reason: REASON_ATTRIBUTES_NOT_MATCH.to_string(),
+                                message: format!(
+                                    "Group members differ from the external source: {} to add, {} to remove.",
+                                    to_add.len(),
+                                    to_remove.len()
+                                ),
+                                last_transition_time: Time(now),
+                                observed_generation: self.metadata.generation,
+                            }
                         }
                     }
+                    Err(e) => Condition {
+                        type_: TYPE_SOURCE_SYNCED.to_string(),
+                        status: CONDITION_FALSE.to_string(),
+                        reason: "SourceUnavailable".to_string(),
+                        message: format!("Failed to fetch members from the external source: {e}"),
+                        last_transition_time: Time(now),
+                        observed_generation: self.metadata.generation,
+                    },
                 });
+                let last_source_sync_time = self.last_source_sync_time(source_condition.as_ref());
                 let current_group_posix = KanidmGroupPosixAttributes::from(g);
                 let posix_initialized_condition = if current_group_posix.gidnumber.is_some() {
                     Condition {
@@ -496,6 +668,7 @@ impl KanidmGroup {
                     .chain(managed_by_condition)
                     .chain(mail_condition)
                     .chain(members_condition)
+                    .chain(source_condition)
                     .chain(posix_updated_condition)
                     .collect::<Vec<_>>();
                 let status = conditions
@@ -506,6 +679,7 @@ impl KanidmGroup {
                     conditions: Some(conditions),
                     ready: status,
                     gid: current_group_posix.gidnumber,
+                    last_source_sync_time,
                     kanidm_ref: self.kanidm_ref(),
                 })
             }
@@ -522,6 +696,7 @@ impl KanidmGroup {
                     conditions: Some(conditions),
                     ready: false,
                     gid: None,
+                    last_source_sync_time: None,
                     kanidm_ref: self.kanidm_ref(),
                 })
             }
@@ -529,6 +704,24 @@ impl KanidmGroup {
     }
 }
 
+impl KanidmGroup {
+    /// Time of the last sync with the external source: kept while members stay synced, and set to
+    /// the condition time when they become synced.
+    fn last_source_sync_time(&self, source_condition: Option<&Condition>) -> Option<Time> {
+        let previous = self.status.as_ref();
+        let last_source_sync_time = previous.and_then(|s| s.last_source_sync_time.clone());
+        match source_condition {
+            Some(c) if c.status == CONDITION_TRUE => {
+                match previous.is_some_and(|s| is_group(TYPE_SOURCE_SYNCED, s.clone())) {
+                    true => last_source_sync_time.or(Some(c.last_transition_time.clone())),
+                    false => Some(c.last_transition_time.clone()),
+                }
+            }
+            _ => last_source_sync_time,
+        }
+    }
+}
+
 pub fn is_group(type_: &str, status: KanidmGroupStatus) -> bool {
     status
         .conditions
@@ -0,0 +1,249 @@
+use crate::crd::{KanidmGroup, KanidmGroupExternalSource};
+
+use kaniop_k8s_util::types::normalize_spn;
+use kaniop_operator::controller::context::Context;
+use kaniop_operator::error::{Error, Result};
+
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::Api;
+use serde::Deserialize;
+
+const SOURCE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// HTTP client shared by every URL source, built on first use.
+static SOURCE_CLIENT: OnceLock<std::result::Result<reqwest::Client, String>> = OnceLock::new();
+
+/// SCIM Group resource, only the fields used to read its members.
+#[derive(Deserialize, Debug)]
+struct ScimGroup {
+    #[serde(default)]
+    members: Vec<ScimMember>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScimMember {
+    value: String,
+}
+
+impl KanidmGroupExternalSource {
+    /// Fetch the member names or SPNs from the source. URLs have to start with one of the
+    /// prefixes allowed in the operator.
+    pub async fn fetch_members(
+        &self,
+        ctx: &Context<KanidmGroup>,
+        namespace: &str,
+    ) -> Result<Vec<String>> {
+        match (self.config_map_ref.as_ref(), self.url.as_ref()) {
+            (Some(config_map_ref), None) => {
+                let name = &config_map_ref.name;
+                let config_map = Api::<ConfigMap>::namespaced(ctx.client.clone(), namespace)
+                    .get(name)
+                    .await
+                    .map_err(|e| {
+                        Error::KubeError(format!("failed to get configmap: {namespace}/{name}"), e)
+                    })?;
+                let members = config_map
+                    .data
+                    .and_then(|mut data| data.remove(&config_map_ref.key))
+                    .ok_or_else(|| {
+                        Error::MissingData(format!(
+                            "missing {key} in configmap: {namespace}/{name}",
+                            key = config_map_ref.key,
+                        ))
+                    })?;
+                Ok(parse_member_list(&members))
+            }
+            (None, Some(url)) => {
+                if !is_url_allowed(url, &ctx.external_source_allowed_urls) {
+                    return Err(Error::ValidationError(format!(
+                        "url {url} is not allowed in externalSource, check the \
+                        --external-source-allowed-url operator option"
+                    )));
+                }
+                let response = source_client(ctx.ca_bundle.as_deref())?
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| Error::MissingData(format!("failed to get {url}: {e}")))?;
+                let body = response
+                    .text()
+                    .await
+                    .map_err(|e| Error::MissingData(format!("failed to read {url}: {e}")))?;
+                parse_scim_members(&body)
+            }
+            _ => Err(Error::ValidationError(
+                "exactly one of configMapRef or url has to be defined in externalSource"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+/// Whether `url` starts with any of the `allowed` prefixes. Prefixes match whole path segments,
+/// so `https://example.com/scim` allows neither `https://example.com/scimx` nor
+/// `https://example.com.evil.com`.
+pub fn is_url_allowed(url: &str, allowed: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|prefix| match url.strip_prefix(prefix.as_str()) {
+            Some(rest) => {
+                rest.is_empty() || prefix.ends_with('/') || rest.starts_with(['/', '?', '#'])
+            }
+            None => false,
+        })
+}
+
+fn source_client(ca_bundle: Option<&[u8]>) -> Result<reqwest::Client> {
+    SOURCE_CLIENT
+        .get_or_init(|| {
+            let builder = reqwest::Client::builder()
+                .timeout(SOURCE_TIMEOUT)
+                // redirects could point to URLs which are not allowed
+                .redirect(reqwest::redirect::Policy::none());
+            let builder = match ca_bundle {
+                Some(ca_bundle) => reqwest::Certificate::from_pem_bundle(ca_bundle)
+                    .map_err(|e| format!("failed to parse CA bundle: {e}"))?
+                    .into_iter()
+                    .fold(builder, |builder, cert| builder.add_root_certificate(cert)),
+                None => builder,
+            };
+            builder
+                .build()
+                .map_err(|e| format!("failed to build HTTP client: {e}"))
+        })
+        .clone()
+        .map_err(Error::MissingData)
+}
+
+/// Members listed one per line, ignoring empty lines and `#` comments.
+pub fn parse_member_list(members: &str) -> Vec<String> {
+    members
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Members of a SCIM Group resource.
+pub fn parse_scim_members(body: &str) -> Result<Vec<String>> {
+    serde_json::from_str::<ScimGroup>(body)
+        .map(|group| group.members.into_iter().map(|m| m.value).collect())
+        .map_err(|e| Error::SerializationError("failed to parse SCIM group".to_string(), e))
+}
+
+/// Members to add to and to remove from the `current` members of the group, respectively, to
+/// synchronize it with the `desired` ones. Names and SPNs are compared ignoring case and domain.
+pub fn members_diff(desired: &[String], current: &[String]) -> (Vec<String>, Vec<String>) {
+    let desired_names = desired
+        .iter()
+        .map(|m| normalize_spn(m))
+        .collect::<BTreeSet<_>>();
+    let current_names = current
+        .iter()
+        .map(|m| normalize_spn(m))
+        .collect::<BTreeSet<_>>();
+    (
+        desired
+            .iter()
+            .filter(|m| !current_names.contains(&normalize_spn(m)))
+            .cloned()
+            .collect(),
+        current
+            .iter()
+            .filter(|m| !desired_names.contains(&normalize_spn(m)))
+            .cloned()
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_url_allowed, members_diff, parse_member_list, parse_scim_members};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_member_list() {
+        assert_eq!(
+            parse_member_list("alice\n\n# former admins\n  bob@idm.example.com  \n"),
+            names(&["alice", "bob@idm.example.com"])
+        );
+        assert!(parse_member_list("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_scim_members() {
+        let body = r#"{
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:Group"],
+            "displayName": "admins",
+            "members": [
+                {"value": "alice", "display": "Alice"},
+                {"value": "bob@idm.example.com"}
+            ]
+        }"#;
+        assert_eq!(
+            parse_scim_members(body).unwrap(),
+            names(&["alice", "bob@idm.example.com"])
+        );
+        assert!(parse_scim_members(r#"{"displayName": "empty"}"#)
+            .unwrap()
+            .is_empty());
+        assert!(parse_scim_members("not json").is_err());
+    }
+
+    #[test]
+    fn test_is_url_allowed() {
+        let allowed = names(&["https://scim.example.com/Groups", "http://directory/"]);
+        assert!(is_url_allowed("https://scim.example.com/Groups", &allowed));
+        assert!(is_url_allowed(
+            "https://scim.example.com/Groups/admins",
+            &allowed
+        ));
+        assert!(is_url_allowed("http://directory/admins", &allowed));
+        assert!(!is_url_allowed(
+            "https://scim.example.com/GroupsX",
+            &allowed
+        ));
+        assert!(!is_url_allowed("https://scim.example.com/Users", &allowed));
+        assert!(!is_url_allowed(
+            "http://directory.evil.com/admins",
+            &allowed
+        ));
+        assert!(!is_url_allowed("http://169.254.169.254/", &allowed));
+        assert!(!is_url_allowed("https://scim.example.com/Groups", &[]));
+    }
+
+    #[test]
+    fn test_members_diff() {
+        let (to_add, to_remove) = members_diff(
+            &names(&["Alice", "carol"]),
+            &names(&["alice@idm.example.com", "bob@idm.example.com"]),
+        );
+        assert_eq!(to_add, names(&["carol"]));
+        assert_eq!(to_remove, names(&["bob@idm.example.com"]));
+    }
+
+    #[test]
+    fn test_members_diff_in_sync() {
+        let (to_add, to_remove) = members_diff(
+            &names(&["alice", "bob"]),
+            &names(&["bob@idm.example.com", "alice@idm.example.com"]),
+        );
+        assert!(to_add.is_empty());
+        assert!(to_remove.is_empty());
+    }
+
+    #[test]
+    fn test_members_diff_empty_source() {
+        let (to_add, to_remove) = members_diff(&[], &names(&["alice@idm.example.com"]));
+        assert!(to_add.is_empty());
+        assert_eq!(to_remove, names(&["alice@idm.example.com"]));
+    }
+}
@@ -1,5 +1,18 @@
+//! Kubernetes and Kanidm helpers shared by the Kaniop controllers.
+//!
+//! The functions re-exported at the crate root are the stable public API. The modules are
+//! public too, but their remaining items are implementation details of the operator.
+
 pub mod client;
 pub mod metrics;
 pub mod resources;
 pub mod types;
 mod url;
+
+pub use resources::{
+    controller_owner_references, is_status_unchanged, is_subset, merge_containers,
+};
+pub use types::{
+    compare_names, compare_urls, compare_with_spn, diff_set, get_first_as_bool, get_first_cloned,
+    normalize_spn, normalize_url, parse_quantity, parse_time, short_type_name,
+};
@@ -1,6 +1,45 @@
+use crate::types::parse_quantity;
+
 use json_patch::merge;
 use k8s_openapi::api::core::v1::Container;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::Resource;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Fields holding `Quantity` values, or maps of them, which the API server canonicalizes.
+const QUANTITY_FIELDS: [&str; 4] = ["capacity", "limits", "requests", "sizeLimit"];
+
+/// Owner references for a child resource created by the controller of `owner`.
+///
+/// Kubernetes garbage collects the child when the owner is deleted, even if the finalizer is
+/// bypassed, and foreground deletion of the owner waits until the child is gone.
+pub fn controller_owner_references<K>(owner: &K) -> Option<Vec<OwnerReference>>
+where
+    K: Resource<DynamicType = ()>,
+{
+    owner.controller_owner_ref(&()).map(|oref| {
+        vec![OwnerReference {
+            block_owner_deletion: Some(true),
+            ..oref
+        }]
+    })
+}
 
+/// Merge `container` into the container with the same name, or append it if there is none.
+///
+/// Fields set in `container` take precedence over the existing ones.
+///
+/// ```
+/// use k8s_openapi::api::core::v1::Container;
+/// use kaniop_k8s_util::merge_containers;
+///
+/// let sidecar = Container { name: "sidecar".to_string(), ..Container::default() };
+/// let kanidm = Container { name: "kanidm".to_string(), ..Container::default() };
+/// let containers = merge_containers(Some(vec![sidecar]), &kanidm);
+/// assert_eq!(containers.len(), 2);
+/// ```
 pub fn merge_containers(
     containers: Option<Vec<Container>>,
     container: &Container,
@@ -34,9 +73,125 @@ pub fn merge_containers(
         .collect()
 }
 
+/// Whether the `new` status is the same as the `current` one, ignoring the `lastTransitionTime`
+/// of the conditions. Used to skip status patches that would only bump the resource version.
+///
+/// ```
+/// use kaniop_k8s_util::is_status_unchanged;
+/// use serde_json::json;
+///
+/// let current = json!({"conditions": [
+///     {"type": "Ready", "status": "True", "lastTransitionTime": "2024-01-01T00:00:00Z"}
+/// ]});
+/// let new = json!({"conditions": [
+///     {"type": "Ready", "status": "True", "lastTransitionTime": "2024-01-02T00:00:00Z"}
+/// ]});
+/// assert!(is_status_unchanged(Some(&current), &new));
+/// ```
+pub fn is_status_unchanged<S: Serialize>(current: Option<&S>, new: &S) -> bool {
+    let Some(current) = current else {
+        return false;
+    };
+    match (serde_json::to_value(current), serde_json::to_value(new)) {
+        (Ok(mut current), Ok(mut new)) => {
+            remove_transition_times(&mut current);
+            remove_transition_times(&mut new);
+            current == new
+        }
+        _ => false,
+    }
+}
+
+/// Whether every field set in `subset` has the same value in `superset`. Arrays must have the
+/// same length and match element by element, while `null`, empty arrays and empty objects in
+/// `subset` match missing fields. Used to compare desired resources with live ones, which the
+/// API server fills with defaulted fields. Quantities under resource `limits`, `requests`,
+/// `capacity` and `sizeLimit` are compared by value, because the API server canonicalizes them
+/// (e.g. `1000m` is stored as `1`).
+///
+/// ```
+/// use kaniop_k8s_util::is_subset;
+/// use serde_json::json;
+///
+/// let desired = json!({"replicas": 1, "volumes": []});
+/// let live = json!({"replicas": 1, "revisionHistoryLimit": 10});
+/// assert!(is_subset(&desired, &live));
+/// assert!(!is_subset(&json!({"replicas": 2}), &live));
+/// assert!(is_subset(
+///     &json!({"resources": {"limits": {"cpu": "1000m"}}}),
+///     &json!({"resources": {"limits": {"cpu": "1"}}})
+/// ));
+/// ```
+pub fn is_subset<S: Serialize, T: Serialize>(subset: &S, superset: &T) -> bool {
+    match (serde_json::to_value(subset), serde_json::to_value(superset)) {
+        (Ok(subset), Ok(superset)) => is_value_subset(&subset, Some(&superset), false),
+        _ => false,
+    }
+}
+
+fn is_value_subset(subset: &Value, superset: Option<&Value>, quantity: bool) -> bool {
+    match (subset, superset) {
+        (Value::Null, None | Some(Value::Null)) => true,
+        (Value::Array(values), None) => values.is_empty(),
+        (Value::Object(map), None) => map.is_empty(),
+        (Value::Object(map), Some(Value::Object(superset_map))) => {
+            map.iter().all(|(key, value)| {
+                is_value_subset(
+                    value,
+                    superset_map.get(key),
+                    quantity || QUANTITY_FIELDS.contains(&key.as_str()),
+                )
+            })
+        }
+        (Value::Array(values), Some(Value::Array(superset_values))) => {
+            values.len() == superset_values.len()
+                && values
+                    .iter()
+                    .zip(superset_values)
+                    .all(|(value, superset_value)| {
+                        is_value_subset(value, Some(superset_value), quantity)
+                    })
+        }
+        (Value::String(value), Some(Value::String(superset_value)))
+            if quantity && value != superset_value =>
+        {
+            is_same_quantity(value, superset_value)
+        }
+        (value, superset_value) => Some(value) == superset_value,
+    }
+}
+
+fn is_same_quantity(a: &str, b: &str) -> bool {
+    match (
+        parse_quantity(&Quantity(a.to_string())),
+        parse_quantity(&Quantity(b.to_string())),
+    ) {
+        (Some(a), Some(b)) => (a - b).abs() <= f64::EPSILON * a.abs().max(b.abs()),
+        _ => false,
+    }
+}
+
+fn remove_transition_times(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.remove("lastTransitionTime");
+            map.values_mut().for_each(remove_transition_times);
+        }
+        Value::Array(values) => values.iter_mut().for_each(remove_transition_times),
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{merge_containers, Container};
+    use super::{
+        controller_owner_references, is_status_unchanged, is_subset, merge_containers, Container,
+    };
+
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+    use k8s_openapi::chrono::{TimeZone, Utc};
+    use kube::api::ObjectMeta;
 
     const CONTAINER_NAME: &str = "kanidm";
 
@@ -76,4 +231,145 @@ mod test {
         assert_eq!(containers.len(), 2);
         assert!(containers.iter().any(|c| c.name == CONTAINER_NAME));
     }
+
+    #[test]
+    fn test_controller_owner_references() {
+        let owner = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("owner".to_string()),
+                uid: Some("00000000-0000-0000-0000-000000000000".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        let owner_references = controller_owner_references(&owner).unwrap();
+
+        assert_eq!(owner_references.len(), 1);
+        assert_eq!(owner_references[0].kind, "ConfigMap");
+        assert_eq!(owner_references[0].name, "owner");
+        assert_eq!(owner_references[0].controller, Some(true));
+        assert_eq!(owner_references[0].block_owner_deletion, Some(true));
+    }
+
+    #[test]
+    fn test_controller_owner_references_without_uid() {
+        let owner = ConfigMap::default();
+        assert_eq!(controller_owner_references(&owner), None);
+    }
+
+    #[derive(serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TestStatus {
+        conditions: Vec<Condition>,
+        ready: bool,
+    }
+
+    fn condition(status: &str, seconds: i64) -> Condition {
+        Condition {
+            type_: "Ready".to_string(),
+            status: status.to_string(),
+            reason: "Test".to_string(),
+            message: "test".to_string(),
+            last_transition_time: Time(Utc.timestamp_opt(seconds, 0).unwrap()),
+            observed_generation: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_is_status_unchanged_ignores_transition_time() {
+        let current = TestStatus {
+            conditions: vec![condition("True", 0)],
+            ready: true,
+        };
+        let new = TestStatus {
+            conditions: vec![condition("True", 60)],
+            ready: true,
+        };
+        assert!(is_status_unchanged(Some(&current), &new));
+    }
+
+    #[test]
+    fn test_is_status_unchanged_detects_changes() {
+        let current = TestStatus {
+            conditions: vec![condition("True", 0)],
+            ready: true,
+        };
+        let new_condition = TestStatus {
+            conditions: vec![condition("False", 0)],
+            ready: true,
+        };
+        assert!(!is_status_unchanged(Some(&current), &new_condition));
+
+        let new_field = TestStatus {
+            conditions: vec![condition("True", 0)],
+            ready: false,
+        };
+        assert!(!is_status_unchanged(Some(&current), &new_field));
+    }
+
+    #[test]
+    fn test_is_status_unchanged_without_current_status() {
+        let new = TestStatus {
+            conditions: Vec::new(),
+            ready: false,
+        };
+        assert!(!is_status_unchanged(None, &new));
+    }
+
+    #[test]
+    fn test_is_subset_ignores_defaulted_fields() {
+        let desired = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("test".to_string()),
+                ..ObjectMeta::default()
+            },
+            data: Some(Default::default()),
+            ..ConfigMap::default()
+        };
+        let live = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("test".to_string()),
+                resource_version: Some("1".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        assert!(is_subset(&desired, &live));
+    }
+
+    #[test]
+    fn test_is_subset_detects_changes() {
+        let desired = serde_json::json!({"containers": [{"name": "kanidm", "image": "a"}]});
+        let changed = serde_json::json!({"containers": [{"name": "kanidm", "image": "b"}]});
+        let added = serde_json::json!({"containers": [
+            {"name": "kanidm", "image": "a"},
+            {"name": "sidecar", "image": "a"}
+        ]});
+        assert!(!is_subset(&desired, &changed));
+        assert!(!is_subset(&desired, &added));
+        assert!(!is_subset(&desired, &serde_json::json!({})));
+    }
+
+    #[test]
+    fn test_is_subset_compares_quantities() {
+        let desired = serde_json::json!({"resources": {
+            "limits": {"cpu": "1000m", "memory": "1024Mi"},
+            "requests": {"cpu": "500m"}
+        }});
+        let live = serde_json::json!({"resources": {
+            "limits": {"cpu": "1", "memory": "1Gi"},
+            "requests": {"cpu": "500m"}
+        }});
+        let changed = serde_json::json!({"resources": {
+            "limits": {"cpu": "2", "memory": "1Gi"},
+            "requests": {"cpu": "500m"}
+        }});
+        assert!(is_subset(&desired, &live));
+        assert!(!is_subset(&desired, &changed));
+        // only quantity fields are compared by value
+        assert!(!is_subset(
+            &serde_json::json!({"image": "1000m"}),
+            &serde_json::json!({"image": "1"})
+        ));
+    }
 }
@@ -1,16 +1,34 @@
 use std::any::type_name;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
 
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use k8s_openapi::chrono::{DateTime, ParseError, Utc};
 use kanidm_proto::v1::Entry;
 
+/// Lowercase name part of a Kanidm SPN (`name@domain`), or the name itself if it is not an SPN.
+///
+/// ```
+/// use kaniop_k8s_util::normalize_spn;
+///
+/// assert_eq!(normalize_spn("Alice@idm.example.com"), "alice");
+/// assert_eq!(normalize_spn("alice"), "alice");
+/// ```
 #[inline]
 pub fn normalize_spn(spn: &str) -> String {
     // safe unwrap: split always returns at least one element
     spn.split('@').next().unwrap().to_lowercase()
 }
 
+/// Whether a name or SPN refers to the same entity as the lowercase `spn` returned by Kanidm.
+///
+/// ```
+/// use kaniop_k8s_util::compare_with_spn;
+///
+/// assert!(compare_with_spn("Alice", "alice@idm.example.com"));
+/// assert!(!compare_with_spn("bob@idm.example.com", "alice@idm.example.com"));
+/// ```
 #[inline]
 pub fn compare_with_spn(name_or_spn: &str, spn: &str) -> bool {
     if name_or_spn.contains("@") {
@@ -20,12 +38,29 @@ pub fn compare_with_spn(name_or_spn: &str, spn: &str) -> bool {
     }
 }
 
+/// Compare two lists of names or SPNs as sets, ignoring case and domain.
+///
+/// ```
+/// use kaniop_k8s_util::compare_names;
+///
+/// let desired = vec!["Alice".to_string(), "bob".to_string()];
+/// let current = vec!["bob@idm.example.com".to_string(), "alice@idm.example.com".to_string()];
+/// assert!(compare_names(&desired, &current));
+/// ```
 #[inline]
 pub fn compare_names(a: &[String], b: &[String]) -> bool {
     a.iter().map(|s| normalize_spn(s)).collect::<HashSet<_>>()
         == b.iter().map(|s| normalize_spn(s)).collect::<HashSet<_>>()
 }
 
+/// Serialize a URL in its canonical form, or return it unchanged if it cannot be parsed.
+///
+/// ```
+/// use kaniop_k8s_util::normalize_url;
+///
+/// assert_eq!(normalize_url("https://example.com"), "https://example.com/");
+/// assert_eq!(normalize_url("not a url"), "not a url");
+/// ```
 #[inline]
 pub fn normalize_url(url: &str) -> String {
     url::Url::parse(url)
@@ -33,6 +68,15 @@ pub fn normalize_url(url: &str) -> String {
         .unwrap_or_else(|_| url.to_string())
 }
 
+/// Compare two lists of URLs as sets, after normalizing them.
+///
+/// ```
+/// use kaniop_k8s_util::compare_urls;
+///
+/// let desired = vec!["https://example.com".to_string()];
+/// let current = vec!["https://example.com/".to_string()];
+/// assert!(compare_urls(&desired, &current));
+/// ```
 #[inline]
 pub fn compare_urls(a: &[String], b: &[String]) -> bool {
     a.iter().map(|s| normalize_url(s)).collect::<HashSet<_>>()
@@ -44,10 +88,31 @@ fn parse_datetime_from_string(date_str: &str) -> Result<DateTime<Utc>, ParseErro
     DateTime::parse_from_rfc3339(date_str).map(|dt| dt.with_timezone(&Utc))
 }
 
+/// First value of the attribute `key` of a Kanidm entry.
+///
+/// ```
+/// use kanidm_proto::v1::Entry;
+/// use kaniop_k8s_util::get_first_cloned;
+///
+/// let mut entry = Entry::default();
+/// entry.attrs.insert("name".to_string(), vec!["alice".to_string()]);
+/// assert_eq!(get_first_cloned(&entry, "name"), Some("alice".to_string()));
+/// assert_eq!(get_first_cloned(&entry, "mail"), None);
+/// ```
 pub fn get_first_cloned(entry: &Entry, key: &str) -> Option<String> {
     entry.attrs.get(key).and_then(|v| v.first().cloned())
 }
 
+/// First value of the attribute `key` of a Kanidm entry parsed as a boolean.
+///
+/// ```
+/// use kanidm_proto::v1::Entry;
+/// use kaniop_k8s_util::get_first_as_bool;
+///
+/// let mut entry = Entry::default();
+/// entry.attrs.insert("enabled".to_string(), vec!["true".to_string()]);
+/// assert_eq!(get_first_as_bool(&entry, "enabled"), Some(true));
+/// ```
 pub fn get_first_as_bool(entry: &Entry, key: &str) -> Option<bool> {
     entry
         .attrs
@@ -56,6 +121,16 @@ pub fn get_first_as_bool(entry: &Entry, key: &str) -> Option<bool> {
         .and_then(|s| s.parse().ok())
 }
 
+/// First value of the attribute `key` of a Kanidm entry parsed as an RFC 3339 time.
+///
+/// ```
+/// use kanidm_proto::v1::Entry;
+/// use kaniop_k8s_util::parse_time;
+///
+/// let mut entry = Entry::default();
+/// entry.attrs.insert("expire".to_string(), vec!["2021-09-14T12:34:56Z".to_string()]);
+/// assert!(parse_time(&entry, "expire").is_some());
+/// ```
 pub fn parse_time(entry: &Entry, key: &str) -> Option<Time> {
     entry
         .attrs
@@ -64,20 +139,137 @@ pub fn parse_time(entry: &Entry, key: &str) -> Option<Time> {
         .and_then(|s| parse_datetime_from_string(s).map(Time).ok())
 }
 
-#[inline]
-pub fn short_type_name<K>() -> Option<&'static str> {
+/// Items to add to and to remove from `current`, respectively, to make it equal to `desired`.
+///
+/// ```
+/// use std::collections::BTreeSet;
+/// use kaniop_k8s_util::diff_set;
+///
+/// let current = BTreeSet::from(["a", "b"]);
+/// let desired = BTreeSet::from(["b", "c"]);
+/// let (to_add, to_remove) = diff_set(&current, &desired);
+/// assert_eq!(to_add, BTreeSet::from([&"c"]));
+/// assert_eq!(to_remove, BTreeSet::from([&"a"]));
+/// ```
+pub fn diff_set<'a, T: Ord>(
+    current: &'a BTreeSet<T>,
+    desired: &'a BTreeSet<T>,
+) -> (BTreeSet<&'a T>, BTreeSet<&'a T>) {
+    (
+        desired.difference(current).collect(),
+        current.difference(desired).collect(),
+    )
+}
+
+/// Value of a Kubernetes quantity in base units, or `None` if it is not a valid quantity.
+///
+/// Binary (`Ki`, `Mi`, ...), decimal (`k`, `M`, ...) and exponent (`1e3`) suffixes are
+/// supported, so quantities written in different units can be compared.
+///
+/// ```
+/// use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+/// use kaniop_k8s_util::parse_quantity;
+///
+/// assert_eq!(parse_quantity(&Quantity("1Gi".to_string())), Some(1073741824.0));
+/// assert_eq!(parse_quantity(&Quantity("1.5k".to_string())), Some(1500.0));
+/// assert_eq!(parse_quantity(&Quantity("1Gb".to_string())), None);
+/// ```
+pub fn parse_quantity(quantity: &Quantity) -> Option<f64> {
+    let value = quantity.0.trim();
+    let suffix_start = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-'))
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(suffix_start);
+    let number = number.parse::<f64>().ok()?;
+    let multiplier = match suffix {
+        "" => 1.0,
+        "Ki" => 2f64.powi(10),
+        "Mi" => 2f64.powi(20),
+        "Gi" => 2f64.powi(30),
+        "Ti" => 2f64.powi(40),
+        "Pi" => 2f64.powi(50),
+        "Ei" => 2f64.powi(60),
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        exponent => {
+            let exponent = exponent.strip_prefix(['e', 'E'])?;
+            10f64.powi(exponent.parse::<i32>().ok()?)
+        }
+    };
+    Some(number * multiplier)
+}
+
+/// Type name of `K` without module paths, used in logs and events.
+///
+/// Paths are stripped from generic arguments too. If nothing is left after stripping, the full
+/// type name is returned.
+///
+/// ```
+/// use kaniop_k8s_util::short_type_name;
+///
+/// assert_eq!(short_type_name::<k8s_openapi::api::core::v1::Pod>(), "Pod");
+/// assert_eq!(
+///     short_type_name::<Option<k8s_openapi::api::core::v1::Pod>>(),
+///     "Option<Pod>"
+/// );
+/// ```
+pub fn short_type_name<K>() -> Cow<'static, str> {
     let type_name = type_name::<K>();
-    type_name.split("::").last()
+    if !type_name.contains(|c: char| !is_path_char(c)) {
+        return match type_name.rsplit("::").next() {
+            Some(short) if !short.is_empty() => Cow::Borrowed(short),
+            _ => Cow::Borrowed(type_name),
+        };
+    }
+
+    let mut short = String::with_capacity(type_name.len());
+    let mut segment_start = 0;
+    for (i, c) in type_name.char_indices() {
+        if !is_path_char(c) {
+            short.push_str(last_path_segment(&type_name[segment_start..i]));
+            short.push(c);
+            segment_start = i + c.len_utf8();
+        }
+    }
+    short.push_str(last_path_segment(&type_name[segment_start..]));
+
+    if short.is_empty() {
+        Cow::Borrowed(type_name)
+    } else {
+        Cow::Owned(short)
+    }
+}
+
+#[inline]
+fn is_path_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == ':'
+}
+
+#[inline]
+fn last_path_segment(path: &str) -> &str {
+    path.rsplit("::").next().unwrap_or(path)
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        compare_names, compare_urls, compare_with_spn, get_first_as_bool, get_first_cloned,
-        normalize_spn, normalize_url, parse_datetime_from_string, parse_time, short_type_name,
+        compare_names, compare_urls, compare_with_spn, diff_set, get_first_as_bool,
+        get_first_cloned, normalize_spn, normalize_url, parse_datetime_from_string, parse_quantity,
+        parse_time, short_type_name,
     };
 
-    use std::{collections::BTreeMap, ops::Not};
+    use std::{
+        borrow::Cow,
+        collections::{BTreeMap, BTreeSet},
+        ops::Not,
+    };
 
     use kanidm_proto::v1::Entry;
 
@@ -213,12 +405,103 @@ mod tests {
         assert!(parse_datetime_from_string(invalid_date_str).is_err());
     }
 
+    #[test]
+    fn test_diff_set_empty() {
+        let empty = BTreeSet::<String>::new();
+        let (to_add, to_remove) = diff_set(&empty, &empty);
+        assert!(to_add.is_empty());
+        assert!(to_remove.is_empty());
+
+        let none = BTreeSet::new();
+        let items = BTreeSet::from([1, 2]);
+        let (to_add, to_remove) = diff_set(&none, &items);
+        assert_eq!(to_add, BTreeSet::from([&1, &2]));
+        assert!(to_remove.is_empty());
+
+        let (to_add, to_remove) = diff_set(&items, &none);
+        assert!(to_add.is_empty());
+        assert_eq!(to_remove, BTreeSet::from([&1, &2]));
+    }
+
+    #[test]
+    fn test_diff_set_disjoint() {
+        let current = BTreeSet::from([1, 2]);
+        let desired = BTreeSet::from([3, 4]);
+        let (to_add, to_remove) = diff_set(&current, &desired);
+        assert_eq!(to_add, BTreeSet::from([&3, &4]));
+        assert_eq!(to_remove, BTreeSet::from([&1, &2]));
+    }
+
+    #[test]
+    fn test_diff_set_overlapping() {
+        let current = BTreeSet::from([1, 2, 3]);
+        let desired = BTreeSet::from([2, 3, 4]);
+        let (to_add, to_remove) = diff_set(&current, &desired);
+        assert_eq!(to_add, BTreeSet::from([&4]));
+        assert_eq!(to_remove, BTreeSet::from([&1]));
+
+        let (to_add, to_remove) = diff_set(&current, &current);
+        assert!(to_add.is_empty());
+        assert!(to_remove.is_empty());
+    }
+
     #[test]
     fn test_short_type_name() {
-        assert_eq!(short_type_name::<i32>(), Some("i32"));
-        assert_eq!(
+        assert_eq!(short_type_name::<i32>(), "i32");
+        assert_eq!(short_type_name::<k8s_openapi::api::core::v1::Pod>(), "Pod");
+    }
+
+    #[test]
+    fn test_short_type_name_borrows_non_generic_types() {
+        assert!(matches!(
             short_type_name::<k8s_openapi::api::core::v1::Pod>(),
-            Some("Pod")
+            Cow::Borrowed("Pod")
+        ));
+    }
+
+    #[test]
+    fn test_short_type_name_nested() {
+        mod outer {
+            pub mod inner {
+                pub struct Nested;
+            }
+        }
+
+        assert_eq!(short_type_name::<outer::inner::Nested>(), "Nested");
+    }
+
+    #[test]
+    fn test_short_type_name_generic() {
+        use k8s_openapi::api::core::v1::{Pod, Service};
+
+        assert_eq!(short_type_name::<Vec<Pod>>(), "Vec<Pod>");
+        assert_eq!(
+            short_type_name::<std::collections::HashMap<String, Vec<Service>>>(),
+            "HashMap<String, Vec<Service>>"
         );
+        assert_eq!(
+            short_type_name::<(Pod, Option<Service>)>(),
+            "(Pod, Option<Service>)"
+        );
+        assert_eq!(short_type_name::<&[Pod]>(), "&[Pod]");
+    }
+
+    #[test]
+    fn test_parse_quantity() {
+        use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+        let parse = |q: &str| parse_quantity(&Quantity(q.to_string()));
+        assert_eq!(parse("100"), Some(100.0));
+        assert_eq!(parse("100Mi"), Some(100.0 * 1024.0 * 1024.0));
+        assert_eq!(parse("2G"), Some(2e9));
+        assert_eq!(parse("500m"), Some(0.5));
+        assert_eq!(parse("1e3"), Some(1000.0));
+        assert_eq!(parse("1E3"), Some(1000.0));
+        assert!(parse("1Gi") > parse("1G"));
+        assert!(parse("1024Mi") == parse("1Gi"));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("Gi"), None);
+        assert_eq!(parse("1Xi"), None);
+        assert_eq!(parse("1e"), None);
     }
 }
@@ -0,0 +1,169 @@
+use crate::crd::KanidmOAuth2Client;
+
+use kaniop_operator::controller::kanidm::KanidmResource;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use kanidm_proto::v1::Entry;
+use kube::ResourceExt;
+use tokio::time::{Duration, Instant};
+
+/// Time a read of an OAuth2 client from Kanidm is reused by following reconciles.
+pub const OAUTH2_CACHE_TTL: Duration = Duration::from_secs(5);
+/// Maximum number of OAuth2 clients kept in the cache.
+pub const OAUTH2_CACHE_MAX_ENTRIES: usize = 1024;
+
+/// Kanidm namespace, Kanidm name and OAuth2 client name.
+type OAuth2CacheKey = (String, String, String);
+
+/// Short lived cache of the OAuth2 clients read from Kanidm, so reconciles of the same object in
+/// quick succession do not read it again. Entries must be invalidated after any write to the
+/// OAuth2 client in Kanidm.
+pub struct OAuth2Cache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<OAuth2CacheKey, (Instant, Option<Entry>)>>,
+}
+
+impl Default for OAuth2Cache {
+    fn default() -> Self {
+        Self::new(OAUTH2_CACHE_TTL, OAUTH2_CACHE_MAX_ENTRIES)
+    }
+}
+
+impl OAuth2Cache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Last read of the OAuth2 client, if it is not older than the TTL. The inner `None` means
+    /// the client did not exist in Kanidm.
+    pub fn get(&self, oauth2: &KanidmOAuth2Client) -> Option<Option<Entry>> {
+        // safe unwrap: the lock is never held while panicking
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&cache_key(oauth2))
+            .filter(|(read_at, _)| read_at.elapsed() < self.ttl)
+            .map(|(_, entry)| entry.clone())
+    }
+
+    /// Store a read of the OAuth2 client. When the cache is full, expired entries are dropped
+    /// and, if it is still full, the oldest one.
+    pub fn insert(&self, oauth2: &KanidmOAuth2Client, entry: Option<Entry>) {
+        // safe unwrap: the lock is never held while panicking
+        let mut entries = self.entries.lock().unwrap();
+        let key = cache_key(oauth2);
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            entries.retain(|_, (read_at, _)| read_at.elapsed() < self.ttl);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (read_at, _))| *read_at)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), entry));
+    }
+
+    /// Drop the read of the OAuth2 client, forcing the next one to get it from Kanidm.
+    pub fn invalidate(&self, oauth2: &KanidmOAuth2Client) {
+        // safe unwrap: the lock is never held while panicking
+        self.entries.lock().unwrap().remove(&cache_key(oauth2));
+    }
+}
+
+fn cache_key(oauth2: &KanidmOAuth2Client) -> OAuth2CacheKey {
+    (
+        oauth2.kanidm_namespace(),
+        oauth2.kanidm_name(),
+        oauth2.name_any(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::crd::KanidmOAuth2ClientSpec;
+
+    use std::collections::BTreeMap;
+
+    use kaniop_operator::crd::KanidmRef;
+
+    fn oauth2(name: &str) -> KanidmOAuth2Client {
+        let mut oauth2 = KanidmOAuth2Client::new(
+            name,
+            KanidmOAuth2ClientSpec {
+                kanidm_ref: KanidmRef {
+                    name: "idm".to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        oauth2.metadata.namespace = Some("default".to_string());
+        oauth2
+    }
+
+    fn entry(displayname: &str) -> Entry {
+        Entry {
+            attrs: BTreeMap::from([("displayname".to_string(), vec![displayname.to_string()])]),
+        }
+    }
+
+    #[test]
+    fn test_cache_hit_within_ttl() {
+        let cache = OAuth2Cache::default();
+        let client = oauth2("client");
+        assert_eq!(cache.get(&client), None);
+
+        cache.insert(&client, Some(entry("Client")));
+        assert_eq!(cache.get(&client), Some(Some(entry("Client"))));
+        assert_eq!(cache.get(&oauth2("other")), None);
+
+        cache.insert(&client, None);
+        assert_eq!(cache.get(&client), Some(None));
+    }
+
+    #[test]
+    fn test_cache_miss_after_ttl() {
+        let cache = OAuth2Cache::new(Duration::ZERO, OAUTH2_CACHE_MAX_ENTRIES);
+        let client = oauth2("client");
+        cache.insert(&client, Some(entry("Client")));
+        assert_eq!(cache.get(&client), None);
+    }
+
+    #[test]
+    fn test_cache_invalidate() {
+        let cache = OAuth2Cache::default();
+        let client = oauth2("client");
+        let other = oauth2("other");
+        cache.insert(&client, Some(entry("Client")));
+        cache.insert(&other, Some(entry("Other")));
+
+        cache.invalidate(&client);
+        assert_eq!(cache.get(&client), None);
+        assert_eq!(cache.get(&other), Some(Some(entry("Other"))));
+    }
+
+    #[test]
+    fn test_cache_bounded() {
+        let cache = OAuth2Cache::new(OAUTH2_CACHE_TTL, 2);
+        cache.insert(&oauth2("first"), None);
+        std::thread::sleep(Duration::from_millis(1));
+        cache.insert(&oauth2("second"), None);
+        cache.insert(&oauth2("third"), None);
+
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert_eq!(cache.get(&oauth2("first")), None);
+        assert_eq!(cache.get(&oauth2("third")), Some(None));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::cache::OAuth2Cache;
 use crate::crd::KanidmOAuth2Client;
 use crate::reconcile::reconcile_oauth2;
 
@@ -6,11 +7,10 @@ use kaniop_operator::backoff_reconciler;
 use kaniop_operator::controller::{
     check_api_queryable,
     context::{BackoffContext, Context as KaniopContext, IdmClientContext},
-    ControllerId, State,
-};
-use kaniop_operator::controller::{
-    create_subscriber, create_watcher, RELOAD_BUFFER_SIZE, SUBSCRIBE_BUFFER_SIZE,
+    list_store, managed_by_selector, owned_stream, reconcile_once, reconcile_watcher,
+    track_all_queued, ControllerId, ReconcileErrors, State,
 };
+use kaniop_operator::controller::{create_subscriber, create_watcher};
 use kaniop_operator::error::{Error, Result};
 use kaniop_operator::metrics::ControllerMetrics;
 
@@ -19,10 +19,10 @@ use std::sync::Arc;
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::Secret;
 use kanidm_client::KanidmClient;
+use kube::api::{Api, ListParams};
 use kube::client::Client;
 use kube::runtime::controller::{self, Controller};
-use kube::runtime::reflector::{ObjectRef, Store};
-use kube::runtime::watcher;
+use kube::runtime::reflector::{self, ObjectRef, Store};
 use tokio::time::Duration;
 use tracing::info;
 
@@ -33,6 +33,8 @@ pub struct Context {
     pub kaniop_ctx: KaniopContext<KanidmOAuth2Client>,
     /// Secret store for OAuth2 clients
     pub secret_store: Store<Secret>,
+    /// OAuth2 clients recently read from Kanidm
+    pub oauth2_cache: Arc<OAuth2Cache>,
 }
 
 impl Context {
@@ -40,6 +42,7 @@ impl Context {
         Context {
             kaniop_ctx,
             secret_store,
+            oauth2_cache: Arc::default(),
         }
     }
 }
@@ -55,6 +58,10 @@ impl BackoffContext<KanidmOAuth2Client> for Context {
     async fn reset_backoff(&self, obj_ref: ObjectRef<KanidmOAuth2Client>) {
         self.kaniop_ctx.reset_backoff(obj_ref).await
     }
+
+    async fn publish_terminal_error(&self, obj: &KanidmOAuth2Client, error: &Error) {
+        self.kaniop_ctx.publish_terminal_error(obj, error).await
+    }
 }
 
 impl IdmClientContext<KanidmOAuth2Client> for Context {
@@ -67,9 +74,9 @@ impl IdmClientContext<KanidmOAuth2Client> for Context {
 pub async fn run(state: State, client: Client) {
     let oauth2 = check_api_queryable::<KanidmOAuth2Client>(client.clone()).await;
     let secret = check_api_queryable::<Secret>(client.clone()).await;
-    let secret_r = create_subscriber::<Secret>(SUBSCRIBE_BUFFER_SIZE);
+    let secret_r = create_subscriber::<Secret>(state.buffer_sizes.subscribe);
 
-    let (reload_tx, reload_rx) = mpsc::channel(RELOAD_BUFFER_SIZE);
+    let (reload_tx, reload_rx) = mpsc::channel(state.buffer_sizes.reload);
     let ctx = Arc::new(Context::new(
         state.to_context(client, CONTROLLER_ID),
         secret_r.store,
@@ -87,11 +94,18 @@ pub async fn run(state: State, client: Client) {
     info!(msg = format!("starting {CONTROLLER_ID} controller"));
     // TODO: watcher::Config::default().streaming_lists() when stabilized in K8s
     // https://kubernetes.io/docs/reference/using-api/api-concepts/#streaming-lists
-    let oauth2_controller = Controller::new(oauth2, watcher::Config::default().any_semantic())
+    let metrics = ctx.kaniop_ctx.metrics.clone();
+    let (oauth2_store, oauth2_writer) = reflector::store();
+    let oauth2_watcher = reconcile_watcher(oauth2, oauth2_writer, metrics.clone());
+    let oauth2_reload_store = oauth2_store.clone();
+    let oauth2_controller = Controller::for_stream(oauth2_watcher, oauth2_store)
         // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
         .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
-        .owns_shared_stream(secret_r.subscriber)
-        .reconcile_all_on(reload_rx.map(|_| ()))
+        .owns_shared_stream(owned_stream::<KanidmOAuth2Client, _>(
+            secret_r.subscriber,
+            metrics.clone(),
+        ))
+        .reconcile_all_on(reload_rx.map(move |_| track_all_queued(&metrics, &oauth2_reload_store)))
         .shutdown_on_signal()
         .run(
             backoff_reconciler!(reconcile_oauth2),
@@ -107,3 +121,20 @@ pub async fn run(state: State, client: Client) {
         _ = secret_watcher => {},
     }
 }
+
+/// Reconcile every OAuth2 client once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let secret_store = list_store(
+        &Api::<Secret>::all(client.clone()),
+        &ListParams::default().labels(&managed_by_selector(CONTROLLER_ID)),
+    )
+    .await?;
+    let ctx = Arc::new(Context::new(
+        state.to_context(client.clone(), CONTROLLER_ID),
+        secret_store,
+    ));
+    reconcile_once(&Api::<KanidmOAuth2Client>::all(client), |oauth2| {
+        reconcile_oauth2(oauth2, ctx.clone())
+    })
+    .await
+}
@@ -1,8 +1,9 @@
 use kaniop_k8s_util::types::normalize_spn;
-use kaniop_operator::controller::kanidm::KanidmResource;
-use kaniop_operator::crd::KanidmRef;
+use kaniop_operator::crd::{KanidmRef, KanidmResource, ReadyStatus};
+use kaniop_operator::error::{Error, Result};
 
 use std::{
+    cmp::Ordering,
     collections::{BTreeSet, HashMap},
     ops::Not,
 };
@@ -30,6 +31,8 @@ use serde::{Deserialize, Serialize};
     plural = "kanidmoauth2clients",
     singular = "kanidmoauth2client",
     shortname = "oauth2",
+    shortname = "kmoauth2",
+    category = "kaniop",
     namespaced,
     status = "KanidmOAuth2ClientStatus",
     doc = r#"The Kanidm OAuth2 client custom resource definition (CRD) defines an OAuth2 client
@@ -38,6 +41,8 @@ use serde::{Deserialize, Serialize};
     printcolumn = r#"{"name":"Kanidm","type":"string","jsonPath":".status.kanidmRef"}"#,
     printcolumn = r#"{"name":"Public","type":"string","jsonPath":".spec.public"}"#,
     printcolumn = r#"{"name":"Secret","type":"string","jsonPath":".status.secretName"}"#,
+    printcolumn = r#"{"name":"Exists","type":"string","jsonPath":".status.conditions[?(@.type == 'Exists')].status"}"#,
+    printcolumn = r#"{"name":"Updated","type":"string","jsonPath":".status.conditions[?(@.type == 'Updated')].status"}"#,
     printcolumn = r#"{"name":"Ready","type":"boolean","jsonPath":".status.ready"}"#,
     derive = "Default"
 )]
@@ -73,6 +78,13 @@ pub struct KanidmOAuth2ClientSpec {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub scope_map: Option<BTreeSet<KanidmScopeMap>>,
 
+    /// Require the `openid` scope in every scope map, so the client can use OpenID Connect.
+    /// Disable it for pure OAuth2 clients.
+    ///
+    /// Enabled by default.
+    #[serde(default = "default_require_openid")]
+    pub require_openid: bool,
+
     /// Supplementary scope maps for the OAuth2 client. These function the same as scope maps where
     /// membership of a group provides a set of scopes to the account.
     /// However these scopes are NOT consulted during authorization decisions made by Kanidm.
@@ -121,6 +133,48 @@ pub struct KanidmOAuth2ClientSpec {
     /// Disabled by default.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub jwt_legacy_crypto_enable: Option<bool>,
+
+    /// Enable the OAuth2 device authorization grant (RFC 8628) on this client, allowing devices
+    /// with limited input capabilities to obtain tokens.
+    ///
+    /// Just public clients can enable device flow.
+    /// Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_flow_enable: Option<bool>,
+
+    /// Type of the Secret generated with the client credentials, `Opaque` or a custom type in
+    /// the `<domain>/<name>` format. `kubernetes.io/basic-auth` is also supported: `username` and
+    /// `password` keys are added with the client ID and secret.
+    ///
+    /// This cannot be changed after creation. Default value is `Opaque`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_type: Option<String>,
+
+    /// Format of the client credentials in the generated Secret, along with the OpenID Connect
+    /// issuer and discovery URL:
+    /// `raw` -> `CLIENT_ID`, `CLIENT_SECRET`, `ISSUER` and `DISCOVERY_URL` keys
+    /// `dotenv` -> `credentials.env` key with the same variables
+    /// `json` -> `credentials.json` key with `client_id`, `client_secret`, `issuer` and
+    /// `discovery_url` fields
+    ///
+    /// This cannot be changed after creation. Default value is `raw`.
+    #[serde(default)]
+    pub secret_format: KanidmOAuth2SecretFormat,
+
+    /// Include the client secret in plain text in the status, in the `clientSecret` field.
+    ///
+    /// WARNING: the status is not protected like Secrets are. Anyone allowed to read
+    /// KanidmOAuth2Client resources can read the secret, and it is stored unencrypted in etcd and
+    /// shown by `kubectl get -o yaml`. Prefer the generated Secret; enable this only if you
+    /// cannot consume it.
+    ///
+    /// Ignored for public clients. Disabled by default.
+    #[serde(default)]
+    pub expose_secret_in_status: bool,
+}
+
+fn default_require_openid() -> bool {
+    true
 }
 
 impl KanidmResource for KanidmOAuth2Client {
@@ -140,6 +194,13 @@ impl KanidmResource for KanidmOAuth2Client {
     }
 }
 
+impl ReadyStatus for KanidmOAuth2Client {
+    #[inline]
+    fn ready(&self) -> Option<bool> {
+        self.status.as_ref().map(|status| status.ready)
+    }
+}
+
 /// The `KanidmScopeMap` struct represents a mapping of a group to a set of OAuth2 scopes in Kanidm.
 ///
 /// Scope maps in Kanidm are used to define the permissions that a client application can request on
@@ -149,7 +210,7 @@ impl KanidmResource for KanidmOAuth2Client {
 /// These provide a set of scopes if a user is a member of a specific group within Kanidm. This
 /// allows you to create a relationship between the scopes of a service, and the groups/roles in
 /// Kanidm which can be specific to that service.
-#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct KanidmScopeMap {
@@ -166,7 +227,7 @@ pub struct KanidmScopeMap {
     /// claims will be added.
     ///
     /// - `profile`: name, family_name, given_name, middle_name, nickname, preferred_username, profile,
-    ///    picture, website, gender, birthdate, zoneinfo, locale, and updated_at
+    ///   picture, website, gender, birthdate, zoneinfo, locale, and updated_at
     /// - `email`: email, email_verified
     /// - `address`: address
     /// - `phone`: phone_number, phone_number_verified
@@ -178,6 +239,29 @@ pub struct KanidmScopeMap {
 }
 
 impl KanidmScopeMap {
+    /// Create a scope map for a group, removing duplicated scopes.
+    ///
+    /// ```rust
+    /// use kaniop_oauth2::crd::KanidmScopeMap;
+    ///
+    /// let scope_map = KanidmScopeMap::new("my-group", ["openid", "profile", "openid"]).unwrap();
+    /// assert_eq!(scope_map.group, "my-group");
+    /// assert_eq!(scope_map.scopes, vec!["openid", "profile"]);
+    ///
+    /// assert!(KanidmScopeMap::new("", ["openid"]).is_err());
+    /// ```
+    pub fn new<I, S>(group: impl Into<String>, scopes: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let group = non_empty_group(group.into())?;
+        Ok(Self {
+            group,
+            scopes: dedup(scopes),
+        })
+    }
+
     pub fn normalize(self) -> Self {
         Self {
             group: normalize_spn(&self.group),
@@ -203,6 +287,27 @@ impl KanidmScopeMap {
     }
 }
 
+/// Scopes are compared as a set: Kanidm does not keep their order.
+impl PartialEq for KanidmScopeMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for KanidmScopeMap {}
+
+impl PartialOrd for KanidmScopeMap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KanidmScopeMap {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.group, as_set(&self.scopes)).cmp(&(&other.group, as_set(&other.scopes)))
+    }
+}
+
 /// Some OAuth2 services may consume custom claims from an id token for access control or other
 /// policy decisions. Each custom claim is a key:values set, where there can be many values
 /// associated to a claim name. Different applications may expect these values to be formatted
@@ -283,7 +388,7 @@ impl KanidmClaimMap {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct KanidmClaimsValuesMap {
@@ -295,6 +400,19 @@ pub struct KanidmClaimsValuesMap {
 }
 
 impl KanidmClaimsValuesMap {
+    /// Create the claim values provided by a group, removing duplicated values.
+    pub fn new<I, S>(group: impl Into<String>, values: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let group = non_empty_group(group.into())?;
+        Ok(Self {
+            group,
+            values: dedup(values),
+        })
+    }
+
     pub fn normalize(self) -> Self {
         Self {
             group: normalize_spn(&self.group),
@@ -303,6 +421,135 @@ impl KanidmClaimsValuesMap {
     }
 }
 
+/// Values are compared as a set: Kanidm does not keep their order.
+impl PartialEq for KanidmClaimsValuesMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for KanidmClaimsValuesMap {}
+
+impl PartialOrd for KanidmClaimsValuesMap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KanidmClaimsValuesMap {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.group, as_set(&self.values)).cmp(&(&other.group, as_set(&other.values)))
+    }
+}
+
+/// Builder for `KanidmClaimMap`, validating that groups are not empty nor repeated.
+///
+/// ```rust
+/// use kaniop_oauth2::crd::{ClaimMapBuilder, KanidmClaimMapJoinStrategy};
+///
+/// let claim_map = ClaimMapBuilder::new("account_role")
+///     .join_strategy(KanidmClaimMapJoinStrategy::Csv)
+///     .values("admins", ["admin", "login", "admin"])
+///     .values("users", ["login"])
+///     .build()
+///     .unwrap();
+/// assert_eq!(claim_map.name, "account_role");
+/// assert_eq!(claim_map.values_map.len(), 2);
+/// assert_eq!(claim_map.values_map.first().unwrap().values, vec!["admin", "login"]);
+///
+/// assert!(ClaimMapBuilder::new("account_role")
+///     .values("admins", ["admin"])
+///     .values("admins@idm.example.com", ["login"])
+///     .build()
+///     .is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClaimMapBuilder {
+    name: String,
+    values: Vec<(String, Vec<String>)>,
+    join_strategy: KanidmClaimMapJoinStrategy,
+}
+
+impl ClaimMapBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Values provided to the members of a group.
+    pub fn values<I, S>(mut self, group: impl Into<String>, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.values.push((
+            group.into(),
+            values.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    pub fn join_strategy(mut self, join_strategy: KanidmClaimMapJoinStrategy) -> Self {
+        self.join_strategy = join_strategy;
+        self
+    }
+
+    pub fn build(self) -> Result<KanidmClaimMap> {
+        if self.name.is_empty() {
+            return Err(Error::ValidationError("claim name cannot be empty".to_string()));
+        }
+        let mut groups = BTreeSet::new();
+        let values_map = self
+            .values
+            .into_iter()
+            .map(|(group, values)| {
+                let values_map = KanidmClaimsValuesMap::new(group, values)?;
+                if !groups.insert(normalize_spn(&values_map.group)) {
+                    return Err(Error::ValidationError(format!(
+                        "group {} is repeated in claim {}",
+                        values_map.group, self.name
+                    )));
+                }
+                Ok(values_map)
+            })
+            .collect::<Result<BTreeSet<_>>>()?;
+        Ok(KanidmClaimMap {
+            name: self.name,
+            values_map,
+            join_strategy: self.join_strategy,
+        })
+    }
+}
+
+fn non_empty_group(group: String) -> Result<String> {
+    if group.is_empty() {
+        Err(Error::ValidationError("group cannot be empty".to_string()))
+    } else {
+        Ok(group)
+    }
+}
+
+/// Strings as a set, ignoring their order and duplicates.
+fn as_set(items: &[String]) -> BTreeSet<&str> {
+    items.iter().map(String::as_str).collect()
+}
+
+/// Collect strings removing duplicates while keeping the original order.
+fn dedup<I, S>(items: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut seen = BTreeSet::new();
+    items
+        .into_iter()
+        .map(Into::into)
+        .filter(|item| seen.insert(item.clone()))
+        .collect()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[serde(rename_all = "snake_case")]
@@ -323,10 +570,20 @@ impl KanidmClaimMapJoinStrategy {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum KanidmOAuth2SecretFormat {
+    #[default]
+    Raw,
+    Dotenv,
+    Json,
+}
+
 /// Most recent observed status of the Kanidm Group. Read-only.
 /// More info:
 /// https://github.com/kubernetes/community/blob/master/contributors/devel/sig-architecture/api-conventions.md#spec-and-status
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct KanidmOAuth2ClientStatus {
@@ -345,14 +602,36 @@ pub struct KanidmOAuth2ClientStatus {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub claims_map: Option<Vec<String>>,
 
+    /// The OAuth2 client exists and all its conditions are true.
     pub ready: bool,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub secret_name: Option<String>,
 
+    /// Client secret in plain text. Only set when `exposeSecretInStatus` is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+
     pub kanidm_ref: String,
 }
 
+/// Redacts the client secret, so it is never written to logs.
+impl std::fmt::Debug for KanidmOAuth2ClientStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KanidmOAuth2ClientStatus")
+            .field("conditions", &self.conditions)
+            .field("origin", &self.origin)
+            .field("scope_map", &self.scope_map)
+            .field("sup_scope_map", &self.sup_scope_map)
+            .field("claims_map", &self.claims_map)
+            .field("ready", &self.ready)
+            .field("secret_name", &self.secret_name)
+            .field("client_secret", &self.client_secret.as_ref().map(|_| "<redacted>"))
+            .field("kanidm_ref", &self.kanidm_ref)
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
@@ -488,4 +767,35 @@ mod tests {
             claim_map,
         );
     }
+
+    #[test]
+    fn test_kanidm_scope_map_eq_ignores_scopes_order() {
+        let scope_map = KanidmScopeMap::new("group", ["openid", "profile"]).unwrap();
+        let reordered = KanidmScopeMap::new("group", ["profile", "openid"]).unwrap();
+
+        assert_eq!(scope_map, reordered);
+        assert_eq!(BTreeSet::from([scope_map.clone(), reordered]).len(), 1);
+        assert_ne!(
+            scope_map,
+            KanidmScopeMap::new("group", ["openid", "email"]).unwrap()
+        );
+        assert_ne!(
+            scope_map,
+            KanidmScopeMap::new("other", ["openid", "profile"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_kanidm_claim_map_eq_ignores_values_order() {
+        let claim_map = |values: &[&str]| KanidmClaimMap {
+            name: "claim".to_string(),
+            values_map: BTreeSet::from([
+                KanidmClaimsValuesMap::new("group", values.to_vec()).unwrap()
+            ]),
+            join_strategy: KanidmClaimMapJoinStrategy::Array,
+        };
+
+        assert_eq!(claim_map(&["a", "b"]), claim_map(&["b", "a"]));
+        assert_ne!(claim_map(&["a", "b"]), claim_map(&["a", "c"]));
+    }
 }
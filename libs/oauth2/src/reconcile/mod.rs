@@ -4,37 +4,41 @@ mod status;
 use self::secret::SecretExt;
 use self::status::{
     StatusExt, CONDITION_FALSE, CONDITION_TRUE, TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED,
-    TYPE_CLAIMS_MAP_UPDATED, TYPE_DISABLE_PKCE_UPDATED, TYPE_EXISTS, TYPE_LEGACY_CRYPTO_UPDATED,
-    TYPE_PREFER_SHORT_NAME_UPDATED, TYPE_REDIRECT_URL_UPDATED, TYPE_SCOPE_MAP_UPDATED,
-    TYPE_SECRET_INITIALIZED, TYPE_STRICT_REDIRECT_URL_UPDATED, TYPE_SUP_SCOPE_MAP_UPDATED,
-    TYPE_UPDATED,
+    TYPE_CLAIMS_MAP_UPDATED, TYPE_DEVICE_FLOW_UPDATED, TYPE_DISABLE_PKCE_UPDATED, TYPE_EXISTS,
+    TYPE_LEGACY_CRYPTO_UPDATED, TYPE_PREFER_SHORT_NAME_UPDATED, TYPE_REDIRECT_URL_UPDATED,
+    TYPE_SCOPE_MAP_UPDATED, TYPE_SECRET_INITIALIZED, TYPE_STRICT_REDIRECT_URL_UPDATED,
+    TYPE_SUP_SCOPE_MAP_UPDATED, TYPE_UPDATED,
 };
 
 use crate::{
     controller::Context,
-    crd::{KanidmClaimMap, KanidmOAuth2Client, KanidmOAuth2ClientStatus, KanidmScopeMap},
+    crd::{
+        KanidmClaimMap, KanidmClaimMapJoinStrategy, KanidmClaimsValuesMap, KanidmOAuth2Client,
+        KanidmOAuth2ClientStatus, KanidmScopeMap,
+    },
 };
 
-use kaniop_k8s_util::types::short_type_name;
-use kaniop_operator::controller::kanidm::KanidmResource;
+use kaniop_k8s_util::types::{diff_set, normalize_spn, short_type_name};
+use kaniop_operator::controller::kanidm::{KanidmApiLimiter, KanidmResource};
 use kaniop_operator::controller::{context::IdmClientContext, DEFAULT_RECONCILE_INTERVAL};
 use kaniop_operator::error::{Error, Result};
 use kaniop_operator::telemetry;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
-use std::time::Duration;
 
-use futures::future::TryJoinAll;
-use futures::try_join;
+use futures::future::{BoxFuture, TryJoinAll};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::{try_join, FutureExt};
 use k8s_openapi::NamespaceResourceScope;
 use kanidm_client::KanidmClient;
 use kanidm_proto::constants::{
     ATTR_OAUTH2_ALLOW_INSECURE_CLIENT_DISABLE_PKCE, ATTR_OAUTH2_ALLOW_LOCALHOST_REDIRECT,
-    ATTR_OAUTH2_JWT_LEGACY_CRYPTO_ENABLE, ATTR_OAUTH2_PREFER_SHORT_USERNAME,
-    ATTR_OAUTH2_RS_CLAIM_MAP, ATTR_OAUTH2_RS_ORIGIN, ATTR_OAUTH2_RS_SCOPE_MAP,
-    ATTR_OAUTH2_RS_SUP_SCOPE_MAP, ATTR_OAUTH2_STRICT_REDIRECT_URI,
+    ATTR_OAUTH2_DEVICE_FLOW_ENABLE, ATTR_OAUTH2_JWT_LEGACY_CRYPTO_ENABLE,
+    ATTR_OAUTH2_PREFER_SHORT_USERNAME, ATTR_OAUTH2_RS_CLAIM_MAP, ATTR_OAUTH2_RS_ORIGIN,
+    ATTR_OAUTH2_RS_SCOPE_MAP, ATTR_OAUTH2_RS_SUP_SCOPE_MAP, ATTR_OAUTH2_STRICT_REDIRECT_URI,
 };
+use kanidm_proto::v1::Entry;
 use kube::api::{Api, Patch, PatchParams};
 use kube::core::{Selector, SelectorExt};
 use kube::runtime::controller::Action;
@@ -42,7 +46,10 @@ use kube::runtime::events::{Event, EventType};
 use kube::runtime::finalizer::{finalizer, Event as Finalizer};
 use kube::{Resource, ResourceExt};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, field, info, instrument, trace, warn, Span};
+use tracing::{debug, field, info, instrument, trace, Span};
+
+/// Maximum number of attribute updates sent concurrently to Kanidm.
+const MAX_CONCURRENT_UPDATES: usize = 4;
 
 static OAUTH2_OPERATOR_NAME: &str = "kanidmoauth2clients.kaniop.rs";
 static OAUTH2_FINALIZER: &str = "kanidms.kaniop.rs/oauth2-client";
@@ -60,9 +67,13 @@ pub fn watched_resource(oauth2: &KanidmOAuth2Client, ctx: Arc<Context>) -> bool
     let namespace_selector = if let Some(l) = kanidm.spec.oauth2_client_namespace_selector.clone() {
         l
     } else {
-        trace!(msg = "no namespace selector found, defaulting to current namespace");
-        // A null label selector (default value) matches the current namespace only
-        return kanidm.namespace().unwrap() == namespace;
+        let default_selector = ctx.kaniop_ctx.default_namespace_selector;
+        trace!(
+            msg = "no namespace selector found, using default",
+            ?default_selector
+        );
+        // safe unwrap: kanidm is namespaced scoped
+        return default_selector.matches(&namespace, &kanidm.namespace().unwrap());
     };
 
     let selector: Selector = if let Ok(s) = namespace_selector.try_into() {
@@ -91,45 +102,57 @@ pub async fn reconcile_oauth2(
         .kaniop_ctx
         .metrics
         .reconcile_count_and_measure(&trace_id);
-    let kanidm_client = ctx.get_idm_client(&oauth2).await?;
+    let kanidm_client = match ctx.get_idm_client(&oauth2).await {
+        Ok(kanidm_client) => kanidm_client,
+        Err(e) if oauth2.metadata.deletion_timestamp.is_some() => {
+            return cleanup_unavailable(oauth2, ctx, e).await;
+        }
+        Err(e) => return Err(e),
+    };
 
     if !watched_resource(&oauth2, ctx.clone()) {
         debug!(msg = "resource not watched, skipping reconcile");
-        ctx.kaniop_ctx.recorder
-        .publish(
-            &Event {
-                type_: EventType::Warning,
-                reason: "ResourceNotWatched".to_string(),
-                note: Some("configure `oauth2ClientNamespaceSelector` on Kanidm resource to watch this namespace".to_string()),
-                action: "Reconcile".to_string(),
-                secondary: None,
-            },
-            &oauth2.object_ref(&()),
-        )
-        .await
-        .map_err(|e| {
-            warn!(msg = "failed to publish KanidmError event", %e);
-            Error::KubeError("failed to publish event".to_string(), e)
-        })?;
+        ctx.kaniop_ctx
+            .publish_event(
+                &oauth2,
+                Event {
+                    type_: EventType::Warning,
+                    reason: "ResourceNotWatched".to_string(),
+                    note: Some("configure `oauth2ClientNamespaceSelector` on Kanidm resource to watch this namespace".to_string()),
+                    action: "Reconcile".to_string(),
+                    secondary: None,
+                },
+            )
+            .await?;
         return Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL));
     }
 
     info!(msg = "reconciling oauth2 client");
     let namespace = oauth2.get_namespace();
-    let status = oauth2
+    let status = match oauth2
         .update_status(kanidm_client.clone(), ctx.clone())
         .await
-        .map_err(|e| {
+    {
+        Ok(status) => status,
+        Err(e) => {
             debug!(msg = "failed to reconcile status", %e);
             ctx.kaniop_ctx.metrics.status_update_errors_inc();
-            e
-        })?;
+            if oauth2.metadata.deletion_timestamp.is_some() {
+                return cleanup_unavailable(oauth2, ctx, e).await;
+            }
+            return Err(e);
+        }
+    };
     let persons_api: Api<KanidmOAuth2Client> =
         Api::namespaced(ctx.kaniop_ctx.client.clone(), &namespace);
     finalizer(&persons_api, OAUTH2_FINALIZER, oauth2, |event| async {
         match event {
             Finalizer::Apply(p) => p.reconcile(kanidm_client, status, ctx).await,
-            Finalizer::Cleanup(p) => p.cleanup(kanidm_client, status).await,
+            Finalizer::Cleanup(p) => {
+                let result = p.cleanup(kanidm_client, status).await;
+                ctx.oauth2_cache.invalidate(&p);
+                ctx.kaniop_ctx.cleanup_with_grace(&p, result).await
+            }
         }
     })
     .await
@@ -138,6 +161,24 @@ pub async fn reconcile_oauth2(
     })
 }
 
+/// Apply the deletion grace policy to an OAuth2 client being deleted whose Kanidm client or status
+/// could not be obtained, so a deleted or unreachable Kanidm does not block the deletion forever.
+async fn cleanup_unavailable(
+    oauth2: Arc<KanidmOAuth2Client>,
+    ctx: Arc<Context>,
+    error: Error,
+) -> Result<Action> {
+    let oauth2_api: Api<KanidmOAuth2Client> =
+        Api::namespaced(ctx.kaniop_ctx.client.clone(), &oauth2.get_namespace());
+    ctx.oauth2_cache.invalidate(&oauth2);
+    ctx.kaniop_ctx
+        .cleanup_unavailable(&oauth2_api, OAUTH2_FINALIZER, oauth2, error)
+        .await
+        .map_err(|e| {
+            Error::FinalizerError("failed on oauth2 client finalizer".to_string(), Box::new(e))
+        })
+}
+
 impl KanidmOAuth2Client {
     #[inline]
     fn get_namespace(&self) -> String {
@@ -160,22 +201,17 @@ impl KanidmOAuth2Client {
             Err(e) => match e {
                 Error::KanidmClientError(_, _) => {
                     ctx.kaniop_ctx
-                        .recorder
-                        .publish(
-                            &Event {
+                        .publish_event(
+                            self,
+                            Event {
                                 type_: EventType::Warning,
                                 reason: "KanidmError".to_string(),
                                 note: Some(format!("{e:?}")),
                                 action: "KanidmRequest".to_string(),
                                 secondary: None,
                             },
-                            &self.object_ref(&()),
                         )
-                        .await
-                        .map_err(|e| {
-                            warn!(msg = "failed to publish KanidmError event", %e);
-                            Error::KubeError("failed to publish event".to_string(), e)
-                        })?;
+                        .await?;
                     Err(e)
                 }
                 _ => Err(e),
@@ -191,79 +227,123 @@ impl KanidmOAuth2Client {
         ctx: Arc<Context>,
     ) -> Result<Action> {
         let name = &self.name_any();
-        let mut require_status_update = false;
+        let limiter = ctx.kaniop_ctx.kanidm_api_limiter(self);
 
+        // creation and secret initialization must happen before any other update
+        let mut stages: Vec<Vec<BoxFuture<Result<()>>>> = Vec::new();
         if is_oauth2_false(TYPE_EXISTS, status.clone()) {
-            self.create(&kanidm_client, name).await?;
-            require_status_update = true;
+            stages.push(vec![self.create(&kanidm_client, name).boxed()]);
         }
 
         if is_oauth2_false(TYPE_SECRET_INITIALIZED, status.clone()) {
-            let secret = self.generate_secret(&kanidm_client).await?;
-            self.patch(ctx, secret).await?;
-            require_status_update = true;
+            let ctx = ctx.clone();
+            let kanidm_client = kanidm_client.clone();
+            stages.push(vec![async move {
+                let kanidm = ctx.kaniop_ctx.get_kanidm(self).ok_or_else(|| {
+                    Error::MissingData(format!(
+                        "failed to get Kanidm {namespace}/{kanidm}",
+                        namespace = self.kanidm_namespace(),
+                        kanidm = self.kanidm_name(),
+                    ))
+                })?;
+                let secret = self
+                    .generate_secret(&kanidm_client, &kanidm.spec.domain)
+                    .await?;
+                self.patch(ctx, secret).await.map(|_| ())
+            }
+            .boxed()]);
         }
 
-        if is_oauth2_false(TYPE_UPDATED, status.clone()) {
-            self.update(&kanidm_client, name).await?;
-            require_status_update = true;
+        // a full reconcile applies every attribute, correcting changes made outside the operator
+        // that the status does not detect
+        let full_reconcile =
+            is_oauth2(TYPE_EXISTS, status.clone()) && ctx.kaniop_ctx.full_reconcile_due(self).await;
+        if full_reconcile {
+            debug!(msg = "full reconcile");
         }
+        let needs_update = |type_: &str| is_update_needed(type_, &status, full_reconcile);
 
-        if is_oauth2_false(TYPE_REDIRECT_URL_UPDATED, status.clone()) {
-            self.update_redirect_url(&kanidm_client, name, &status)
-                .await?;
-            require_status_update = true;
+        let mut updates: Vec<BoxFuture<Result<()>>> = Vec::new();
+        if needs_update(TYPE_UPDATED) {
+            updates.push(self.update(&kanidm_client, name).boxed());
         }
 
-        if is_oauth2_false(TYPE_SCOPE_MAP_UPDATED, status.clone()) {
-            self.update_scope_map(&kanidm_client, name, &status).await?;
-            require_status_update = true;
+        if needs_update(TYPE_REDIRECT_URL_UPDATED) {
+            updates.push(
+                self.update_redirect_url(&kanidm_client, &limiter, name, &status)
+                    .boxed(),
+            );
         }
 
-        if is_oauth2_false(TYPE_SUP_SCOPE_MAP_UPDATED, status.clone()) {
-            self.update_sup_scope_map(&kanidm_client, name, &status)
-                .await?;
-            require_status_update = true;
+        if needs_update(TYPE_SCOPE_MAP_UPDATED) {
+            updates.push(
+                self.update_scope_map(&kanidm_client, &limiter, name, &status)
+                    .boxed(),
+            );
         }
 
-        if is_oauth2_false(TYPE_CLAIMS_MAP_UPDATED, status.clone()) {
-            self.update_claims_map(&kanidm_client, name, &status)
-                .await?;
-            require_status_update = true;
+        if needs_update(TYPE_SUP_SCOPE_MAP_UPDATED) {
+            updates.push(
+                self.update_sup_scope_map(&kanidm_client, &limiter, name, &status, ctx.clone())
+                    .boxed(),
+            );
         }
 
-        if is_oauth2_false(TYPE_STRICT_REDIRECT_URL_UPDATED, status.clone()) {
-            self.update_strict_redirect_url(&kanidm_client, name)
-                .await?;
-            require_status_update = true;
+        if needs_update(TYPE_CLAIMS_MAP_UPDATED) {
+            updates.push(
+                self.update_claims_map(&kanidm_client, &limiter, name, &status)
+                    .boxed(),
+            );
         }
 
-        if is_oauth2_false(TYPE_DISABLE_PKCE_UPDATED, status.clone()) {
-            self.update_disable_pkce(&kanidm_client, name).await?;
-            require_status_update = true;
+        if needs_update(TYPE_STRICT_REDIRECT_URL_UPDATED) {
+            updates.push(
+                self.update_strict_redirect_url(&kanidm_client, name)
+                    .boxed(),
+            );
         }
 
-        if is_oauth2_false(TYPE_PREFER_SHORT_NAME_UPDATED, status.clone()) {
-            self.update_prefer_short_name(&kanidm_client, name).await?;
-            require_status_update = true;
+        if needs_update(TYPE_DISABLE_PKCE_UPDATED) {
+            updates.push(self.update_disable_pkce(&kanidm_client, name).boxed());
         }
 
-        if is_oauth2_false(TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED, status.clone()) {
-            self.update_allow_localhost_redirect(&kanidm_client, name)
-                .await?;
-            require_status_update = true;
+        if needs_update(TYPE_PREFER_SHORT_NAME_UPDATED) {
+            updates.push(self.update_prefer_short_name(&kanidm_client, name).boxed());
+        }
+
+        if needs_update(TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED) {
+            updates.push(
+                self.update_allow_localhost_redirect(&kanidm_client, name)
+                    .boxed(),
+            );
+        }
+
+        if needs_update(TYPE_LEGACY_CRYPTO_UPDATED) {
+            updates.push(self.update_legacy_crypto(&kanidm_client, name).boxed());
+        }
+
+        if needs_update(TYPE_DEVICE_FLOW_UPDATED) {
+            updates.push(self.update_device_flow(&kanidm_client, name).boxed());
+        }
+        if !updates.is_empty() {
+            stages.push(updates);
         }
 
-        if is_oauth2_false(TYPE_LEGACY_CRYPTO_UPDATED, status.clone()) {
-            self.update_legacy_crypto(&kanidm_client, name).await?;
-            require_status_update = true;
+        let require_status_update = !stages.is_empty();
+        let result = run_stages(stages, MAX_CONCURRENT_UPDATES).await;
+        if require_status_update {
+            // even failed stages may have written some attributes
+            ctx.oauth2_cache.invalidate(self);
+        }
+        result?;
+        if full_reconcile {
+            ctx.kaniop_ctx.full_reconcile_done(self).await;
         }
 
         if require_status_update {
-            trace!(msg = "status update required, requeueing in 500ms");
-            Ok(Action::requeue(Duration::from_millis(500)))
+            Ok(ctx.kaniop_ctx.status_update_requeue(self).await)
         } else {
-            Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL))
+            Ok(ctx.kaniop_ctx.status_converged_requeue(self).await)
         }
     }
 
@@ -280,7 +360,7 @@ impl KanidmOAuth2Client {
         let name = obj.name_any();
         let namespace = self.get_namespace();
         trace!(
-            msg = format!("patching {}", short_type_name::<K>().unwrap_or("Unknown")),
+            msg = format!("patching {}", short_type_name::<K>()),
             resource.name = &name,
             resource.namespace = &namespace
         );
@@ -300,7 +380,7 @@ impl KanidmOAuth2Client {
                     info!(
                         msg = format!(
                             "recreating {} because the update operation was not possible",
-                            short_type_name::<K>().unwrap_or("Unknown")
+                            short_type_name::<K>()
                         ),
                         reason = ae.reason
                     );
@@ -318,7 +398,7 @@ impl KanidmOAuth2Client {
                             Error::KubeError(
                                 format!(
                                     "failed to re-try patch {} {namespace}/{name}",
-                                    short_type_name::<K>().unwrap_or("Unknown")
+                                    short_type_name::<K>()
                                 ),
                                 e,
                             )
@@ -327,7 +407,7 @@ impl KanidmOAuth2Client {
                 _ => Err(Error::KubeError(
                     format!(
                         "failed to patch {} {namespace}/{name}",
-                        short_type_name::<K>().unwrap_or("Unknown")
+                        short_type_name::<K>()
                     ),
                     e,
                 )),
@@ -348,7 +428,7 @@ impl KanidmOAuth2Client {
         let name = obj.name_any();
         let namespace = self.get_namespace();
         trace!(
-            msg = format!("deleting {}", short_type_name::<K>().unwrap_or("Unknown")),
+            msg = format!("deleting {}", short_type_name::<K>()),
             resource.name = &name,
             resource.namespace = &namespace
         );
@@ -357,7 +437,7 @@ impl KanidmOAuth2Client {
             Error::KubeError(
                 format!(
                     "failed to delete {} {namespace}/{name}",
-                    short_type_name::<K>().unwrap_or("Unknown")
+                    short_type_name::<K>()
                 ),
                 e,
             )
@@ -429,6 +509,7 @@ impl KanidmOAuth2Client {
     async fn update_redirect_url(
         &self,
         kanidm_client: &KanidmClient,
+        limiter: &KanidmApiLimiter,
         name: &str,
         status: &KanidmOAuth2ClientStatus,
     ) -> Result<()> {
@@ -458,14 +539,15 @@ impl KanidmOAuth2Client {
             })
             .collect::<Result<BTreeSet<_>>>()?;
 
-        let delete_futures = current_urls
-            .difference(&redirect_url)
-            .map(|url| kanidm_client.idm_oauth2_client_remove_origin(name, url))
+        let (urls_to_add, urls_to_remove) = diff_set(&current_urls, &redirect_url);
+        let delete_futures = urls_to_remove
+            .into_iter()
+            .map(|url| limiter.run(kanidm_client.idm_oauth2_client_remove_origin(name, url)))
             .collect::<TryJoinAll<_>>();
 
-        let add_futures = redirect_url
-            .difference(&current_urls)
-            .map(|url| kanidm_client.idm_oauth2_client_add_origin(name, url))
+        let add_futures = urls_to_add
+            .into_iter()
+            .map(|url| limiter.run(kanidm_client.idm_oauth2_client_add_origin(name, url)))
             .collect::<TryJoinAll<_>>();
 
         futures::try_join!(delete_futures, add_futures).map_err(|e| {
@@ -485,6 +567,7 @@ impl KanidmOAuth2Client {
     async fn update_scope_map(
         &self,
         kanidm_client: &KanidmClient,
+        limiter: &KanidmApiLimiter,
         name: &str,
         status: &KanidmOAuth2ClientStatus,
     ) -> Result<()> {
@@ -505,19 +588,20 @@ impl KanidmOAuth2Client {
             .into_iter()
             .collect();
 
-        let delete_futures = current_scope_map
-            .difference(&scope_map)
-            .map(|s| kanidm_client.idm_oauth2_rs_delete_scope_map(name, &s.group))
+        let (scope_map_to_add, scope_map_to_remove) = diff_set(&current_scope_map, &scope_map);
+        let delete_futures = scope_map_to_remove
+            .into_iter()
+            .map(|s| limiter.run(kanidm_client.idm_oauth2_rs_delete_scope_map(name, &s.group)))
             .collect::<TryJoinAll<_>>();
 
-        let add_futures = scope_map
-            .difference(&current_scope_map)
+        let add_futures = scope_map_to_add
+            .into_iter()
             .map(|s| {
-                kanidm_client.idm_oauth2_rs_update_scope_map(
+                limiter.run(kanidm_client.idm_oauth2_rs_update_scope_map(
                     name,
                     &s.group,
                     s.scopes.iter().map(|s| s.as_str()).collect(),
-                )
+                ))
             })
             .collect::<TryJoinAll<_>>();
 
@@ -537,8 +621,10 @@ impl KanidmOAuth2Client {
     async fn update_sup_scope_map(
         &self,
         kanidm_client: &KanidmClient,
+        limiter: &KanidmApiLimiter,
         name: &str,
         status: &KanidmOAuth2ClientStatus,
+        ctx: Arc<Context>,
     ) -> Result<()> {
         debug!(msg = format!("update {ATTR_OAUTH2_RS_SUP_SCOPE_MAP} attribute"));
 
@@ -557,19 +643,40 @@ impl KanidmOAuth2Client {
             .into_iter()
             .collect();
 
-        let delete_futures = current_sup_scope_map
-            .difference(&sup_scope_map)
-            .map(|s| kanidm_client.idm_oauth2_rs_delete_sup_scope_map(name, &s.group))
+        let missing_groups = self
+            .missing_groups(kanidm_client, current_sup_scope_map.union(&sup_scope_map))
+            .await?;
+        let (current_sup_scope_map, stale_sup_scope_map) =
+            partition_by_missing_group(current_sup_scope_map, &missing_groups);
+        let (sup_scope_map, unresolved_sup_scope_map) =
+            partition_by_missing_group(sup_scope_map, &missing_groups);
+
+        // Kanidm drops references to deleted groups by itself, so stale entries may already be
+        // gone by the time we try to remove them.
+        for s in stale_sup_scope_map.iter() {
+            if let Err(e) = kanidm_client
+                .idm_oauth2_rs_delete_sup_scope_map(name, &s.group)
+                .await
+            {
+                debug!(msg = format!("failed to prune {ATTR_OAUTH2_RS_SUP_SCOPE_MAP} for missing group {}", s.group), error = ?e);
+            }
+        }
+
+        let (sup_scope_map_to_add, sup_scope_map_to_remove) =
+            diff_set(&current_sup_scope_map, &sup_scope_map);
+        let delete_futures = sup_scope_map_to_remove
+            .into_iter()
+            .map(|s| limiter.run(kanidm_client.idm_oauth2_rs_delete_sup_scope_map(name, &s.group)))
             .collect::<TryJoinAll<_>>();
 
-        let add_futures = sup_scope_map
-            .difference(&current_sup_scope_map)
+        let add_futures = sup_scope_map_to_add
+            .into_iter()
             .map(|s| {
-                kanidm_client.idm_oauth2_rs_update_sup_scope_map(
+                limiter.run(kanidm_client.idm_oauth2_rs_update_sup_scope_map(
                     name,
                     &s.group,
                     s.scopes.iter().map(|s| s.as_str()).collect(),
-                )
+                ))
             })
             .collect::<TryJoinAll<_>>();
 
@@ -583,12 +690,67 @@ impl KanidmOAuth2Client {
                 Box::new(e),
             )
         })?;
+
+        if !unresolved_sup_scope_map.is_empty() {
+            let groups = unresolved_sup_scope_map
+                .iter()
+                .map(|s| s.group.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            ctx.kaniop_ctx
+                .publish_event(
+                    self,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "SupScopeMapGroupNotFound".to_string(),
+                        note: Some(format!(
+                            "skipped {ATTR_OAUTH2_RS_SUP_SCOPE_MAP} for missing groups: {groups}"
+                        )),
+                        action: "UpdateSupScopeMap".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await?;
+            // returning an error keeps retrying with backoff until the groups are created
+            return Err(Error::MissingData(format!(
+                "{ATTR_OAUTH2_RS_SUP_SCOPE_MAP} groups not found in {namespace}/{kanidm}: {groups}",
+                namespace = self.kanidm_namespace(),
+                kanidm = self.kanidm_name(),
+            )));
+        }
         Ok(())
     }
 
+    /// Returns the normalized names of the scope map groups that do not exist in Kanidm.
+    async fn missing_groups<'a>(
+        &self,
+        kanidm_client: &KanidmClient,
+        scope_maps: impl Iterator<Item = &'a KanidmScopeMap>,
+    ) -> Result<BTreeSet<String>> {
+        let groups: BTreeSet<String> = scope_maps.map(|s| normalize_spn(&s.group)).collect();
+        let mut missing_groups = BTreeSet::new();
+        for group in groups {
+            let entry = kanidm_client.idm_group_get(&group).await.map_err(|e| {
+                Error::KanidmClientError(
+                    format!(
+                        "failed to get group {group} from {namespace}/{kanidm}",
+                        namespace = self.kanidm_namespace(),
+                        kanidm = self.kanidm_name(),
+                    ),
+                    Box::new(e),
+                )
+            })?;
+            if entry.is_none() {
+                missing_groups.insert(group);
+            }
+        }
+        Ok(missing_groups)
+    }
+
     async fn update_claims_map(
         &self,
         kanidm_client: &KanidmClient,
+        limiter: &KanidmApiLimiter,
         name: &str,
         status: &KanidmOAuth2ClientStatus,
     ) -> Result<()> {
@@ -615,32 +777,34 @@ impl KanidmOAuth2Client {
             .into_iter()
             .collect();
 
-        let delete_futures = current_claims_map
-            .difference(&claims_map)
-            .flat_map(|c| {
-                c.values_map
-                    .iter()
-                    .map(|v| kanidm_client.idm_oauth2_rs_delete_claim_map(name, &c.name, &v.group))
+        let diff = ClaimsMapDiff::new(&current_claims_map, &claims_map);
+        let delete_futures = diff
+            .values_to_remove
+            .iter()
+            .map(|(claim, group)| {
+                limiter.run(kanidm_client.idm_oauth2_rs_delete_claim_map(name, claim, group))
             })
             .collect::<TryJoinAll<_>>();
 
-        let claims_to_add = claims_map.difference(&current_claims_map);
-        let add_futures = claims_to_add
-            .clone()
-            .flat_map(|c| {
-                c.values_map.iter().map(|v| {
-                    kanidm_client.idm_oauth2_rs_update_claim_map(name, &c.name, &v.group, &v.values)
-                })
+        let add_futures = diff
+            .values_to_add
+            .iter()
+            .map(|(claim, v)| {
+                limiter.run(
+                    kanidm_client.idm_oauth2_rs_update_claim_map(name, claim, &v.group, &v.values),
+                )
             })
             .collect::<TryJoinAll<_>>();
 
-        let join_strategy_futures = claims_to_add
-            .map(|c| {
-                kanidm_client.idm_oauth2_rs_update_claim_map_join(
+        let join_strategy_futures = diff
+            .join_strategies_to_update
+            .iter()
+            .map(|(claim, join_strategy)| {
+                limiter.run(kanidm_client.idm_oauth2_rs_update_claim_map_join(
                     name,
-                    &c.name,
-                    c.join_strategy.to_oauth2_claim_map_join(),
-                )
+                    claim,
+                    join_strategy.to_oauth2_claim_map_join(),
+                ))
             })
             .collect::<TryJoinAll<_>>();
 
@@ -869,6 +1033,35 @@ impl KanidmOAuth2Client {
         Ok(())
     }
 
+    async fn update_device_flow(&self, kanidm_client: &KanidmClient, name: &str) -> Result<()> {
+        debug!(msg = format!("update {ATTR_OAUTH2_DEVICE_FLOW_ENABLE} attribute"));
+        if let Some(device_flow) = self.spec.device_flow_enable {
+            // kanidm client does not expose a method for this attribute yet
+            let value = if device_flow {
+                vec!["true".to_string()]
+            } else {
+                Vec::new()
+            };
+            let update_oauth2_rs = Entry {
+                attrs: BTreeMap::from([(ATTR_OAUTH2_DEVICE_FLOW_ENABLE.to_string(), value)]),
+            };
+            kanidm_client
+                .perform_patch_request::<_, ()>(&format!("/v1/oauth2/{name}"), update_oauth2_rs)
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to update {ATTR_OAUTH2_DEVICE_FLOW_ENABLE} for {name} from {namespace}/{kanidm}",
+                            namespace = self.kanidm_namespace(),
+                            kanidm = self.kanidm_name(),
+                        ),
+                        Box::new(e),
+                    )
+                })?;
+        };
+        Ok(())
+    }
+
     async fn cleanup(
         &self,
         kanidm_client: Arc<KanidmClient>,
@@ -895,6 +1088,91 @@ impl KanidmOAuth2Client {
     }
 }
 
+/// Run stages in order, running the operations of each stage concurrently up to `limit` at a
+/// time. Stops at the first failing operation.
+async fn run_stages(stages: Vec<Vec<BoxFuture<'_, Result<()>>>>, limit: usize) -> Result<()> {
+    for operations in stages {
+        stream::iter(operations)
+            .buffer_unordered(limit)
+            .try_collect::<Vec<_>>()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Changes to apply to the claims map of an OAuth2 client.
+///
+/// Values and join strategies are diffed separately: a join strategy change only updates the
+/// strategy, and values are only removed for the groups no longer mapping the claim.
+#[derive(Debug, Default, PartialEq)]
+struct ClaimsMapDiff {
+    /// Claim name and values map to set.
+    values_to_add: BTreeSet<(String, KanidmClaimsValuesMap)>,
+    /// Claim name and group whose values have to be removed.
+    values_to_remove: BTreeSet<(String, String)>,
+    /// Claim name and join strategy to set.
+    join_strategies_to_update: BTreeSet<(String, KanidmClaimMapJoinStrategy)>,
+}
+
+impl ClaimsMapDiff {
+    fn new(current: &BTreeSet<KanidmClaimMap>, desired: &BTreeSet<KanidmClaimMap>) -> Self {
+        let values = |claims_map: &BTreeSet<KanidmClaimMap>| {
+            claims_map
+                .iter()
+                .flat_map(|c| {
+                    c.values_map
+                        .iter()
+                        .map(|v| (c.name.clone(), v.clone().normalize()))
+                })
+                .collect::<BTreeSet<_>>()
+        };
+        let join_strategies = |claims_map: &BTreeSet<KanidmClaimMap>| {
+            claims_map
+                .iter()
+                .map(|c| (c.name.clone(), c.join_strategy.clone()))
+                .collect::<BTreeSet<_>>()
+        };
+
+        let (current_values, desired_values) = (values(current), values(desired));
+        let (values_to_add, values_to_remove) = diff_set(&current_values, &desired_values);
+        let updated_groups = values_to_add
+            .iter()
+            .map(|(claim, v)| (claim.clone(), v.group.clone()))
+            .collect::<BTreeSet<_>>();
+        let (current_join_strategies, desired_join_strategies) =
+            (join_strategies(current), join_strategies(desired));
+        let (join_strategies_to_update, _) =
+            diff_set(&current_join_strategies, &desired_join_strategies);
+
+        Self {
+            values_to_add: values_to_add.into_iter().cloned().collect(),
+            // values of updated groups are replaced, removing them would race with the update
+            values_to_remove: values_to_remove
+                .into_iter()
+                .map(|(claim, v)| (claim.clone(), v.group.clone()))
+                .filter(|claim_group| !updated_groups.contains(claim_group))
+                .collect(),
+            join_strategies_to_update: join_strategies_to_update.into_iter().cloned().collect(),
+        }
+    }
+}
+
+/// Splits scope maps between the ones whose group exists and the ones whose group is missing.
+fn partition_by_missing_group(
+    scope_maps: BTreeSet<KanidmScopeMap>,
+    missing_groups: &BTreeSet<String>,
+) -> (BTreeSet<KanidmScopeMap>, BTreeSet<KanidmScopeMap>) {
+    scope_maps
+        .into_iter()
+        .partition(|s| !missing_groups.contains(&normalize_spn(&s.group)))
+}
+
+/// Whether reconcile has to update the attributes of the condition: the condition is false or,
+/// on a full reconcile, true.
+fn is_update_needed(type_: &str, status: &KanidmOAuth2ClientStatus, full_reconcile: bool) -> bool {
+    is_oauth2_false(type_, status.clone()) || (full_reconcile && is_oauth2(type_, status.clone()))
+}
+
 pub fn is_oauth2(type_: &str, status: KanidmOAuth2ClientStatus) -> bool {
     status
         .conditions
@@ -910,3 +1188,306 @@ pub fn is_oauth2_false(type_: &str, status: KanidmOAuth2ClientStatus) -> bool {
         .iter()
         .any(|c| c.type_ == type_ && c.status == CONDITION_FALSE)
 }
+
+#[cfg(test)]
+mod test {
+    use super::status::{
+        CONDITION_TRUE, TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED, TYPE_CLAIMS_MAP_UPDATED,
+        TYPE_DEVICE_FLOW_UPDATED, TYPE_DISABLE_PKCE_UPDATED, TYPE_EXISTS,
+        TYPE_LEGACY_CRYPTO_UPDATED, TYPE_PREFER_SHORT_NAME_UPDATED, TYPE_REDIRECT_URL_UPDATED,
+        TYPE_SCOPE_MAP_UPDATED, TYPE_SECRET_INITIALIZED, TYPE_STRICT_REDIRECT_URL_UPDATED,
+        TYPE_SUP_SCOPE_MAP_UPDATED, TYPE_UPDATED,
+    };
+    use super::{is_update_needed, partition_by_missing_group, run_stages, ClaimsMapDiff};
+
+    use crate::crd::{
+        KanidmClaimMap, KanidmClaimMapJoinStrategy, KanidmClaimsValuesMap, KanidmOAuth2Client,
+        KanidmOAuth2ClientSpec, KanidmOAuth2ClientStatus, KanidmScopeMap,
+    };
+
+    use kaniop_operator::controller::State;
+    use kaniop_operator::crd::KanidmRef;
+    use kaniop_operator::error::{Error, Result};
+
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+    use k8s_openapi::chrono::Utc;
+    use kube::runtime::reflector::store::Writer;
+    use kube::{Client, Config};
+
+    /// Conditions of the attributes that reconcile updates.
+    const UPDATE_CONDITIONS: [&str; 11] = [
+        TYPE_UPDATED,
+        TYPE_REDIRECT_URL_UPDATED,
+        TYPE_SCOPE_MAP_UPDATED,
+        TYPE_SUP_SCOPE_MAP_UPDATED,
+        TYPE_CLAIMS_MAP_UPDATED,
+        TYPE_STRICT_REDIRECT_URL_UPDATED,
+        TYPE_DISABLE_PKCE_UPDATED,
+        TYPE_PREFER_SHORT_NAME_UPDATED,
+        TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED,
+        TYPE_LEGACY_CRYPTO_UPDATED,
+        TYPE_DEVICE_FLOW_UPDATED,
+    ];
+
+    fn scope_map(group: &str) -> KanidmScopeMap {
+        KanidmScopeMap {
+            group: group.to_string(),
+            scopes: vec!["openid".to_string()],
+        }
+    }
+
+    fn claim_map(
+        groups: &[&str],
+        values: &[&str],
+        join_strategy: KanidmClaimMapJoinStrategy,
+    ) -> KanidmClaimMap {
+        KanidmClaimMap {
+            name: "account_role".to_string(),
+            values_map: groups
+                .iter()
+                .map(|group| KanidmClaimsValuesMap {
+                    group: group.to_string(),
+                    values: values.iter().map(|v| v.to_string()).collect(),
+                })
+                .collect(),
+            join_strategy,
+        }
+    }
+
+    #[test]
+    fn test_claims_map_diff_join_strategy_only() {
+        let current = BTreeSet::from([claim_map(
+            &["admins@idm.example.com"],
+            &["admin"],
+            KanidmClaimMapJoinStrategy::Array,
+        )]);
+        let desired = BTreeSet::from([claim_map(
+            &["admins"],
+            &["admin"],
+            KanidmClaimMapJoinStrategy::Csv,
+        )]);
+
+        assert_eq!(
+            ClaimsMapDiff::new(&current, &desired),
+            ClaimsMapDiff {
+                join_strategies_to_update: BTreeSet::from([(
+                    "account_role".to_string(),
+                    KanidmClaimMapJoinStrategy::Csv
+                )]),
+                ..ClaimsMapDiff::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_claims_map_diff_values() {
+        let current = BTreeSet::from([claim_map(
+            &["admins@idm.example.com", "users@idm.example.com"],
+            &["admin"],
+            KanidmClaimMapJoinStrategy::Csv,
+        )]);
+        let desired = BTreeSet::from([claim_map(
+            &["admins"],
+            &["admin", "login"],
+            KanidmClaimMapJoinStrategy::Csv,
+        )]);
+
+        let diff = ClaimsMapDiff::new(&current, &desired);
+
+        assert_eq!(
+            diff.values_to_add,
+            BTreeSet::from([(
+                "account_role".to_string(),
+                KanidmClaimsValuesMap {
+                    group: "admins".to_string(),
+                    values: vec!["admin".to_string(), "login".to_string()],
+                }
+            )])
+        );
+        assert_eq!(
+            diff.values_to_remove,
+            BTreeSet::from([("account_role".to_string(), "users".to_string())])
+        );
+        assert!(diff.join_strategies_to_update.is_empty());
+    }
+
+    #[test]
+    fn test_claims_map_diff_new_claim() {
+        let desired = BTreeSet::from([claim_map(
+            &["admins"],
+            &["admin"],
+            KanidmClaimMapJoinStrategy::Ssv,
+        )]);
+
+        let diff = ClaimsMapDiff::new(&BTreeSet::new(), &desired);
+
+        assert_eq!(diff.values_to_add.len(), 1);
+        assert!(diff.values_to_remove.is_empty());
+        assert_eq!(
+            diff.join_strategies_to_update,
+            BTreeSet::from([("account_role".to_string(), KanidmClaimMapJoinStrategy::Ssv)])
+        );
+    }
+
+    #[test]
+    fn test_partition_by_missing_group() {
+        let scope_maps =
+            BTreeSet::from([scope_map("admins"), scope_map("deleted@idm.example.com")]);
+        let missing_groups = BTreeSet::from(["deleted".to_string()]);
+
+        let (existing, missing) = partition_by_missing_group(scope_maps, &missing_groups);
+
+        assert_eq!(existing, BTreeSet::from([scope_map("admins")]));
+        assert_eq!(
+            missing,
+            BTreeSet::from([scope_map("deleted@idm.example.com")])
+        );
+    }
+
+    #[test]
+    fn test_partition_by_missing_group_without_missing_groups() {
+        let scope_maps = BTreeSet::from([scope_map("admins"), scope_map("users")]);
+
+        let (existing, missing) = partition_by_missing_group(scope_maps.clone(), &BTreeSet::new());
+
+        assert_eq!(existing, scope_maps);
+        assert!(missing.is_empty());
+    }
+
+    fn tracked_operation<'a>(
+        in_flight: &'a AtomicUsize,
+        max_in_flight: &'a AtomicUsize,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn test_run_stages_concurrently_with_limit() {
+        let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let operations = (0..5)
+            .map(|_| tracked_operation(&in_flight, &max_in_flight))
+            .collect();
+
+        run_stages(vec![operations], 3).await.unwrap();
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_stages_in_order() {
+        let created = AtomicBool::new(false);
+        let create: BoxFuture<Result<()>> = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            created.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        .boxed();
+        let updates = (0..3)
+            .map(|_| {
+                async {
+                    assert!(created.load(Ordering::SeqCst));
+                    Ok(())
+                }
+                .boxed()
+            })
+            .collect();
+
+        run_stages(vec![vec![create], updates], 3).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_stages_stops_on_error() {
+        let updated = AtomicBool::new(false);
+        let create: BoxFuture<Result<()>> =
+            async { Err(Error::MissingData("failed".to_string())) }.boxed();
+        let update: BoxFuture<Result<()>> = async {
+            updated.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        .boxed();
+
+        assert!(run_stages(vec![vec![create], vec![update]], 3)
+            .await
+            .is_err());
+        assert!(!updated.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_reconcile_updates_every_attribute() {
+        let full_reconcile_interval = Duration::from_secs(3600);
+        let client = Client::try_from(Config::new("http://127.0.0.1:1".parse().unwrap())).unwrap();
+        let ctx = State::new(
+            Default::default(),
+            &["test"],
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            Default::default(),
+            Default::default(),
+            1,
+        )
+        .with_full_reconcile_interval(Some(full_reconcile_interval))
+        .to_context::<KanidmOAuth2Client>(client, "test");
+        let mut oauth2 = KanidmOAuth2Client::new(
+            "client",
+            KanidmOAuth2ClientSpec {
+                kanidm_ref: KanidmRef {
+                    name: "idm".to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        oauth2.metadata.namespace = Some("default".to_string());
+        // every condition is true, even when an attribute was changed outside the operator
+        let status = KanidmOAuth2ClientStatus {
+            conditions: Some(
+                [TYPE_EXISTS, TYPE_SECRET_INITIALIZED]
+                    .into_iter()
+                    .chain(UPDATE_CONDITIONS)
+                    .map(|type_| Condition {
+                        type_: type_.to_string(),
+                        status: CONDITION_TRUE.to_string(),
+                        reason: String::new(),
+                        message: String::new(),
+                        last_transition_time: Time(Utc::now()),
+                        observed_generation: None,
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        let full_reconcile = ctx.full_reconcile_due(&oauth2).await;
+        assert!(!full_reconcile);
+        assert!(UPDATE_CONDITIONS.iter().all(|type_| !is_update_needed(
+            type_,
+            &status,
+            full_reconcile
+        )));
+
+        tokio::time::advance(full_reconcile_interval).await;
+        let full_reconcile = ctx.full_reconcile_due(&oauth2).await;
+        assert!(full_reconcile);
+        assert!(UPDATE_CONDITIONS.iter().all(|type_| is_update_needed(
+            type_,
+            &status,
+            full_reconcile
+        )));
+
+        ctx.full_reconcile_done(&oauth2).await;
+        assert!(!ctx.full_reconcile_due(&oauth2).await);
+    }
+}
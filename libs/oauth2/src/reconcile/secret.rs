@@ -1,7 +1,8 @@
 use crate::controller::CONTROLLER_ID;
-use crate::crd::KanidmOAuth2Client;
+use crate::crd::{KanidmOAuth2Client, KanidmOAuth2SecretFormat};
 
 use kanidm_client::KanidmClient;
+use kaniop_k8s_util::resources::controller_owner_references;
 use kaniop_operator::controller::kanidm::KanidmResource;
 use kaniop_operator::controller::{INSTANCE_LABEL, MANAGED_BY_LABEL, NAME_LABEL};
 use kaniop_operator::error::{Error, Result};
@@ -10,8 +11,22 @@ use std::collections::BTreeMap;
 use std::sync::LazyLock;
 
 use k8s_openapi::api::core::v1::Secret;
-use kube::api::{ObjectMeta, Resource};
+use kube::api::ObjectMeta;
 use kube::ResourceExt;
+use serde_json::json;
+
+/// Issuer of the client when its credentials Secret was generated, to regenerate it when the
+/// Kanidm domain changes.
+pub const ISSUER_ANNOTATION: &str = "kaniop.rs/oauth2-issuer";
+const CLIENT_ID_KEY: &str = "CLIENT_ID";
+const CLIENT_SECRET_KEY: &str = "CLIENT_SECRET";
+const ISSUER_KEY: &str = "ISSUER";
+const DISCOVERY_URL_KEY: &str = "DISCOVERY_URL";
+const DOTENV_KEY: &str = "credentials.env";
+const JSON_KEY: &str = "credentials.json";
+const SECRET_TYPE_BASIC_AUTH: &str = "kubernetes.io/basic-auth";
+const BASIC_AUTH_USERNAME_KEY: &str = "username";
+const BASIC_AUTH_PASSWORD_KEY: &str = "password";
 
 static LABELS: LazyLock<BTreeMap<String, String>> = LazyLock::new(|| {
     BTreeMap::from([
@@ -26,7 +41,7 @@ static LABELS: LazyLock<BTreeMap<String, String>> = LazyLock::new(|| {
 #[allow(async_fn_in_trait)]
 pub trait SecretExt {
     fn secret_name(&self) -> String;
-    async fn generate_secret(&self, kanidm_client: &KanidmClient) -> Result<Secret>;
+    async fn generate_secret(&self, kanidm_client: &KanidmClient, domain: &str) -> Result<Secret>;
 }
 
 impl SecretExt for KanidmOAuth2Client {
@@ -35,7 +50,7 @@ impl SecretExt for KanidmOAuth2Client {
         format!("{}-kanidm-oauth2-credentials", self.name_any())
     }
 
-    async fn generate_secret(&self, kanidm_client: &KanidmClient) -> Result<Secret> {
+    async fn generate_secret(&self, kanidm_client: &KanidmClient, domain: &str) -> Result<Secret> {
         let name = &self.name_any();
         let client_secret = kanidm_client
             .idm_oauth2_rs_get_basic_secret(name)
@@ -57,31 +72,183 @@ impl SecretExt for KanidmOAuth2Client {
                     kanidm = self.kanidm_name(),
                 ))
             })?;
+        Ok(self.credentials_secret(client_secret, domain))
+    }
+}
+
+impl KanidmOAuth2Client {
+    /// OpenID Connect issuer of the client in the Kanidm with the given `domain`.
+    pub fn issuer(&self, domain: &str) -> String {
+        format!("https://{domain}/oauth2/openid/{}", self.name_any())
+    }
+
+    /// Secret with the client credentials, in the format defined in the spec. `domain` is the
+    /// domain of the Kanidm cluster, used to build the OpenID Connect issuer and discovery URL.
+    fn credentials_secret(&self, client_secret: String, domain: &str) -> Secret {
+        let name = self.name_any();
         let labels = LABELS
             .clone()
             .into_iter()
             .chain([(INSTANCE_LABEL.to_string(), name.clone())])
             .collect();
-        let secret = Secret {
+        let issuer = self.issuer(domain);
+        let discovery_url = format!("{issuer}/.well-known/openid-configuration");
+        let mut string_data = match self.spec.secret_format {
+            KanidmOAuth2SecretFormat::Raw => BTreeMap::from([
+                (CLIENT_ID_KEY.to_string(), name.clone()),
+                (CLIENT_SECRET_KEY.to_string(), client_secret.clone()),
+                (ISSUER_KEY.to_string(), issuer.clone()),
+                (DISCOVERY_URL_KEY.to_string(), discovery_url),
+            ]),
+            KanidmOAuth2SecretFormat::Dotenv => BTreeMap::from([(
+                DOTENV_KEY.to_string(),
+                format!(
+                    "{CLIENT_ID_KEY}={name}\n{CLIENT_SECRET_KEY}={client_secret}\n\
+                    {ISSUER_KEY}={issuer}\n{DISCOVERY_URL_KEY}={discovery_url}\n"
+                ),
+            )]),
+            KanidmOAuth2SecretFormat::Json => BTreeMap::from([(
+                JSON_KEY.to_string(),
+                json!({
+                    "client_id": name,
+                    "client_secret": client_secret,
+                    "issuer": issuer,
+                    "discovery_url": discovery_url,
+                })
+                .to_string(),
+            )]),
+        };
+        if self.spec.secret_type.as_deref() == Some(SECRET_TYPE_BASIC_AUTH) {
+            string_data.insert(BASIC_AUTH_USERNAME_KEY.to_string(), name);
+            string_data.insert(BASIC_AUTH_PASSWORD_KEY.to_string(), client_secret);
+        }
+        Secret {
             metadata: ObjectMeta {
                 name: Some(self.secret_name()),
                 namespace: Some(self.namespace().unwrap()),
-                owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
+                owner_references: controller_owner_references(self),
                 labels: Some(labels),
+                annotations: Some(BTreeMap::from([(ISSUER_ANNOTATION.to_string(), issuer)])),
                 ..ObjectMeta::default()
             },
-            string_data: Some(
-                [
-                    ("CLIENT_ID".to_string(), name.clone()),
-                    ("CLIENT_SECRET".to_string(), client_secret),
-                ]
-                .iter()
-                .cloned()
-                .collect(),
-            ),
+            string_data: Some(string_data),
+            type_: self.spec.secret_type.clone(),
             ..Secret::default()
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::crd::KanidmOAuth2ClientSpec;
+
+    const DOMAIN: &str = "idm.example.com";
+    const ISSUER: &str = "https://idm.example.com/oauth2/openid/my-service";
+    const DISCOVERY_URL: &str =
+        "https://idm.example.com/oauth2/openid/my-service/.well-known/openid-configuration";
+
+    fn oauth2(secret_type: Option<&str>) -> KanidmOAuth2Client {
+        oauth2_with_format(secret_type, KanidmOAuth2SecretFormat::Raw)
+    }
+
+    fn oauth2_with_format(
+        secret_type: Option<&str>,
+        secret_format: KanidmOAuth2SecretFormat,
+    ) -> KanidmOAuth2Client {
+        let mut oauth2 = KanidmOAuth2Client::new(
+            "my-service",
+            KanidmOAuth2ClientSpec {
+                secret_type: secret_type.map(str::to_string),
+                secret_format,
+                ..KanidmOAuth2ClientSpec::default()
+            },
+        );
+        oauth2.metadata.namespace = Some("default".to_string());
+        oauth2
+    }
+
+    #[test]
+    fn test_credentials_secret_default_type() {
+        let secret = oauth2(None).credentials_secret("secret".to_string(), DOMAIN);
+        assert_eq!(secret.type_, None);
+        assert_eq!(
+            secret.string_data.unwrap(),
+            BTreeMap::from([
+                (CLIENT_ID_KEY.to_string(), "my-service".to_string()),
+                (CLIENT_SECRET_KEY.to_string(), "secret".to_string()),
+                (DISCOVERY_URL_KEY.to_string(), DISCOVERY_URL.to_string()),
+                (ISSUER_KEY.to_string(), ISSUER.to_string()),
+            ])
+        );
+        assert_eq!(
+            secret.metadata.annotations.unwrap()[ISSUER_ANNOTATION],
+            ISSUER
+        );
+    }
+
+    #[test]
+    fn test_credentials_secret_basic_auth() {
+        let secret =
+            oauth2(Some(SECRET_TYPE_BASIC_AUTH)).credentials_secret("secret".to_string(), DOMAIN);
+        assert_eq!(secret.type_.as_deref(), Some(SECRET_TYPE_BASIC_AUTH));
+        let string_data = secret.string_data.unwrap();
+        assert_eq!(string_data[BASIC_AUTH_USERNAME_KEY], "my-service");
+        assert_eq!(string_data[BASIC_AUTH_PASSWORD_KEY], "secret");
+        assert_eq!(string_data[CLIENT_ID_KEY], "my-service");
+    }
+
+    #[test]
+    fn test_credentials_secret_custom_type() {
+        let secret =
+            oauth2(Some("example.com/oauth2")).credentials_secret("secret".to_string(), DOMAIN);
+        assert_eq!(secret.type_.as_deref(), Some("example.com/oauth2"));
+        assert_eq!(secret.string_data.unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_credentials_secret_dotenv() {
+        let secret = oauth2_with_format(None, KanidmOAuth2SecretFormat::Dotenv)
+            .credentials_secret("secret".to_string(), DOMAIN);
+        assert_eq!(
+            secret.string_data.unwrap(),
+            BTreeMap::from([(
+                DOTENV_KEY.to_string(),
+                format!(
+                    "CLIENT_ID=my-service\nCLIENT_SECRET=secret\nISSUER={ISSUER}\n\
+                    DISCOVERY_URL={DISCOVERY_URL}\n"
+                )
+            )])
+        );
+    }
+
+    #[test]
+    fn test_credentials_secret_json() {
+        let secret = oauth2_with_format(None, KanidmOAuth2SecretFormat::Json)
+            .credentials_secret("secret".to_string(), DOMAIN);
+        let string_data = secret.string_data.unwrap();
+        assert_eq!(string_data.len(), 1);
+        let credentials: serde_json::Value = serde_json::from_str(&string_data[JSON_KEY]).unwrap();
+        assert_eq!(
+            credentials,
+            json!({
+                "client_id": "my-service",
+                "client_secret": "secret",
+                "issuer": ISSUER,
+                "discovery_url": DISCOVERY_URL,
+            })
+        );
+    }
 
-        Ok(secret)
+    #[test]
+    fn test_credentials_secret_json_basic_auth() {
+        let secret =
+            oauth2_with_format(Some(SECRET_TYPE_BASIC_AUTH), KanidmOAuth2SecretFormat::Json)
+                .credentials_secret("secret".to_string(), DOMAIN);
+        assert_eq!(
+            secret.string_data.unwrap().into_keys().collect::<Vec<_>>(),
+            vec![JSON_KEY, BASIC_AUTH_PASSWORD_KEY, BASIC_AUTH_USERNAME_KEY]
+        );
     }
 }
@@ -1,9 +1,12 @@
-use super::{secret::SecretExt, OAUTH2_OPERATOR_NAME};
+use super::secret::{SecretExt, ISSUER_ANNOTATION};
+use super::OAUTH2_OPERATOR_NAME;
 
 use crate::controller::Context;
 use crate::crd::{KanidmClaimMap, KanidmOAuth2Client, KanidmOAuth2ClientStatus, KanidmScopeMap};
 
+use kaniop_k8s_util::resources::is_status_unchanged;
 use kaniop_k8s_util::types::{compare_urls, get_first_as_bool, get_first_cloned, normalize_url};
+use kaniop_operator::controller::context::out_of_sync_conditions;
 use kaniop_operator::controller::kanidm::KanidmResource;
 use kaniop_operator::error::{Error, Result};
 
@@ -11,20 +14,21 @@ use std::collections::BTreeSet;
 use std::sync::Arc;
 
 use futures::TryFutureExt;
+use k8s_openapi::api::core::v1::Secret;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use k8s_openapi::chrono::Utc;
 use kanidm_client::KanidmClient;
 use kanidm_proto::constants::{
     ATTR_DISPLAYNAME, ATTR_OAUTH2_ALLOW_INSECURE_CLIENT_DISABLE_PKCE,
-    ATTR_OAUTH2_ALLOW_LOCALHOST_REDIRECT, ATTR_OAUTH2_JWT_LEGACY_CRYPTO_ENABLE,
-    ATTR_OAUTH2_PREFER_SHORT_USERNAME, ATTR_OAUTH2_RS_CLAIM_MAP, ATTR_OAUTH2_RS_ORIGIN,
-    ATTR_OAUTH2_RS_ORIGIN_LANDING, ATTR_OAUTH2_RS_SCOPE_MAP, ATTR_OAUTH2_RS_SUP_SCOPE_MAP,
-    ATTR_OAUTH2_STRICT_REDIRECT_URI,
+    ATTR_OAUTH2_ALLOW_LOCALHOST_REDIRECT, ATTR_OAUTH2_DEVICE_FLOW_ENABLE,
+    ATTR_OAUTH2_JWT_LEGACY_CRYPTO_ENABLE, ATTR_OAUTH2_PREFER_SHORT_USERNAME,
+    ATTR_OAUTH2_RS_CLAIM_MAP, ATTR_OAUTH2_RS_ORIGIN, ATTR_OAUTH2_RS_ORIGIN_LANDING,
+    ATTR_OAUTH2_RS_SCOPE_MAP, ATTR_OAUTH2_RS_SUP_SCOPE_MAP, ATTR_OAUTH2_STRICT_REDIRECT_URI,
 };
 use kanidm_proto::v1::Entry;
 use kube::api::{Api, Patch, PatchParams};
 use kube::ResourceExt;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 pub const TYPE_EXISTS: &str = "Exists";
 pub const TYPE_SECRET_INITIALIZED: &str = "SecretInitialized";
@@ -38,6 +42,7 @@ pub const TYPE_DISABLE_PKCE_UPDATED: &str = "DisablePkceUpdated";
 pub const TYPE_PREFER_SHORT_NAME_UPDATED: &str = "PreferShortNameUpdated";
 pub const TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED: &str = "AllowLocalhostRedirectUpdated";
 pub const TYPE_LEGACY_CRYPTO_UPDATED: &str = "LegacyCryptoUpdated";
+pub const TYPE_DEVICE_FLOW_UPDATED: &str = "DeviceFlowUpdated";
 pub const CONDITION_TRUE: &str = "True";
 pub const CONDITION_FALSE: &str = "False";
 const REASON_ATTRIBUTE_MATCH: &str = "AttributeMatch";
@@ -45,6 +50,23 @@ const REASON_ATTRIBUTE_NOT_MATCH: &str = "AttributeNotMatch";
 const REASON_ATTRIBUTES_MATCH: &str = "AttributesMatch";
 const REASON_ATTRIBUTES_NOT_MATCH: &str = "AttributesNotMatch";
 
+/// Conditions that reconcile fixes when they are false
+const SYNC_CONDITIONS: [&str; 13] = [
+    TYPE_EXISTS,
+    TYPE_SECRET_INITIALIZED,
+    TYPE_UPDATED,
+    TYPE_REDIRECT_URL_UPDATED,
+    TYPE_SCOPE_MAP_UPDATED,
+    TYPE_SUP_SCOPE_MAP_UPDATED,
+    TYPE_CLAIMS_MAP_UPDATED,
+    TYPE_STRICT_REDIRECT_URL_UPDATED,
+    TYPE_DISABLE_PKCE_UPDATED,
+    TYPE_PREFER_SHORT_NAME_UPDATED,
+    TYPE_ALLOW_LOCALHOST_REDIRECT_UPDATED,
+    TYPE_LEGACY_CRYPTO_UPDATED,
+    TYPE_DEVICE_FLOW_UPDATED,
+];
+
 #[allow(async_fn_in_trait)]
 pub trait StatusExt {
     async fn update_status(
@@ -63,29 +85,79 @@ impl StatusExt for KanidmOAuth2Client {
         // safe unwrap: person is namespaced scoped
         let namespace = self.get_namespace();
         let name = self.name_any();
-        let current_oauth2 = kanidm_client
-            .idm_oauth2_rs_get(&name)
-            .map_err(|e| {
-                Error::KanidmClientError(
-                    format!(
-                        "failed to get {name} from {namespace}/{kanidm}",
-                        kanidm = self.spec.kanidm_ref.name
-                    ),
-                    Box::new(e),
-                )
-            })
-            .await?;
+        let current_oauth2 = match ctx.oauth2_cache.get(self) {
+            Some(cached) => {
+                trace!(msg = "using cached oauth2 client");
+                ctx.kaniop_ctx.metrics.kanidm_cache_hits_inc();
+                cached
+            }
+            None => {
+                ctx.kaniop_ctx.metrics.kanidm_cache_misses_inc();
+                let current_oauth2 = kanidm_client
+                    .idm_oauth2_rs_get(&name)
+                    .map_err(|e| {
+                        Error::KanidmClientError(
+                            format!(
+                                "failed to get {name} from {namespace}/{kanidm}",
+                                kanidm = self.spec.kanidm_ref.name
+                            ),
+                            Box::new(e),
+                        )
+                    })
+                    .await?;
+                ctx.oauth2_cache.insert(self, current_oauth2.clone());
+                current_oauth2
+            }
+        };
 
         let secret = if self.spec.public {
             None
         } else {
-            ctx.secret_store
-                .find(|s| {
-                    s.name_any() == self.secret_name() && s.namespace().as_ref() == Some(&namespace)
-                })
-                .map(|s| s.name_any())
+            ctx.secret_store.find(|s| {
+                s.name_any() == self.secret_name() && s.namespace().as_ref() == Some(&namespace)
+            })
         };
-        let status = self.generate_status(current_oauth2, secret)?;
+        let issuer = ctx
+            .kaniop_ctx
+            .get_kanidm(self)
+            .map(|k| self.issuer(&k.spec.domain));
+        let client_secret = if self.is_secret_exposed() && current_oauth2.is_some() {
+            warn!(msg = "exposing client secret in status");
+            kanidm_client
+                .idm_oauth2_rs_get_basic_secret(&name)
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to get basic secret for {name} from {namespace}/{kanidm}",
+                            kanidm = self.spec.kanidm_ref.name
+                        ),
+                        Box::new(e),
+                    )
+                })?
+        } else {
+            None
+        };
+        let mut status = self.generate_status(
+            current_oauth2,
+            secret.as_deref(),
+            issuer.as_deref(),
+            client_secret,
+        )?;
+        if let Some(stalled) = ctx
+            .kaniop_ctx
+            .stalled_condition(
+                self,
+                out_of_sync_conditions(status.conditions.as_deref(), &SYNC_CONDITIONS),
+            )
+            .await
+        {
+            status.conditions.get_or_insert_with(Vec::new).push(stalled);
+        }
+        if is_status_unchanged(self.status.as_ref(), &status) {
+            trace!(msg = "status unchanged, skipping patch");
+            return Ok(status);
+        }
         let status_patch = Patch::Apply(KanidmOAuth2Client {
             status: Some(status.clone()),
             ..KanidmOAuth2Client::default()
@@ -109,10 +181,20 @@ impl StatusExt for KanidmOAuth2Client {
 }
 
 impl KanidmOAuth2Client {
+    #[inline]
+    fn is_secret_exposed(&self) -> bool {
+        self.spec.expose_secret_in_status && !self.spec.public
+    }
+
+    /// Status of the client. The credentials `secret` is outdated when it was generated for an
+    /// issuer other than `issuer`, e.g. after the Kanidm domain changes. `client_secret` is only
+    /// included when the spec asks to expose it.
     fn generate_status(
         &self,
         oauth2_opt: Option<Entry>,
-        secret: Option<String>,
+        secret: Option<&Secret>,
+        issuer: Option<&str>,
+        client_secret: Option<String>,
     ) -> Result<KanidmOAuth2ClientStatus> {
         let now = Utc::now();
         let conditions = match oauth2_opt.clone() {
@@ -126,8 +208,20 @@ impl KanidmOAuth2Client {
                     observed_generation: self.metadata.generation,
                 };
 
+                let secret_issuer = secret
+                    .and_then(|s| s.annotations().get(ISSUER_ANNOTATION))
+                    .map(String::as_str);
                 let secret_initialized_condition = if self.spec.public {
                     None
+                } else if secret.is_some() && issuer.is_some() && secret_issuer != issuer {
+                    Some(Condition {
+                        type_: TYPE_SECRET_INITIALIZED.to_string(),
+                        status: CONDITION_FALSE.to_string(),
+                        reason: "SecretOutdated".to_string(),
+                        message: "Secret was generated for a different issuer.".to_string(),
+                        last_transition_time: Time(now),
+                        observed_generation: self.metadata.generation,
+                    })
                 } else if secret.is_some() {
                     Some(Condition {
                         type_: TYPE_SECRET_INITIALIZED.to_string(),
@@ -426,6 +520,33 @@ impl KanidmOAuth2Client {
                         }
                     }
                 });
+                let device_flow_condition = self.spec.device_flow_enable.as_ref().map(|device_flow| {
+                    if Some(device_flow) == get_first_as_bool(&oauth2, ATTR_OAUTH2_DEVICE_FLOW_ENABLE).as_ref()
+                    || (!device_flow && !oauth2.attrs.contains_key(ATTR_OAUTH2_DEVICE_FLOW_ENABLE))
+                    {
+                        Condition {
+                            type_: TYPE_DEVICE_FLOW_UPDATED.to_string(),
+                            status: CONDITION_TRUE.to_string(),
+                            reason: REASON_ATTRIBUTE_MATCH.to_string(),
+                            message: format!(
+                                "OAuth2 client exists with desired {ATTR_OAUTH2_DEVICE_FLOW_ENABLE} attribute."
+                            ),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    } else {
+                        Condition {
+                            type_: TYPE_DEVICE_FLOW_UPDATED.to_string(),
+                            status: CONDITION_FALSE.to_string(),
+                            reason: REASON_ATTRIBUTE_NOT_MATCH.to_string(),
+                            message: format!(
+                                "OAuth2 client exists with different {ATTR_OAUTH2_DEVICE_FLOW_ENABLE} attribute."
+                            ),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    }
+                });
                 vec![exist_condition, updated_condition, redirect_url_condition]
                     .into_iter()
                     .chain(secret_initialized_condition)
@@ -437,6 +558,7 @@ impl KanidmOAuth2Client {
                     .chain(prefer_short_name_condition)
                     .chain(allow_localhost_redirect_condition)
                     .chain(jwt_legacy_crypto_enable_condition)
+                    .chain(device_flow_condition)
                     .collect()
             }
             None => vec![Condition {
@@ -466,8 +588,211 @@ impl KanidmOAuth2Client {
                 .and_then(|o| o.attrs.get(ATTR_OAUTH2_RS_SUP_SCOPE_MAP).cloned()),
             claims_map: oauth2_opt.and_then(|o| o.attrs.get(ATTR_OAUTH2_RS_CLAIM_MAP).cloned()),
             ready: status,
-            secret_name: secret,
+            secret_name: secret.map(|s| s.name_any()),
+            client_secret: client_secret.filter(|_| self.is_secret_exposed()),
             kanidm_ref: self.kanidm_ref(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::crd::KanidmOAuth2ClientSpec;
+
+    use std::collections::BTreeMap;
+
+    fn oauth2(origin: &str) -> KanidmOAuth2Client {
+        let mut oauth2 = KanidmOAuth2Client::new(
+            "test",
+            KanidmOAuth2ClientSpec {
+                displayname: "Test".to_string(),
+                origin: origin.to_string(),
+                public: true,
+                ..Default::default()
+            },
+        );
+        oauth2.metadata.namespace = Some("default".to_string());
+        oauth2
+    }
+
+    fn entry(origin_landing: &str) -> Entry {
+        Entry {
+            attrs: BTreeMap::from([
+                (ATTR_DISPLAYNAME.to_string(), vec!["Test".to_string()]),
+                (
+                    ATTR_OAUTH2_RS_ORIGIN_LANDING.to_string(),
+                    vec![origin_landing.to_string()],
+                ),
+            ]),
+        }
+    }
+
+    fn condition_status(status: &KanidmOAuth2ClientStatus, type_: &str) -> Option<String> {
+        status
+            .conditions
+            .as_ref()
+            .and_then(|c| c.iter().find(|c| c.type_ == type_))
+            .map(|c| c.status.clone())
+    }
+
+    #[test]
+    fn test_device_flow_condition() {
+        let mut oauth2 = oauth2("https://example.com");
+        let mut entry = entry("https://example.com/");
+        let status = oauth2
+            .generate_status(Some(entry.clone()), None, None, None)
+            .unwrap();
+        assert_eq!(condition_status(&status, TYPE_DEVICE_FLOW_UPDATED), None);
+
+        oauth2.spec.device_flow_enable = Some(false);
+        let status = oauth2
+            .generate_status(Some(entry.clone()), None, None, None)
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_DEVICE_FLOW_UPDATED),
+            Some(CONDITION_TRUE.to_string())
+        );
+
+        oauth2.spec.device_flow_enable = Some(true);
+        let status = oauth2
+            .generate_status(Some(entry.clone()), None, None, None)
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_DEVICE_FLOW_UPDATED),
+            Some(CONDITION_FALSE.to_string())
+        );
+
+        entry.attrs.insert(
+            ATTR_OAUTH2_DEVICE_FLOW_ENABLE.to_string(),
+            vec!["true".to_string()],
+        );
+        let status = oauth2
+            .generate_status(Some(entry), None, None, None)
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_DEVICE_FLOW_UPDATED),
+            Some(CONDITION_TRUE.to_string())
+        );
+    }
+
+    #[test]
+    fn test_updated_condition_with_same_origin_landing() {
+        let status = oauth2("https://example.com")
+            .generate_status(Some(entry("https://example.com/")), None, None, None)
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_UPDATED),
+            Some(CONDITION_TRUE.to_string())
+        );
+    }
+
+    #[test]
+    fn test_updated_condition_with_different_origin_landing() {
+        let status = oauth2("https://new.example.com")
+            .generate_status(Some(entry("https://example.com/")), None, None, None)
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_UPDATED),
+            Some(CONDITION_FALSE.to_string())
+        );
+    }
+
+    #[test]
+    fn test_secret_initialized_condition_with_issuer() {
+        let mut oauth2 = oauth2("https://example.com");
+        oauth2.spec.public = false;
+        let issuer = oauth2.issuer("idm.example.com");
+        let mut secret = Secret::default();
+        secret.metadata.name = Some(oauth2.secret_name());
+        secret.metadata.annotations = Some(BTreeMap::from([(
+            ISSUER_ANNOTATION.to_string(),
+            issuer.clone(),
+        )]));
+
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                None,
+                Some(&issuer),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_SECRET_INITIALIZED),
+            Some(CONDITION_FALSE.to_string())
+        );
+
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                Some(&secret),
+                Some(&issuer),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_SECRET_INITIALIZED),
+            Some(CONDITION_TRUE.to_string())
+        );
+        assert_eq!(status.secret_name, Some(oauth2.secret_name()));
+
+        let new_issuer = oauth2.issuer("idm.example.org");
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                Some(&secret),
+                Some(&new_issuer),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            condition_status(&status, TYPE_SECRET_INITIALIZED),
+            Some(CONDITION_FALSE.to_string())
+        );
+    }
+
+    #[test]
+    fn test_client_secret_in_status() {
+        let mut oauth2 = oauth2("https://example.com");
+        oauth2.spec.public = false;
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                None,
+                None,
+                Some("secret".to_string()),
+            )
+            .unwrap();
+        assert_eq!(status.client_secret, None);
+
+        oauth2.spec.expose_secret_in_status = true;
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                None,
+                None,
+                Some("secret".to_string()),
+            )
+            .unwrap();
+        assert_eq!(status.client_secret, Some("secret".to_string()));
+        assert!(!format!("{:?}", status).contains("\"secret\""));
+        let status_patch = Patch::Apply(KanidmOAuth2Client {
+            status: Some(status),
+            ..KanidmOAuth2Client::default()
+        });
+        assert!(!format!("{:?}", status_patch).contains("\"secret\""));
+
+        oauth2.spec.public = true;
+        let status = oauth2
+            .generate_status(
+                Some(entry("https://example.com/")),
+                None,
+                None,
+                Some("secret".to_string()),
+            )
+            .unwrap();
+        assert_eq!(status.client_secret, None);
+    }
+}
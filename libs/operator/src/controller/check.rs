@@ -0,0 +1,415 @@
+use super::api_queryable;
+use super::kanidm::{is_reachable, ClientSettings};
+
+use crate::error::{Error, Result};
+use crate::kanidm::crd::Kanidm;
+
+use kaniop_k8s_util::types::short_type_name;
+
+use std::fmt::{self, Debug, Display};
+
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::api::{Api, ListParams, PostParams, ResourceExt};
+use kube::client::Client;
+use kube::Resource;
+use serde::de::DeserializeOwned;
+
+/// Verbs required by the controllers on the resources they watch.
+pub const WATCH_VERBS: &[&str] = &["get", "list", "watch"];
+/// Verbs required by the controllers on the resources they reconcile.
+pub const RECONCILE_VERBS: &[&str] = &["get", "list", "watch", "patch"];
+/// Verbs required by the controllers on the resources they own.
+pub const OWN_VERBS: &[&str] = &["get", "list", "watch", "create", "patch", "delete"];
+
+/// Outcome of a single operator prerequisite check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckResult {
+    pub name: String,
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            None => write!(f, "[ OK ] {}", self.name),
+            Some(e) => write!(f, "[FAIL] {}: {e}", self.name),
+        }
+    }
+}
+
+/// Results of the operator prerequisite checks, in the order they were run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckReport {
+    results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    pub fn push(&mut self, name: impl Into<String>, result: Result<()>) {
+        self.results.push(CheckResult {
+            name: name.into(),
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+
+    pub fn results(&self) -> &[CheckResult] {
+        &self.results
+    }
+
+    pub fn failed(&self) -> Vec<&CheckResult> {
+        self.results.iter().filter(|r| !r.is_ok()).collect()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(CheckResult::is_ok)
+    }
+
+    /// Check that the API of the resource is queryable and that the operator is allowed to use
+    /// every verb on it.
+    pub async fn check_resource<K>(&mut self, client: Client, verbs: &[&str])
+    where
+        K: Resource + Clone + DeserializeOwned + Debug,
+        <K as Resource>::DynamicType: Default,
+    {
+        let kind = short_type_name::<K>();
+        self.push(
+            format!("{kind} API is queryable"),
+            api_queryable(&Api::<K>::all(client.clone())).await,
+        );
+        for verb in verbs {
+            self.push(
+                format!("{kind} {verb} is allowed"),
+                is_allowed::<K>(client.clone(), verb).await,
+            );
+        }
+    }
+
+    /// Check that the CRD of the resource is installed and serves the version used by the
+    /// operator.
+    pub async fn check_crd<K>(&mut self, client: Client)
+    where
+        K: Resource,
+        <K as Resource>::DynamicType: Default,
+    {
+        let dt = K::DynamicType::default();
+        let crd_name = format!("{}.{}", K::plural(&dt), K::group(&dt));
+        let version = K::version(&dt);
+        self.push(
+            format!("{crd_name} CRD serves {version}"),
+            is_crd_served(client, &crd_name, &version).await,
+        );
+    }
+
+    /// Check that every Kanidm in the cluster is reachable by the operator.
+    pub async fn check_kanidms(&mut self, client: Client, ca_bundle: Option<Vec<u8>>) {
+        let kanidms = match Api::<Kanidm>::all(client.clone())
+            .list(&ListParams::default())
+            .await
+        {
+            Ok(kanidms) => kanidms,
+            Err(e) => {
+                self.push(
+                    "Kanidms are listed",
+                    Err(Error::KubeError("failed to list Kanidms".to_string(), e)),
+                );
+                return;
+            }
+        };
+        for kanidm in kanidms {
+            let namespace = kanidm.namespace().unwrap_or_default();
+            let url = kanidm.client_url();
+            let result = match ClientSettings::new(
+                Some(&kanidm),
+                &namespace,
+                client.clone(),
+                ca_bundle.clone(),
+            )
+            .await
+            {
+                Ok(settings) if is_reachable(&url, &settings).await => Ok(()),
+                Ok(_) => Err(Error::MissingData(format!("{url} is not reachable"))),
+                Err(e) => Err(e),
+            };
+            self.push(
+                format!("Kanidm {namespace}/{} is reachable", kanidm.name_any()),
+                result,
+            );
+        }
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(f, "{result}")?;
+        }
+        write!(
+            f,
+            "{} checks, {} failed",
+            self.results.len(),
+            self.failed().len()
+        )
+    }
+}
+
+async fn is_allowed<K>(client: Client, verb: &str) -> Result<()>
+where
+    K: Resource,
+    <K as Resource>::DynamicType: Default,
+{
+    let dt = K::DynamicType::default();
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                group: Some(K::group(&dt).to_string()),
+                resource: Some(K::plural(&dt).to_string()),
+                verb: Some(verb.to_string()),
+                ..ResourceAttributes::default()
+            }),
+            ..SelfSubjectAccessReviewSpec::default()
+        },
+        ..SelfSubjectAccessReview::default()
+    };
+    let review = Api::<SelfSubjectAccessReview>::all(client)
+        .create(&PostParams::default(), &review)
+        .await
+        .map_err(|e| Error::KubeError("failed to review access".to_string(), e))?;
+    match review.status {
+        Some(status) if status.allowed => Ok(()),
+        status => Err(Error::PermissionDenied(
+            status
+                .and_then(|s| s.reason)
+                .unwrap_or_else(|| "denied".to_string()),
+        )),
+    }
+}
+
+async fn is_crd_served(client: Client, crd_name: &str, version: &str) -> Result<()> {
+    let crd = Api::<CustomResourceDefinition>::all(client)
+        .get(crd_name)
+        .await
+        .map_err(|e| Error::KubeError(format!("failed to get CRD {crd_name}"), e))?;
+    match crd
+        .spec
+        .versions
+        .iter()
+        .any(|v| v.name == version && v.served)
+    {
+        true => Ok(()),
+        false => Err(Error::MissingData(format!(
+            "version {version} is not served, available: {}",
+            crd.spec
+                .versions
+                .iter()
+                .filter(|v| v.served)
+                .map(|v| v.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use serde_json::json;
+
+    /// Client answering every request with the response returned by `respond` for its method and
+    /// path.
+    fn mock_client<F>(respond: F) -> (Client, tokio::task::JoinHandle<()>)
+    where
+        F: Fn(&http::Method, &str) -> (u16, serde_json::Value) + Send + 'static,
+    {
+        let (mock_service, mut handle) = tower_test::mock::pair::<
+            http::Request<kube::client::Body>,
+            http::Response<kube::client::Body>,
+        >();
+        let api_server = tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await {
+                let (status, body) = respond(request.method(), request.uri().path());
+                send.send_response(
+                    http::Response::builder()
+                        .status(status)
+                        .body(kube::client::Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        (Client::new(mock_service, "default"), api_server)
+    }
+
+    fn config_map_list() -> serde_json::Value {
+        json!({"apiVersion": "v1", "kind": "ConfigMapList", "metadata": {}, "items": []})
+    }
+
+    fn access_review(allowed: bool) -> serde_json::Value {
+        json!({
+            "apiVersion": "authorization.k8s.io/v1",
+            "kind": "SelfSubjectAccessReview",
+            "spec": {},
+            "status": {"allowed": allowed, "reason": (!allowed).then_some("forbidden by RBAC")},
+        })
+    }
+
+    fn forbidden() -> serde_json::Value {
+        json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": "forbidden",
+            "reason": "Forbidden",
+            "code": 403
+        })
+    }
+
+    fn crd(versions: &[(&str, bool)]) -> serde_json::Value {
+        json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "CustomResourceDefinition",
+            "metadata": {"name": "kanidms.kaniop.rs"},
+            "spec": {
+                "group": "kaniop.rs",
+                "names": {"kind": "Kanidm", "plural": "kanidms"},
+                "scope": "Namespaced",
+                "versions": versions
+                    .iter()
+                    .map(|(name, served)| json!({"name": name, "served": served, "storage": served}))
+                    .collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_check_resource() {
+        let (client, api_server) = mock_client(|method, path| match (method, path) {
+            (&http::Method::GET, "/api/v1/configmaps") => (200, config_map_list()),
+            (&http::Method::POST, "/apis/authorization.k8s.io/v1/selfsubjectaccessreviews") => {
+                (200, access_review(true))
+            }
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let mut report = CheckReport::default();
+        report
+            .check_resource::<ConfigMap>(client, WATCH_VERBS)
+            .await;
+        api_server.abort();
+
+        assert!(report.is_ok());
+        assert_eq!(
+            report
+                .results()
+                .iter()
+                .map(|r| r.name.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "ConfigMap API is queryable",
+                "ConfigMap get is allowed",
+                "ConfigMap list is allowed",
+                "ConfigMap watch is allowed",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_resource_aggregates_failures() {
+        let (client, api_server) = mock_client(|method, path| match (method, path) {
+            (&http::Method::GET, "/api/v1/configmaps") => (403, forbidden()),
+            (&http::Method::POST, "/apis/authorization.k8s.io/v1/selfsubjectaccessreviews") => {
+                (200, access_review(false))
+            }
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let mut report = CheckReport::default();
+        report.check_resource::<ConfigMap>(client, &["list"]).await;
+        api_server.abort();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.failed().len(), 2);
+        assert_eq!(
+            report.results()[1],
+            CheckResult {
+                name: "ConfigMap list is allowed".to_string(),
+                error: Some("forbidden by RBAC".to_string()),
+            }
+        );
+        assert!(report.to_string().ends_with("2 checks, 2 failed"));
+    }
+
+    #[tokio::test]
+    async fn test_is_allowed_denied_is_retryable() {
+        let (client, api_server) = mock_client(|method, path| match (method, path) {
+            (&http::Method::POST, "/apis/authorization.k8s.io/v1/selfsubjectaccessreviews") => {
+                (200, access_review(false))
+            }
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let result = is_allowed::<ConfigMap>(client, "list").await;
+        api_server.abort();
+
+        assert!(
+            matches!(&result, Err(e @ Error::PermissionDenied(_)) if e.is_retryable()),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_crd() {
+        let (client, api_server) = mock_client(|method, path| match path {
+            "/apis/apiextensions.k8s.io/v1/customresourcedefinitions/kanidms.kaniop.rs" => {
+                (200, crd(&[("v1alpha1", false), ("v1beta1", true)]))
+            }
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let mut report = CheckReport::default();
+        report.check_crd::<Kanidm>(client).await;
+        api_server.abort();
+
+        assert!(report.is_ok());
+        assert_eq!(
+            report.results()[0].name,
+            "kanidms.kaniop.rs CRD serves v1beta1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_crd_version_not_served() {
+        let (client, api_server) = mock_client(|method, path| match path {
+            "/apis/apiextensions.k8s.io/v1/customresourcedefinitions/kanidms.kaniop.rs" => {
+                (200, crd(&[("v1alpha1", true), ("v1beta1", false)]))
+            }
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let mut report = CheckReport::default();
+        report.check_crd::<Kanidm>(client).await;
+        api_server.abort();
+
+        assert_eq!(
+            report.failed()[0].error.as_deref(),
+            Some("version v1beta1 is not served, available: v1alpha1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_kanidms_list_failure() {
+        let (client, api_server) = mock_client(|method, path| match path {
+            "/apis/kaniop.rs/v1beta1/kanidms" => (403, forbidden()),
+            _ => panic!("unexpected request: {method} {path}"),
+        });
+        let mut report = CheckReport::default();
+        report.check_kanidms(client, None).await;
+        api_server.abort();
+
+        assert_eq!(report.failed().len(), 1);
+        assert_eq!(report.results()[0].name, "Kanidms are listed");
+    }
+}
@@ -1,26 +1,48 @@
 use super::{
-    kanidm::{KanidmKey, KanidmResource, KanidmUser},
-    ControllerId, KanidmClients, DEFAULT_RECONCILE_INTERVAL,
+    events::Recorder,
+    kanidm::{KanidmApiLimiter, KanidmApiLimits, KanidmKey, KanidmResource, KanidmUser},
+    ControllerId, DefaultNamespaceSelector, DeletionGrace, KanidmClients, DEFAULT_EXEC_TIMEOUT,
+    DEFAULT_RECONCILE_INTERVAL,
 };
 
 use crate::error::{Error, Result};
 use crate::kanidm::crd::Kanidm;
 use crate::metrics::ControllerMetrics;
+use crate::telemetry;
 
 use kanidm_client::KanidmClient;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
 use std::sync::Arc;
 
 use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder};
 use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use k8s_openapi::chrono::Utc;
+use kube::api::Api;
 use kube::client::Client;
-use kube::runtime::events::{Event, EventType, Recorder};
+use kube::runtime::controller::Action;
+use kube::runtime::events::{Event, EventType};
+use kube::runtime::finalizer::{self, finalizer, Event as Finalizer};
 use kube::runtime::reflector::{Lookup, ObjectRef, Store};
 use kube::{Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use tokio::sync::RwLock;
-use tokio::time::Duration;
-use tracing::{error, trace};
+use tokio::time::{Duration, Instant};
+use tracing::{trace, warn};
+
+/// Consecutive reconciles requiring a status update before backing off to the default interval
+pub const MAX_STATUS_UPDATE_ATTEMPTS: u32 = 10;
+const STATUS_UPDATE_MIN_DELAY: Duration = Duration::from_millis(500);
+const STATUS_UPDATE_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Consecutive reconciles with the same conditions out of sync before the object is stalled
+pub const MAX_OUT_OF_SYNC_RECONCILES: u32 = 5;
+pub const TYPE_STALLED: &str = "Stalled";
+
+/// Conditions out of sync of an object and the consecutive reconciles they have been so.
+pub type OutOfSyncConditions = (BTreeSet<String>, u32);
 
 // Context for our reconciler
 #[derive(Clone)]
@@ -33,6 +55,8 @@ pub struct Context<K: Resource> {
     pub metrics: Arc<ControllerMetrics>,
     /// State of the error backoff policy per object
     error_backoff_cache: Arc<RwLock<HashMap<ObjectRef<K>, RwLock<ExponentialBackoff>>>>,
+    /// Consecutive failed reconciles per object
+    reconcile_failures: Arc<RwLock<HashMap<ObjectRef<K>, u32>>>,
     /// Event recorder
     pub recorder: Recorder,
     /// Cache for Namespace resources
@@ -44,6 +68,28 @@ pub struct Context<K: Resource> {
     /// Shared Kanidm cache clients with the ability to manage the operation of Kanidm as a
     /// database and service
     system_clients: Arc<RwLock<KanidmClients>>,
+    /// Shared concurrent request limits per Kanidm
+    kanidm_api_limits: Arc<KanidmApiLimits>,
+    /// Policy for objects whose finalizer cleanup keeps failing
+    deletion_grace: DeletionGrace,
+    /// Failed finalizer cleanup attempts per object
+    cleanup_failures: Arc<RwLock<HashMap<ObjectRef<K>, u32>>>,
+    /// Consecutive reconciles per object that required a status update
+    status_update_attempts: Arc<RwLock<HashMap<ObjectRef<K>, u32>>>,
+    /// Conditions out of sync per object and the consecutive reconciles they have been so
+    out_of_sync_conditions: Arc<RwLock<HashMap<ObjectRef<K>, OutOfSyncConditions>>>,
+    /// CA certificate trusted by Kanidm clients, unless the Kanidm defines its own
+    pub ca_bundle: Option<Vec<u8>>,
+    /// Maximum duration of a command executed in a Kanidm pod
+    pub exec_timeout: Duration,
+    /// Interval between full reconciles, which apply every attribute regardless of the status
+    pub full_reconcile_interval: Option<Duration>,
+    /// Start of the current full reconcile interval per object
+    full_reconciles: Arc<RwLock<HashMap<ObjectRef<K>, Instant>>>,
+    /// Namespaces watched when a Kanidm has no namespace selector
+    pub default_namespace_selector: DefaultNamespaceSelector,
+    /// URL prefixes that external sources of objects are allowed to read from
+    pub external_source_allowed_urls: Vec<String>,
 }
 
 impl<K> Context<K>
@@ -59,8 +105,10 @@ where
         recorder: Recorder,
         idm_clients: Arc<RwLock<KanidmClients>>,
         system_clients: Arc<RwLock<KanidmClients>>,
+        kanidm_api_limits: Arc<KanidmApiLimits>,
         namespace_store: Store<Namespace>,
         kanidm_store: Store<Kanidm>,
+        deletion_grace: DeletionGrace,
     ) -> Self {
         Self {
             controller_id,
@@ -71,8 +119,342 @@ where
             kanidm_store,
             idm_clients,
             system_clients,
+            kanidm_api_limits,
             error_backoff_cache: Arc::default(),
+            reconcile_failures: Arc::default(),
+            deletion_grace,
+            cleanup_failures: Arc::default(),
+            status_update_attempts: Arc::default(),
+            out_of_sync_conditions: Arc::default(),
+            ca_bundle: None,
+            exec_timeout: DEFAULT_EXEC_TIMEOUT,
+            full_reconcile_interval: None,
+            full_reconciles: Arc::default(),
+            default_namespace_selector: DefaultNamespaceSelector::default(),
+            external_source_allowed_urls: Vec::new(),
+        }
+    }
+
+    /// Trust `ca_bundle` in the Kanidm clients, unless the Kanidm defines its own CA.
+    pub fn with_ca_bundle(mut self, ca_bundle: Option<Vec<u8>>) -> Self {
+        self.ca_bundle = ca_bundle;
+        self
+    }
+
+    /// Abort commands executed in Kanidm pods that take longer than `exec_timeout`.
+    pub fn with_exec_timeout(mut self, exec_timeout: Duration) -> Self {
+        self.exec_timeout = exec_timeout;
+        self
+    }
+
+    /// Apply every attribute of the objects each `full_reconcile_interval`, correcting changes
+    /// made outside the operator that the status does not detect.
+    pub fn with_full_reconcile_interval(
+        mut self,
+        full_reconcile_interval: Option<Duration>,
+    ) -> Self {
+        self.full_reconcile_interval = full_reconcile_interval;
+        self
+    }
+
+    /// Watch the namespaces of `default_namespace_selector` for the objects referencing a Kanidm
+    /// without a namespace selector.
+    pub fn with_default_namespace_selector(
+        mut self,
+        default_namespace_selector: DefaultNamespaceSelector,
+    ) -> Self {
+        self.default_namespace_selector = default_namespace_selector;
+        self
+    }
+
+    /// Allow external sources to read from URLs starting with any of
+    /// `external_source_allowed_urls`. URL sources are rejected when it is empty.
+    pub fn with_external_source_allowed_urls(
+        mut self,
+        external_source_allowed_urls: Vec<String>,
+    ) -> Self {
+        self.external_source_allowed_urls = external_source_allowed_urls;
+        self
+    }
+}
+
+impl<K> Context<K>
+where
+    K: Resource<DynamicType = ()> + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    /// Drop the state tracked for the object, once its finalizer is removed.
+    async fn forget(&self, obj: &K) {
+        let obj_ref = ObjectRef::from(obj);
+        self.cleanup_failures.write().await.remove(&obj_ref);
+        self.status_update_attempts.write().await.remove(&obj_ref);
+        self.out_of_sync_conditions.write().await.remove(&obj_ref);
+        self.full_reconciles.write().await.remove(&obj_ref);
+    }
+
+    /// Apply the deletion grace policy to the result of a finalizer cleanup. Once the cleanup of
+    /// an object fails `max_cleanup_attempts` times, a warning event is published on each failure
+    /// and, if forced removal is enabled, the error is ignored so the finalizer is removed.
+    pub async fn cleanup_with_grace(&self, obj: &K, result: Result<Action>) -> Result<Action> {
+        let e = match result {
+            Ok(action) => {
+                self.forget(obj).await;
+                return Ok(action);
+            }
+            Err(e) => e,
+        };
+        self.metrics
+            .finalizer_cleanup_failures_inc(&<K as Resource>::kind(&()));
+        let attempts = {
+            let mut cleanup_failures = self.cleanup_failures.write().await;
+            let attempts = cleanup_failures.entry(ObjectRef::from(obj)).or_default();
+            *attempts += 1;
+            *attempts
+        };
+        match cleanup_decision(&self.deletion_grace, attempts) {
+            CleanupDecision::Retry => Err(e),
+            CleanupDecision::Warn => {
+                self.publish_event(
+                    obj,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "CleanupFailed".to_string(),
+                        note: Some(format!("cleanup failed {attempts} times: {e}")),
+                        action: "Cleanup".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await?;
+                Err(e)
+            }
+            CleanupDecision::Force => {
+                warn!(
+                    msg = "removing finalizer after failed cleanups",
+                    attempts,
+                    %e
+                );
+                self.publish_event(
+                    obj,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "FinalizerForceRemoved".to_string(),
+                        note: Some(format!(
+                            "cleanup failed {attempts} times, removing finalizer without deleting \
+                            the object from Kanidm: {e}"
+                        )),
+                        action: "Cleanup".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await?;
+                self.forget(obj).await;
+                Ok(Action::await_change())
+            }
+        }
+    }
+}
+
+impl<K> Context<K>
+where
+    K: Resource<DynamicType = ()> + Lookup + Clone + DeserializeOwned + Serialize + Debug + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    /// Run the finalizer cleanup of an object being deleted whose Kanidm client or status could
+    /// not be obtained, e.g. because Kanidm is unreachable or was deleted. The error counts as a
+    /// failed cleanup, so the deletion grace policy applies and can remove the finalizer.
+    pub async fn cleanup_unavailable(
+        &self,
+        api: &Api<K>,
+        finalizer_name: &str,
+        obj: Arc<K>,
+        error: Error,
+    ) -> Result<Action, finalizer::Error<Error>> {
+        finalizer(api, finalizer_name, obj, |event| async {
+            match event {
+                Finalizer::Apply(_) => Err(error),
+                Finalizer::Cleanup(obj) => self.cleanup_with_grace(&obj, Err(error)).await,
+            }
+        })
+        .await
+    }
+}
+
+impl<K> Context<K>
+where
+    K: Resource<DynamicType = ()> + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    /// Action after a reconcile that applied changes, so the status has to be updated. Requeues
+    /// are escalated with jitter while the object keeps requiring updates and, after
+    /// `MAX_STATUS_UPDATE_ATTEMPTS`, a warning event is published and the object is requeued in
+    /// the default interval.
+    pub async fn status_update_requeue(&self, obj: &K) -> Action {
+        let obj_ref = ObjectRef::from(obj);
+        let attempts = {
+            let mut status_update_attempts = self.status_update_attempts.write().await;
+            let attempts = status_update_attempts.entry(obj_ref.clone()).or_default();
+            *attempts += 1;
+            *attempts
+        };
+        if let Some(duration) = status_update_delay(attempts) {
+            trace!(msg = "status update required", attempts, ?duration);
+            return Action::requeue(duration);
         }
+        self.status_update_attempts.write().await.remove(&obj_ref);
+        warn!(msg = "status not converging after updates", attempts);
+        // publish errors are already logged
+        let _ = self
+            .publish_event(
+                obj,
+                Event {
+                    type_: EventType::Warning,
+                    reason: "StatusNotConverging".to_string(),
+                    note: Some(format!(
+                        "status still requires updates after {MAX_STATUS_UPDATE_ATTEMPTS} \
+                    reconciles, retrying in {}s",
+                        DEFAULT_RECONCILE_INTERVAL.as_secs()
+                    )),
+                    action: "Reconcile".to_string(),
+                    secondary: None,
+                },
+            )
+            .await;
+        Action::requeue(DEFAULT_RECONCILE_INTERVAL)
+    }
+
+    /// Action after a reconcile that did not require any change.
+    pub async fn status_converged_requeue(&self, obj: &K) -> Action {
+        self.status_update_attempts
+            .write()
+            .await
+            .remove(&ObjectRef::from(obj));
+        Action::requeue(DEFAULT_RECONCILE_INTERVAL)
+    }
+}
+
+impl<K> Context<K>
+where
+    K: Resource + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    /// Whether `full_reconcile_interval` has elapsed since the last full reconcile of the object.
+    /// The interval of an object starts the first time it is checked.
+    pub async fn full_reconcile_due(&self, obj: &K) -> bool {
+        let Some(interval) = self.full_reconcile_interval else {
+            return false;
+        };
+        let now = Instant::now();
+        let last = *self
+            .full_reconciles
+            .write()
+            .await
+            .entry(ObjectRef::from(obj))
+            .or_insert(now);
+        now.duration_since(last) >= interval
+    }
+
+    /// Start a new full reconcile interval for the object.
+    pub async fn full_reconcile_done(&self, obj: &K) {
+        if self.full_reconcile_interval.is_some() {
+            self.full_reconciles
+                .write()
+                .await
+                .insert(ObjectRef::from(obj), Instant::now());
+        }
+    }
+
+    /// Track the conditions of the object that are out of sync with its spec, i.e. the ones
+    /// reconcile will try to fix. When the same conditions stay out of sync for
+    /// `MAX_OUT_OF_SYNC_RECONCILES` consecutive reconciles, a `Stalled` condition naming them is
+    /// returned to be added to the status.
+    pub async fn stalled_condition(
+        &self,
+        obj: &K,
+        out_of_sync: BTreeSet<String>,
+    ) -> Option<Condition> {
+        let obj_ref = ObjectRef::from(obj);
+        if out_of_sync.is_empty() {
+            self.out_of_sync_conditions.write().await.remove(&obj_ref);
+            return None;
+        }
+        let reconciles = {
+            let mut out_of_sync_conditions = self.out_of_sync_conditions.write().await;
+            let (conditions, reconciles) = out_of_sync_conditions
+                .entry(obj_ref)
+                .or_insert_with(|| (out_of_sync.clone(), 0));
+            if *conditions != out_of_sync {
+                *conditions = out_of_sync.clone();
+                *reconciles = 0;
+            }
+            *reconciles += 1;
+            *reconciles
+        };
+        if reconciles < MAX_OUT_OF_SYNC_RECONCILES {
+            return None;
+        }
+        warn!(msg = "conditions not converging", ?out_of_sync, reconciles);
+        Some(stalled_condition_for(&out_of_sync, obj.meta().generation))
+    }
+}
+
+/// Types of the `conditions` in `types` whose status is `False`, i.e. out of sync with the spec.
+pub fn out_of_sync_conditions(
+    conditions: Option<&[Condition]>,
+    types: &[&str],
+) -> BTreeSet<String> {
+    conditions
+        .unwrap_or_default()
+        .iter()
+        .filter(|c| c.status == "False" && types.contains(&c.type_.as_str()))
+        .map(|c| c.type_.clone())
+        .collect()
+}
+
+/// Condition for an object whose `out_of_sync` conditions do not converge.
+fn stalled_condition_for(out_of_sync: &BTreeSet<String>, generation: Option<i64>) -> Condition {
+    let out_of_sync = out_of_sync.iter().cloned().collect::<Vec<_>>().join(", ");
+    Condition {
+        type_: TYPE_STALLED.to_string(),
+        status: "True".to_string(),
+        reason: "ConditionsNotConverging".to_string(),
+        message: format!(
+            "{out_of_sync} still out of sync after {MAX_OUT_OF_SYNC_RECONCILES} reconciles."
+        ),
+        last_transition_time: Time(Utc::now()),
+        observed_generation: generation,
+    }
+}
+
+/// Delay before the given consecutive attempt to update the status: 500ms, 1s, 2s, 4s... up to
+/// 30s, plus a jitter of up to 500ms. `None` once `MAX_STATUS_UPDATE_ATTEMPTS` are exceeded.
+fn status_update_delay(attempt: u32) -> Option<Duration> {
+    let attempt = usize::try_from(attempt).ok()?.checked_sub(1)?;
+    ExponentialBuilder::default()
+        .with_min_delay(STATUS_UPDATE_MIN_DELAY)
+        .with_max_delay(STATUS_UPDATE_MAX_DELAY)
+        .with_max_times(MAX_STATUS_UPDATE_ATTEMPTS as usize)
+        .with_jitter()
+        .build()
+        .nth(attempt)
+}
+
+#[derive(Debug, PartialEq)]
+enum CleanupDecision {
+    /// Return the error and retry with backoff
+    Retry,
+    /// Publish a warning event and retry with backoff
+    Warn,
+    /// Publish a warning event and remove the finalizer
+    Force,
+}
+
+fn cleanup_decision(deletion_grace: &DeletionGrace, attempts: u32) -> CleanupDecision {
+    if attempts < deletion_grace.max_cleanup_attempts {
+        CleanupDecision::Retry
+    } else if deletion_grace.force_finalizer_removal {
+        CleanupDecision::Force
+    } else {
+        CleanupDecision::Warn
     }
 }
 
@@ -98,45 +480,67 @@ where
             name: name.clone(),
         };
 
-        if let Some(client) = cache.read().await.get(&key) {
+        // clients are recreated when the Kanidm changes, e.g. its TLS or timeout settings
+        let kanidm = self.get_kanidm(obj);
+        let generation = kanidm.as_ref().and_then(|k| k.metadata.generation);
+        if let Some(pool) = cache.read().await.get(&key, generation) {
             trace!(
-                msg = "check existing Kanidm client session",
+                msg = "check existing Kanidm client sessions",
                 namespace,
                 name
             );
-            if client.auth_valid().await.is_ok() {
+            if let Some(client) = pool
+                .acquire(|client| async move { client.auth_valid().await.is_ok() })
+                .await
+            {
                 trace!(msg = "reuse Kanidm client session", namespace, name);
-                return Ok(client.clone());
+                return Ok(client);
             }
         }
 
-        match KanidmClients::create_client(&namespace, &name, user, self.client.clone()).await {
+        match KanidmClients::create_client(
+            &namespace,
+            &name,
+            user,
+            self.client.clone(),
+            kanidm,
+            self.ca_bundle.clone(),
+        )
+        .await
+        {
             Ok(client) => {
-                cache.write().await.insert(key.clone(), client.clone());
+                cache
+                    .write()
+                    .await
+                    .insert(key.clone(), client.clone(), generation);
                 Ok(client)
             }
             Err(e) => {
-                self.recorder
-                    .publish(
-                        &Event {
-                            type_: EventType::Warning,
-                            reason: "KanidmClientError".to_string(),
-                            note: Some(e.to_string()),
-                            action: "KanidmClientCreating".into(),
-                            secondary: None,
-                        },
-                        &obj.object_ref(&()),
-                    )
-                    .await
-                    .map_err(|e| {
-                        error!(msg = "failed to create Kanidm client", %e);
-                        Error::KubeError("failed to publish event".to_string(), e)
-                    })?;
+                self.publish_event(
+                    obj,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "KanidmClientError".to_string(),
+                        note: Some(e.to_string()),
+                        action: "KanidmClientCreating".into(),
+                        secondary: None,
+                    },
+                )
+                .await?;
                 Err(e)
             }
         }
     }
 
+    /// Limiter for the concurrent requests sent to the Kanidm of the given object.
+    pub fn kanidm_api_limiter(&self, obj: &K) -> KanidmApiLimiter {
+        let key = KanidmKey {
+            namespace: obj.kanidm_namespace(),
+            name: obj.kanidm_name(),
+        };
+        self.kanidm_api_limits.limiter(&key, self.metrics.clone())
+    }
+
     /// Return [`Kanidm`] of the given object
     ///
     /// [`Kanidm`]: struct.Kanidm.html
@@ -149,11 +553,56 @@ where
     }
 }
 
+impl<K> Context<K>
+where
+    K: Resource<DynamicType = ()>,
+{
+    /// Healthy and total number of cached clients for a Kanidm, summing both users.
+    pub async fn client_pool_health(&self, namespace: &str, name: &str) -> (usize, usize) {
+        let key = KanidmKey {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        };
+        let (idm_healthy, idm_total) = self.idm_clients.read().await.health(&key);
+        let (system_healthy, system_total) = self.system_clients.read().await.health(&key);
+        (idm_healthy + system_healthy, idm_total + system_total)
+    }
+
+    /// Publish an event for the given object. The trace ID of the current span is annotated in
+    /// the event, so events can be correlated with the reconcile that emitted them.
+    pub async fn publish_event(&self, obj: &K, event: Event) -> Result<()> {
+        self.recorder
+            .publish(&event, &obj.object_ref(&()), telemetry::get_trace_id())
+            .await
+            .map_err(|e| {
+                warn!(msg = format!("failed to publish {} event", event.reason), %e);
+                Error::KubeError("failed to publish event".to_string(), e)
+            })
+    }
+}
+
 #[allow(async_fn_in_trait)]
 pub trait BackoffContext<K: Resource> {
     fn metrics(&self) -> &Arc<ControllerMetrics>;
     async fn get_backoff(&self, obj_ref: ObjectRef<K>) -> Duration;
     async fn reset_backoff(&self, obj_ref: ObjectRef<K>);
+    async fn publish_terminal_error(&self, obj: &K, error: &Error);
+}
+
+impl<K> Context<K>
+where
+    K: Resource<DynamicType = ()> + ResourceExt + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Eq + std::hash::Hash + Clone,
+{
+    /// Number of consecutive failed reconciles of the given object.
+    pub async fn reconcile_failures(&self, obj_ref: &ObjectRef<K>) -> u32 {
+        self.reconcile_failures
+            .read()
+            .await
+            .get(obj_ref)
+            .copied()
+            .unwrap_or_default()
+    }
 }
 
 impl<K> BackoffContext<K> for Context<K>
@@ -167,6 +616,12 @@ where
 
     /// Return next duration of the backoff policy for the given object
     async fn get_backoff(&self, obj_ref: ObjectRef<K>) -> Duration {
+        *self
+            .reconcile_failures
+            .write()
+            .await
+            .entry(obj_ref.clone())
+            .or_default() += 1;
         {
             let read_guard = self.error_backoff_cache.read().await;
             if let Some(backoff) = read_guard.get(&obj_ref) {
@@ -195,8 +650,26 @@ where
         duration
     }
 
+    /// Publish a warning event for an error that is not retried until the object changes
+    async fn publish_terminal_error(&self, obj: &K, error: &Error) {
+        // publish errors are already logged
+        let _ = self
+            .publish_event(
+                obj,
+                Event {
+                    type_: EventType::Warning,
+                    reason: "ReconcileFailed".to_string(),
+                    note: Some(format!("{error}, waiting for changes to retry")),
+                    action: "Reconcile".to_string(),
+                    secondary: None,
+                },
+            )
+            .await;
+    }
+
     /// Reset the backoff policy for the given object
     async fn reset_backoff(&self, obj_ref: ObjectRef<K>) {
+        self.reconcile_failures.write().await.remove(&obj_ref);
         let read_guard = self.error_backoff_cache.read().await;
         if read_guard.get(&obj_ref).is_some() {
             drop(read_guard);
@@ -240,3 +713,416 @@ where
         self.get_kanidm_client(obj, KanidmUser::Admin).await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::metrics::Metrics;
+
+    use http::{Method, Request, Response};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::api::ObjectMeta;
+    use kube::client::Body;
+    use kube::runtime::reflector::store::Writer;
+    use prometheus_client::registry::Registry;
+
+    #[test]
+    fn test_cleanup_decision_retry_then_warn() {
+        let deletion_grace = DeletionGrace {
+            max_cleanup_attempts: 3,
+            force_finalizer_removal: false,
+        };
+        assert_eq!(cleanup_decision(&deletion_grace, 1), CleanupDecision::Retry);
+        assert_eq!(cleanup_decision(&deletion_grace, 2), CleanupDecision::Retry);
+        assert_eq!(cleanup_decision(&deletion_grace, 3), CleanupDecision::Warn);
+        assert_eq!(cleanup_decision(&deletion_grace, 4), CleanupDecision::Warn);
+    }
+
+    #[test]
+    fn test_cleanup_decision_retry_then_force() {
+        let deletion_grace = DeletionGrace {
+            max_cleanup_attempts: 3,
+            force_finalizer_removal: true,
+        };
+        assert_eq!(cleanup_decision(&deletion_grace, 2), CleanupDecision::Retry);
+        assert_eq!(cleanup_decision(&deletion_grace, 3), CleanupDecision::Force);
+    }
+
+    #[test]
+    fn test_status_update_delay_escalates_until_max_attempts() {
+        let expected_secs = [0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 30.0, 30.0, 30.0, 30.0];
+        assert_eq!(expected_secs.len(), MAX_STATUS_UPDATE_ATTEMPTS as usize);
+        for (attempt, expected) in (1..).zip(expected_secs) {
+            let delay = status_update_delay(attempt).unwrap();
+            let expected = Duration::from_secs_f64(expected);
+            assert!(
+                delay >= expected && delay <= expected + STATUS_UPDATE_MIN_DELAY,
+                "attempt {attempt}: {delay:?} not in [{expected:?}, {expected:?} + jitter]"
+            );
+        }
+        assert_eq!(status_update_delay(MAX_STATUS_UPDATE_ATTEMPTS + 1), None);
+        assert_eq!(status_update_delay(0), None);
+    }
+
+    #[test]
+    fn test_stalled_condition_names_conditions() {
+        let out_of_sync = BTreeSet::from(["MailUpdated".to_string(), "Updated".to_string()]);
+        let condition = stalled_condition_for(&out_of_sync, Some(3));
+        assert_eq!(condition.type_, TYPE_STALLED);
+        assert_eq!(condition.status, "True");
+        assert_eq!(
+            condition.message,
+            "MailUpdated, Updated still out of sync after 5 reconciles."
+        );
+        assert_eq!(condition.observed_generation, Some(3));
+    }
+
+    #[test]
+    fn test_out_of_sync_conditions() {
+        let condition = |type_: &str, status: &str| Condition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            reason: String::new(),
+            message: String::new(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: None,
+        };
+        let conditions = vec![
+            condition("Exists", "True"),
+            condition("Updated", "False"),
+            condition("Valid", "False"),
+        ];
+        assert_eq!(
+            out_of_sync_conditions(Some(&conditions), &["Exists", "Updated"]),
+            BTreeSet::from(["Updated".to_string()])
+        );
+        assert!(out_of_sync_conditions(None, &["Exists"]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_failures() {
+        let (mock_service, _handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            Arc::default(),
+            Recorder::new(client, "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace::default(),
+        );
+        let obj_ref = ObjectRef::<ConfigMap>::new("test").within("default");
+
+        assert_eq!(ctx.reconcile_failures(&obj_ref).await, 0);
+        ctx.get_backoff(obj_ref.clone()).await;
+        ctx.get_backoff(obj_ref.clone()).await;
+        assert_eq!(ctx.reconcile_failures(&obj_ref).await, 2);
+        ctx.reset_backoff(obj_ref.clone()).await;
+        assert_eq!(ctx.reconcile_failures(&obj_ref).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_full_reconcile_due() {
+        let (mock_service, _handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            Arc::default(),
+            Recorder::new(client, "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace::default(),
+        )
+        .with_full_reconcile_interval(Some(Duration::from_millis(50)));
+        let obj = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("test".to_string()),
+                namespace: Some("default".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+
+        assert!(!ctx.full_reconcile_due(&obj).await);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(ctx.full_reconcile_due(&obj).await);
+        ctx.full_reconcile_done(&obj).await;
+        assert!(!ctx.full_reconcile_due(&obj).await);
+
+        let ctx = ctx.with_full_reconcile_interval(None);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!ctx.full_reconcile_due(&obj).await);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_with_grace_failure() {
+        let (mock_service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["test"]);
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            metrics.controllers.get("test").unwrap().clone(),
+            Recorder::new(client, "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace {
+                max_cleanup_attempts: 2,
+                force_finalizer_removal: false,
+            },
+        );
+        let mut config_map = ConfigMap::default();
+        config_map.metadata.name = Some("test".to_string());
+        config_map.metadata.namespace = Some("default".to_string());
+
+        let result = ctx
+            .cleanup_with_grace(&config_map, Err(Error::MissingData("test".to_string())))
+            .await;
+        assert!(result.is_err());
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::POST);
+            assert_eq!(
+                request.uri().path(),
+                "/apis/events.k8s.io/v1/namespaces/default/events"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["type"], "Warning");
+            assert_eq!(event["reason"], "CleanupFailed");
+            send.send_response(Response::builder().body(Body::from(body)).unwrap());
+        });
+        let result = ctx
+            .cleanup_with_grace(&config_map, Err(Error::MissingData("test".to_string())))
+            .await;
+        assert!(result.is_err());
+        api_server.await.unwrap();
+
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &metrics.registry).unwrap();
+        assert!(buffer.contains(
+            r#"kaniop_finalizer_cleanup_failures_total{controller="test",kind="ConfigMap"} 2"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_unavailable_kanidm_removes_finalizer() {
+        let (mock_service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            Arc::default(),
+            Recorder::new(client.clone(), "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace {
+                max_cleanup_attempts: 2,
+                force_finalizer_removal: true,
+            },
+        );
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let mut config_map = ConfigMap::default();
+        config_map.metadata.name = Some("test".to_string());
+        config_map.metadata.namespace = Some("default".to_string());
+        config_map.metadata.finalizers = Some(vec!["kaniop.rs/test".to_string()]);
+        config_map.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        let config_map = Arc::new(config_map);
+        let unreachable = || {
+            Error::KanidmClientError(
+                "failed to get Kanidm client".to_string(),
+                Box::new(kanidm_client::ClientError::Http(
+                    http::StatusCode::SERVICE_UNAVAILABLE,
+                    None,
+                    String::new(),
+                )),
+            )
+        };
+
+        let result = ctx
+            .cleanup_unavailable(&api, "kaniop.rs/test", config_map.clone(), unreachable())
+            .await;
+        assert!(matches!(
+            result,
+            Err(finalizer::Error::CleanupFailed(Error::KanidmClientError(
+                ..
+            )))
+        ));
+        assert_eq!(
+            ctx.cleanup_failures
+                .read()
+                .await
+                .get(&ObjectRef::from(config_map.as_ref())),
+            Some(&1)
+        );
+
+        ctx.status_update_attempts
+            .write()
+            .await
+            .insert(ObjectRef::from(config_map.as_ref()), 1);
+        ctx.out_of_sync_conditions.write().await.insert(
+            ObjectRef::from(config_map.as_ref()),
+            (BTreeSet::from(["Updated".to_string()]), 1),
+        );
+        let removed = config_map.as_ref().clone();
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::POST);
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["reason"], "FinalizerForceRemoved");
+            send.send_response(Response::builder().body(Body::from(body)).unwrap());
+
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::PATCH);
+            assert_eq!(
+                request.uri().path(),
+                "/api/v1/namespaces/default/configmaps/test"
+            );
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let patch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(patch[1]["op"], "remove");
+            assert_eq!(patch[1]["path"], "/metadata/finalizers/0");
+            let mut removed = removed;
+            removed.metadata.finalizers = None;
+            send.send_response(
+                Response::builder()
+                    .body(Body::from(serde_json::to_vec(&removed).unwrap()))
+                    .unwrap(),
+            );
+        });
+        let action = ctx
+            .cleanup_unavailable(&api, "kaniop.rs/test", config_map.clone(), unreachable())
+            .await
+            .unwrap();
+        assert_eq!(action, Action::await_change());
+        api_server.await.unwrap();
+        assert!(ctx.cleanup_failures.read().await.is_empty());
+        assert!(ctx.status_update_attempts.read().await.is_empty());
+        assert!(ctx.out_of_sync_conditions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_status_update_requeue_not_converging() {
+        let (mock_service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            Arc::default(),
+            Recorder::new(client, "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace::default(),
+        );
+        let mut config_map = ConfigMap::default();
+        config_map.metadata.name = Some("test".to_string());
+        config_map.metadata.namespace = Some("default".to_string());
+
+        for _ in 0..MAX_STATUS_UPDATE_ATTEMPTS {
+            let action = ctx.status_update_requeue(&config_map).await;
+            assert_ne!(action, Action::requeue(DEFAULT_RECONCILE_INTERVAL));
+        }
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::POST);
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["type"], "Warning");
+            assert_eq!(event["reason"], "StatusNotConverging");
+            send.send_response(Response::builder().body(Body::from(body)).unwrap());
+        });
+        let action = ctx.status_update_requeue(&config_map).await;
+        assert_eq!(action, Action::requeue(DEFAULT_RECONCILE_INTERVAL));
+        api_server.await.unwrap();
+
+        let obj_ref = ObjectRef::from(&config_map);
+        // attempts start again after backing off
+        ctx.status_update_requeue(&config_map).await;
+        assert_eq!(
+            ctx.status_update_attempts.read().await.get(&obj_ref),
+            Some(&1)
+        );
+        assert_eq!(
+            ctx.status_converged_requeue(&config_map).await,
+            Action::requeue(DEFAULT_RECONCILE_INTERVAL)
+        );
+        assert_eq!(ctx.status_update_attempts.read().await.get(&obj_ref), None);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_condition_perpetually_false() {
+        let (mock_service, _handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(mock_service, "default");
+        let ctx = Context::<ConfigMap>::new(
+            "test",
+            client.clone(),
+            Arc::default(),
+            Recorder::new(client, "test".into()),
+            Arc::default(),
+            Arc::default(),
+            Arc::new(KanidmApiLimits::new(1)),
+            Writer::default().as_reader(),
+            Writer::default().as_reader(),
+            DeletionGrace::default(),
+        );
+        let mut config_map = ConfigMap::default();
+        config_map.metadata.name = Some("test".to_string());
+        config_map.metadata.namespace = Some("default".to_string());
+        let out_of_sync = BTreeSet::from(["MailUpdated".to_string()]);
+
+        for _ in 1..MAX_OUT_OF_SYNC_RECONCILES {
+            assert!(ctx
+                .stalled_condition(&config_map, out_of_sync.clone())
+                .await
+                .is_none());
+        }
+        let condition = ctx
+            .stalled_condition(&config_map, out_of_sync.clone())
+            .await
+            .unwrap();
+        assert_eq!(condition.type_, TYPE_STALLED);
+        assert!(condition.message.starts_with("MailUpdated "));
+        assert!(ctx
+            .stalled_condition(&config_map, out_of_sync.clone())
+            .await
+            .is_some());
+
+        // other conditions out of sync start the count again
+        let other_out_of_sync = BTreeSet::from(["Updated".to_string()]);
+        assert!(ctx
+            .stalled_condition(&config_map, other_out_of_sync)
+            .await
+            .is_none());
+
+        // converging resets the count
+        assert!(ctx
+            .stalled_condition(&config_map, BTreeSet::new())
+            .await
+            .is_none());
+        assert!(ctx
+            .stalled_condition(&config_map, out_of_sync)
+            .await
+            .is_none());
+    }
+}
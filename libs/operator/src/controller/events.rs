@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::ObjectReference;
+use k8s_openapi::api::events::v1::{Event as K8sEvent, EventSeries};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{Duration, Utc};
+use kube::api::{Api, Patch, PatchParams, PostParams};
+use kube::client::Client;
+use kube::runtime::events::{Event, EventType, Reporter};
+use kube::ResourceExt;
+use opentelemetry::trace::TraceId;
+use tokio::sync::RwLock;
+
+/// Annotation of the events with the trace ID of the last reconcile that published them.
+pub const TRACE_ID_ANNOTATION: &str = "kaniop.rs/trace-id";
+
+/// Time since an event was last observed during which it is aggregated with its repetitions.
+const CACHE_TTL: Duration = Duration::minutes(6);
+
+/// Object referenced by an event, identified as in [`kube::runtime::events::Reference`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ReferenceKey {
+    api_version: Option<String>,
+    kind: Option<String>,
+    namespace: Option<String>,
+    name: Option<String>,
+    uid: Option<String>,
+}
+
+impl From<&ObjectReference> for ReferenceKey {
+    fn from(reference: &ObjectReference) -> Self {
+        Self {
+            api_version: reference.api_version.clone(),
+            kind: reference.kind.clone(),
+            namespace: reference.namespace.clone(),
+            name: reference.name.clone(),
+            uid: reference.uid.clone(),
+        }
+    }
+}
+
+/// Events with the same key are aggregated in a series. The note is not part of the key, so it
+/// is kept from the first event of the series.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    event_type: EventType,
+    action: String,
+    reason: String,
+    regarding: ReferenceKey,
+    related: Option<ReferenceKey>,
+}
+
+/// Publisher of Kubernetes events as [`kube::runtime::events::Recorder`], which also annotates
+/// them with the trace ID of the reconcile that published them, so events can be correlated
+/// with traces while their notes stay stable.
+#[derive(Clone)]
+pub struct Recorder {
+    client: Client,
+    reporter: Reporter,
+    cache: Arc<RwLock<HashMap<EventKey, K8sEvent>>>,
+}
+
+impl Recorder {
+    pub fn new(client: Client, reporter: Reporter) -> Self {
+        Self {
+            client,
+            reporter,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Publish an event for the referenced object. If an event with the same key was published
+    /// within the cache TTL, its series is updated instead of creating a new one.
+    pub async fn publish(
+        &self,
+        ev: &Event,
+        reference: &ObjectReference,
+        trace_id: TraceId,
+    ) -> Result<(), kube::Error> {
+        let now = Utc::now();
+        self.cache.write().await.retain(|_, v| {
+            if let Some(series) = v.series.as_ref() {
+                series.last_observed_time.0 + CACHE_TTL > now
+            } else if let Some(event_time) = v.event_time.as_ref() {
+                event_time.0 + CACHE_TTL > now
+            } else {
+                true
+            }
+        });
+
+        let key = event_key(ev, reference);
+        let cached = self.cache.read().await.get(&key).cloned();
+        let event = match cached {
+            Some(mut event) => {
+                let count = event.series.as_ref().map_or(2, |s| s.count + 1);
+                event.series = Some(EventSeries {
+                    count,
+                    last_observed_time: MicroTime(now),
+                });
+                if let Some(annotations) = trace_id_annotations(trace_id) {
+                    event.metadata.annotations = Some(annotations);
+                }
+                event
+            }
+            None => self.generate_event(ev, reference, trace_id),
+        };
+
+        let events = Api::<K8sEvent>::namespaced(
+            self.client.clone(),
+            reference.namespace.as_deref().unwrap_or("default"),
+        );
+        if event.series.is_some() {
+            events
+                .patch(
+                    &event.name_any(),
+                    &PatchParams::default(),
+                    &Patch::Merge(&event),
+                )
+                .await?;
+        } else {
+            events.create(&PostParams::default(), &event).await?;
+        }
+        self.cache.write().await.insert(key, event);
+        Ok(())
+    }
+
+    fn generate_event(
+        &self,
+        ev: &Event,
+        reference: &ObjectReference,
+        trace_id: TraceId,
+    ) -> K8sEvent {
+        let now = Utc::now();
+        K8sEvent {
+            action: Some(ev.action.clone()),
+            reason: Some(ev.reason.clone()),
+            event_time: Some(MicroTime(now)),
+            regarding: Some(reference.clone()),
+            note: ev.note.clone(),
+            metadata: ObjectMeta {
+                namespace: reference.namespace.clone(),
+                name: Some(format!(
+                    "{}.{:x}",
+                    reference.name.as_ref().unwrap_or(&self.reporter.controller),
+                    now.timestamp_nanos_opt().unwrap_or_else(|| now.timestamp())
+                )),
+                annotations: trace_id_annotations(trace_id),
+                ..ObjectMeta::default()
+            },
+            reporting_controller: Some(self.reporter.controller.clone()),
+            reporting_instance: Some(
+                self.reporter
+                    .instance
+                    .clone()
+                    .unwrap_or_else(|| self.reporter.controller.clone()),
+            ),
+            type_: Some(
+                match ev.type_ {
+                    EventType::Normal => "Normal",
+                    EventType::Warning => "Warning",
+                }
+                .to_string(),
+            ),
+            related: ev.secondary.clone(),
+            ..K8sEvent::default()
+        }
+    }
+}
+
+fn event_key(ev: &Event, reference: &ObjectReference) -> EventKey {
+    EventKey {
+        event_type: ev.type_,
+        action: ev.action.clone(),
+        reason: ev.reason.clone(),
+        regarding: ReferenceKey::from(reference),
+        related: ev.secondary.as_ref().map(ReferenceKey::from),
+    }
+}
+
+fn trace_id_annotations(trace_id: TraceId) -> Option<BTreeMap<String, String>> {
+    (trace_id != TraceId::INVALID)
+        .then(|| BTreeMap::from([(TRACE_ID_ANNOTATION.to_string(), trace_id.to_string())]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use http::{Method, Request, Response};
+    use kube::client::Body;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const OTHER_TRACE_ID: &str = "00f067aa0ba902b700f067aa0ba902b7";
+
+    fn event() -> Event {
+        Event {
+            type_: EventType::Warning,
+            reason: "Failed".to_string(),
+            note: Some("failed".to_string()),
+            action: "Reconcile".to_string(),
+            secondary: None,
+        }
+    }
+
+    fn reference() -> ObjectReference {
+        ObjectReference {
+            kind: Some("ConfigMap".to_string()),
+            name: Some("test".to_string()),
+            namespace: Some("default".to_string()),
+            ..ObjectReference::default()
+        }
+    }
+
+    #[test]
+    fn test_trace_id_annotations_invalid_trace_id() {
+        assert_eq!(trace_id_annotations(TraceId::INVALID), None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_annotates_trace_id() {
+        let (mock_service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let recorder = Recorder::new(Client::new(mock_service, "default"), "test".into());
+
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::POST);
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["note"], "failed");
+            assert_eq!(
+                event["metadata"]["annotations"][TRACE_ID_ANNOTATION],
+                TRACE_ID
+            );
+            send.send_response(Response::builder().body(Body::from(body)).unwrap());
+
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::PATCH);
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(event["note"], "failed");
+            assert_eq!(event["series"]["count"], 2);
+            assert_eq!(
+                event["metadata"]["annotations"][TRACE_ID_ANNOTATION],
+                OTHER_TRACE_ID
+            );
+            send.send_response(Response::builder().body(Body::from(body)).unwrap());
+        });
+        recorder
+            .publish(&event(), &reference(), TraceId::from_hex(TRACE_ID).unwrap())
+            .await
+            .unwrap();
+        recorder
+            .publish(
+                &event(),
+                &reference(),
+                TraceId::from_hex(OTHER_TRACE_ID).unwrap(),
+            )
+            .await
+            .unwrap();
+        api_server.await.unwrap();
+    }
+}
@@ -1,29 +1,39 @@
+pub use crate::crd::KanidmResource;
+
 use crate::{
     error::{Error, Result},
+    kanidm::crd::{Kanidm, KanidmClientTimeouts},
     kanidm::reconcile::secret::{
-        ADMIN_PASSWORD_KEY, ADMIN_USER, IDM_ADMIN_PASSWORD_KEY, IDM_ADMIN_USER,
+        SecretExt, ADMIN_PASSWORD_KEY, ADMIN_USER, ADMIN_USERNAME_KEY, IDM_ADMIN_PASSWORD_KEY,
+        IDM_ADMIN_USER, IDM_ADMIN_USERNAME_KEY,
     },
+    kanidm::reconcile::service::DEFAULT_SERVICE_PORT,
+    metrics::ControllerMetrics,
 };
 
-use kanidm_client::{KanidmClient, KanidmClientBuilder};
+use kanidm_client::{ClientError, KanidmClient, KanidmClientBuilder};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
 use kube::api::Api;
 use kube::client::Client;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
 use tracing::{debug, trace};
 
-pub trait KanidmResource {
-    fn kanidm_name(&self) -> String;
-    fn kanidm_namespace(&self) -> String;
-    fn kanidm_ref(&self) -> String {
-        format!("{}/{}", self.kanidm_namespace(), self.kanidm_name())
-    }
-}
+const CA_CERT_KEY: &str = "ca.crt";
+
+/// Suffix of the temporary files where CA certificates are written before renaming them.
+static CA_CERT_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Serialize, Clone, Debug)]
 pub enum KanidmUser {
@@ -31,20 +41,38 @@ pub enum KanidmUser {
     Admin,
 }
 
+/// Number of clients kept per Kanidm and user.
+pub const CLIENT_POOL_SIZE: usize = 3;
+
 #[derive(Default)]
-pub struct KanidmClients(HashMap<KanidmKey, Arc<KanidmClient>>);
+pub struct KanidmClients(HashMap<KanidmKey, KanidmClientPool>);
 
 impl KanidmClients {
-    pub fn get(&self, key: &KanidmKey) -> Option<&Arc<KanidmClient>> {
-        self.0.get(key)
+    /// Pool of the Kanidm, unless its clients were created for another `generation` of it, whose
+    /// URL, TLS or timeout settings may differ.
+    pub fn get(&self, key: &KanidmKey, generation: Option<i64>) -> Option<&KanidmClientPool> {
+        self.0.get(key).filter(|pool| pool.generation == generation)
     }
 
-    pub fn insert(
-        &mut self,
-        key: KanidmKey,
-        client: Arc<KanidmClient>,
-    ) -> Option<Arc<KanidmClient>> {
-        self.0.insert(key, client)
+    /// Add a client created for the given `generation` of the Kanidm, dropping the clients of
+    /// other generations.
+    pub fn insert(&mut self, key: KanidmKey, client: Arc<KanidmClient>, generation: Option<i64>) {
+        let pool = self.0.entry(key).or_default();
+        if pool.generation != generation {
+            *pool = KanidmClientPool {
+                generation,
+                ..KanidmClientPool::default()
+            };
+        }
+        pool.insert(client)
+    }
+
+    /// Healthy and total number of clients in the pool of a Kanidm.
+    pub fn health(&self, key: &KanidmKey) -> (usize, usize) {
+        self.0
+            .get(key)
+            .map(KanidmClientPool::health)
+            .unwrap_or_default()
     }
 
     pub async fn create_client(
@@ -52,23 +80,29 @@ impl KanidmClients {
         name: &str,
         user: KanidmUser,
         k_client: Client,
+        kanidm: Option<Arc<Kanidm>>,
+        ca_bundle: Option<Vec<u8>>,
     ) -> Result<Arc<KanidmClient>> {
         debug!(msg = "create Kanidm client", namespace, name);
 
-        let client = KanidmClientBuilder::new()
-            .danger_accept_invalid_certs(true)
-            // TODO: ensure that URL matches the service name and port programmatically
-            // using Kanidm object from cache is the unique way
-            .address(format!("https://{name}.{namespace}.svc:8443"))
-            .connect_timeout(5)
-            .build()
-            .map_err(|e| {
-                Error::KanidmClientError("failed to build Kanidm client".to_string(), Box::new(e))
-            })?;
+        // generated admin secret and service are used until the Kanidm cache is populated
+        let (url, secret_name) = match kanidm.as_ref() {
+            Some(kanidm) => (
+                kanidm.client_url(),
+                kanidm.operator_credentials_secret_name(),
+            ),
+            None => (
+                format!("https://{name}.{namespace}.svc:{DEFAULT_SERVICE_PORT}"),
+                format!("{name}-admin-passwords"),
+            ),
+        };
+        let secret_name = secret_name.as_str();
+        let settings =
+            ClientSettings::new(kanidm.as_deref(), namespace, k_client.clone(), ca_bundle).await?;
+        let client = build_client(&url, &settings)?;
 
         let secret_api = Api::<Secret>::namespaced(k_client.clone(), namespace);
-        let secret_name = format!("{name}-admin-passwords");
-        let admin_secret = secret_api.get(&secret_name).await.map_err(|e| {
+        let admin_secret = secret_api.get(secret_name).await.map_err(|e| {
             Error::KubeError(
                 format!("failed to get secret: {namespace}/{secret_name}"),
                 e,
@@ -80,41 +114,632 @@ impl KanidmClients {
             ))
         })?;
 
-        let (username, password_key) = match user {
-            KanidmUser::Admin => (ADMIN_USER, ADMIN_PASSWORD_KEY),
-            KanidmUser::IdmAdmin => (IDM_ADMIN_USER, IDM_ADMIN_PASSWORD_KEY),
-        };
         trace!(
-            msg = format!("fetch Kanidm {username} password"),
+            msg = format!("fetch Kanidm {user:?} credentials"),
             namespace,
             name,
             secret_name
         );
-        let password_bytes = secret_data.get(password_key).ok_or_else(|| {
-            Error::MissingData(format!(
-                "missing password for {username} in secret: {namespace}/{secret_name}"
-            ))
+        let (username, password) = get_credentials(&secret_data, &user).map_err(|e| match e {
+            Error::MissingData(msg) => {
+                Error::MissingData(format!("{msg} in secret: {namespace}/{secret_name}"))
+            }
+            e => e,
         })?;
-
-        let password = std::str::from_utf8(&password_bytes.0)
-            .map_err(|e| Error::Utf8Error("failed to convert password to string".to_string(), e))?;
         trace!(
             msg = format!("authenticating with new client and user {username}"),
             namespace,
             name
         );
         client
-            .auth_simple_password(username, password)
+            .auth_simple_password(&username, &password)
             .await
-            .map_err(|e| {
-                Error::KanidmClientError("client failed to authenticate".to_string(), Box::new(e))
-            })?;
+            .map_err(|e| authentication_error(&url, e))?;
         Ok(Arc::new(client))
     }
 }
 
+/// Error of a failed authentication, explaining how to fix untrusted server certificates. The
+/// operator verifies them by default, so they have to be valid for the URL it connects to.
+fn authentication_error(url: &str, e: ClientError) -> Error {
+    match e {
+        ClientError::UntrustedCertificate(_) => Error::KanidmClientError(
+            format!(
+                "failed to verify the Kanidm server certificate for {url}, it has to be valid for \
+                that host and signed by a trusted CA: set caSecret, the operator CA bundle or \
+                insecureSkipVerify"
+            ),
+            Box::new(e),
+        ),
+        e => Error::KanidmClientError("client failed to authenticate".to_string(), Box::new(e)),
+    }
+}
+
+/// Username and password of the user in the secret data. The username defaults to the one of the
+/// generated admin secret if it is not present.
+fn get_credentials(
+    secret_data: &BTreeMap<String, ByteString>,
+    user: &KanidmUser,
+) -> Result<(String, String)> {
+    let (username_key, default_username, password_key) = match user {
+        KanidmUser::Admin => (ADMIN_USERNAME_KEY, ADMIN_USER, ADMIN_PASSWORD_KEY),
+        KanidmUser::IdmAdmin => (
+            IDM_ADMIN_USERNAME_KEY,
+            IDM_ADMIN_USER,
+            IDM_ADMIN_PASSWORD_KEY,
+        ),
+    };
+    let username = match secret_data.get(username_key) {
+        Some(username_bytes) => std::str::from_utf8(&username_bytes.0)
+            .map_err(|e| Error::Utf8Error("failed to convert username to string".to_string(), e))?
+            .to_string(),
+        None => default_username.to_string(),
+    };
+    let password_bytes = secret_data
+        .get(password_key)
+        .ok_or_else(|| Error::MissingData(format!("missing password for {username}")))?;
+    let password = std::str::from_utf8(&password_bytes.0)
+        .map_err(|e| Error::Utf8Error("failed to convert password to string".to_string(), e))?;
+    Ok((username, password.to_string()))
+}
+
+/// TLS and timeout settings used by the operator to connect to a Kanidm server.
+#[derive(Clone, Debug, Default)]
+pub struct ClientSettings {
+    ca_cert: Option<Vec<u8>>,
+    insecure_skip_verify: bool,
+    timeouts: KanidmClientTimeouts,
+}
+
+impl ClientSettings {
+    /// Settings from the Kanidm spec, fetching the CA certificate from its secret if defined.
+    /// Otherwise, the operator `ca_bundle` is trusted.
+    pub async fn new(
+        kanidm: Option<&Kanidm>,
+        namespace: &str,
+        k_client: Client,
+        ca_bundle: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        let Some(kanidm) = kanidm else {
+            return Ok(Self {
+                ca_cert: ca_bundle,
+                ..Self::default()
+            });
+        };
+        let ca_cert = match kanidm.spec.ca_secret.as_ref() {
+            Some(ca_secret) => {
+                let secret_name = ca_secret.name.as_str();
+                let secret = Api::<Secret>::namespaced(k_client, namespace)
+                    .get(secret_name)
+                    .await
+                    .map_err(|e| {
+                        Error::KubeError(
+                            format!("failed to get secret: {namespace}/{secret_name}"),
+                            e,
+                        )
+                    })?;
+                let ca_cert = secret
+                    .data
+                    .and_then(|mut data| data.remove(CA_CERT_KEY))
+                    .ok_or_else(|| {
+                        Error::MissingData(format!(
+                            "missing {CA_CERT_KEY} in secret: {namespace}/{secret_name}"
+                        ))
+                    })?;
+                Some(ca_cert.0)
+            }
+            None => ca_bundle,
+        };
+        Ok(Self {
+            ca_cert,
+            insecure_skip_verify: kanidm.spec.insecure_skip_verify,
+            timeouts: kanidm.spec.client_timeouts.clone().unwrap_or_default(),
+        })
+    }
+}
+
+fn client_builder(url: &str, settings: &ClientSettings) -> Result<KanidmClientBuilder> {
+    let builder = KanidmClientBuilder::new()
+        .danger_accept_invalid_certs(settings.insecure_skip_verify)
+        .address(url.to_string())
+        .connect_timeout(settings.timeouts.connect)
+        .request_timeout(settings.timeouts.request);
+    match settings.ca_cert.as_ref() {
+        Some(ca_cert) => {
+            let ca_path = ca_cert_file(ca_cert)?;
+            let ca_path = ca_path.to_string_lossy();
+            builder
+                .add_root_certificate_filepath(&ca_path)
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        "failed to add CA certificate".to_string(),
+                        Box::new(e),
+                    )
+                })
+        }
+        None => Ok(builder),
+    }
+}
+
+/// File with the CA certificate, because Kanidm client builder only reads root certificates from
+/// files. It is named after the certificate digest, so clients trusting the same one reuse it.
+fn ca_cert_file(ca_cert: &[u8]) -> Result<PathBuf> {
+    let digest = Sha256::digest(ca_cert)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let path = std::env::temp_dir().join(format!("kaniop-ca-{digest}.pem"));
+    if path.exists() {
+        return Ok(path);
+    }
+    // written to a unique file and renamed, so other clients never read it partially written
+    let tmp_path = path.with_extension(format!(
+        "pem.{}.{}",
+        std::process::id(),
+        CA_CERT_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&tmp_path, ca_cert).map_err(|e| {
+        Error::IoError(
+            format!("failed to write CA certificate file {}", tmp_path.display()),
+            e,
+        )
+    })?;
+    std::fs::rename(&tmp_path, &path).map_err(|e| {
+        Error::IoError(
+            format!("failed to write CA certificate file {}", path.display()),
+            e,
+        )
+    })?;
+    Ok(path)
+}
+
+fn build_client(url: &str, settings: &ClientSettings) -> Result<KanidmClient> {
+    client_builder(url, settings)?.build().map_err(|e| {
+        Error::KanidmClientError("failed to build Kanidm client".to_string(), Box::new(e))
+    })
+}
+
+/// Check if the Kanidm server is up and responding to requests.
+pub async fn is_reachable(url: &str, settings: &ClientSettings) -> bool {
+    match build_client(url, settings) {
+        Ok(client) => client.perform_get_request::<bool>("/status").await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+struct KanidmClientPoolMember {
+    client: Arc<KanidmClient>,
+    healthy: AtomicBool,
+}
+
+/// Authenticated clients of a Kanidm, used in round-robin to spread the load between sessions.
+pub struct KanidmClientPool {
+    size: usize,
+    members: Vec<KanidmClientPoolMember>,
+    next: AtomicUsize,
+    /// Generation of the Kanidm the clients were created for
+    generation: Option<i64>,
+}
+
+impl Default for KanidmClientPool {
+    fn default() -> Self {
+        Self::new(CLIENT_POOL_SIZE)
+    }
+}
+
+impl KanidmClientPool {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            members: Vec::with_capacity(size),
+            next: AtomicUsize::new(0),
+            generation: None,
+        }
+    }
+
+    /// Return the next healthy client in round-robin order. Members failing the health check
+    /// are marked as unhealthy and skipped. Returns `None` if the pool is not full yet or no
+    /// member is healthy, so a new client has to be created and inserted.
+    pub async fn acquire<F, Fut>(&self, is_healthy: F) -> Option<Arc<KanidmClient>>
+    where
+        F: Fn(Arc<KanidmClient>) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        if self.members.len() < self.size {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.members.len() {
+            let member = &self.members[(start + i) % self.members.len()];
+            let healthy = is_healthy(member.client.clone()).await;
+            member.healthy.store(healthy, Ordering::Relaxed);
+            if healthy {
+                return Some(member.client.clone());
+            }
+        }
+        None
+    }
+
+    /// Add a client to the pool, replacing an unhealthy member when it is full.
+    pub fn insert(&mut self, client: Arc<KanidmClient>) {
+        let member = KanidmClientPoolMember {
+            client,
+            healthy: AtomicBool::new(true),
+        };
+        if self.members.len() < self.size {
+            self.members.push(member);
+            return;
+        }
+        let position = self
+            .members
+            .iter()
+            .position(|m| !m.healthy.load(Ordering::Relaxed))
+            .unwrap_or_else(|| self.next.load(Ordering::Relaxed) % self.members.len());
+        self.members[position] = member;
+    }
+
+    /// Healthy and total number of clients.
+    pub fn health(&self) -> (usize, usize) {
+        let healthy = self
+            .members
+            .iter()
+            .filter(|m| m.healthy.load(Ordering::Relaxed))
+            .count();
+        (healthy, self.members.len())
+    }
+}
+
+/// Concurrent request limits per Kanidm, shared by every controller.
+pub struct KanidmApiLimits {
+    max_concurrent_requests: usize,
+    semaphores: Mutex<HashMap<KanidmKey, Arc<Semaphore>>>,
+}
+
+impl KanidmApiLimits {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        Self {
+            max_concurrent_requests,
+            semaphores: Mutex::default(),
+        }
+    }
+
+    /// Limiter for the requests to a Kanidm. Wait time is recorded in `metrics`.
+    pub fn limiter(&self, key: &KanidmKey, metrics: Arc<ControllerMetrics>) -> KanidmApiLimiter {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .expect("Kanidm API limits lock poisoned")
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent_requests)))
+            .clone();
+        KanidmApiLimiter { semaphore, metrics }
+    }
+}
+
+/// Caps the concurrent requests sent to a Kanidm, so a big object cannot burst dozens of them.
+#[derive(Clone)]
+pub struct KanidmApiLimiter {
+    semaphore: Arc<Semaphore>,
+    metrics: Arc<ControllerMetrics>,
+}
+
+impl KanidmApiLimiter {
+    /// Wait for a free slot and run the Kanidm request.
+    pub async fn run<F: Future>(&self, request: F) -> F::Output {
+        let start = Instant::now();
+        // safe expect: the semaphore is never closed
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Kanidm API semaphore closed");
+        self.metrics
+            .kanidm_request_wait_duration_observe(start.elapsed().as_secs_f64());
+        request.await
+    }
+}
+
 #[derive(Clone, PartialEq, Hash, Eq)]
 pub struct KanidmKey {
     pub namespace: String,
     pub name: String,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secret_data(data: &[(&str, &str)]) -> BTreeMap<String, ByteString> {
+        data.iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.as_bytes().to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn test_get_credentials_default_usernames() {
+        let data = secret_data(&[
+            (ADMIN_PASSWORD_KEY, "admin-password"),
+            (IDM_ADMIN_PASSWORD_KEY, "idm-admin-password"),
+        ]);
+        assert_eq!(
+            get_credentials(&data, &KanidmUser::Admin).unwrap(),
+            (ADMIN_USER.to_string(), "admin-password".to_string())
+        );
+        assert_eq!(
+            get_credentials(&data, &KanidmUser::IdmAdmin).unwrap(),
+            (IDM_ADMIN_USER.to_string(), "idm-admin-password".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_credentials_custom_username() {
+        let data = secret_data(&[
+            (IDM_ADMIN_USERNAME_KEY, "kaniop"),
+            (IDM_ADMIN_PASSWORD_KEY, "kaniop-password"),
+        ]);
+        assert_eq!(
+            get_credentials(&data, &KanidmUser::IdmAdmin).unwrap(),
+            ("kaniop".to_string(), "kaniop-password".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_credentials_missing_password() {
+        let data = secret_data(&[(ADMIN_PASSWORD_KEY, "admin-password")]);
+        assert!(matches!(
+            get_credentials(&data, &KanidmUser::IdmAdmin),
+            Err(Error::MissingData(_))
+        ));
+    }
+
+    const CA_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----\nMIICPjCCAeSgAwIBAgIBATAKBggqhkjOPQQDAjCBhDELMAkGA1UEBhMCQVUxDDAK\nBgNVBAgMA1FMRDEPMA0GA1UECgwGS2FuaWRtMRwwGgYDVQQDDBNLYW5pZG0gR2Vu\nZXJhdGVkIENBMTgwNgYDVQQLDC9EZXZlbG9wbWVudCBhbmQgRXZhbHVhdGlvbiAt\nIE5PVCBGT1IgUFJPRFVDVElPTjAeFw0yNDEwMTMyMDQzMjhaFw0yNDExMTIyMDQz\nMjhaMIGEMQswCQYDVQQGEwJBVTEMMAoGA1UECAwDUUxEMQ8wDQYDVQQKDAZLYW5p\nZG0xHDAaBgNVBAMME0thbmlkbSBHZW5lcmF0ZWQgQ0ExODA2BgNVBAsML0RldmVs\nb3BtZW50IGFuZCBFdmFsdWF0aW9uIC0gTk9UIEZPUiBQUk9EVUNUSU9OMFkwEwYH\nKoZIzj0CAQYIKoZIzj0DAQcDQgAEiz5mqHozpsj5iGCDH8uSJy8TFqNIGnIw8U/L\nswyeFTGHT4S2HwBb7QAouYVuXdwL8hZGMtzAqoYMFhCt1epXjqNFMEMwEgYDVR0T\nAQH/BAgwBgEB/wIBADAOBgNVHQ8BAf8EBAMCAQYwHQYDVR0OBBYEFNo5o+5ea0sN\nMlW/75VgGJCv2AcJMAoGCCqGSM49BAMCA0gAMEUCIGyZjBs4pp1HAlFdk0mdVBz4\n440t8pRHh8/SOY5ZtMcSAiEA6qOf9aQbWwEXLj0jajX9lHgdqlwRk7wnnyLMGF5/\nlz8=\n-----END CERTIFICATE-----\n";
+
+    #[test]
+    fn test_client_builder_verifies_by_default() {
+        let builder =
+            client_builder("https://idm.example.com", &ClientSettings::default()).unwrap();
+        let builder_str = builder.to_string();
+        assert!(builder_str.contains("verify_ca: true"));
+        assert!(builder_str.contains("ca: unset"));
+    }
+
+    #[test]
+    fn test_client_builder_uses_ca_cert() {
+        let settings = ClientSettings {
+            ca_cert: Some(CA_CERT.to_vec()),
+            insecure_skip_verify: false,
+            ..ClientSettings::default()
+        };
+        let builder = client_builder("https://idm.example.com", &settings).unwrap();
+        let builder_str = builder.to_string();
+        assert!(builder_str.contains("verify_ca: true"));
+        assert!(!builder_str.contains("ca: unset"));
+    }
+
+    #[test]
+    fn test_ca_cert_file_reused() {
+        let path = ca_cert_file(CA_CERT).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), CA_CERT);
+        assert_eq!(ca_cert_file(CA_CERT).unwrap(), path);
+        assert_ne!(ca_cert_file(b"other").unwrap(), path);
+    }
+
+    #[test]
+    fn test_authentication_error_explains_untrusted_certificate() {
+        let e = authentication_error(
+            "https://idm.default.svc:8443",
+            ClientError::UntrustedCertificate("invalid peer certificate".to_string()),
+        );
+        assert!(e.to_string().contains(
+            "failed to verify the Kanidm server certificate for https://idm.default.svc:8443"
+        ));
+        let e = authentication_error("https://idm.default.svc:8443", ClientError::Unauthorized);
+        assert!(e.to_string().starts_with("client failed to authenticate"));
+    }
+
+    #[test]
+    fn test_client_builder_insecure_skip_verify() {
+        let settings = ClientSettings {
+            ca_cert: None,
+            insecure_skip_verify: true,
+            ..ClientSettings::default()
+        };
+        let builder = client_builder("https://idm.example.com", &settings).unwrap();
+        assert!(builder.to_string().contains("verify_ca: false"));
+    }
+
+    #[test]
+    fn test_client_builder_default_timeouts() {
+        let builder =
+            client_builder("https://idm.example.com", &ClientSettings::default()).unwrap();
+        let builder_str = builder.to_string();
+        assert!(builder_str.contains("connect_timeout: 5"));
+        assert!(builder_str.contains("request_timeout: 30"));
+    }
+
+    #[tokio::test]
+    async fn test_client_builder_uses_kanidm_timeouts() {
+        let mut kanidm = Kanidm::default();
+        kanidm.spec.client_timeouts = Some(KanidmClientTimeouts {
+            connect: 10,
+            request: 120,
+        });
+        let settings = ClientSettings::new(Some(&kanidm), "default", unused_client(), None)
+            .await
+            .unwrap();
+        let builder = client_builder("https://idm.example.com", &settings).unwrap();
+        let builder_str = builder.to_string();
+        assert!(builder_str.contains("connect_timeout: 10"));
+        assert!(builder_str.contains("request_timeout: 120"));
+    }
+
+    fn unused_client() -> Client {
+        let (mock_service, _handle) = tower_test::mock::pair::<
+            http::Request<kube::client::Body>,
+            http::Response<kube::client::Body>,
+        >();
+        Client::new(mock_service, "default")
+    }
+
+    #[tokio::test]
+    async fn test_client_tls_uses_ca_bundle() {
+        let settings =
+            ClientSettings::new(None, "default", unused_client(), Some(CA_CERT.to_vec()))
+                .await
+                .unwrap();
+        assert_eq!(settings.ca_cert.as_deref(), Some(CA_CERT));
+        let builder = client_builder("https://idm.example.com", &settings).unwrap();
+        assert!(!builder.to_string().contains("ca: unset"));
+
+        let kanidm = Kanidm::default();
+        let settings = ClientSettings::new(
+            Some(&kanidm),
+            "default",
+            unused_client(),
+            Some(CA_CERT.to_vec()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(settings.ca_cert.as_deref(), Some(CA_CERT));
+        assert!(!settings.insecure_skip_verify);
+        assert!(build_client("https://idm.example.com", &settings).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_tls_without_ca_bundle() {
+        let settings = ClientSettings::new(None, "default", unused_client(), None)
+            .await
+            .unwrap();
+        assert!(settings.ca_cert.is_none());
+    }
+
+    fn test_client() -> Arc<KanidmClient> {
+        Arc::new(build_client("https://idm.example.com", &ClientSettings::default()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_client_pool_round_robin() {
+        let mut pool = KanidmClientPool::new(2);
+        let (first, second) = (test_client(), test_client());
+        pool.insert(first.clone());
+        assert!(pool.acquire(|_| async { true }).await.is_none());
+        pool.insert(second.clone());
+
+        let acquired = pool.acquire(|_| async { true }).await.unwrap();
+        assert!(Arc::ptr_eq(&acquired, &first));
+        let acquired = pool.acquire(|_| async { true }).await.unwrap();
+        assert!(Arc::ptr_eq(&acquired, &second));
+        let acquired = pool.acquire(|_| async { true }).await.unwrap();
+        assert!(Arc::ptr_eq(&acquired, &first));
+        assert_eq!(pool.health(), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_client_pool_skips_unhealthy_member() {
+        let mut pool = KanidmClientPool::new(2);
+        let (failing, healthy) = (test_client(), test_client());
+        pool.insert(failing.clone());
+        pool.insert(healthy.clone());
+
+        let is_healthy = |client: Arc<KanidmClient>| {
+            let failing = failing.clone();
+            async move { !Arc::ptr_eq(&client, &failing) }
+        };
+        for _ in 0..3 {
+            let acquired = pool.acquire(is_healthy).await.unwrap();
+            assert!(Arc::ptr_eq(&acquired, &healthy));
+        }
+        assert_eq!(pool.health(), (1, 2));
+
+        let replacement = test_client();
+        pool.insert(replacement.clone());
+        assert_eq!(pool.health(), (2, 2));
+        assert!(pool
+            .members
+            .iter()
+            .all(|m| !Arc::ptr_eq(&m.client, &failing)));
+    }
+
+    #[tokio::test]
+    async fn test_client_pool_without_healthy_members() {
+        let mut pool = KanidmClientPool::new(1);
+        pool.insert(test_client());
+        assert!(pool.acquire(|_| async { false }).await.is_none());
+        assert_eq!(pool.health(), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_clients_dropped_on_kanidm_generation_change() {
+        let mut clients = KanidmClients::default();
+        let first = test_client();
+        clients.insert(key("idm"), first.clone(), Some(1));
+        assert!(clients.get(&key("idm"), Some(1)).is_some());
+        assert!(clients.get(&key("idm"), Some(2)).is_none());
+
+        let second = test_client();
+        clients.insert(key("idm"), second.clone(), Some(2));
+        let pool = clients.get(&key("idm"), Some(2)).unwrap();
+        assert_eq!(pool.health(), (1, 1));
+        assert!(Arc::ptr_eq(&pool.members[0].client, &second));
+        assert!(clients.get(&key("idm"), Some(1)).is_none());
+    }
+
+    fn key(name: &str) -> KanidmKey {
+        KanidmKey {
+            namespace: "default".to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_limiter_never_exceeds_cap() {
+        let limits = KanidmApiLimits::new(3);
+        let limiter = limits.limiter(&key("test"), Arc::default());
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let requests = (0..20).map(|_| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            limiter.run(async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        futures::future::join_all(requests).await;
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_api_limiter_shared_per_kanidm() {
+        let limits = KanidmApiLimits::new(1);
+        let limiter = limits.limiter(&key("test"), Arc::default());
+        let same_kanidm = limits.limiter(&key("test"), Arc::default());
+        let other_kanidm = limits.limiter(&key("other"), Arc::default());
+
+        let _permit = limiter.semaphore.acquire().await.unwrap();
+        assert_eq!(same_kanidm.semaphore.available_permits(), 0);
+        assert_eq!(other_kanidm.semaphore.available_permits(), 1);
+        assert_eq!(other_kanidm.run(async { 42 }).await, 42);
+    }
+
+    #[tokio::test]
+    async fn test_api_limiter_records_wait_time() {
+        let metrics = Arc::new(ControllerMetrics::new("test"));
+        let limiter = KanidmApiLimits::new(1).limiter(&key("test"), metrics.clone());
+        limiter.run(async {}).await;
+        limiter.run(async {}).await;
+
+        let mut registry = prometheus_client::registry::Registry::default();
+        let _ = Arc::unwrap_or_clone(metrics).register(&mut registry);
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &registry).unwrap();
+        assert!(
+            buffer.contains("kanidm_request_wait_duration_seconds_count{controller=\"test\"} 2")
+        );
+    }
+}
@@ -1,31 +1,41 @@
+pub mod check;
 pub mod context;
+pub mod events;
 pub mod kanidm;
 
-use self::{context::Context, kanidm::KanidmClients};
+use self::{
+    context::Context,
+    events::Recorder,
+    kanidm::{KanidmApiLimits, KanidmClients},
+};
 
+use crate::crd::ReadyStatus;
 use crate::error::{Error, Result};
+use crate::kanidm::controller::context::Stores;
 use crate::kanidm::crd::Kanidm;
 use crate::metrics;
 
 use kaniop_k8s_util::types::short_type_name;
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
 
 use futures::channel::mpsc;
 use futures::future::BoxFuture;
-use futures::{FutureExt, StreamExt};
+use futures::{FutureExt, Stream, StreamExt};
 use k8s_openapi::api::core::v1::Namespace;
-use kube::api::{Api, ListParams, ResourceExt};
+use kube::api::{Api, ListParams, PartialObjectMeta, ResourceExt};
 use kube::client::Client;
 use kube::runtime::controller::Action;
-use kube::runtime::events::Recorder;
 use kube::runtime::reflector::store::Writer;
 use kube::runtime::reflector::{self, Lookup, ReflectHandle, Store};
 use kube::runtime::{watcher, WatchStreamExt};
 use kube::Resource;
 use prometheus_client::registry::Registry;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use tokio::sync::RwLock;
 use tokio::time::Duration;
 use tracing::{debug, error, trace};
@@ -33,12 +43,74 @@ use tracing::{debug, error, trace};
 pub const DEFAULT_RECONCILE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 pub const SUBSCRIBE_BUFFER_SIZE: usize = 256;
 pub const RELOAD_BUFFER_SIZE: usize = 16;
+pub const MAX_CLEANUP_ATTEMPTS: u32 = 10;
+pub const MAX_CONCURRENT_KANIDM_REQUESTS: usize = 8;
+pub const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
 pub const NAME_LABEL: &str = "app.kubernetes.io/name";
 pub const INSTANCE_LABEL: &str = "app.kubernetes.io/instance";
 pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
 
 pub type ControllerId = &'static str;
 
+/// Buffer sizes of the channels propagating watch events to the controllers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferSizes {
+    /// Buffer size of the shared reflector stores subscribers
+    pub subscribe: usize,
+    /// Buffer size of the channel triggering the reconcile of all objects
+    pub reload: usize,
+}
+
+impl Default for BufferSizes {
+    fn default() -> Self {
+        Self {
+            subscribe: SUBSCRIBE_BUFFER_SIZE,
+            reload: RELOAD_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Policy applied when the finalizer cleanup of an object keeps failing, e.g. because Kanidm is
+/// unreachable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeletionGrace {
+    /// Failed cleanup attempts before publishing a warning event
+    pub max_cleanup_attempts: u32,
+    /// Remove the finalizer once `max_cleanup_attempts` is reached, orphaning the Kanidm objects
+    pub force_finalizer_removal: bool,
+}
+
+impl Default for DeletionGrace {
+    fn default() -> Self {
+        Self {
+            max_cleanup_attempts: MAX_CLEANUP_ATTEMPTS,
+            force_finalizer_removal: false,
+        }
+    }
+}
+
+/// Namespaces watched for the objects referencing a Kanidm that has no namespace selector. The
+/// selector of the Kanidm takes precedence when it is set.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum DefaultNamespaceSelector {
+    /// Only the namespace of the Kanidm.
+    #[default]
+    KanidmNamespace,
+
+    /// Every namespace.
+    All,
+}
+
+impl DefaultNamespaceSelector {
+    /// Whether objects in `namespace` are watched for a Kanidm in `kanidm_namespace`.
+    pub fn matches(&self, namespace: &str, kanidm_namespace: &str) -> bool {
+        match self {
+            DefaultNamespaceSelector::KanidmNamespace => namespace == kanidm_namespace,
+            DefaultNamespaceSelector::All => true,
+        }
+    }
+}
+
 /// State shared between the controller and the web server
 // Kanidm defined as a generic because it causes a cycle dependency with the kaniop_kanidm crate
 #[derive(Clone)]
@@ -50,10 +122,59 @@ pub struct State {
     /// Shared Kanidm cache clients with the ability to manage the operation of Kanidm as a
     /// database and service
     system_clients: Arc<RwLock<KanidmClients>>,
+    /// Concurrent request limits per Kanidm
+    kanidm_api_limits: Arc<KanidmApiLimits>,
     /// Cache for Namespace resources
     pub namespace_store: Store<Namespace>,
     /// Cache for Kanidm resources
     pub kanidm_store: Store<Kanidm>,
+    /// Caches for the resources owned by Kanidm, available once its controller is running
+    kanidm_stores: Arc<OnceLock<Arc<Stores>>>,
+    /// Buffer sizes used by the controllers watchers
+    pub buffer_sizes: BufferSizes,
+    /// Policy for objects whose finalizer cleanup keeps failing
+    deletion_grace: DeletionGrace,
+    /// CA certificate trusted by Kanidm clients, unless the Kanidm defines its own
+    ca_bundle: Option<Vec<u8>>,
+    /// Maximum duration of a command executed in a Kanidm pod
+    exec_timeout: Duration,
+    /// Interval between full reconciles, which apply every attribute regardless of the status
+    full_reconcile_interval: Option<Duration>,
+    /// Roll out the Kanidm pods when their server TLS Secret changes
+    pub(crate) tls_secret_rollout: bool,
+    /// Namespaces watched when a Kanidm has no namespace selector
+    default_namespace_selector: DefaultNamespaceSelector,
+    /// URL prefixes that external sources of objects are allowed to read from
+    external_source_allowed_urls: Vec<String>,
+}
+
+/// Size and object keys of a reflector store, used for troubleshooting
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StoreSummary {
+    pub size: usize,
+    pub keys: Vec<String>,
+}
+
+impl<K> From<&Store<K>> for StoreSummary
+where
+    K: Resource + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Eq + std::hash::Hash + Clone,
+{
+    fn from(store: &Store<K>) -> Self {
+        let mut keys = store
+            .state()
+            .iter()
+            .map(|obj| match ResourceExt::namespace(obj.as_ref()) {
+                Some(namespace) => format!("{namespace}/{}", obj.name_any()),
+                None => obj.name_any(),
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        StoreSummary {
+            size: keys.len(),
+            keys,
+        }
+    }
 }
 
 /// Shared state for a resource stream
@@ -74,16 +195,114 @@ impl State {
         controller_names: &[&'static str],
         namespace_store: Store<Namespace>,
         kanidm_store: Store<Kanidm>,
+        buffer_sizes: BufferSizes,
+        deletion_grace: DeletionGrace,
+        max_concurrent_kanidm_requests: usize,
     ) -> Self {
         Self {
             metrics: Arc::new(metrics::Metrics::new(registry, controller_names)),
             idm_clients: Arc::default(),
             system_clients: Arc::default(),
+            kanidm_api_limits: Arc::new(KanidmApiLimits::new(max_concurrent_kanidm_requests)),
             namespace_store,
             kanidm_store,
+            kanidm_stores: Arc::default(),
+            buffer_sizes,
+            deletion_grace,
+            ca_bundle: None,
+            exec_timeout: DEFAULT_EXEC_TIMEOUT,
+            full_reconcile_interval: None,
+            tls_secret_rollout: false,
+            default_namespace_selector: DefaultNamespaceSelector::default(),
+            external_source_allowed_urls: Vec::new(),
         }
     }
 
+    /// Trust `ca_bundle` in the Kanidm clients, unless the Kanidm defines its own CA.
+    pub fn with_ca_bundle(mut self, ca_bundle: Option<Vec<u8>>) -> Self {
+        self.ca_bundle = ca_bundle;
+        self
+    }
+
+    /// Abort commands executed in Kanidm pods that take longer than `exec_timeout`.
+    pub fn with_exec_timeout(mut self, exec_timeout: Duration) -> Self {
+        self.exec_timeout = exec_timeout;
+        self
+    }
+
+    /// Apply every attribute of the objects each `full_reconcile_interval`, correcting changes
+    /// made outside the operator that the status does not detect.
+    pub fn with_full_reconcile_interval(
+        mut self,
+        full_reconcile_interval: Option<Duration>,
+    ) -> Self {
+        self.full_reconcile_interval = full_reconcile_interval;
+        self
+    }
+
+    /// Watch the metadata of the Kanidm server TLS Secrets and roll out the pods when their
+    /// content changes, e.g. after a certificate renewal.
+    pub fn with_tls_secret_rollout(mut self, tls_secret_rollout: bool) -> Self {
+        self.tls_secret_rollout = tls_secret_rollout;
+        self
+    }
+
+    /// Watch the namespaces of `default_namespace_selector` for the objects referencing a Kanidm
+    /// without a namespace selector.
+    pub fn with_default_namespace_selector(
+        mut self,
+        default_namespace_selector: DefaultNamespaceSelector,
+    ) -> Self {
+        self.default_namespace_selector = default_namespace_selector;
+        self
+    }
+
+    /// Allow external sources to read from URLs starting with any of
+    /// `external_source_allowed_urls`. URL sources are rejected when it is empty.
+    pub fn with_external_source_allowed_urls(
+        mut self,
+        external_source_allowed_urls: Vec<String>,
+    ) -> Self {
+        self.external_source_allowed_urls = external_source_allowed_urls;
+        self
+    }
+
+    /// Register the caches of the Kanidm controller. Only the first registration is kept.
+    pub fn register_kanidm_stores(&self, stores: Arc<Stores>) {
+        let _ignore_already_set = self.kanidm_stores.set(stores);
+    }
+
+    /// Summary of every reflector store, keyed by resource plural name
+    pub fn stores(&self) -> BTreeMap<&'static str, StoreSummary> {
+        let mut stores = BTreeMap::from([
+            ("namespaces", StoreSummary::from(&self.namespace_store)),
+            ("kanidms", StoreSummary::from(&self.kanidm_store)),
+        ]);
+        if let Some(kanidm_stores) = self.kanidm_stores.get() {
+            stores.extend([
+                (
+                    "statefulsets",
+                    StoreSummary::from(&kanidm_stores.stateful_set_store),
+                ),
+                ("services", StoreSummary::from(&kanidm_stores.service_store)),
+                (
+                    "ingresses",
+                    StoreSummary::from(&kanidm_stores.ingress_store),
+                ),
+                ("secrets", StoreSummary::from(&kanidm_stores.secret_store)),
+                (
+                    "configmaps",
+                    StoreSummary::from(&kanidm_stores.config_map_store),
+                ),
+                (
+                    "tlssecrets",
+                    StoreSummary::from(&kanidm_stores.tls_secret_store),
+                ),
+            ]);
+        }
+        stores
+    }
+
     /// Metrics getter
     pub fn metrics(&self) -> Result<String> {
         let mut buffer = String::new();
@@ -110,9 +329,16 @@ impl State {
             Recorder::new(client.clone(), controller_id.into()),
             self.idm_clients.clone(),
             self.system_clients.clone(),
+            self.kanidm_api_limits.clone(),
             self.namespace_store.clone(),
             self.kanidm_store.clone(),
+            self.deletion_grace,
         )
+        .with_ca_bundle(self.ca_bundle.clone())
+        .with_exec_timeout(self.exec_timeout)
+        .with_full_reconcile_interval(self.full_reconcile_interval)
+        .with_default_namespace_selector(self.default_namespace_selector)
+        .with_external_source_allowed_urls(self.external_source_allowed_urls.clone())
     }
 }
 
@@ -122,16 +348,24 @@ where
     <K as Resource>::DynamicType: Default,
 {
     let api = Api::<K>::all(client.clone());
-    if let Err(e) = api.list(&ListParams::default().limit(1)).await {
-        error!(
-            "{} is not queryable; {e:?}. Check controller permissions",
-            short_type_name::<K>().unwrap_or("Unknown resource"),
-        );
+    if let Err(e) = api_queryable(&api).await {
+        error!("{e}. Check controller permissions");
         std::process::exit(1);
     }
     api
 }
 
+/// Check that the operator can list objects of the API.
+pub async fn api_queryable<K>(api: &Api<K>) -> Result<()>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    api.list(&ListParams::default().limit(1))
+        .await
+        .map(|_| ())
+        .map_err(|e| Error::KubeError(format!("{} is not queryable", short_type_name::<K>()), e))
+}
+
 pub fn create_subscriber<K>(buffer_size: usize) -> ResourceReflector<K>
 where
     K: Resource + Lookup + Clone + 'static,
@@ -163,21 +397,25 @@ where
     T: Resource<DynamicType = ()> + ResourceExt + Lookup + Clone + 'static,
     <T as Lookup>::DynamicType: Eq + std::hash::Hash + Clone + Send + Sync,
 {
-    let resource_name = short_type_name::<K>().unwrap_or("Unknown");
+    let resource_name = short_type_name::<K>();
+    let store = writer.as_reader();
 
     watcher(
         api,
-        watcher::Config::default().labels(&format!("{MANAGED_BY_LABEL}=kaniop-{controller_id}")),
+        watcher::Config::default().labels(&managed_by_selector(controller_id)),
     )
     .default_backoff()
     .reflect_shared(writer)
     .for_each(move |res| {
         let mut reload_tx_clone = reload_tx.clone();
         let ctx = ctx.clone();
+        let store = store.clone();
+        let resource_name = resource_name.clone();
         async move {
             match res {
                 Ok(event) => {
                     trace!(msg = "watched event", ?event);
+                    ctx.metrics.store_objects_set(&resource_name, store.len());
                     match event {
                         watcher::Event::Delete(d) => {
                             debug!(
@@ -189,11 +427,15 @@ where
                             // TODO: remove for each trigger on delete logic when
                             // (dispatch delete events issue)[https://github.com/kube-rs/kube/issues/1590]
                             // is solved
-                            let _ignore_errors = reload_tx_clone.try_send(()).map_err(
-                                |e| error!(msg = "failed to trigger reconcile on delete", %e),
-                            );
+                            match trigger_reload(&mut reload_tx_clone) {
+                                ReloadTrigger::Sent => {}
+                                ReloadTrigger::Coalesced => {
+                                    ctx.metrics.reload_triggers_coalesced_inc()
+                                }
+                                ReloadTrigger::Dropped => ctx.metrics.reload_triggers_dropped_inc(),
+                            }
                             ctx.metrics
-                                .triggered_inc(metrics::Action::Delete, resource_name);
+                                .triggered_inc(metrics::Action::Delete, &resource_name);
                         }
                         watcher::Event::Apply(d) => {
                             debug!(
@@ -202,7 +444,7 @@ where
                                 name = d.name_any()
                             );
                             ctx.metrics
-                                .triggered_inc(metrics::Action::Apply, resource_name);
+                                .triggered_inc(metrics::Action::Apply, &resource_name);
                         }
                         _ => {}
                     }
@@ -217,6 +459,204 @@ where
     .boxed()
 }
 
+#[derive(Debug, PartialEq)]
+enum ReloadTrigger {
+    Sent,
+    Coalesced,
+    Dropped,
+}
+
+/// Request the reconcile of all objects.
+///
+/// When the channel is full, pending triggers are consumed after this call and reconcile all
+/// objects anyway, so the trigger is coalesced with them instead of lost.
+fn trigger_reload(reload_tx: &mut mpsc::Sender<()>) -> ReloadTrigger {
+    match reload_tx.try_send(()) {
+        Ok(()) => ReloadTrigger::Sent,
+        Err(e) if e.is_full() => {
+            trace!(msg = "reconcile already triggered, coalescing trigger");
+            ReloadTrigger::Coalesced
+        }
+        Err(e) => {
+            error!(msg = "failed to trigger reconcile", %e);
+            ReloadTrigger::Dropped
+        }
+    }
+}
+
+/// Watch stream of the objects reconciled by a controller, to build it with
+/// [`kube::runtime::Controller::for_stream`]. The objects of the events are tracked as queued
+/// until their reconcile starts, and counted per sync state from their status.
+pub fn reconcile_watcher<K>(
+    api: Api<K>,
+    writer: Writer<K>,
+    metrics: Arc<metrics::ControllerMetrics>,
+) -> impl Stream<Item = Result<K, watcher::Error>> + Send + 'static
+where
+    K: Resource + ReadyStatus + Lookup + Clone + DeserializeOwned + Send + Sync + Debug + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone + Send + Sync,
+    <K as Resource>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    let resource_name = short_type_name::<K>();
+    let kind = <K as Resource>::kind(&Default::default()).to_string();
+    let store = writer.as_reader();
+    watcher(api, watcher::Config::default().any_semantic())
+        .default_backoff()
+        .reflect(writer)
+        .inspect(move |event| {
+            metrics.store_objects_set(&resource_name, store.len());
+            metrics.objects_set(&kind, object_states(&store.state()));
+            track_queued(&metrics, event);
+        })
+        .touched_objects()
+}
+
+/// Sync state of the objects with a status: synced when it is ready, error otherwise.
+fn object_states<K>(objects: &[Arc<K>]) -> Vec<metrics::ObjectState>
+where
+    K: ReadyStatus,
+{
+    objects
+        .iter()
+        .filter_map(|obj| obj.ready())
+        .map(metrics::ObjectState::from)
+        .collect()
+}
+
+/// Track the object of a watch event as queued until its reconcile starts. Deleted objects are
+/// not reconciled anymore.
+pub fn track_queued<K>(
+    metrics: &metrics::ControllerMetrics,
+    event: &watcher::Result<watcher::Event<K>>,
+) where
+    K: ResourceExt,
+{
+    match event {
+        Ok(watcher::Event::Apply(obj) | watcher::Event::InitApply(obj)) => metrics
+            .reconcile_queued(
+                &ResourceExt::namespace(obj).unwrap_or_default(),
+                &obj.name_any(),
+            ),
+        Ok(watcher::Event::Delete(obj)) => metrics.reconcile_dequeued(
+            &ResourceExt::namespace(obj).unwrap_or_default(),
+            &obj.name_any(),
+        ),
+        _ => {}
+    }
+}
+
+/// Stream of owned objects, to use with [`kube::runtime::Controller::owns_shared_stream`]. Their
+/// owners of kind `K` are tracked as queued until their reconcile starts.
+pub fn owned_stream<K, Child>(
+    subscriber: ReflectHandle<Child>,
+    metrics: Arc<metrics::ControllerMetrics>,
+) -> impl Stream<Item = Arc<Child>> + Send + 'static
+where
+    K: Resource<DynamicType = ()>,
+    Child: Resource + Lookup + Clone + Send + Sync + 'static,
+    <Child as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone + Send + Sync,
+{
+    subscriber.inspect(move |child| {
+        let namespace = ResourceExt::namespace(child.as_ref()).unwrap_or_default();
+        child
+            .owner_references()
+            .iter()
+            .filter(|owner| owner.kind == K::kind(&()))
+            .for_each(|owner| metrics.reconcile_queued(&namespace, &owner.name));
+    })
+}
+
+/// Track every object in the store as queued until its reconcile starts, for the triggers of
+/// [`kube::runtime::Controller::reconcile_all_on`].
+pub fn track_all_queued<K>(metrics: &metrics::ControllerMetrics, store: &Store<K>)
+where
+    K: ResourceExt + Lookup + Clone + 'static,
+    <K as Lookup>::DynamicType: Eq + std::hash::Hash + Clone,
+{
+    for obj in store.state() {
+        metrics.reconcile_queued(
+            &ResourceExt::namespace(obj.as_ref()).unwrap_or_default(),
+            &obj.name_any(),
+        );
+    }
+}
+
+/// Label selector of the resources managed by a controller.
+pub fn managed_by_selector(controller_id: ControllerId) -> String {
+    format!("{MANAGED_BY_LABEL}=kaniop-{controller_id}")
+}
+
+/// Errors of the objects that failed to reconcile, keyed by `namespace/name`.
+pub type ReconcileErrors = Vec<(String, Error)>;
+
+/// Store filled with a single list of objects. Used instead of a reflector when reconciling once.
+pub async fn list_store<K>(api: &Api<K>, lp: &ListParams) -> Result<Store<K>>
+where
+    K: Resource + Lookup + Clone + DeserializeOwned + Debug + 'static,
+    <K as Lookup>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    let objects = api
+        .list(lp)
+        .await
+        .map_err(|e| Error::KubeError(format!("failed to list {}", short_type_name::<K>()), e))?;
+    let mut writer = Writer::default();
+    for obj in objects {
+        writer.apply_watcher_event(&watcher::Event::Apply(obj));
+    }
+    Ok(writer.as_reader())
+}
+
+/// Store filled with a single list of objects metadata. Used instead of a metadata reflector when
+/// reconciling once.
+pub async fn list_metadata_store<K>(
+    api: &Api<K>,
+    lp: &ListParams,
+) -> Result<Store<PartialObjectMeta<K>>>
+where
+    K: Resource + Clone + DeserializeOwned + Debug + 'static,
+    <K as Resource>::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    let objects = api.list_metadata(lp).await.map_err(|e| {
+        Error::KubeError(
+            format!("failed to list {} metadata", short_type_name::<K>()),
+            e,
+        )
+    })?;
+    let mut writer = Writer::default();
+    for obj in objects {
+        writer.apply_watcher_event(&watcher::Event::Apply(obj));
+    }
+    Ok(writer.as_reader())
+}
+
+/// Reconcile every object once, sequentially, instead of running the control loop. Failed
+/// reconciles do not stop the remaining ones, their errors are returned.
+pub async fn reconcile_once<K, F, Fut>(api: &Api<K>, reconcile: F) -> Result<ReconcileErrors>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+    F: Fn(Arc<K>) -> Fut,
+    Fut: Future<Output = Result<Action>>,
+{
+    let objects = api
+        .list(&ListParams::default())
+        .await
+        .map_err(|e| Error::KubeError(format!("failed to list {}", short_type_name::<K>()), e))?;
+    let mut errors = Vec::new();
+    for obj in objects {
+        let key = format!(
+            "{}/{}",
+            ResourceExt::namespace(&obj).unwrap_or_default(),
+            obj.name_any()
+        );
+        debug!(msg = format!("reconciling {} {key} once", short_type_name::<K>()));
+        if let Err(e) = reconcile(Arc::new(obj)).await {
+            error!(msg = format!("failed to reconcile {} {key}", short_type_name::<K>()), %e);
+            errors.push((key, e));
+        }
+    }
+    Ok(errors)
+}
+
 pub fn error_policy<K>(_obj: Arc<K>, _error: &Error, _ctx: Arc<Context<K>>) -> Action
 where
     K: Resource + Lookup + Clone + 'static,
@@ -225,11 +665,23 @@ where
     unreachable!("Handle in backoff_reconciler macro")
 }
 
+/// Requeue delay after a failed reconcile: the backoff of the object, unless Kanidm asked to wait
+/// longer because it is overloaded or unavailable.
+pub fn requeue_duration(error: &Error, backoff: Duration) -> Duration {
+    error
+        .retry_after()
+        .map_or(backoff, |retry_after| retry_after.max(backoff))
+}
+
 #[macro_export]
 macro_rules! backoff_reconciler {
     ($inner_reconciler:ident) => {
         |obj, ctx| async move {
             use $crate::controller::context::BackoffContext;
+            // safe unwrap: all resources in the operator are namespace scoped resources
+            let namespace = kube::ResourceExt::namespace(obj.as_ref()).unwrap();
+            let name = kube::ResourceExt::name_any(obj.as_ref());
+            ctx.metrics().reconcile_dequeued(&namespace, &name);
             match $inner_reconciler(obj.clone(), ctx.clone()).await {
                 Ok(action) => {
                     ctx.reset_backoff(kube::runtime::reflector::ObjectRef::from(obj.as_ref()))
@@ -237,14 +689,22 @@ macro_rules! backoff_reconciler {
                     Ok(action)
                 }
                 Err(error) => {
-                    // safe unwrap: all resources in the operator are namespace scoped resources
-                    let namespace = kube::ResourceExt::namespace(obj.as_ref()).unwrap();
-                    let name = kube::ResourceExt::name_any(obj.as_ref());
                     tracing::error!(msg = "failed reconciliation", %namespace, %name, %error);
                     ctx.metrics().reconcile_failure_inc();
-                    let backoff_duration = ctx
-                        .get_backoff(kube::runtime::reflector::ObjectRef::from(obj.as_ref()))
-                        .await;
+                    if !error.is_retryable() {
+                        tracing::debug!(
+                            msg = "terminal error, waiting for changes to retry",
+                            %namespace,
+                            %name,
+                        );
+                        ctx.publish_terminal_error(obj.as_ref(), &error).await;
+                        return Ok(kube::runtime::controller::Action::await_change());
+                    }
+                    let backoff_duration = $crate::controller::requeue_duration(
+                        &error,
+                        ctx.get_backoff(kube::runtime::reflector::ObjectRef::from(obj.as_ref()))
+                            .await,
+                    );
                     tracing::trace!(
                         msg = format!("backoff duration: {backoff_duration:?}"),
                         %namespace,
@@ -256,3 +716,366 @@ macro_rules! backoff_reconciler {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::error::KANIDM_RETRY_AFTER;
+    use crate::kanidm::crd::KanidmStatus;
+
+    use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+    use kube::runtime::watcher;
+    use serde_json::json;
+
+    fn kanidm_http_error(status: u16) -> Error {
+        Error::KanidmClientError(
+            "failed to update".to_string(),
+            Box::new(kanidm_client::ClientError::Http(
+                http::StatusCode::from_u16(status).unwrap(),
+                None,
+                "opid".to_string(),
+            )),
+        )
+    }
+
+    #[test]
+    fn test_requeue_duration_rate_limited() {
+        let backoff = Duration::from_secs(2);
+        assert_eq!(
+            requeue_duration(&kanidm_http_error(429), backoff),
+            KANIDM_RETRY_AFTER
+        );
+        assert_eq!(
+            requeue_duration(&kanidm_http_error(503), backoff),
+            KANIDM_RETRY_AFTER
+        );
+    }
+
+    #[test]
+    fn test_requeue_duration_keeps_longer_backoff() {
+        let backoff = KANIDM_RETRY_AFTER * 2;
+        assert_eq!(requeue_duration(&kanidm_http_error(429), backoff), backoff);
+    }
+
+    #[test]
+    fn test_requeue_duration_other_errors() {
+        let backoff = Duration::from_secs(2);
+        assert_eq!(requeue_duration(&kanidm_http_error(500), backoff), backoff);
+        assert_eq!(
+            requeue_duration(&Error::MissingData("missing".to_string()), backoff),
+            backoff
+        );
+    }
+
+    #[test]
+    fn test_requeue_duration_finalizer_error() {
+        let error = Error::FinalizerError(
+            "failed to reconcile".to_string(),
+            Box::new(kube::runtime::finalizer::Error::ApplyFailed(
+                kanidm_http_error(429),
+            )),
+        );
+        assert_eq!(
+            requeue_duration(&error, Duration::from_secs(2)),
+            KANIDM_RETRY_AFTER
+        );
+    }
+
+    fn config_map_list(names: &[&str]) -> serde_json::Value {
+        json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMapList",
+            "metadata": {},
+            "items": names
+                .iter()
+                .map(|name| json!({"metadata": {"name": name, "namespace": "default"}}))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn mock_list(
+        path: &'static str,
+        response: http::Response<kube::client::Body>,
+    ) -> (Client, tokio::task::JoinHandle<()>) {
+        let (mock_service, mut handle) = tower_test::mock::pair::<
+            http::Request<kube::client::Body>,
+            http::Response<kube::client::Body>,
+        >();
+        let api_server = tokio::spawn(async move {
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(request.uri().path(), path);
+            send.send_response(response);
+        });
+        (Client::new(mock_service, "default"), api_server)
+    }
+
+    fn ok_response(body: serde_json::Value) -> http::Response<kube::client::Body> {
+        http::Response::builder()
+            .body(kube::client::Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_once_aggregates_errors() {
+        let (client, api_server) = mock_list(
+            "/api/v1/configmaps",
+            ok_response(config_map_list(&["ok", "broken", "other"])),
+        );
+        let reconciled = std::sync::Mutex::new(Vec::new());
+        let errors = reconcile_once(&Api::<ConfigMap>::all(client), |cm| {
+            reconciled.lock().unwrap().push(cm.name_any());
+            async move {
+                match cm.name_any().as_str() {
+                    "broken" => Err(Error::MissingData("broken".to_string())),
+                    _ => Ok(Action::await_change()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        api_server.await.unwrap();
+
+        assert_eq!(*reconciled.lock().unwrap(), vec!["ok", "broken", "other"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "default/broken");
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_once_without_errors() {
+        let (client, api_server) =
+            mock_list("/api/v1/configmaps", ok_response(config_map_list(&["ok"])));
+        let errors = reconcile_once(&Api::<ConfigMap>::all(client), |_| async {
+            Ok(Action::await_change())
+        })
+        .await
+        .unwrap();
+        api_server.await.unwrap();
+        assert!(errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_once_list_failure() {
+        let (client, api_server) = mock_list(
+            "/api/v1/configmaps",
+            http::Response::builder()
+                .status(403)
+                .body(kube::client::Body::from(
+                    serde_json::to_vec(&json!({
+                        "kind": "Status",
+                        "apiVersion": "v1",
+                        "status": "Failure",
+                        "message": "forbidden",
+                        "reason": "Forbidden",
+                        "code": 403
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        );
+        let result = reconcile_once(&Api::<ConfigMap>::all(client), |_| async {
+            Ok(Action::await_change())
+        })
+        .await;
+        api_server.await.unwrap();
+        assert!(matches!(result, Err(Error::KubeError(_, _))));
+    }
+
+    #[tokio::test]
+    async fn test_list_store() {
+        let (client, api_server) = mock_list(
+            "/api/v1/configmaps",
+            ok_response(config_map_list(&["a", "b"])),
+        );
+        let store = list_store(&Api::<ConfigMap>::all(client), &ListParams::default())
+            .await
+            .unwrap();
+        api_server.await.unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store
+            .get(&reflector::ObjectRef::new("a").within("default"))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_reload_coalesces_when_channel_is_full() {
+        let (mut reload_tx, mut reload_rx) = mpsc::channel(0);
+
+        assert_eq!(trigger_reload(&mut reload_tx), ReloadTrigger::Sent);
+        assert_eq!(trigger_reload(&mut reload_tx), ReloadTrigger::Coalesced);
+
+        tokio::time::timeout(Duration::from_secs(1), reload_rx.next())
+            .await
+            .expect("trigger delivered")
+            .expect("channel open");
+        assert_eq!(trigger_reload(&mut reload_tx), ReloadTrigger::Sent);
+    }
+
+    #[test]
+    fn test_trigger_reload_dropped_when_channel_is_closed() {
+        let (mut reload_tx, reload_rx) = mpsc::channel(0);
+        drop(reload_rx);
+
+        assert_eq!(trigger_reload(&mut reload_tx), ReloadTrigger::Dropped);
+    }
+
+    #[test]
+    fn test_stores() {
+        let mut kanidm_writer = Writer::<Kanidm>::default();
+        let mut kanidm = Kanidm::default();
+        kanidm.metadata.name = Some("test".to_string());
+        kanidm.metadata.namespace = Some("default".to_string());
+        kanidm_writer.apply_watcher_event(&watcher::Event::Apply(kanidm));
+
+        let mut secret_writer = Writer::<Secret>::default();
+        let mut secret = Secret::default();
+        secret.metadata.name = Some("test-admin-passwords".to_string());
+        secret.metadata.namespace = Some("default".to_string());
+        secret_writer.apply_watcher_event(&watcher::Event::Apply(secret));
+
+        let state = State::new(
+            Registry::default(),
+            &[],
+            Writer::default().as_reader(),
+            kanidm_writer.as_reader(),
+            BufferSizes::default(),
+            DeletionGrace::default(),
+            MAX_CONCURRENT_KANIDM_REQUESTS,
+        );
+        assert_eq!(
+            serde_json::to_value(state.stores()).unwrap(),
+            json!({
+                "kanidms": {"size": 1, "keys": ["default/test"]},
+                "namespaces": {"size": 0, "keys": []},
+            })
+        );
+
+        state.register_kanidm_stores(Arc::new(Stores {
+            stateful_set_store: Writer::default().as_reader(),
+            service_store: Writer::default().as_reader(),
+            ingress_store: Writer::default().as_reader(),
+            secret_store: secret_writer.as_reader(),
+            config_map_store: Writer::default().as_reader(),
+            tls_secret_store: Writer::default().as_reader(),
+        }));
+        assert_eq!(
+            serde_json::to_value(state.stores()).unwrap(),
+            json!({
+                "configmaps": {"size": 0, "keys": []},
+                "ingresses": {"size": 0, "keys": []},
+                "kanidms": {"size": 1, "keys": ["default/test"]},
+                "namespaces": {"size": 0, "keys": []},
+                "secrets": {"size": 1, "keys": ["default/test-admin-passwords"]},
+                "services": {"size": 0, "keys": []},
+                "statefulsets": {"size": 0, "keys": []},
+                "tlssecrets": {"size": 0, "keys": []},
+            })
+        );
+    }
+
+    #[test]
+    fn test_default_namespace_selector_kanidm_namespace() {
+        let selector = DefaultNamespaceSelector::default();
+        assert_eq!(selector, DefaultNamespaceSelector::KanidmNamespace);
+        assert!(selector.matches("kanidm", "kanidm"));
+        assert!(!selector.matches("apps", "kanidm"));
+    }
+
+    #[test]
+    fn test_default_namespace_selector_all() {
+        let selector = DefaultNamespaceSelector::All;
+        assert!(selector.matches("kanidm", "kanidm"));
+        assert!(selector.matches("apps", "kanidm"));
+    }
+
+    fn queue_depth(metrics: &metrics::ControllerMetrics) -> i64 {
+        metrics
+            .reconcile_queue_depth
+            .get_or_create(&metrics::ControllerLabels {
+                controller: "test".to_string(),
+            })
+            .get()
+    }
+
+    fn config_map(name: &str) -> ConfigMap {
+        ConfigMap {
+            metadata: kube::api::ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            ..ConfigMap::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_track_queued_burst_of_watch_events() {
+        let metrics = metrics::ControllerMetrics::new("test");
+        let events = (0..100)
+            .map(|i| watcher::Event::Apply(config_map(&format!("config-{}", i % 3))))
+            .chain([
+                watcher::Event::InitApply(config_map("config-3")),
+                watcher::Event::Delete(config_map("config-2")),
+            ])
+            .map(Ok::<_, watcher::Error>);
+
+        let touched = futures::stream::iter(events)
+            .inspect(|event| track_queued(&metrics, event))
+            .touched_objects()
+            .count()
+            .await;
+        assert_eq!(touched, 102);
+        // config-0, config-1 and config-3, each of them reconciled once
+        assert_eq!(queue_depth(&metrics), 3);
+
+        metrics.reconcile_dequeued("default", "config-0");
+        assert_eq!(queue_depth(&metrics), 2);
+    }
+
+    #[tokio::test]
+    async fn test_owned_stream_and_reload_track_queued() {
+        let metrics = Arc::new(metrics::ControllerMetrics::new("test"));
+        let kanidm = Kanidm::test();
+        let mut owned_config_map = config_map("other");
+        owned_config_map.metadata.owner_references =
+            Some(vec![kanidm.controller_owner_ref(&()).unwrap()]);
+
+        let (store, writer) = reflector::store_shared(SUBSCRIBE_BUFFER_SIZE);
+        let subscriber = writer.subscribe().unwrap();
+        // the writer is dropped at the end of the stream, closing the subscriber
+        futures::stream::iter([Ok::<_, watcher::Error>(watcher::Event::Apply(
+            owned_config_map,
+        ))])
+        .reflect_shared(writer)
+        .for_each(|_| futures::future::ready(()))
+        .await;
+        let owned = owned_stream::<Kanidm, _>(subscriber, metrics.clone())
+            .count()
+            .await;
+        assert_eq!(owned, 1);
+        assert_eq!(queue_depth(&metrics), 1);
+
+        // the owner `test` and every object in the store, `other`
+        track_all_queued(&metrics, &store);
+        assert_eq!(queue_depth(&metrics), 2);
+    }
+
+    #[test]
+    fn test_object_states() {
+        let kanidm = |ready: Option<bool>| {
+            Arc::new(Kanidm {
+                status: ready.map(|ready| KanidmStatus {
+                    ready,
+                    ..KanidmStatus::default()
+                }),
+                ..Kanidm::test()
+            })
+        };
+        // objects without status are not reconciled yet
+        assert_eq!(
+            object_states(&[kanidm(Some(true)), kanidm(Some(false)), kanidm(None)]),
+            vec![metrics::ObjectState::Synced, metrics::ObjectState::Error]
+        );
+    }
+}
@@ -14,6 +14,21 @@ pub fn is_default<T: Default + PartialEq>(value: &T) -> bool {
     value == &T::default()
 }
 
+/// Resource managed in a Kanidm cluster.
+pub trait KanidmResource {
+    fn kanidm_name(&self) -> String;
+    fn kanidm_namespace(&self) -> String;
+    fn kanidm_ref(&self) -> String {
+        format!("{}/{}", self.kanidm_namespace(), self.kanidm_name())
+    }
+}
+
+/// Resource whose status reports whether it is ready.
+pub trait ReadyStatus {
+    /// Whether the status is ready, `None` until the status is first written.
+    fn ready(&self) -> Option<bool>;
+}
+
 /// KanidmRef is a reference to a Kanidm object in the same cluster. It is used to specify where
 /// the object is stored.
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
@@ -1,7 +1,15 @@
+#[cfg(feature = "client")]
+use std::time::Duration;
+
 use thiserror::Error;
 
+/// Delay before retrying a request that Kanidm rejected because it is overloaded or unavailable.
+#[cfg(feature = "client")]
+pub const KANIDM_RETRY_AFTER: Duration = Duration::from_secs(30);
+
 #[derive(Error, Debug)]
 pub enum Error {
+    #[cfg(feature = "client")]
     #[error("{0}: {1:?}")]
     // Boxing this error because the size can be large
     KanidmClientError(String, Box<kanidm_client::ClientError>),
@@ -9,6 +17,9 @@ pub enum Error {
     #[error("{0}: {1}")]
     KubeError(String, #[source] kube::Error),
 
+    #[error("{0}")]
+    ConflictError(String),
+
     #[error("{0}: {1}")]
     // NB: awkward type because finalizer::Error embeds the reconciler error (which is this)
     // so boxing this error to break cycles
@@ -23,20 +34,139 @@ pub enum Error {
     #[error("invalid trace ID")]
     InvalidTraceId,
 
+    #[error("{0}: {1}")]
+    IoError(String, #[source] std::io::Error),
+
     #[error("{0}")]
     MissingData(String),
 
     #[error("{0}: {1}")]
     ParseError(String, #[source] url::ParseError),
 
+    #[error("{0}")]
+    PermissionDenied(String),
+
     #[error("receive output error: {0}")]
     ReceiveOutput(String),
 
     #[error("{0}: {0}")]
     SerializationError(String, #[source] serde_json::Error),
 
+    #[error("{0}")]
+    Timeout(String),
+
     #[error("{0}: {0}")]
     Utf8Error(String, #[source] std::str::Utf8Error),
+
+    #[error("{0}")]
+    ValidationError(String),
+}
+
+impl Error {
+    /// Whether retrying the reconcile may fix the error. Terminal errors, like an invalid spec,
+    /// fail the same way until the object changes, so they are not retried. Cleanup failures are
+    /// always retried: the object is being deleted and its finalizer is only removed by a
+    /// successful cleanup.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::FinalizerError(_, e) => match e.as_ref() {
+                kube::runtime::finalizer::Error::ApplyFailed(e) => e.is_retryable(),
+                kube::runtime::finalizer::Error::CleanupFailed(_) => true,
+                kube::runtime::finalizer::Error::UnnamedObject
+                | kube::runtime::finalizer::Error::InvalidFinalizer => false,
+                _ => true,
+            },
+            Error::FormattingError(..)
+            | Error::InvalidTraceId
+            | Error::ParseError(..)
+            | Error::ValidationError(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Requeue delay for Kanidm `429 Too Many Requests` and `503 Service Unavailable` responses.
+    ///
+    /// `kanidm_client` does not expose the response headers, so `Retry-After` cannot be read and
+    /// [`KANIDM_RETRY_AFTER`] is used instead.
+    #[cfg(feature = "client")]
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::KanidmClientError(_, e) => match e.as_ref() {
+                kanidm_client::ClientError::Http(status, _, _)
+                    if matches!(status.as_u16(), 429 | 503) =>
+                {
+                    Some(KANIDM_RETRY_AFTER)
+                }
+                _ => None,
+            },
+            Error::FinalizerError(_, e) => match e.as_ref() {
+                kube::runtime::finalizer::Error::ApplyFailed(e)
+                | kube::runtime::finalizer::Error::CleanupFailed(e) => e.retry_after(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use super::*;
+
+    fn kanidm_http_error(status: u16) -> Error {
+        Error::KanidmClientError(
+            "failed to update".to_string(),
+            Box::new(kanidm_client::ClientError::Http(
+                http::StatusCode::from_u16(status).unwrap(),
+                None,
+                "opid".to_string(),
+            )),
+        )
+    }
+
+    #[test]
+    fn test_terminal_errors_not_retryable() {
+        assert!(!Error::ValidationError("invalid spec".to_string()).is_retryable());
+        assert!(!Error::ParseError(
+            "failed to parse URL".to_string(),
+            url::ParseError::EmptyHost
+        )
+        .is_retryable());
+        assert!(!Error::InvalidTraceId.is_retryable());
+        assert!(!Error::FinalizerError(
+            "failed on finalizer".to_string(),
+            Box::new(kube::runtime::finalizer::Error::ApplyFailed(
+                Error::ValidationError("invalid spec".to_string())
+            ))
+        )
+        .is_retryable());
+    }
+
+    #[test]
+    fn test_transient_errors_retryable() {
+        assert!(kanidm_http_error(500).is_retryable());
+        assert!(kanidm_http_error(429).is_retryable());
+        assert!(Error::MissingData("missing".to_string()).is_retryable());
+        assert!(Error::Timeout("timeout".to_string()).is_retryable());
+        assert!(Error::FinalizerError(
+            "failed on finalizer".to_string(),
+            Box::new(kube::runtime::finalizer::Error::CleanupFailed(
+                kanidm_http_error(503)
+            ))
+        )
+        .is_retryable());
+    }
+
+    #[test]
+    fn test_cleanup_errors_retryable() {
+        assert!(Error::FinalizerError(
+            "failed on finalizer".to_string(),
+            Box::new(kube::runtime::finalizer::Error::CleanupFailed(
+                Error::ValidationError("invalid spec".to_string())
+            ))
+        )
+        .is_retryable());
+    }
+}
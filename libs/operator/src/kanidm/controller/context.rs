@@ -1,20 +1,42 @@
 use crate::controller::context::BackoffContext;
+use crate::error::Error;
 use crate::metrics::ControllerMetrics;
 use crate::{controller::context::Context as KaniopContext, kanidm::crd::Kanidm};
 
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::{Secret, Service};
+use k8s_openapi::api::core::v1::{ConfigMap, PersistentVolumeClaim, Secret, Service};
 use k8s_openapi::api::networking::v1::Ingress;
+use kube::api::PartialObjectMeta;
 use kube::runtime::reflector::{ObjectRef, Store};
+use tokio::sync::RwLock;
+
+/// Resource version of a server TLS Secret and the digest of its certificate.
+pub type TlsSecretDigest = (String, String);
+
+/// Image of a Kanidm and generation and current revision of its StatefulSets.
+pub type RunningVersionKey = (String, Vec<(Option<i64>, Option<String>)>);
+
+/// Key the running version of a Kanidm was read with and the version.
+pub type RunningVersion = (RunningVersionKey, String);
 
 #[derive(Clone)]
 pub struct Context {
     pub kaniop_ctx: KaniopContext<Kanidm>,
     /// Shared store
     pub stores: Arc<Stores>,
+    /// Roll out the pods when their server TLS Secret changes
+    pub tls_secret_rollout: bool,
+    /// Certificate digests of the server TLS Secrets, read again only when they change
+    pub tls_secret_digests:
+        Arc<RwLock<HashMap<ObjectRef<PartialObjectMeta<Secret>>, TlsSecretDigest>>>,
+    /// Versions reported by the Kanidm pods, read again only when their key changes
+    pub running_versions: Arc<RwLock<HashMap<ObjectRef<Kanidm>, RunningVersion>>>,
+    /// Storage size each PVC was last checked against, read again only when it changes
+    pub pvc_sizes: Arc<RwLock<HashMap<ObjectRef<PersistentVolumeClaim>, String>>>,
 }
 
 impl Context {
@@ -22,8 +44,19 @@ impl Context {
         Context {
             kaniop_ctx,
             stores: Arc::new(stores),
+            tls_secret_rollout: false,
+            tls_secret_digests: Arc::default(),
+            running_versions: Arc::default(),
+            pvc_sizes: Arc::default(),
         }
     }
+
+    /// Annotate the pod templates with the server TLS Secret version, so its changes roll out
+    /// the pods.
+    pub fn with_tls_secret_rollout(mut self, tls_secret_rollout: bool) -> Self {
+        self.tls_secret_rollout = tls_secret_rollout;
+        self
+    }
 }
 
 impl BackoffContext<Kanidm> for Context {
@@ -37,6 +70,10 @@ impl BackoffContext<Kanidm> for Context {
     async fn reset_backoff(&self, obj_ref: ObjectRef<Kanidm>) {
         self.kaniop_ctx.reset_backoff(obj_ref).await
     }
+
+    async fn publish_terminal_error(&self, obj: &Kanidm, error: &Error) {
+        self.kaniop_ctx.publish_terminal_error(obj, error).await
+    }
 }
 
 pub struct Stores {
@@ -44,4 +81,7 @@ pub struct Stores {
     pub service_store: Store<Service>,
     pub ingress_store: Store<Ingress>,
     pub secret_store: Store<Secret>,
+    pub config_map_store: Store<ConfigMap>,
+    /// Metadata of the server TLS Secrets, which are not managed by the operator
+    pub tls_secret_store: Store<PartialObjectMeta<Secret>>,
 }
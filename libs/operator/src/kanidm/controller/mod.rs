@@ -6,26 +6,31 @@ use super::reconcile::reconcile_kanidm;
 
 use crate::backoff_reconciler;
 use crate::controller::{
-    check_api_queryable, create_subscriber, create_watcher, ControllerId, ResourceReflector, State,
-    RELOAD_BUFFER_SIZE, SUBSCRIBE_BUFFER_SIZE,
+    check_api_queryable, create_subscriber, create_watcher, list_metadata_store, list_store,
+    managed_by_selector, owned_stream, reconcile_once, reconcile_watcher, track_all_queued,
+    ControllerId, ReconcileErrors, ResourceReflector, State,
 };
-use crate::error::Error;
+use crate::error::{Error, Result};
 
 use std::sync::Arc;
 
 use futures::channel::mpsc;
 use futures::StreamExt;
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::{Namespace, Secret, Service};
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace, Secret, Service};
 use k8s_openapi::api::networking::v1::Ingress;
-use kube::api::Api;
+use kube::api::{Api, ListParams, PartialObjectMeta};
 use kube::client::Client;
 use kube::runtime::controller::{self, Controller};
-use kube::runtime::{watcher, WatchStreamExt};
+use kube::runtime::reflector::{self, ObjectRef, Store};
+use kube::runtime::{metadata_watcher, watcher, WatchStreamExt};
+use kube::ResourceExt;
 use tokio::time::Duration;
 use tracing::{error, info, trace};
 
 pub const CONTROLLER_ID: ControllerId = "kanidm";
+/// Server TLS Secrets are watched by type because they are not managed by the operator.
+const TLS_SECRET_FIELD_SELECTOR: &str = "type=kubernetes.io/tls";
 
 /// Initialize Kanidm controller and shared state
 pub async fn run(
@@ -40,25 +45,32 @@ pub async fn run(
     let service = check_api_queryable::<Service>(client.clone()).await;
     let ingress = check_api_queryable::<Ingress>(client.clone()).await;
     let secret = check_api_queryable::<Secret>(client.clone()).await;
+    let config_map = check_api_queryable::<ConfigMap>(client.clone()).await;
 
-    let statefulset_r = create_subscriber::<StatefulSet>(SUBSCRIBE_BUFFER_SIZE);
-    let service_r = create_subscriber::<Service>(SUBSCRIBE_BUFFER_SIZE);
-    let ingress_r = create_subscriber::<Ingress>(SUBSCRIBE_BUFFER_SIZE);
-    let secret_r = create_subscriber::<Secret>(SUBSCRIBE_BUFFER_SIZE);
+    let statefulset_r = create_subscriber::<StatefulSet>(state.buffer_sizes.subscribe);
+    let service_r = create_subscriber::<Service>(state.buffer_sizes.subscribe);
+    let ingress_r = create_subscriber::<Ingress>(state.buffer_sizes.subscribe);
+    let secret_r = create_subscriber::<Secret>(state.buffer_sizes.subscribe);
+    let config_map_r = create_subscriber::<ConfigMap>(state.buffer_sizes.subscribe);
 
-    let (reload_tx, reload_rx) = mpsc::channel(RELOAD_BUFFER_SIZE);
+    let (reload_tx, reload_rx) = mpsc::channel(state.buffer_sizes.reload);
+
+    let (tls_secret_store, tls_secret_writer) = reflector::store();
 
     let stores = Stores {
         stateful_set_store: statefulset_r.store,
         service_store: service_r.store,
         ingress_store: ingress_r.store,
         secret_store: secret_r.store,
+        config_map_store: config_map_r.store,
+        tls_secret_store: tls_secret_store.clone(),
     };
 
-    let ctx = Arc::new(Context::new(
-        state.to_context(client, CONTROLLER_ID),
-        stores,
-    ));
+    let ctx = Arc::new(
+        Context::new(state.to_context(client, CONTROLLER_ID), stores)
+            .with_tls_secret_rollout(state.tls_secret_rollout),
+    );
+    state.register_kanidm_stores(ctx.stores.clone());
     let kaniop_ctx = Arc::new(ctx.kaniop_ctx.clone());
     let statefulset_watcher = create_watcher(
         statefulset,
@@ -82,22 +94,34 @@ pub async fn run(
         kaniop_ctx.clone(),
     );
     let secret_watcher = create_watcher(
-        secret,
+        secret.clone(),
         secret_r.writer,
         reload_tx.clone(),
         CONTROLLER_ID,
+        kaniop_ctx.clone(),
+    );
+    let config_map_watcher = create_watcher(
+        config_map,
+        config_map_r.writer,
+        reload_tx.clone(),
+        CONTROLLER_ID,
         kaniop_ctx,
     );
 
+    let namespace_store = namespace_r.writer.as_reader();
     let namespace_watcher = watcher(namespace_api, watcher::Config::default().any_semantic())
         .default_backoff()
         .reflect(namespace_r.writer)
         .for_each(|res| {
             let ctx = ctx.clone();
+            let namespace_store = namespace_store.clone();
             async move {
                 match res {
                     Ok(event) => {
-                        trace!(msg = format!("receive namespace event: {event:?}"),)
+                        trace!(msg = format!("receive namespace event: {event:?}"),);
+                        ctx.kaniop_ctx
+                            .metrics
+                            .store_objects_set("Namespace", namespace_store.len());
                     }
                     Err(e) => {
                         error!(msg = format!("unexpected error when watching namespace"), %e);
@@ -110,19 +134,59 @@ pub async fn run(
     info!(msg = format!("starting {CONTROLLER_ID} controller"));
     // TODO: watcher::Config::default().streaming_lists() when stabilized in K8s
     // https://kubernetes.io/docs/reference/using-api/api-concepts/#streaming-lists
-    let kanidm_watcher = watcher(kanidm_api, watcher::Config::default().any_semantic())
-        .default_backoff()
-        .reflect(kanidm_r.writer)
-        .touched_objects();
+    let metrics = ctx.kaniop_ctx.metrics.clone();
+    let kanidm_watcher = reconcile_watcher(kanidm_api, kanidm_r.writer, metrics.clone());
+
+    // TLS Secrets are not owned by the Kanidms, so they are mapped to the Kanidms serving them
+    let tls_secret_metrics = metrics.clone();
+    let tls_secret_watcher = metadata_watcher(
+        secret,
+        watcher::Config::default()
+            .fields(TLS_SECRET_FIELD_SELECTOR)
+            .any_semantic(),
+    )
+    .default_backoff()
+    .reflect(tls_secret_writer)
+    .inspect(move |_| tls_secret_metrics.store_objects_set("TlsSecret", tls_secret_store.len()))
+    .touched_objects();
+    let kanidm_lookup = kanidm_r.store.clone();
+    let kanidm_reload_store = kanidm_r.store.clone();
+    let tls_secret_trigger_metrics = metrics.clone();
 
     let kanidm_controller = Controller::for_stream(kanidm_watcher, kanidm_r.store)
         // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
         .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
-        .owns_shared_stream(statefulset_r.subscriber)
-        .owns_shared_stream(service_r.subscriber)
-        .owns_shared_stream(ingress_r.subscriber)
-        .owns_shared_stream(secret_r.subscriber)
-        .reconcile_all_on(reload_rx.map(|_| ()))
+        .owns_shared_stream(owned_stream::<Kanidm, _>(
+            statefulset_r.subscriber,
+            metrics.clone(),
+        ))
+        .owns_shared_stream(owned_stream::<Kanidm, _>(
+            service_r.subscriber,
+            metrics.clone(),
+        ))
+        .owns_shared_stream(owned_stream::<Kanidm, _>(
+            ingress_r.subscriber,
+            metrics.clone(),
+        ))
+        .owns_shared_stream(owned_stream::<Kanidm, _>(
+            secret_r.subscriber,
+            metrics.clone(),
+        ))
+        .owns_shared_stream(owned_stream::<Kanidm, _>(
+            config_map_r.subscriber,
+            metrics.clone(),
+        ))
+        .watches_stream(tls_secret_watcher, move |secret| {
+            let kanidms = kanidms_using_tls_secret(&kanidm_lookup, &secret);
+            for kanidm in &kanidms {
+                tls_secret_trigger_metrics.reconcile_queued(
+                    kanidm.namespace.as_deref().unwrap_or_default(),
+                    &kanidm.name,
+                );
+            }
+            kanidms
+        })
+        .reconcile_all_on(reload_rx.map(move |_| track_all_queued(&metrics, &kanidm_reload_store)))
         .shutdown_on_signal()
         .run(
             backoff_reconciler!(reconcile_kanidm),
@@ -140,5 +204,110 @@ pub async fn run(
         _ = service_watcher => {},
         _ = ingress_watcher => {},
         _ = secret_watcher => {},
+        _ = config_map_watcher => {},
+    }
+}
+
+/// Kanidms in the namespace of `secret` using it as server TLS Secret.
+fn kanidms_using_tls_secret(
+    kanidm_store: &Store<Kanidm>,
+    secret: &PartialObjectMeta<Secret>,
+) -> Vec<ObjectRef<Kanidm>> {
+    kanidm_store
+        .state()
+        .iter()
+        .filter(|kanidm| {
+            kanidm.namespace() == secret.namespace()
+                && kanidm.server_tls_secret_name() == secret.name_any()
+        })
+        .map(|kanidm| ObjectRef::from_obj(kanidm.as_ref()))
+        .collect()
+}
+
+/// Reconcile every Kanidm once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let lp = ListParams::default().labels(&managed_by_selector(CONTROLLER_ID));
+    let stores = Stores {
+        stateful_set_store: list_store(&Api::<StatefulSet>::all(client.clone()), &lp).await?,
+        service_store: list_store(&Api::<Service>::all(client.clone()), &lp).await?,
+        ingress_store: list_store(&Api::<Ingress>::all(client.clone()), &lp).await?,
+        secret_store: list_store(&Api::<Secret>::all(client.clone()), &lp).await?,
+        config_map_store: list_store(&Api::<ConfigMap>::all(client.clone()), &lp).await?,
+        tls_secret_store: list_metadata_store(
+            &Api::<Secret>::all(client.clone()),
+            &ListParams::default().fields(TLS_SECRET_FIELD_SELECTOR),
+        )
+        .await?,
+    };
+    let ctx = Arc::new(
+        Context::new(state.to_context(client.clone(), CONTROLLER_ID), stores)
+            .with_tls_secret_rollout(state.tls_secret_rollout),
+    );
+    reconcile_once(&Api::<Kanidm>::all(client), |kanidm| {
+        reconcile_kanidm(kanidm, ctx.clone())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::kanidms_using_tls_secret;
+
+    use crate::kanidm::crd::Kanidm;
+
+    use k8s_openapi::api::core::v1::Secret;
+    use kube::api::{ObjectMeta, PartialObjectMeta};
+    use kube::runtime::reflector::store::Writer;
+    use kube::runtime::reflector::ObjectRef;
+    use kube::runtime::watcher;
+    use serde_json::json;
+
+    fn kanidm(name: &str, tls_secret_name: Option<&str>) -> Kanidm {
+        let mut kanidm = Kanidm::new(
+            name,
+            serde_json::from_value(json!({
+                "domain": "idm.example.com",
+                "replicaGroups": [{"name": "default", "replicas": 1}],
+                "tlsSecretName": tls_secret_name,
+            }))
+            .unwrap(),
+        );
+        kanidm.metadata.namespace = Some("default".to_string());
+        kanidm
+    }
+
+    fn tls_secret(name: &str, namespace: &str) -> PartialObjectMeta<Secret> {
+        PartialObjectMeta {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some(namespace.to_string()),
+                ..ObjectMeta::default()
+            },
+            ..PartialObjectMeta::default()
+        }
+    }
+
+    #[test]
+    fn test_tls_secret_creation_triggers_kanidm_reconcile() {
+        let mut writer = Writer::default();
+        for kanidm in [
+            kanidm("idm", None),
+            kanidm("custom", Some("custom-cert")),
+            kanidm("other", None),
+        ] {
+            writer.apply_watcher_event(&watcher::Event::Apply(kanidm));
+        }
+        let store = writer.as_reader();
+
+        assert_eq!(
+            kanidms_using_tls_secret(&store, &tls_secret("idm-tls", "default")),
+            vec![ObjectRef::new("idm").within("default")]
+        );
+        assert_eq!(
+            kanidms_using_tls_secret(&store, &tls_secret("custom-cert", "default")),
+            vec![ObjectRef::new("custom").within("default")]
+        );
+        assert!(kanidms_using_tls_secret(&store, &tls_secret("custom-tls", "default")).is_empty());
+        assert!(kanidms_using_tls_secret(&store, &tls_secret("idm-tls", "other")).is_empty());
     }
 }
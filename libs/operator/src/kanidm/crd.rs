@@ -1,4 +1,4 @@
-use crate::crd::is_default;
+use crate::crd::{is_default, KanidmResource, ReadyStatus};
 
 use std::collections::BTreeMap;
 
@@ -6,10 +6,12 @@ use k8s_openapi::api::apps::v1::StatefulSetPersistentVolumeClaimRetentionPolicy;
 use k8s_openapi::api::core::v1::{
     Affinity, Container, EmptyDirVolumeSource, EnvVar, EphemeralVolumeSource, HostAlias,
     PersistentVolumeClaim, PodDNSConfig, PodSecurityContext, ResourceRequirements,
-    SecretKeySelector, Toleration, TopologySpreadConstraint, Volume, VolumeMount,
+    SecretKeySelector, SecurityContext, Toleration, TopologySpreadConstraint, Volume, VolumeMount,
 };
+use k8s_openapi::api::networking::v1::IngressBackend;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector};
-use kube::CustomResource;
+use kube::{CustomResource, ResourceExt};
 #[cfg(feature = "schemars")]
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -37,11 +39,17 @@ use serde::{Deserialize, Serialize};
     plural = "kanidms",
     singular = "kanidm",
     shortname = "idm",
+    shortname = "km",
+    category = "kaniop",
     namespaced,
     status = "KanidmStatus",
     printcolumn = r#"{"name":"Replicas","type":"string","description":"The number of replicas: ready/desired","jsonPath":".status.replicaColumn"}"#,
+    printcolumn = r#"{"name":"Available","type":"string","jsonPath":".status.conditions[?(@.type == 'Available')].status"}"#,
     printcolumn = r#"{"name":"Domain","type":"string","jsonPath":".spec.domain"}"#,
     printcolumn = r#"{"name":"Secret","type":"string","jsonPath":".status.secretName"}"#,
+    printcolumn = r#"{"name":"Ready","type":"boolean","jsonPath":".status.ready"}"#,
+    printcolumn = r#"{"name":"Image","type":"string","priority":1,"jsonPath":".spec.image"}"#,
+    printcolumn = r#"{"name":"Version","type":"string","priority":1,"jsonPath":".status.version"}"#,
     printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#,
     derive = "Default"
 )]
@@ -62,13 +70,31 @@ pub struct KanidmSpec {
     ))]
     pub domain: String,
 
+    /// Human-readable name of the domain, shown in the Kanidm web UI and used as the issuer
+    /// name of TOTP credentials. It is set once Kanidm is available and initialized, reporting
+    /// the result in the `DomainConfigured` condition. If omitted, the operator does not manage
+    /// it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_display_name: Option<String>,
+
+    /// Global account policy of Kanidm. It is applied once Kanidm is available and initialized,
+    /// reporting the result in the `PolicyApplied` condition. If omitted, the operator does not
+    /// manage it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_policy: Option<KanidmSystemPolicy>,
+
     /// Different group of replicas with specific configuration as role, resources, affinity rules, and more.
     /// Each group will be deployed as a separate StatefulSet.
+    ///
+    /// Removing every write replica group is rejected, because it would leave Kanidm read-only,
+    /// unless the `kaniop.rs/allow-write-replica-groups-removal` annotation is set to `true`.
     // TODO: move from ValidatingAdmissionPolicy to here when schemars 1.0.0 is released
     //#[schemars(extend("x-kubernetes-validations" = [{"message": "Value is immutable", "rule": "self.size() > 0"}]))]
     // max is defined for allowing CEL expression in validation admission policy estimate
     // expression costs
-    #[validate(length(min = 1, max = 100))]
+    // min is validated in the validation admission policy because it can be empty for external
+    // Kanidm
+    #[validate(length(max = 100))]
     pub replica_groups: Vec<ReplicaGroup>,
 
     /// List of external replication nodes. This is used to configure replication between
@@ -81,6 +107,33 @@ pub struct KanidmSpec {
     #[serde(default, skip_serializing_if = "is_default")]
     pub external_replication_nodes: Vec<ExternalReplicationNode>,
 
+    /// Restart the StatefulSets automatically when new replica certificates are generated, so
+    /// the pods load them. When disabled, the certificates are still generated, but the
+    /// `RestartRequired` condition lists the StatefulSets to restart manually, e.g. with
+    /// `kubectl rollout restart`. Defaults to true.
+    #[serde(default = "default_auto_restart_on_cert_renewal")]
+    pub auto_restart_on_cert_renewal: bool,
+
+    /// Number of days before the replica certificates expire when the operator renews them.
+    /// Renewed certificates are loaded restarting the StatefulSets, like new ones. Kanidm
+    /// generates the certificates with a fixed validity of four years. Defaults to 30.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replica_cert_renew_before_days: Option<u32>,
+
+    /// Validate the Kanidm configuration with `kanidmd configtest` in an init container before
+    /// the server starts, so misconfigurations fail fast with a clear error in its logs.
+    /// Defaults to true.
+    #[serde(default = "default_validate_config")]
+    pub validate_config: bool,
+
+    /// Additional Kanidm server configuration in TOML format. It is merged into the `server.toml`
+    /// generated by the operator, stored in the `<name>-config` ConfigMap, and its values take
+    /// precedence over the generated ones. The `role` and `replication` options are managed by
+    /// the operator and cannot be set here.
+    /// More info: https://kanidm.github.io/kanidm/stable/server_configuration.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_config: Option<String>,
+
     /// Container image name. More info: https://kubernetes.io/docs/concepts/containers/images
     /// This field is optional to allow higher level config management to default or override
     /// container images in workload controllers like StatefulSets.
@@ -110,7 +163,8 @@ pub struct KanidmSpec {
     /// Namespaces to match for KanidmOAuth2Clients discovery.
     ///
     /// An empty label selector matches all namespaces.
-    /// A null label selector (default value) matches the current namespace only.
+    /// A null label selector (default value) matches the namespaces of the operator
+    /// `--default-namespace-selector`, the current namespace only unless it is changed.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub oauth2_client_namespace_selector: Option<LabelSelector>,
 
@@ -123,9 +177,13 @@ pub struct KanidmSpec {
     ///  2. ephemeral
     ///  3. volumeClaimTemplate
     ///
-    /// Note: Kaniop does not resize PVCs until Kubernetes fix
+    /// `size`, `storageClassName` and `accessModes` are a shorthand for `volumeClaimTemplate`. They
+    /// are merged into it, and any value set explicitly in `volumeClaimTemplate` takes precedence.
+    ///
+    /// When the storage size grows, existing PVCs are expanded in place if their StorageClass
+    /// allows volume expansion. Shrinking is not supported. The StatefulSet is recreated too,
+    /// because its volume claim templates cannot be updated until Kubernetes fix
     /// [KEP-4650](https://github.com/kubernetes/enhancements/pull/4651).
-    /// Although, StatefulSet will be recreated if the PVC is resized.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub storage: Option<KanidmStorage>,
 
@@ -171,10 +229,39 @@ pub struct KanidmSpec {
         Option<StatefulSetPersistentVolumeClaimRetentionPolicy>,
 
     /// SecurityContext holds pod-level security attributes and common container settings.
-    /// This defaults to the default PodSecurityContext.
+    /// This defaults to the default PodSecurityContext. Set `runAsNonRoot`, `runAsUser` and
+    /// `fsGroup` to run Kanidm as a non-root user that owns the data volume.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub security_context: Option<PodSecurityContext>,
 
+    /// SecurityContext applied to the containers managed by the operator. Unset by default. To
+    /// harden them, disallow privilege escalation, drop all capabilities, use the runtime
+    /// default seccomp profile and mount the root filesystem as read-only.
+    /// More info: https://kubernetes.io/docs/tasks/configure-pod-container/security-context/
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_security_context: Option<SecurityContext>,
+
+    /// Name of the RuntimeClass used to run the pods, e.g. to run them in a sandbox like gVisor.
+    /// More info: https://kubernetes.io/docs/concepts/containers/runtime-class/
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_class_name: Option<String>,
+
+    /// Defines on which Nodes the Pods of every replica group are scheduled. Merged with the
+    /// `nodeSelector` of each replica group, which takes precedence on conflicting keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_selector: Option<BTreeMap<String, String>>,
+
+    /// Defines the Pods’ tolerations of every replica group. The `tolerations` of each replica
+    /// group are appended to them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerations: Option<Vec<Toleration>>,
+
+    /// Name of the PriorityClass of the pods. Pods with a higher priority are scheduled ahead of
+    /// pending pods with a lower one, and may preempt them.
+    /// More info: https://kubernetes.io/docs/concepts/scheduling-eviction/pod-priority-preemption/
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority_class_name: Option<String>,
+
     /// Defines the DNS policy for the pods.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dns_policy: Option<String>,
@@ -203,7 +290,9 @@ pub struct KanidmSpec {
     /// InitContainers described here modify an operator generated init containers if they share
     /// the same name and modifications are done via a strategic merge patch.
     ///
-    /// The names of init container name managed by the operator are: * init-config-reloader.
+    /// The names of init container name managed by the operator are:
+    /// * kanidm-generate-replication-config
+    /// * kanidm-config-test
     ///
     /// Overriding init containers is entirely outside the scope of what the maintainers will
     /// support and by doing so, you accept that this behaviour may break at any time without notice.
@@ -229,6 +318,121 @@ pub struct KanidmSpec {
     /// automatically.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub host_network: Option<bool>,
+
+    /// Secret with the credentials used by the operator to manage this Kanidm instance,
+    /// overriding the generated admin secret. Useful for externally provisioned instances.
+    ///
+    /// The secret must be in the same namespace and contain the `ADMIN_PASSWORD` and
+    /// `IDM_ADMIN_PASSWORD` keys. Usernames default to `admin` and `idm_admin`, and can be
+    /// overridden with the `ADMIN_USERNAME` and `IDM_ADMIN_USERNAME` keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator_credentials: Option<SecretRef>,
+
+    /// Manage an externally hosted Kanidm instead of deploying it. When set, the operator does
+    /// not create StatefulSets, Services or Ingresses, and `replicaGroups` must be empty. Kanidm is
+    /// only used by the controllers of the resources referencing it, like KanidmOAuth2Clients,
+    /// KanidmGroups or KanidmPersonAccounts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external: Option<KanidmExternal>,
+
+    /// Secret with the CA certificate used by the operator to verify the Kanidm server
+    /// certificate, under the `ca.crt` key. If not set, system roots are used.
+    ///
+    /// The server certificate must be valid for the URL the operator connects to: the external
+    /// URL or `<name>.<namespace>.svc`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_secret: Option<SecretRef>,
+
+    /// Skip the verification of the Kanidm server certificate when the operator connects to it.
+    /// This is insecure and should only be used for testing. Defaults to `false`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub insecure_skip_verify: bool,
+
+    /// Timeouts of the connections and requests from the operator to Kanidm. Slow instances may
+    /// need higher values, otherwise reconciliations fail once they are exceeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_timeouts: Option<KanidmClientTimeouts>,
+}
+
+impl KanidmResource for Kanidm {
+    #[inline]
+    fn kanidm_name(&self) -> String {
+        self.name_any()
+    }
+
+    #[inline]
+    fn kanidm_namespace(&self) -> String {
+        // safe unwrap: Kanidm is namespaced scoped
+        self.namespace().unwrap()
+    }
+}
+
+impl ReadyStatus for Kanidm {
+    #[inline]
+    fn ready(&self) -> Option<bool> {
+        self.status.as_ref().map(|status| status.ready)
+    }
+}
+
+/// Global account policy of Kanidm. Only the defined fields are managed by the operator.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmSystemPolicy {
+    /// Minimum length of the passwords of every person, set in the account policy of the
+    /// `idm_all_persons` group. Kanidm rejects values below its own minimum.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_minimum_length: Option<u32>,
+
+    /// Passwords rejected by Kanidm, compared case-insensitively. It replaces the whole Kanidm
+    /// badlist, including the passwords added by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_badlist: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmClientTimeouts {
+    /// Seconds to wait for a connection to be established. Defaults to 5.
+    #[serde(default = "default_client_connect_timeout")]
+    #[cfg_attr(feature = "schemars", schemars(range(min = 1)))]
+    pub connect: u64,
+
+    /// Seconds to wait for a request to complete. Defaults to 30.
+    #[serde(default = "default_client_request_timeout")]
+    #[cfg_attr(feature = "schemars", schemars(range(min = 1)))]
+    pub request: u64,
+}
+
+impl Default for KanidmClientTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: default_client_connect_timeout(),
+            request: default_client_request_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmExternal {
+    /// URL of the Kanidm server, e.g. `https://idm.example.com`.
+    pub url: String,
+
+    /// Secret with the credentials used by the operator to manage Kanidm. It takes precedence
+    /// over `operatorCredentials` and follows the same format.
+    pub credentials_secret: SecretRef,
+}
+
+/// Reference to a secret in the same namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SecretRef {
+    /// Name of the secret.
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -274,6 +478,11 @@ pub struct ReplicaGroup {
     /// Defines the pod’s topology spread constraints if specified.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub topology_spread_constraints: Option<Vec<TopologySpreadConstraint>>,
+
+    /// Additional Kanidm server configuration in TOML format for the nodes of this replica group.
+    /// It is merged on top of the Kanidm `extraConfig`, so its values take precedence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_config: Option<String>,
 }
 
 // re-implementation of kanidmd_core::config::ServerRole because it is not Serialize
@@ -331,6 +540,22 @@ fn default_image() -> String {
     "kanidm/server:latest".to_string()
 }
 
+fn default_auto_restart_on_cert_renewal() -> bool {
+    true
+}
+
+fn default_validate_config() -> bool {
+    true
+}
+
+fn default_client_connect_timeout() -> u64 {
+    5
+}
+
+fn default_client_request_timeout() -> u64 {
+    30
+}
+
 // re-implementation of sketching::LogLevel because it is not Serialize
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
@@ -366,6 +591,21 @@ pub struct KanidmStorage {
     /// created PersistentVolumes.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub volume_claim_template: Option<PersistentVolumeClaim>,
+
+    /// Storage size requested for the PVC of each Kanidm server. Shorthand for
+    /// `volumeClaimTemplate.spec.resources.requests.storage`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<Quantity>,
+
+    /// Name of the StorageClass used by the PVC of each Kanidm server. Shorthand for
+    /// `volumeClaimTemplate.spec.storageClassName`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_class_name: Option<String>,
+
+    /// Access modes of the PVC of each Kanidm server. Shorthand for
+    /// `volumeClaimTemplate.spec.accessModes`. Defaults to `ReadWriteOnce`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_modes: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -395,6 +635,15 @@ pub struct KanidmService {
     /// More info: https://kubernetes.io/docs/concepts/services-networking/service/#publishing-services-service-types
     #[serde(skip_serializing_if = "Option::is_none")]
     pub type_: Option<String>,
+
+    /// Name of the HTTPS port of the Service. Useful for Ingress controllers matching ports by
+    /// name. Defaults to `portName`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_name: Option<String>,
+
+    /// Number of the HTTPS port of the Service. Defaults to `8443`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -418,6 +667,18 @@ pub struct KanidmIngress {
     /// compatibility reasons, ingress controllers should still honor that annotation if present.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ingress_class_name: Option<String>,
+
+    /// defaultBackend is the backend that should handle requests that don't match any rule. If
+    /// not specified, the behavior is up to the Ingress controller.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<IngressBackend>,
+
+    /// Pass TLS connections through to Kanidm, which terminates TLS itself. Sets the
+    /// `ssl-passthrough` and `backend-protocol: HTTPS` annotations of ingress-nginx. Annotations
+    /// defined in `annotations` take precedence.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub tls_passthrough: bool,
+
     /// Defines the name of the secret that contains the TLS private key and certificate for the
     /// server. If not defined, the default will be the Kanidm name appended with `-tls`.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -456,6 +717,30 @@ pub struct KanidmStatus {
 
     /// Admin users secret name.
     pub secret_name: Option<String>,
+
+    /// Kanidm is `Available` and `Initialized`, and no `ReplicaFailure` is present.
+    #[serde(default)]
+    pub ready: bool,
+
+    /// Health of the clients used by the operator to manage this Kanidm.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_pool: Option<KanidmClientPoolStatus>,
+
+    /// Kanidm server version reported by the running pods. Unlike the image tag, it is the
+    /// version actually deployed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmClientPoolStatus {
+    /// Number of clients with a valid session.
+    pub healthy: u32,
+
+    /// Total number of clients.
+    pub total: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -478,4 +763,6 @@ pub struct KanidmReplicaStatus {
 pub enum KanidmReplicaState {
     Initialized,
     Pending,
+    /// The replica certificate expires within `replicaCertRenewBeforeDays`.
+    CertificateExpiring,
 }
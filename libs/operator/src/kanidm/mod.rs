@@ -1,4 +1,6 @@
 #[rustfmt::skip]
 pub mod crd;
+#[cfg(feature = "client")]
 pub mod controller;
+#[cfg(feature = "client")]
 pub mod reconcile;
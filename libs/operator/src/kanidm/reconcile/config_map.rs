@@ -0,0 +1,268 @@
+use super::statefulset::{
+    CONTAINER_HTTPS_PORT, CONTAINER_LDAP_PORT, VOLUME_DATA_PATH, VOLUME_TLS_PATH,
+};
+
+use crate::error::{Error, Result};
+use crate::kanidm::crd::{Kanidm, ReplicaGroup};
+
+use kaniop_k8s_util::resources::controller_owner_references;
+
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::ObjectMeta;
+use kube::ResourceExt;
+use toml::{Table, Value};
+
+/// Kanidm server configuration options managed by the operator, they cannot be set in
+/// `extraConfig`.
+const OPERATOR_CONFIG_KEYS: [&str; 2] = ["role", "replication"];
+
+pub trait ConfigMapExt {
+    fn config_map_name(&self) -> String;
+    fn create_config_map(&self) -> Result<ConfigMap>;
+    fn generate_server_config(&self, replica_group: &ReplicaGroup) -> Result<String>;
+}
+
+/// ConfigMap key holding the `server.toml` of the replica group.
+#[inline]
+pub fn config_map_key(replica_group: &ReplicaGroup) -> String {
+    format!("server-{}.toml", replica_group.name)
+}
+
+impl ConfigMapExt for Kanidm {
+    #[inline]
+    fn config_map_name(&self) -> String {
+        format!("{}-config", self.name_any())
+    }
+
+    fn create_config_map(&self) -> Result<ConfigMap> {
+        let labels = self
+            .generate_resource_labels()
+            .into_iter()
+            .chain(self.labels().clone())
+            .collect();
+        let data = self
+            .spec
+            .replica_groups
+            .iter()
+            .map(|rg| Ok((config_map_key(rg), self.generate_server_config(rg)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(self.config_map_name()),
+                namespace: Some(self.get_namespace()),
+                labels: Some(labels),
+                owner_references: controller_owner_references(self),
+                ..ObjectMeta::default()
+            },
+            data: Some(data),
+            ..ConfigMap::default()
+        })
+    }
+
+    /// Kanidm `server.toml` of the replica group generated from the spec, with `extraConfig`
+    /// and then the replica group `extraConfig` merged on top of it.
+    fn generate_server_config(&self, replica_group: &ReplicaGroup) -> Result<String> {
+        let mut config = Table::from_iter(
+            [
+                ("domain", self.spec.domain.clone()),
+                ("origin", format!("https://{}", self.spec.domain)),
+                ("db_path", format!("{VOLUME_DATA_PATH}/kanidm.db")),
+                ("tls_chain", format!("{VOLUME_TLS_PATH}/tls.crt")),
+                ("tls_key", format!("{VOLUME_TLS_PATH}/tls.key")),
+                ("bindaddress", format!("0.0.0.0:{CONTAINER_HTTPS_PORT}")),
+                (
+                    "log_level",
+                    // safe unwrap: log level is an unit enum
+                    serde_plain::to_string(&self.spec.log_level).unwrap(),
+                ),
+            ]
+            .into_iter()
+            .chain(
+                self.spec
+                    .ldap_port_name
+                    .iter()
+                    .map(|_| ("ldapbindaddress", format!("0.0.0.0:{CONTAINER_LDAP_PORT}"))),
+            )
+            .map(|(key, value)| (key.to_string(), Value::String(value))),
+        );
+
+        if let Some(extra_config) = &self.spec.extra_config {
+            merge_config(
+                &mut config,
+                parse_extra_config(extra_config, "extraConfig")?,
+            );
+        }
+        if let Some(extra_config) = &replica_group.extra_config {
+            let field = format!("replicaGroups[{}].extraConfig", replica_group.name);
+            merge_config(&mut config, parse_extra_config(extra_config, &field)?);
+        }
+        Ok(config.to_string())
+    }
+}
+
+fn parse_extra_config(extra_config: &str, field: &str) -> Result<Table> {
+    let extra_config = extra_config
+        .parse::<Table>()
+        .map_err(|e| Error::ValidationError(format!("invalid {field}: {e}")))?;
+    match extra_config
+        .keys()
+        .find(|key| OPERATOR_CONFIG_KEYS.contains(&key.as_str()))
+    {
+        Some(key) => Err(Error::ValidationError(format!(
+            "invalid {field}: option `{key}` is managed by the operator"
+        ))),
+        None => Ok(extra_config),
+    }
+}
+
+/// Merge `extra` into `config` recursively, values in `extra` take precedence.
+fn merge_config(config: &mut Table, extra: Table) {
+    for (key, value) in extra {
+        match (config.get_mut(&key), value) {
+            (Some(Value::Table(current)), Value::Table(extra)) => merge_config(current, extra),
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_map_key, ConfigMapExt};
+
+    use crate::kanidm::crd::{Kanidm, KanidmServerRole, KanidmSpec, ReplicaGroup};
+
+    use kube::api::ObjectMeta;
+    use toml::{Table, Value};
+
+    fn create_kanidm_with_extra_config(extra_config: Option<&str>) -> Kanidm {
+        Kanidm {
+            metadata: ObjectMeta {
+                name: Some("idm".to_string()),
+                namespace: Some("default".to_string()),
+                ..ObjectMeta::default()
+            },
+            spec: KanidmSpec {
+                domain: "idm.example.com".to_string(),
+                extra_config: extra_config.map(str::to_string),
+                replica_groups: vec![ReplicaGroup {
+                    name: "default".to_string(),
+                    replicas: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn server_config(kanidm: &Kanidm, replica_group: &ReplicaGroup) -> Table {
+        kanidm.create_config_map().unwrap().data.unwrap()[&config_map_key(replica_group)]
+            .parse::<Table>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_create_config_map() {
+        let kanidm = create_kanidm_with_extra_config(None);
+        let config_map = kanidm.create_config_map().unwrap();
+        assert_eq!(config_map.metadata.name, Some("idm-config".to_string()));
+
+        let config = server_config(&kanidm, &kanidm.spec.replica_groups[0]);
+        assert_eq!(config["domain"].as_str(), Some("idm.example.com"));
+        assert_eq!(config["origin"].as_str(), Some("https://idm.example.com"));
+        assert_eq!(config["db_path"].as_str(), Some("/data/kanidm.db"));
+        assert_eq!(config["bindaddress"].as_str(), Some("0.0.0.0:8443"));
+        assert_eq!(config["log_level"].as_str(), Some("info"));
+        assert!(!config.contains_key("ldapbindaddress"));
+    }
+
+    #[test]
+    fn test_create_config_map_with_extra_config() {
+        let kanidm = create_kanidm_with_extra_config(Some(
+            r#"
+            log_level = "debug"
+            trust_x_forward_for = true
+
+            [online_backup]
+            path = "/data/backups"
+            schedule = "00 22 * * *"
+            "#,
+        ));
+
+        let config = server_config(&kanidm, &kanidm.spec.replica_groups[0]);
+        assert_eq!(config["domain"].as_str(), Some("idm.example.com"));
+        assert_eq!(config["log_level"].as_str(), Some("debug"));
+        assert_eq!(config["trust_x_forward_for"], Value::Boolean(true));
+        assert_eq!(
+            config["online_backup"]["schedule"].as_str(),
+            Some("00 22 * * *")
+        );
+    }
+
+    #[test]
+    fn test_create_config_map_with_replica_group_extra_config() {
+        let mut kanidm = create_kanidm_with_extra_config(Some(
+            r#"
+            log_level = "debug"
+
+            [online_backup]
+            path = "/data/backups"
+            schedule = "00 22 * * *"
+            "#,
+        ));
+        kanidm.spec.replica_groups.push(ReplicaGroup {
+            name: "read".to_string(),
+            replicas: 2,
+            role: KanidmServerRole::ReadOnlyReplica,
+            extra_config: Some(
+                r#"
+                log_level = "trace"
+
+                [online_backup]
+                versions = 2
+                "#
+                .to_string(),
+            ),
+            ..Default::default()
+        });
+
+        let default_config = server_config(&kanidm, &kanidm.spec.replica_groups[0]);
+        assert_eq!(default_config["log_level"].as_str(), Some("debug"));
+        assert!(!default_config["online_backup"]
+            .as_table()
+            .unwrap()
+            .contains_key("versions"));
+
+        let read_config = server_config(&kanidm, &kanidm.spec.replica_groups[1]);
+        assert_eq!(read_config["log_level"].as_str(), Some("trace"));
+        assert_eq!(read_config["online_backup"]["versions"], Value::Integer(2));
+        assert_eq!(
+            read_config["online_backup"]["schedule"].as_str(),
+            Some("00 22 * * *")
+        );
+    }
+
+    #[test]
+    fn test_create_config_map_with_invalid_extra_config() {
+        let kanidm = create_kanidm_with_extra_config(Some("log_level = "));
+        assert!(kanidm.create_config_map().is_err());
+
+        let kanidm = create_kanidm_with_extra_config(Some(r#"role = "read_only_replica""#));
+        assert!(kanidm.create_config_map().is_err());
+
+        let mut kanidm = create_kanidm_with_extra_config(None);
+        kanidm.spec.replica_groups[0].extra_config =
+            Some("[replication]\norigin = \"repl://idm:8444\"".to_string());
+        assert!(kanidm.create_config_map().is_err());
+    }
+
+    #[test]
+    fn test_create_config_map_with_new_kanidm_option() {
+        let kanidm = create_kanidm_with_extra_config(Some("new_option = true"));
+        let config = server_config(&kanidm, &kanidm.spec.replica_groups[0]);
+        assert_eq!(config["new_option"], Value::Boolean(true));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::controller::context::SystemClientContext;
+use crate::error::{Error, Result};
+use crate::kanidm::controller::context::Context;
+use crate::kanidm::crd::Kanidm;
+
+use std::sync::Arc;
+
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::ATTR_DOMAIN_DISPLAY_NAME;
+use kanidm_proto::v1::Entry;
+use tracing::{debug, trace};
+
+#[allow(async_fn_in_trait)]
+pub trait DomainExt {
+    /// Whether the domain settings of Kanidm match the given ones. It only reads from Kanidm.
+    async fn is_domain_configured(&self, ctx: Arc<Context>, display_name: &str) -> Result<bool>;
+
+    /// Apply the domain settings to Kanidm, changing only the values that differ from the current
+    /// ones.
+    async fn configure_domain(&self, ctx: Arc<Context>, display_name: &str) -> Result<()>;
+}
+
+impl DomainExt for Kanidm {
+    async fn is_domain_configured(&self, ctx: Arc<Context>, display_name: &str) -> Result<bool> {
+        let kanidm_client = ctx.kaniop_ctx.get_system_client(self).await?;
+        let domain = get_domain(&kanidm_client).await?;
+        Ok(is_domain_display_name_updated(&domain, display_name))
+    }
+
+    async fn configure_domain(&self, ctx: Arc<Context>, display_name: &str) -> Result<()> {
+        let kanidm_client = ctx.kaniop_ctx.get_system_client(self).await?;
+        let domain = get_domain(&kanidm_client).await?;
+        if is_domain_display_name_updated(&domain, display_name) {
+            trace!(msg = "domain display name unchanged");
+            return Ok(());
+        }
+        debug!(msg = "updating domain display name", display_name);
+        kanidm_client
+            .idm_domain_set_display_name(display_name)
+            .await
+            .map_err(|e| {
+                Error::KanidmClientError(
+                    "failed to set Kanidm domain display name".to_string(),
+                    Box::new(e),
+                )
+            })
+    }
+}
+
+async fn get_domain(kanidm_client: &KanidmClient) -> Result<Entry> {
+    kanidm_client.idm_domain_get().await.map_err(|e| {
+        Error::KanidmClientError("failed to get Kanidm domain".to_string(), Box::new(e))
+    })
+}
+
+fn is_domain_display_name_updated(domain: &Entry, display_name: &str) -> bool {
+    domain
+        .attrs
+        .get(ATTR_DOMAIN_DISPLAY_NAME)
+        .and_then(|values| values.first())
+        .is_some_and(|value| value == display_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_domain_display_name_updated() {
+        let domain = |display_name: Option<&str>| Entry {
+            attrs: display_name
+                .map(|name| (ATTR_DOMAIN_DISPLAY_NAME.to_string(), vec![name.to_string()]))
+                .into_iter()
+                .collect(),
+        };
+        assert!(is_domain_display_name_updated(
+            &domain(Some("Example IdM")),
+            "Example IdM"
+        ));
+        assert!(!is_domain_display_name_updated(
+            &domain(Some("Kanidm idm.example.com")),
+            "Example IdM"
+        ));
+        assert!(!is_domain_display_name_updated(
+            &domain(None),
+            "Example IdM"
+        ));
+    }
+}
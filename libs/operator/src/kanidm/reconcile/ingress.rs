@@ -1,12 +1,21 @@
-use crate::kanidm::crd::Kanidm;
+use super::service::ServiceExt;
+
+use crate::kanidm::crd::{Kanidm, KanidmIngress};
+
+use kaniop_k8s_util::resources::controller_owner_references;
 
 use k8s_openapi::api::networking::v1::{
     HTTPIngressPath, HTTPIngressRuleValue, Ingress, IngressBackend, IngressRule,
     IngressServiceBackend, IngressSpec, IngressTLS, ServiceBackendPort,
 };
-use kube::api::{ObjectMeta, Resource};
+use kube::api::ObjectMeta;
 use kube::ResourceExt;
 
+use std::collections::BTreeMap;
+
+const NGINX_SSL_PASSTHROUGH_ANNOTATION: &str = "nginx.ingress.kubernetes.io/ssl-passthrough";
+const NGINX_BACKEND_PROTOCOL_ANNOTATION: &str = "nginx.ingress.kubernetes.io/backend-protocol";
+
 pub trait IngressExt {
     fn create_ingress(&self) -> Option<Ingress>;
 }
@@ -27,12 +36,13 @@ impl IngressExt for Kanidm {
                     name: Some(self.name_any()),
                     namespace: Some(self.namespace().unwrap()),
                     labels: Some(labels),
-                    annotations: ingress.annotations.clone(),
-                    owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
+                    annotations: generate_annotations(&ingress),
+                    owner_references: controller_owner_references(self),
                     ..ObjectMeta::default()
                 },
                 spec: Some(IngressSpec {
                     ingress_class_name: ingress.ingress_class_name.clone(),
+                    default_backend: ingress.default_backend.clone(),
                     rules: Some(
                         hosts
                             .clone()
@@ -44,7 +54,7 @@ impl IngressExt for Kanidm {
                                             service: Some(IngressServiceBackend {
                                                 name: self.name_any(),
                                                 port: Some(ServiceBackendPort {
-                                                    name: Some(self.spec.port_name.clone()),
+                                                    name: Some(self.service_port_name()),
                                                     ..ServiceBackendPort::default()
                                                 }),
                                             }),
@@ -65,10 +75,117 @@ impl IngressExt for Kanidm {
                                 .unwrap_or_else(|| self.get_tls_secret_name()),
                         ),
                     }]),
-                    ..IngressSpec::default()
                 }),
                 ..Ingress::default()
             }
         })
     }
 }
+
+fn generate_annotations(ingress: &KanidmIngress) -> Option<BTreeMap<String, String>> {
+    if !ingress.tls_passthrough {
+        return ingress.annotations.clone();
+    }
+    Some(
+        [
+            (NGINX_SSL_PASSTHROUGH_ANNOTATION, "true"),
+            (NGINX_BACKEND_PROTOCOL_ANNOTATION, "HTTPS"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .chain(ingress.annotations.clone().unwrap_or_default())
+        .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IngressExt, NGINX_BACKEND_PROTOCOL_ANNOTATION, NGINX_SSL_PASSTHROUGH_ANNOTATION};
+
+    use crate::kanidm::crd::{Kanidm, KanidmIngress, KanidmSpec};
+
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::api::networking::v1::{IngressBackend, IngressServiceBackend};
+    use kube::api::ObjectMeta;
+
+    fn create_kanidm_with_ingress(ingress: Option<KanidmIngress>) -> Kanidm {
+        Kanidm {
+            metadata: ObjectMeta {
+                name: Some("idm".to_string()),
+                namespace: Some("default".to_string()),
+                ..ObjectMeta::default()
+            },
+            spec: KanidmSpec {
+                domain: "idm.example.com".to_string(),
+                ingress,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_create_ingress_without_ingress() {
+        assert!(create_kanidm_with_ingress(None).create_ingress().is_none());
+    }
+
+    #[test]
+    fn test_create_ingress_with_class_name_and_default_backend() {
+        let default_backend = IngressBackend {
+            service: Some(IngressServiceBackend {
+                name: "default-http-backend".to_string(),
+                port: None,
+            }),
+            ..IngressBackend::default()
+        };
+        let kanidm = create_kanidm_with_ingress(Some(KanidmIngress {
+            ingress_class_name: Some("nginx".to_string()),
+            default_backend: Some(default_backend.clone()),
+            ..KanidmIngress::default()
+        }));
+
+        let ingress_spec = kanidm.create_ingress().unwrap().spec.unwrap();
+
+        assert_eq!(ingress_spec.ingress_class_name, Some("nginx".to_string()));
+        assert_eq!(ingress_spec.default_backend, Some(default_backend));
+    }
+
+    #[test]
+    fn test_create_ingress_with_tls_passthrough() {
+        let kanidm = create_kanidm_with_ingress(Some(KanidmIngress {
+            annotations: Some(BTreeMap::from([
+                (
+                    NGINX_BACKEND_PROTOCOL_ANNOTATION.to_string(),
+                    "GRPCS".to_string(),
+                ),
+                ("foo".to_string(), "bar".to_string()),
+            ])),
+            tls_passthrough: true,
+            ..KanidmIngress::default()
+        }));
+
+        let annotations = kanidm.create_ingress().unwrap().metadata.annotations;
+
+        assert_eq!(
+            annotations,
+            Some(BTreeMap::from([
+                (
+                    NGINX_SSL_PASSTHROUGH_ANNOTATION.to_string(),
+                    "true".to_string()
+                ),
+                (
+                    NGINX_BACKEND_PROTOCOL_ANNOTATION.to_string(),
+                    "GRPCS".to_string()
+                ),
+                ("foo".to_string(), "bar".to_string()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_create_ingress_without_tls_passthrough() {
+        let kanidm = create_kanidm_with_ingress(Some(KanidmIngress::default()));
+        assert_eq!(kanidm.create_ingress().unwrap().metadata.annotations, None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+
+/// Restricts disruptive StatefulSet restarts to a comma separated list of daily UTC time
+/// ranges, e.g. `22:00-02:00,12:00-13:00`. Ranges ending before they start cross midnight.
+pub const MAINTENANCE_WINDOW_ANNOTATION: &str = "kaniop.rs/maintenance-window";
+
+const TIME_FORMAT: &str = "%H:%M";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    fn until_start(&self, time: NaiveTime) -> Duration {
+        let until_start = self.start.signed_duration_since(time);
+        if until_start < Duration::zero() {
+            until_start + Duration::days(1)
+        } else {
+            until_start
+        }
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| format!("invalid maintenance window `{s}`, expected HH:MM-HH:MM"))?;
+        let parse_time = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), TIME_FORMAT)
+                .map_err(|e| format!("invalid time `{t}` in maintenance window `{s}`: {e}"))
+        };
+        let window = MaintenanceWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("empty maintenance window `{s}`"));
+        }
+        Ok(window)
+    }
+}
+
+pub fn parse_maintenance_windows(value: &str) -> Result<Vec<MaintenanceWindow>, String> {
+    value
+        .split(',')
+        .filter(|w| !w.trim().is_empty())
+        .map(MaintenanceWindow::from_str)
+        .collect()
+}
+
+/// Time to wait until the next maintenance window starts, or `None` if restarts are allowed at
+/// `now`. An empty list of windows never defers restarts.
+pub fn restart_deferral(
+    windows: &[MaintenanceWindow],
+    now: DateTime<Utc>,
+) -> Option<std::time::Duration> {
+    let time = now.time();
+    if windows.is_empty() || windows.iter().any(|w| w.contains(time)) {
+        return None;
+    }
+    windows
+        .iter()
+        .map(|w| w.until_start(time))
+        .min()
+        .and_then(|d| d.to_std().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 6, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_maintenance_windows() {
+        let windows = parse_maintenance_windows("22:00-02:00, 12:00-13:30").unwrap();
+        assert_eq!(windows.len(), 2);
+        assert_eq!(
+            windows[1],
+            MaintenanceWindow {
+                start: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(13, 30, 0).unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_maintenance_windows_invalid() {
+        assert!(parse_maintenance_windows("22:00").is_err());
+        assert!(parse_maintenance_windows("25:00-02:00").is_err());
+        assert!(parse_maintenance_windows("02:00-02:00").is_err());
+    }
+
+    #[test]
+    fn test_restart_deferral_in_window() {
+        let windows = parse_maintenance_windows("12:00-13:00").unwrap();
+        assert_eq!(restart_deferral(&windows, at(12, 30)), None);
+    }
+
+    #[test]
+    fn test_restart_deferral_out_of_window() {
+        let windows = parse_maintenance_windows("12:00-13:00").unwrap();
+        assert_eq!(
+            restart_deferral(&windows, at(10, 15)),
+            Some(std::time::Duration::from_secs(105 * 60))
+        );
+        assert_eq!(
+            restart_deferral(&windows, at(13, 0)),
+            Some(std::time::Duration::from_secs(23 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_restart_deferral_window_crossing_midnight() {
+        let windows = parse_maintenance_windows("22:00-02:00").unwrap();
+        assert_eq!(restart_deferral(&windows, at(23, 0)), None);
+        assert_eq!(restart_deferral(&windows, at(1, 59)), None);
+        assert_eq!(
+            restart_deferral(&windows, at(21, 0)),
+            Some(std::time::Duration::from_secs(60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_restart_deferral_picks_next_window() {
+        let windows = parse_maintenance_windows("22:00-23:00,12:00-13:00").unwrap();
+        assert_eq!(
+            restart_deferral(&windows, at(14, 0)),
+            Some(std::time::Duration::from_secs(8 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_restart_deferral_without_windows() {
+        assert_eq!(restart_deferral(&[], at(14, 0)), None);
+    }
+}
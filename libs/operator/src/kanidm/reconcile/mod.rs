@@ -1,44 +1,138 @@
 pub mod secret;
+pub mod service;
 pub mod statefulset;
 
+mod config_map;
+mod domain;
 mod ingress;
-mod service;
+mod maintenance;
+mod pvc;
 mod status;
+mod system_policy;
 
 use super::controller::{context::Context, CONTROLLER_ID};
 
+use self::config_map::ConfigMapExt;
+use self::domain::DomainExt;
 use self::ingress::IngressExt;
-use self::secret::SecretExt;
+use self::maintenance::{parse_maintenance_windows, MAINTENANCE_WINDOW_ANNOTATION};
+use self::pvc::PersistentVolumeClaimExt;
+use self::secret::{SecretExt, DEFAULT_REPLICA_CERT_RENEW_BEFORE_DAYS};
 use self::service::ServiceExt;
-use self::statefulset::{StatefulSetExt, REPLICA_GROUP_LABEL};
+use self::statefulset::{with_tls_secret_version, StatefulSetExt};
 use self::status::StatusExt;
+use self::system_policy::SystemPolicyExt;
 
 use crate::controller::{DEFAULT_RECONCILE_INTERVAL, INSTANCE_LABEL, MANAGED_BY_LABEL, NAME_LABEL};
 use crate::error::{Error, Result};
-use crate::kanidm::crd::{Kanidm, KanidmReplicaState, KanidmStatus};
+use crate::kanidm::crd::{
+    Kanidm, KanidmReplicaState, KanidmReplicaStatus, KanidmServerRole, KanidmStatus, ReplicaGroup,
+    ReplicationType,
+};
 use crate::telemetry;
 
 use kaniop_k8s_util::client::get_output;
 use kaniop_k8s_util::types::short_type_name;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
-use std::sync::{Arc, LazyLock};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::Duration;
 
+use backon::{ExponentialBuilder, Retryable};
+use chrono::Utc;
 use futures::future::{join_all, try_join_all, TryJoinAll};
 use futures::try_join;
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::Pod;
-use kube::api::{Api, AttachParams, Patch, PatchParams, Resource};
+use k8s_openapi::api::core::v1::{ConfigMap, Pod, Secret};
+use kube::api::{Api, AttachParams, ListParams, PartialObjectMeta, Patch, PatchParams, Resource};
+use kube::client::UpgradeConnectionError;
 use kube::core::NamespaceResourceScope;
 use kube::runtime::controller::Action;
+use kube::runtime::reflector::ObjectRef;
 use kube::ResourceExt;
 use serde::{Deserialize, Serialize};
-use status::{is_kanidm_available, is_kanidm_initialized};
-use tracing::{debug, field, info, instrument, trace, Span};
+use sha2::{Digest, Sha256};
+use status::{
+    is_domain_unconfigured, is_kanidm_available, is_kanidm_initialized, is_policy_unapplied,
+};
+use tracing::{debug, field, info, instrument, trace, warn, Span};
 
-pub const CLUSTER_LABEL: &str = "kanidm.kaniop.rs/cluster";
+/// Default prefix of the cluster and replica group label keys.
+pub const DEFAULT_LABEL_PREFIX: &str = "kanidm.kaniop.rs";
 const KANIDM_OPERATOR_NAME: &str = "kanidms.kaniop.rs";
+/// Delay before refreshing the status once the domain settings or the system policy are applied.
+const SETTINGS_STATUS_REFRESH: Duration = Duration::from_millis(500);
+/// Attempts to attach to a pod before giving up on a pod exec
+const EXEC_ATTACH_ATTEMPTS: usize = 3;
+const EXEC_ATTACH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+static LABEL_KEYS: OnceLock<LabelKeys> = OnceLock::new();
+
+/// Keys of the labels identifying the Kanidm cluster and the replica group of the managed
+/// resources. Every label generator and filter uses them, so both stay consistent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelKeys {
+    pub cluster: String,
+    pub replica_group: String,
+}
+
+impl LabelKeys {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            cluster: format!("{prefix}/cluster"),
+            replica_group: format!("{prefix}/replica-group"),
+        }
+    }
+
+    /// Set the prefix of the keys used by the operator. It has to be called before any label is
+    /// generated, and only once, returning the current keys otherwise.
+    pub fn init(prefix: &str) -> std::result::Result<(), LabelKeys> {
+        LABEL_KEYS.set(Self::new(prefix))
+    }
+
+    /// Validate the prefix of the keys: a DNS subdomain, as required for label key prefixes.
+    pub fn parse_prefix(prefix: &str) -> std::result::Result<String, String> {
+        let is_dns_label = |label: &str| {
+            (1..=63).contains(&label.len())
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        };
+        if prefix.is_empty() {
+            return Err("label prefix cannot be empty".to_string());
+        }
+        if prefix.len() > 253 || !prefix.split('.').all(is_dns_label) {
+            return Err(format!(
+                "label prefix `{prefix}` is not a DNS subdomain: it has to contain only lowercase \
+                alphanumeric characters, '-' or '.', start and end with an alphanumeric character \
+                and be at most 253 characters"
+            ));
+        }
+        Ok(prefix.to_string())
+    }
+
+    /// Keys used by the operator, with the [`DEFAULT_LABEL_PREFIX`] unless [`LabelKeys::init`]
+    /// was called.
+    pub fn get() -> &'static Self {
+        LABEL_KEYS.get_or_init(|| Self::new(DEFAULT_LABEL_PREFIX))
+    }
+
+    /// Whether the labels belong to the resources of the Kanidm cluster `name`.
+    pub fn is_cluster(&self, labels: &BTreeMap<String, String>, name: &str) -> bool {
+        labels.get(&self.cluster).map(String::as_str) == Some(name)
+    }
+
+    /// Label selector of the resources of the Kanidm cluster `name`.
+    pub fn cluster_selector(&self, name: &str) -> String {
+        format!("{}={name}", self.cluster)
+    }
+}
+
+/// Key of the certificate in the server TLS Secret.
+const TLS_CERTIFICATE_KEY: &str = "tls.crt";
 
 static LABELS: LazyLock<BTreeMap<String, String>> = LazyLock::new(|| {
     BTreeMap::from([
@@ -64,6 +158,35 @@ pub async fn reconcile_admins_secret(
     Ok(())
 }
 
+/// Apply the ConfigMap with the server configuration. A ConfigMap with the same name that is not
+/// managed by the operator is never adopted.
+pub async fn reconcile_config_map(
+    kanidm: Arc<Kanidm>,
+    ctx: Arc<Context>,
+    config_map: ConfigMap,
+) -> Result<()> {
+    let namespace = kanidm.get_namespace();
+    let name = kanidm.config_map_name();
+    let config_map_ref = ObjectRef::<ConfigMap>::new_with(&name, ()).within(&namespace);
+    // the store only holds ConfigMaps managed by the operator
+    if ctx.stores.config_map_store.get(&config_map_ref).is_none() {
+        let config_map_api =
+            Api::<ConfigMap>::namespaced(ctx.kaniop_ctx.client.clone(), &namespace);
+        let existing = config_map_api.get_opt(&name).await.map_err(|e| {
+            Error::KubeError(format!("failed to get ConfigMap {namespace}/{name}"), e)
+        })?;
+        if existing
+            .is_some_and(|cm| cm.labels().get(MANAGED_BY_LABEL) != LABELS.get(MANAGED_BY_LABEL))
+        {
+            return Err(Error::ConflictError(format!(
+                "ConfigMap {namespace}/{name} already exists and it is not managed by the operator"
+            )));
+        }
+    }
+    kanidm.patch(ctx, config_map).await?;
+    Ok(())
+}
+
 pub async fn reconcile_replication_secrets(
     kanidm: Arc<Kanidm>,
     ctx: Arc<Context>,
@@ -85,7 +208,7 @@ pub async fn reconcile_replication_secrets(
                     .metadata
                     .labels
                     .iter()
-                    .any(|l| l.get(CLUSTER_LABEL) == Some(&kanidm.name_any()))
+                    .any(|l| LabelKeys::get().is_cluster(l, &kanidm.name_any()))
                     && kanidm.admins_secret_name() != secret.name_any()
                     && secret_names.iter().all(|sn| sn != &secret.name_any())
             })
@@ -97,11 +220,39 @@ pub async fn reconcile_replication_secrets(
         try_join!(secret_delete_future)?;
 
         if kanidm.is_replication_enabled() {
-            let generate_secret_futures = s
-                .replica_statuses
+            // secrets are only generated when the StatefulSets can be restarted right after
+            if let Some(deferral) = kanidm
+                .restart_deferral()
+                .filter(|_| kanidm.spec.auto_restart_on_cert_renewal)
+            {
+                if has_replicas_to_update(s) {
+                    info!(
+                        msg = "deferring replica certificates update until next maintenance window",
+                        ?deferral
+                    );
+                }
+                return Ok(());
+            }
+            let replicas_to_update = replicas_to_update(&kanidm, s, |name| {
+                let secret_ref =
+                    ObjectRef::<Secret>::new_with(name, ()).within(&kanidm.get_namespace());
+                ctx.stores.secret_store.get(&secret_ref).is_some()
+            });
+            let generate_secret_futures = replicas_to_update
                 .iter()
-                .filter(|rs| rs.state == KanidmReplicaState::Pending)
-                .map(|rs| kanidm.generate_replica_secret(ctx.clone(), &rs.pod_name))
+                .map(|rs| {
+                    let kanidm = kanidm.clone();
+                    let ctx = ctx.clone();
+                    async move {
+                        match rs.state {
+                            KanidmReplicaState::CertificateExpiring => {
+                                info!(msg = "renewing replica certificate", pod = rs.pod_name);
+                                kanidm.renew_replica_secret(ctx, &rs.pod_name).await
+                            }
+                            _ => kanidm.generate_replica_secret(ctx, &rs.pod_name).await,
+                        }
+                    }
+                })
                 .collect::<Vec<_>>();
             let secrets = try_join_all(generate_secret_futures).await?;
             let secret_futures = secrets
@@ -109,23 +260,62 @@ pub async fn reconcile_replication_secrets(
                 .map(|secret| kanidm.patch(ctx.clone(), secret))
                 .collect::<Vec<_>>();
             try_join_all(secret_futures).await?;
-            // TODO: rolling restart all of them one by one if you have write-replicas replica
-            // group with one node
-            let sts_api = Api::<StatefulSet>::namespaced(
-                ctx.kaniop_ctx.client.clone(),
-                &kanidm.get_namespace(),
-            );
-            let sts_restart_futures = s
-                .replica_statuses
-                .iter()
-                .filter(|rs| rs.state == KanidmReplicaState::Pending)
-                .map(|rs| sts_api.restart(&rs.statefulset_name));
-            let _ignore_errors = join_all(sts_restart_futures).await;
+            restart_statefulsets(kanidm.clone(), ctx, &replicas_to_update).await;
         }
     }
     Ok(())
 }
 
+/// Pending replicas and replicas with expiring certificates, sorted by pod name. Pending
+/// replicas whose certificate secret already exists are skipped. This keeps secret generation
+/// stable across reconciles with the same input, even when the status is stale.
+fn replicas_to_update<'a>(
+    kanidm: &Kanidm,
+    status: &'a KanidmStatus,
+    secret_exists: impl Fn(&str) -> bool,
+) -> Vec<&'a KanidmReplicaStatus> {
+    let mut replicas = status
+        .replica_statuses
+        .iter()
+        .filter(|rs| match rs.state {
+            KanidmReplicaState::Pending => {
+                !secret_exists(&kanidm.replica_secret_name(&rs.pod_name))
+            }
+            KanidmReplicaState::CertificateExpiring => true,
+            KanidmReplicaState::Initialized => false,
+        })
+        .collect::<Vec<_>>();
+    replicas.sort_by(|a, b| a.pod_name.cmp(&b.pod_name));
+    replicas.dedup_by(|a, b| a.pod_name == b.pod_name);
+    replicas
+}
+
+/// Restart the StatefulSets of the updated replicas, so their pods load the new replica
+/// certificates. Nothing is restarted when `autoRestartOnCertRenewal` is disabled.
+async fn restart_statefulsets(
+    kanidm: Arc<Kanidm>,
+    ctx: Arc<Context>,
+    updated_replicas: &[&KanidmReplicaStatus],
+) {
+    if !kanidm.spec.auto_restart_on_cert_renewal {
+        if !updated_replicas.is_empty() {
+            info!(msg = "automatic restart on certificate renewal disabled, skipping restart");
+        }
+        return;
+    }
+    // TODO: rolling restart all of them one by one if you have write-replicas replica
+    // group with one node
+    let sts_api =
+        Api::<StatefulSet>::namespaced(ctx.kaniop_ctx.client.clone(), &kanidm.get_namespace());
+    let sts_restart_futures = updated_replicas
+        .iter()
+        .map(|rs| rs.statefulset_name.as_str())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|sts_name| sts_api.restart(sts_name));
+    let _ignore_errors = join_all(sts_restart_futures).await;
+}
+
 #[instrument(skip(ctx, kanidm))]
 pub async fn reconcile_kanidm(kanidm: Arc<Kanidm>, ctx: Arc<Context>) -> Result<Action> {
     let trace_id = telemetry::get_trace_id();
@@ -142,6 +332,17 @@ pub async fn reconcile_kanidm(kanidm: Arc<Kanidm>, ctx: Arc<Context>) -> Result<
         e
     });
 
+    if kanidm.spec.external.is_some() {
+        trace!(msg = "external Kanidm, skipping workload resources");
+        let settings_applied = reconcile_settings(&kanidm, ctx.clone(), &status).await?;
+        return Ok(Action::requeue(requeue_interval(settings_applied)));
+    }
+
+    // invalid `extraConfig` must not reach the StatefulSets, and the StatefulSets must not start
+    // before their configuration exists
+    let config_map = kanidm.create_config_map()?;
+    reconcile_config_map(kanidm.clone(), ctx.clone(), config_map).await?;
+
     let admin_secret_future = reconcile_admins_secret(kanidm.clone(), ctx.clone(), &status);
     let replication_secret_future =
         reconcile_replication_secrets(kanidm.clone(), ctx.clone(), &status);
@@ -169,8 +370,8 @@ pub async fn reconcile_kanidm(kanidm: Arc<Kanidm>, ctx: Arc<Context>) -> Result<
         .into_iter()
         .filter(|sts| {
             sts.metadata.labels.iter().any(|l| {
-                l.get(CLUSTER_LABEL) == Some(&kanidm.name_any())
-                    && match l.get(REPLICA_GROUP_LABEL) {
+                LabelKeys::get().is_cluster(l, &kanidm.name_any())
+                    && match l.get(&LabelKeys::get().replica_group) {
                         Some(sts_rg_name) => kanidm
                             .spec
                             .replica_groups
@@ -186,40 +387,128 @@ pub async fn reconcile_kanidm(kanidm: Arc<Kanidm>, ctx: Arc<Context>) -> Result<
         .map(|sts| kanidm.delete(ctx.clone(), sts.as_ref()))
         .collect::<TryJoinAll<_>>();
 
+    kanidm
+        .spec
+        .replica_groups
+        .iter()
+        .try_for_each(|rg| kanidm.check_statefulset_selector(&ctx, rg))?;
+    let tls_secret_version = kanidm.tls_secret_version(&ctx).await?;
     let sts_futures = kanidm
         .spec
         .replica_groups
         .iter()
-        .map(|rg| kanidm.patch(ctx.clone(), kanidm.create_statefulset(rg)))
+        .map(|rg| {
+            if kanidm.is_drifted(&ctx, rg) {
+                info!(
+                    msg = "overwriting manual changes in StatefulSet",
+                    statefulset = kanidm.statefulset_name(&rg.name)
+                );
+                ctx.kaniop_ctx.metrics.drift_corrected_inc();
+            }
+            let statefulset = kanidm.create_statefulset(rg);
+            let statefulset = match &tls_secret_version {
+                Some(version) => with_tls_secret_version(statefulset, version.clone()),
+                None => statefulset,
+            };
+            kanidm.patch(ctx.clone(), statefulset)
+        })
         .collect::<TryJoinAll<_>>();
+    let pvc_future = kanidm.expand_persistent_volume_claims(ctx.clone());
     let service_future = kanidm.patch(ctx.clone(), kanidm.create_service());
     let ingress_future = kanidm
         .create_ingress()
         .into_iter()
         .map(|ingress| kanidm.patch(ctx.clone(), ingress))
         .collect::<TryJoinAll<_>>();
+    let settings_future = reconcile_settings(&kanidm, ctx.clone(), &status);
 
-    try_join!(
+    let (.., settings_applied) = try_join!(
         sts_delete_future,
         admin_secret_future,
         services_per_pod_futures,
         replication_secret_future,
         sts_futures,
+        pvc_future,
         service_future,
-        ingress_future
+        ingress_future,
+        settings_future
     )?;
-    Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL))
+
+    let interval = requeue_interval(settings_applied);
+    match (&status, kanidm.restart_deferral()) {
+        (Ok(s), Some(deferral)) if kanidm.is_replication_enabled() && has_replicas_to_update(s) => {
+            Ok(Action::requeue(deferral.min(interval)))
+        }
+        _ => Ok(Action::requeue(interval)),
+    }
+}
+
+/// Apply the domain settings and the system policy that the status reports as different from
+/// the spec, once Kanidm is available and initialized. Returns whether any of them was applied.
+async fn reconcile_settings(
+    kanidm: &Kanidm,
+    ctx: Arc<Context>,
+    status: &Result<KanidmStatus>,
+) -> Result<bool> {
+    let status = match status {
+        Ok(s) if is_kanidm_available(s.clone()) && is_kanidm_initialized(s.clone()) => s,
+        _ => return Ok(false),
+    };
+    let mut applied = false;
+    if let Some(display_name) = kanidm.spec.domain_display_name.as_deref() {
+        if is_domain_unconfigured(status.clone()) {
+            kanidm.configure_domain(ctx.clone(), display_name).await?;
+            applied = true;
+        }
+    }
+    if let Some(policy) = kanidm.spec.system_policy.as_ref() {
+        if is_policy_unapplied(status.clone()) {
+            kanidm.apply_system_policy(ctx, policy).await?;
+            applied = true;
+        }
+    }
+    Ok(applied)
+}
+
+/// Requeue soon after applying Kanidm settings, so the status reports them.
+#[inline]
+fn requeue_interval(settings_applied: bool) -> Duration {
+    if settings_applied {
+        SETTINGS_STATUS_REFRESH
+    } else {
+        DEFAULT_RECONCILE_INTERVAL
+    }
+}
+
+#[inline]
+fn certificate_digest(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[inline]
+fn has_replicas_to_update(status: &KanidmStatus) -> bool {
+    status
+        .replica_statuses
+        .iter()
+        .any(|rs| rs.state != KanidmReplicaState::Initialized)
 }
 
 impl Kanidm {
     #[inline]
     fn generate_resource_labels(&self) -> BTreeMap<String, String> {
+        self.generate_resource_labels_with(LabelKeys::get())
+    }
+
+    fn generate_resource_labels_with(&self, label_keys: &LabelKeys) -> BTreeMap<String, String> {
         LABELS
             .clone()
             .into_iter()
             .chain([
                 (INSTANCE_LABEL.to_string(), self.name_any()),
-                (CLUSTER_LABEL.to_string(), self.name_any()),
+                (label_keys.cluster.clone(), self.name_any()),
             ])
             .collect()
     }
@@ -229,6 +518,63 @@ impl Kanidm {
         format!("{}-tls", self.name_any())
     }
 
+    /// Name of the Secret with the certificate served by Kanidm.
+    pub fn server_tls_secret_name(&self) -> String {
+        self.spec.tls_secret_name.clone().unwrap_or_else(|| {
+            self.spec
+                .ingress
+                .as_ref()
+                .and_then(|i| i.tls_secret_name.clone())
+                .unwrap_or_else(|| self.get_tls_secret_name())
+        })
+    }
+
+    /// Digest of the server TLS certificate, when pods are rolled out on its changes. Other
+    /// changes of the Secret, like its labels or annotations, do not roll out the pods. The
+    /// Secret is only read when its resource version changes.
+    async fn tls_secret_version(&self, ctx: &Context) -> Result<Option<String>> {
+        if !ctx.tls_secret_rollout {
+            return Ok(None);
+        }
+        let name = self.server_tls_secret_name();
+        let namespace = self.get_namespace();
+        let secret_ref =
+            ObjectRef::<PartialObjectMeta<Secret>>::new_with(&name, ()).within(&namespace);
+        let Some(resource_version) = ctx
+            .stores
+            .tls_secret_store
+            .get(&secret_ref)
+            .and_then(|secret| secret.resource_version())
+        else {
+            return Ok(None);
+        };
+        if let Some((version, digest)) = ctx.tls_secret_digests.read().await.get(&secret_ref) {
+            if version == &resource_version {
+                return Ok(Some(digest.clone()));
+            }
+        }
+
+        let secret_api = Api::<Secret>::namespaced(ctx.kaniop_ctx.client.clone(), &namespace);
+        let secret = secret_api
+            .get_opt(&name)
+            .await
+            .map_err(|e| Error::KubeError(format!("failed to get Secret {namespace}/{name}"), e))?;
+        let Some((resource_version, certificate)) = secret.and_then(|secret| {
+            Some((
+                secret.resource_version()?,
+                secret.data?.get(TLS_CERTIFICATE_KEY)?.clone(),
+            ))
+        }) else {
+            return Ok(None);
+        };
+        let digest = certificate_digest(&certificate.0);
+        ctx.tls_secret_digests
+            .write()
+            .await
+            .insert(secret_ref, (resource_version, digest.clone()));
+        Ok(Some(digest))
+    }
+
     #[inline]
     fn get_namespace(&self) -> String {
         // safe unwrap: Kanidm is namespaced scoped
@@ -237,12 +583,109 @@ impl Kanidm {
 
     #[inline]
     fn is_replication_enabled(&self) -> bool {
-        // safe unwrap: at least one replica group is required
         self.spec.replica_groups.len() > 1
-            || self.spec.replica_groups.first().unwrap().replicas > 1
+            || self
+                .spec
+                .replica_groups
+                .first()
+                .is_some_and(|rg| rg.replicas > 1)
             || !self.spec.external_replication_nodes.is_empty()
     }
 
+    /// Whether more than one node accepts writes, counting write replicas and external nodes
+    /// replicating in both directions.
+    #[inline]
+    fn has_write_redundancy(&self) -> bool {
+        let write_replicas: i32 = self
+            .spec
+            .replica_groups
+            .iter()
+            .filter(|rg| !matches!(rg.role, KanidmServerRole::ReadOnlyReplica))
+            .map(|rg| rg.replicas)
+            .sum();
+        let write_external_nodes = self
+            .spec
+            .external_replication_nodes
+            .iter()
+            .filter(|ern| ern._type == ReplicationType::MutualPull)
+            .count();
+        write_replicas as usize + write_external_nodes > 1
+    }
+
+    fn get_statefulset(
+        &self,
+        ctx: &Context,
+        replica_group: &ReplicaGroup,
+    ) -> Option<Arc<StatefulSet>> {
+        let sts_ref =
+            ObjectRef::<StatefulSet>::new_with(&self.statefulset_name(&replica_group.name), ())
+                .within(&self.get_namespace());
+        ctx.stores.stateful_set_store.get(&sts_ref)
+    }
+
+    /// Whether the StatefulSet of the replica group in the store was modified outside the
+    /// operator.
+    fn is_drifted(&self, ctx: &Context, replica_group: &ReplicaGroup) -> bool {
+        self.get_statefulset(ctx, replica_group)
+            .is_some_and(|sts| self.is_statefulset_drifted(replica_group, &sts))
+    }
+
+    /// Fail when the StatefulSet of the replica group in the store has another selector than the
+    /// generated one, e.g. after changing `--label-prefix`. The selector is immutable, so the
+    /// StatefulSet has to be deleted by the user to be recreated with the new labels.
+    fn check_statefulset_selector(
+        &self,
+        ctx: &Context,
+        replica_group: &ReplicaGroup,
+    ) -> Result<()> {
+        match self.get_statefulset(ctx, replica_group) {
+            Some(sts) if self.is_statefulset_selector_changed(replica_group, &sts) => {
+                Err(Error::ValidationError(format!(
+                    "StatefulSet {namespace}/{name} selector does not match the operator labels \
+                    and it is immutable, e.g. after changing the label prefix: delete the \
+                    StatefulSet to recreate it",
+                    namespace = self.get_namespace(),
+                    name = sts.name_any(),
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// URL used by the operator to connect to Kanidm
+    pub fn client_url(&self) -> String {
+        match &self.spec.external {
+            Some(external) => external.url.clone(),
+            None => format!(
+                "https://{}.{}.svc:{}",
+                self.name_any(),
+                self.get_namespace(),
+                self.service_port()
+            ),
+        }
+    }
+
+    /// How long before expiring replica certificates are renewed.
+    fn replica_cert_renew_before(&self) -> chrono::Duration {
+        chrono::Duration::days(i64::from(
+            self.spec
+                .replica_cert_renew_before_days
+                .unwrap_or(DEFAULT_REPLICA_CERT_RENEW_BEFORE_DAYS),
+        ))
+    }
+
+    /// Time until the next maintenance window when disruptive restarts must be deferred now.
+    fn restart_deferral(&self) -> Option<Duration> {
+        let windows = self.annotations().get(MAINTENANCE_WINDOW_ANNOTATION)?;
+        match parse_maintenance_windows(windows) {
+            Ok(windows) => maintenance::restart_deferral(&windows, Utc::now()),
+            Err(e) => {
+                warn!(msg = "ignoring invalid maintenance window annotation", %e);
+                None
+            }
+        }
+    }
+
     async fn patch<K>(&self, ctx: Arc<Context>, obj: K) -> Result<K>
     where
         K: Resource<Scope = NamespaceResourceScope>
@@ -256,7 +699,7 @@ impl Kanidm {
         let name = obj.name_any();
         let namespace = self.get_namespace();
         trace!(
-            msg = format!("patching {}", short_type_name::<K>().unwrap_or("Unknown")),
+            msg = format!("patching {}", short_type_name::<K>()),
             resource.name = &name,
             resource.namespace = &namespace
         );
@@ -276,7 +719,7 @@ impl Kanidm {
                     info!(
                         msg = format!(
                             "recreating {} because the update operation was not possible",
-                            short_type_name::<K>().unwrap_or("Unknown")
+                            short_type_name::<K>()
                         ),
                         reason = ae.reason
                     );
@@ -294,7 +737,7 @@ impl Kanidm {
                             Error::KubeError(
                                 format!(
                                     "failed to re-try patch {} {namespace}/{name}",
-                                    short_type_name::<K>().unwrap_or("Unknown")
+                                    short_type_name::<K>()
                                 ),
                                 e,
                             )
@@ -303,7 +746,7 @@ impl Kanidm {
                 _ => Err(Error::KubeError(
                     format!(
                         "failed to patch {} {namespace}/{name}",
-                        short_type_name::<K>().unwrap_or("Unknown")
+                        short_type_name::<K>()
                     ),
                     e,
                 )),
@@ -324,7 +767,7 @@ impl Kanidm {
         let name = obj.name_any();
         let namespace = self.get_namespace();
         trace!(
-            msg = format!("deleting {}", short_type_name::<K>().unwrap_or("Unknown")),
+            msg = format!("deleting {}", short_type_name::<K>()),
             resource.name = &name,
             resource.namespace = &namespace
         );
@@ -333,7 +776,7 @@ impl Kanidm {
             Error::KubeError(
                 format!(
                     "failed to delete {} {namespace}/{name}",
-                    short_type_name::<K>().unwrap_or("Unknown")
+                    short_type_name::<K>()
                 ),
                 e,
             )
@@ -352,6 +795,7 @@ impl Kanidm {
         T: Into<String>,
     {
         let namespace = &self.get_namespace();
+        let command = command.into_iter().map(Into::into).collect::<Vec<String>>();
         trace!(
             msg = "pod exec",
             resource.name = &pod_name,
@@ -359,13 +803,39 @@ impl Kanidm {
             ?command
         );
         let pod = Api::<Pod>::namespaced(ctx.kaniop_ctx.client.clone(), namespace);
-        let attached = pod
-            .exec(pod_name, command, &AttachParams::default().stderr(false))
+        let attach_params = AttachParams::default().stderr(false);
+        let attach = || pod.exec(pod_name, command.clone(), &attach_params);
+        let exec = async {
+            let attached = attach
+                .retry(
+                    ExponentialBuilder::default()
+                        .with_min_delay(EXEC_ATTACH_RETRY_DELAY)
+                        .with_max_times(EXEC_ATTACH_ATTEMPTS - 1),
+                )
+                .when(is_transient_attach_error)
+                .notify(|e, after| {
+                    debug!(
+                        msg = "retrying pod exec",
+                        resource.name = &pod_name,
+                        resource.namespace = &namespace,
+                        ?after,
+                        %e
+                    )
+                })
+                .await
+                .map_err(|e| {
+                    Error::KubeError(format!("failed to exec pod {namespace}/{pod_name}"), e)
+                })?;
+            Ok(get_output(attached).await)
+        };
+        let exec_timeout = ctx.kaniop_ctx.exec_timeout;
+        tokio::time::timeout(exec_timeout, exec)
             .await
-            .map_err(|e| {
-                Error::KubeError(format!("failed to exec pod {namespace}/{pod_name}"), e)
-            })?;
-        Ok(get_output(attached).await)
+            .map_err(|_| {
+                Error::Timeout(format!(
+                    "exec in pod {namespace}/{pod_name} timed out after {exec_timeout:?}"
+                ))
+            })?
     }
 
     async fn exec_any<I, T>(&self, ctx: Arc<Context>, command: I) -> Result<Option<String>>
@@ -373,31 +843,96 @@ impl Kanidm {
         I: IntoIterator<Item = T> + Debug,
         T: Into<String>,
     {
-        // TODO: if replicas > 1 and replicas initialized, exec on pod available
-        // safe unwrap: at least one replica group is required
-        let sts_name = self.statefulset_name(&self.spec.replica_groups.first().unwrap().name);
-        let pod_name = format!("{sts_name}-0");
+        let namespace = &self.get_namespace();
+        let pod_api = Api::<Pod>::namespaced(ctx.kaniop_ctx.client.clone(), namespace);
+        let lp = ListParams::default().labels(&LabelKeys::get().cluster_selector(&self.name_any()));
+        let pods = pod_api
+            .list(&lp)
+            .await
+            .map_err(|e| Error::KubeError(format!("failed to list pods in {namespace}"), e))?;
+        let pod_name = self.select_exec_pod(&pods.items).ok_or_else(|| {
+            Error::MissingData(format!(
+                "no ready pod to exec in Kanidm {namespace}/{}",
+                self.name_any()
+            ))
+        })?;
         self.exec(ctx, &pod_name, command).await
     }
+
+    /// First ready pod, following the replica groups order and then the pods ordinal.
+    fn select_exec_pod(&self, pods: &[Pod]) -> Option<String> {
+        self.spec
+            .replica_groups
+            .iter()
+            .flat_map(|rg| {
+                let sts_name = self.statefulset_name(&rg.name);
+                (0..rg.replicas).map(move |i| format!("{sts_name}-{i}"))
+            })
+            .find(|pod_name| {
+                pods.iter()
+                    .any(|pod| &pod.name_any() == pod_name && is_pod_ready(pod))
+            })
+    }
+}
+
+/// Attach errors worth retrying: connection failures and API server errors. Client errors, e.g.
+/// a missing pod, fail straight away.
+fn is_transient_attach_error(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::UpgradeConnection(UpgradeConnectionError::ProtocolSwitch(status)) => {
+            status.is_server_error()
+        }
+        kube::Error::UpgradeConnection(UpgradeConnectionError::GetPendingUpgrade(_))
+        | kube::Error::HyperError(_)
+        | kube::Error::Service(_) => true,
+        _ => false,
+    }
+}
+
+fn is_pod_ready(pod: &Pod) -> bool {
+    pod.metadata.deletion_timestamp.is_none()
+        && pod
+            .status
+            .as_ref()
+            .and_then(|s| s.conditions.as_ref())
+            .is_some_and(|conditions| {
+                conditions
+                    .iter()
+                    .any(|c| c.type_ == "Ready" && c.status == "True")
+            })
 }
 
 #[cfg(test)]
 mod test {
-    use super::statefulset::StatefulSetExt;
-    use super::{reconcile_kanidm, Kanidm};
+    use super::config_map::{config_map_key, ConfigMapExt};
+    use super::pvc::PersistentVolumeClaimExt;
+    use super::secret::SecretExt;
+    use super::statefulset::{StatefulSetExt, TLS_SECRET_VERSION_ANNOTATION};
+    use super::{
+        certificate_digest, reconcile_kanidm, reconcile_settings, replicas_to_update,
+        restart_statefulsets, Kanidm, LabelKeys, DEFAULT_LABEL_PREFIX,
+    };
 
-    use crate::controller::State;
-    use crate::error::Result;
+    use crate::controller::{
+        State, DEFAULT_EXEC_TIMEOUT, MANAGED_BY_LABEL, MAX_CONCURRENT_KANIDM_REQUESTS,
+    };
+    use crate::error::{Error, Result};
     use crate::kanidm::controller::context::{Context, Stores};
-    use crate::kanidm::crd::KanidmStatus;
-    use k8s_openapi::api::core::v1::Service;
+    use crate::kanidm::crd::{KanidmReplicaState, KanidmReplicaStatus, KanidmStatus};
+    use k8s_openapi::api::core::v1::{ConfigMap, Secret, Service};
     use k8s_openapi::api::networking::v1::Ingress;
+    use k8s_openapi::ByteString;
 
+    use std::collections::BTreeMap;
     use std::sync::Arc;
+    use std::time::Duration;
 
     use http::{Request, Response};
     use k8s_openapi::api::apps::v1::StatefulSet;
+    use kube::api::{ObjectMeta, PartialObjectMeta};
     use kube::runtime::reflector::store::Writer;
+    use kube::runtime::reflector::{ObjectRef, Store};
+    use kube::runtime::watcher;
     use kube::{client::Body, Client, Resource, ResourceExt};
     use serde_json::json;
 
@@ -418,9 +953,25 @@ mod test {
                 .unwrap(),
             );
             e.meta_mut().namespace = Some("default".into());
+            e.meta_mut().uid = Some("00000000-0000-0000-0000-000000000000".into());
             e
         }
 
+        /// Modify kanidm to be managed externally. Its CA secret is not found in the mock
+        /// apiserver, so the reachability check never connects to the URL.
+        pub fn with_external(mut self) -> Self {
+            self.spec.external = Some(
+                serde_json::from_value(json!({
+                    "url": "https://127.0.0.1:1",
+                    "credentialsSecret": {"name": "test-credentials"}
+                }))
+                .unwrap(),
+            );
+            self.spec.ca_secret = Some(serde_json::from_value(json!({"name": "test-ca"})).unwrap());
+            self.spec.replica_groups = Vec::new();
+            self
+        }
+
         pub fn with_ingress(mut self) -> Self {
             self.spec.ingress = Some(serde_json::from_value(json!({})).unwrap());
             self
@@ -433,6 +984,18 @@ mod test {
             self
         }
 
+        /// Modify kanidm to use persistent storage of the given size
+        pub fn with_storage_size(mut self, size: &str) -> Self {
+            self.spec.storage = Some(serde_json::from_value(json!({"size": size})).unwrap());
+            self
+        }
+
+        /// Modify kanidm to not restart StatefulSets on certificate renewal
+        pub fn without_auto_restart_on_cert_renewal(mut self) -> Self {
+            self.spec.auto_restart_on_cert_renewal = false;
+            self
+        }
+
         /// Modify kanidm to set a deletion timestamp
         pub fn needs_delete(mut self) -> Self {
             use chrono::prelude::{DateTime, TimeZone, Utc};
@@ -456,9 +1019,28 @@ mod test {
     /// Scenarios we test for in ApiServerVerifier
     pub enum Scenario {
         Create(Kanidm),
+        /// Create reading the given server TLS certificate.
+        CreateWithTlsCertificate(Kanidm, String),
         CreateWithTwoReplicas(Kanidm),
         CreateWithIngress(Kanidm),
         CreateWithIngressWithTwoReplicas(Kanidm),
+        ConfigMapNotManaged(Kanidm),
+        External(Kanidm),
+        ExternalStatusUnchanged,
+        ExpandStorage(Kanidm),
+        /// Expand a PVC without `storageClassName`, using the default StorageClass.
+        ExpandStorageDefaultClass(Kanidm),
+        ShrinkStorage(Kanidm),
+        RestartPendingStatefulSet(Kanidm),
+        RestartDisabled,
+        /// Domain settings and system policy do not need to be applied.
+        SettingsInSync,
+        ExecAttachFailsThenHangs(String),
+        ExecPodNotFound(String),
+        /// List the pods to read the running version, without any ready one.
+        RunningVersionRead,
+        /// Reads of the server TLS Secret, with their resource version and certificate.
+        TlsSecretReads(Kanidm, Vec<(String, String)>),
     }
 
     pub async fn timeout_after_1s(handle: tokio::task::JoinHandle<()>) {
@@ -485,6 +1067,12 @@ mod test {
                 match scenario {
                     Scenario::Create(kanidm) => {
                         self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(kanidm.clone(), None)
+                            .await
+                            .unwrap()
+                            .handle_config_map_patch(kanidm.clone())
                             .await
                             .unwrap()
                             .handle_statefulset_patch(kanidm.clone())
@@ -493,8 +1081,36 @@ mod test {
                             .handle_service_patch(kanidm.clone())
                             .await
                     }
+                    Scenario::CreateWithTlsCertificate(kanidm, certificate) => {
+                        self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(kanidm.clone(), None)
+                            .await
+                            .unwrap()
+                            .handle_config_map_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_tls_secret_get(kanidm.clone(), "1", &certificate)
+                            .await
+                            .unwrap()
+                            .handle_statefulset_patch_with_tls_secret_version(
+                                kanidm.clone(),
+                                &certificate_digest(certificate.as_bytes()),
+                            )
+                            .await
+                            .unwrap()
+                            .handle_service_patch(kanidm.clone())
+                            .await
+                    }
                     Scenario::CreateWithTwoReplicas(kanidm) => {
                         self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(kanidm.clone(), None)
+                            .await
+                            .unwrap()
+                            .handle_config_map_patch(kanidm.clone())
                             .await
                             .unwrap()
                             .handle_statefulset_patch(kanidm.clone())
@@ -505,6 +1121,12 @@ mod test {
                     }
                     Scenario::CreateWithIngress(kanidm) => {
                         self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(kanidm.clone(), None)
+                            .await
+                            .unwrap()
+                            .handle_config_map_patch(kanidm.clone())
                             .await
                             .unwrap()
                             .handle_statefulset_patch(kanidm.clone())
@@ -518,6 +1140,12 @@ mod test {
                     }
                     Scenario::CreateWithIngressWithTwoReplicas(kanidm) => {
                         self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(kanidm.clone(), None)
+                            .await
+                            .unwrap()
+                            .handle_config_map_patch(kanidm.clone())
                             .await
                             .unwrap()
                             .handle_statefulset_patch(kanidm.clone())
@@ -529,6 +1157,116 @@ mod test {
                             .handle_ingress_patch(kanidm.clone())
                             .await
                     }
+                    Scenario::ConfigMapNotManaged(kanidm) => {
+                        self.handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_config_map_get(
+                                kanidm.clone(),
+                                Some(BTreeMap::from([(
+                                    MANAGED_BY_LABEL.to_string(),
+                                    "helm".to_string(),
+                                )])),
+                            )
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::External(kanidm) => {
+                        self.handle_ca_secret_not_found()
+                            .await
+                            .unwrap()
+                            .handle_kanidm_status_patch(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::ExternalStatusUnchanged => {
+                        self.handle_ca_secret_not_found()
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::ExpandStorage(kanidm) => {
+                        self.handle_pvc_get(kanidm.clone(), "1Gi", Some("standard"))
+                            .await
+                            .unwrap()
+                            .handle_storage_class_get(true)
+                            .await
+                            .unwrap()
+                            .handle_pvc_patch(kanidm.clone(), "2Gi")
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::ExpandStorageDefaultClass(kanidm) => {
+                        self.handle_pvc_get(kanidm.clone(), "1Gi", None)
+                            .await
+                            .unwrap()
+                            .handle_storage_class_list()
+                            .await
+                            .unwrap()
+                            .handle_pvc_patch(kanidm.clone(), "2Gi")
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::ShrinkStorage(kanidm) => {
+                        self.handle_pvc_get(kanidm.clone(), "5Gi", Some("standard"))
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::RestartPendingStatefulSet(kanidm) => {
+                        self.handle_statefulset_restart(kanidm.clone())
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::RestartDisabled => self.handle_no_more_requests().await,
+                    Scenario::SettingsInSync => self.handle_no_more_requests().await,
+                    Scenario::ExecAttachFailsThenHangs(pod_name) => {
+                        self.handle_pod_exec(&pod_name, http::StatusCode::SERVICE_UNAVAILABLE)
+                            .await
+                            .unwrap()
+                            .handle_pod_exec_hang(&pod_name)
+                            .await
+                    }
+                    Scenario::ExecPodNotFound(pod_name) => {
+                        self.handle_pod_exec(&pod_name, http::StatusCode::NOT_FOUND)
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::RunningVersionRead => {
+                        self.handle_pod_list()
+                            .await
+                            .unwrap()
+                            .handle_no_more_requests()
+                            .await
+                    }
+                    Scenario::TlsSecretReads(kanidm, reads) => {
+                        let mut verifier = self;
+                        for (resource_version, certificate) in reads {
+                            verifier = verifier
+                                .handle_tls_secret_get(
+                                    kanidm.clone(),
+                                    &resource_version,
+                                    &certificate,
+                                )
+                                .await
+                                .unwrap();
+                        }
+                        verifier.handle_no_more_requests().await
+                    }
                 }
                 .expect("scenario completed without errors");
             })
@@ -556,6 +1294,13 @@ mod test {
             Ok(self)
         }
 
+        async fn handle_no_more_requests(mut self) -> Result<Self> {
+            if let Some((request, _send)) = self.0.next_request().await {
+                panic!("unexpected request: {} {}", request.method(), request.uri());
+            }
+            Ok(self)
+        }
+
         async fn handle_statefulset_patch(mut self, kanidm: Kanidm) -> Result<Self> {
             for rg in kanidm.spec.replica_groups.iter() {
                 let (request, send) = self.0.next_request().await.expect("service not called");
@@ -576,6 +1321,19 @@ mod test {
                     statefulset.clone().spec.unwrap().replicas.unwrap(),
                     rg.replicas
                 );
+                assert!(statefulset
+                    .clone()
+                    .spec
+                    .unwrap()
+                    .template
+                    .spec
+                    .unwrap()
+                    .volumes
+                    .unwrap()
+                    .iter()
+                    .filter_map(|v| v.config_map.as_ref())
+                    .any(|cm| cm.name == kanidm.config_map_name()));
+                assert_controller_owner_reference(&kanidm, statefulset.meta());
                 let response = serde_json::to_vec(&statefulset).unwrap();
                 // pass through kanidm "patch accepted"
                 send.send_response(Response::builder().body(Body::from(response)).unwrap());
@@ -584,11 +1342,271 @@ mod test {
             Ok(self)
         }
 
-        async fn handle_service_patch(mut self, kanidm: Kanidm) -> Result<Self> {
+        async fn handle_statefulset_patch_with_tls_secret_version(
+            mut self,
+            kanidm: Kanidm,
+            version: &str,
+        ) -> Result<Self> {
             let (request, send) = self.0.next_request().await.expect("service not called");
             assert_eq!(request.method(), http::Method::PATCH);
             assert_eq!(
-                request.uri().to_string(),
+                request.uri().path(),
+                format!(
+                    "/apis/apps/v1/namespaces/default/statefulsets/{}",
+                    kanidm.statefulset_name(&kanidm.spec.replica_groups[0].name)
+                )
+            );
+            let req_body = request.into_body().collect_bytes().await.unwrap();
+            let statefulset: StatefulSet =
+                serde_json::from_slice(&req_body).expect("valid statefulset");
+            assert_eq!(
+                statefulset
+                    .clone()
+                    .spec
+                    .unwrap()
+                    .template
+                    .metadata
+                    .unwrap()
+                    .annotations
+                    .unwrap()
+                    .get(TLS_SECRET_VERSION_ANNOTATION),
+                Some(&version.to_string())
+            );
+            let response = serde_json::to_vec(&statefulset).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_tls_secret_get(
+            mut self,
+            kanidm: Kanidm,
+            resource_version: &str,
+            certificate: &str,
+        ) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(
+                request.uri().path(),
+                format!(
+                    "/api/v1/namespaces/default/secrets/{}",
+                    kanidm.server_tls_secret_name()
+                )
+            );
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(kanidm.server_tls_secret_name()),
+                    namespace: kanidm.namespace(),
+                    resource_version: Some(resource_version.to_string()),
+                    ..ObjectMeta::default()
+                },
+                data: Some(BTreeMap::from([(
+                    "tls.crt".to_string(),
+                    ByteString(certificate.as_bytes().to_vec()),
+                )])),
+                ..Secret::default()
+            };
+            let response = serde_json::to_vec(&secret).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_statefulset_restart(mut self, kanidm: Kanidm) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(
+                request.uri().path(),
+                format!(
+                    "/apis/apps/v1/namespaces/default/statefulsets/{}",
+                    kanidm.statefulset_name(&kanidm.spec.replica_groups[0].name)
+                )
+            );
+            let statefulset = kanidm.create_statefulset(&kanidm.spec.replica_groups[0]);
+            let response = serde_json::to_vec(&statefulset).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        fn pod_exec_path(pod_name: &str) -> String {
+            format!("/api/v1/namespaces/default/pods/{pod_name}/exec")
+        }
+
+        async fn handle_pod_exec(
+            mut self,
+            pod_name: &str,
+            status: http::StatusCode,
+        ) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(request.uri().path(), Self::pod_exec_path(pod_name));
+            send.send_response(
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap(),
+            );
+            Ok(self)
+        }
+
+        async fn handle_pod_list(mut self) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(request.uri().path(), "/api/v1/namespaces/default/pods");
+            let pods = json!({
+                "apiVersion": "v1",
+                "kind": "PodList",
+                "metadata": {},
+                "items": []
+            });
+            let response = serde_json::to_vec(&pods).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        /// Accept the exec request and never answer it, like a stuck pod.
+        async fn handle_pod_exec_hang(mut self, pod_name: &str) -> Result<Self> {
+            let (request, _send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.uri().path(), Self::pod_exec_path(pod_name));
+            std::future::pending::<()>().await;
+            Ok(self)
+        }
+
+        fn pvc_path(kanidm: &Kanidm) -> String {
+            format!(
+                "/api/v1/namespaces/default/persistentvolumeclaims/kanidm-data-{}-0",
+                kanidm.statefulset_name(&kanidm.spec.replica_groups[0].name)
+            )
+        }
+
+        async fn handle_pvc_get(
+            mut self,
+            kanidm: Kanidm,
+            size: &str,
+            storage_class_name: Option<&str>,
+        ) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(request.uri().path(), Self::pvc_path(&kanidm));
+            let pvc = json!({
+                "apiVersion": "v1",
+                "kind": "PersistentVolumeClaim",
+                "metadata": {"name": "kanidm-data-test-default-0", "namespace": "default"},
+                "spec": {
+                    "storageClassName": storage_class_name,
+                    "resources": {"requests": {"storage": size}}
+                }
+            });
+            let response = serde_json::to_vec(&pvc).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_ca_secret_not_found(mut self) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(
+                request.uri().path(),
+                "/api/v1/namespaces/default/secrets/test-ca"
+            );
+            let response = Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "kind": "Status",
+                        "apiVersion": "v1",
+                        "status": "Failure",
+                        "reason": "NotFound",
+                        "code": 404
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap();
+            send.send_response(response);
+            Ok(self)
+        }
+
+        async fn handle_storage_class_get(mut self, allow_volume_expansion: bool) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(
+                request.uri().path(),
+                "/apis/storage.k8s.io/v1/storageclasses/standard"
+            );
+            let storage_class = json!({
+                "apiVersion": "storage.k8s.io/v1",
+                "kind": "StorageClass",
+                "metadata": {"name": "standard"},
+                "provisioner": "rancher.io/local-path",
+                "allowVolumeExpansion": allow_volume_expansion
+            });
+            let response = serde_json::to_vec(&storage_class).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_storage_class_list(mut self) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(
+                request.uri().path(),
+                "/apis/storage.k8s.io/v1/storageclasses"
+            );
+            let storage_classes = json!({
+                "apiVersion": "storage.k8s.io/v1",
+                "kind": "StorageClassList",
+                "metadata": {},
+                "items": [
+                    {
+                        "metadata": {"name": "slow"},
+                        "provisioner": "rancher.io/local-path",
+                        "allowVolumeExpansion": false
+                    },
+                    {
+                        "metadata": {
+                            "name": "standard",
+                            "annotations": {
+                                "storageclass.kubernetes.io/is-default-class": "true"
+                            }
+                        },
+                        "provisioner": "rancher.io/local-path",
+                        "allowVolumeExpansion": true
+                    }
+                ]
+            });
+            let response = serde_json::to_vec(&storage_classes).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_pvc_patch(mut self, kanidm: Kanidm, size: &str) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(request.uri().path(), Self::pvc_path(&kanidm));
+            let req_body = request.into_body().collect_bytes().await.unwrap();
+            let json: serde_json::Value =
+                serde_json::from_slice(&req_body).expect("patch object is json");
+            assert_eq!(
+                json,
+                json!({"spec": {"resources": {"requests": {"storage": size}}}})
+            );
+            let pvc = json!({
+                "apiVersion": "v1",
+                "kind": "PersistentVolumeClaim",
+                "metadata": {"name": "kanidm-data-test-default-0", "namespace": "default"},
+                "spec": {
+                    "storageClassName": "standard",
+                    "resources": {"requests": {"storage": size}}
+                }
+            });
+            let response = serde_json::to_vec(&pvc).unwrap();
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
+        async fn handle_service_patch(mut self, kanidm: Kanidm) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(
+                request.uri().to_string(),
                 format!(
                     "/api/v1/namespaces/default/services/{}?&force=true&fieldManager=kanidms.kaniop.rs",
                     kanidm.name_any()
@@ -599,12 +1617,89 @@ mod test {
             let json: serde_json::Value =
                 serde_json::from_slice(&req_body).expect("patch object is json");
             let service: Service = serde_json::from_value(json).expect("valid service");
+            assert_controller_owner_reference(&kanidm, service.meta());
             let response = serde_json::to_vec(&service).unwrap();
             // pass through kanidm "patch accepted"
             send.send_response(Response::builder().body(Body::from(response)).unwrap());
             Ok(self)
         }
 
+        /// Answer the ConfigMap get with a ConfigMap with the given labels, or not found if `None`.
+        async fn handle_config_map_get(
+            mut self,
+            kanidm: Kanidm,
+            labels: Option<BTreeMap<String, String>>,
+        ) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::GET);
+            assert_eq!(
+                request.uri().path(),
+                format!(
+                    "/api/v1/namespaces/default/configmaps/{}",
+                    kanidm.config_map_name()
+                )
+            );
+            let response = match labels {
+                Some(labels) => Response::builder()
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "apiVersion": "v1",
+                            "kind": "ConfigMap",
+                            "metadata": {
+                                "name": kanidm.config_map_name(),
+                                "namespace": "default",
+                                "labels": labels
+                            }
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+                None => Response::builder()
+                    .status(http::StatusCode::NOT_FOUND)
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "kind": "Status",
+                            "apiVersion": "v1",
+                            "status": "Failure",
+                            "reason": "NotFound",
+                            "code": 404
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            };
+            send.send_response(response);
+            Ok(self)
+        }
+
+        async fn handle_config_map_patch(mut self, kanidm: Kanidm) -> Result<Self> {
+            let (request, send) = self.0.next_request().await.expect("service not called");
+            assert_eq!(request.method(), http::Method::PATCH);
+            assert_eq!(
+                request.uri().to_string(),
+                format!(
+                    "/api/v1/namespaces/default/configmaps/{}?&force=true&fieldManager=kanidms.kaniop.rs",
+                    kanidm.config_map_name()
+                )
+            );
+
+            let req_body = request.into_body().collect_bytes().await.unwrap();
+            let json: serde_json::Value =
+                serde_json::from_slice(&req_body).expect("patch object is json");
+            let config_map: ConfigMap = serde_json::from_value(json).expect("valid configmap");
+            assert_controller_owner_reference(&kanidm, config_map.meta());
+            for rg in kanidm.spec.replica_groups.iter() {
+                assert_eq!(
+                    config_map.data.as_ref().unwrap()[&config_map_key(rg)],
+                    kanidm.generate_server_config(rg).unwrap()
+                );
+            }
+            let response = serde_json::to_vec(&config_map).unwrap();
+            // pass through kanidm "patch accepted"
+            send.send_response(Response::builder().body(Body::from(response)).unwrap());
+            Ok(self)
+        }
+
         async fn handle_ingress_patch(mut self, kanidm: Kanidm) -> Result<Self> {
             let (request, send) = self.0.next_request().await.expect("service not called");
             assert_eq!(request.method(), http::Method::PATCH);
@@ -620,6 +1715,7 @@ mod test {
             let json: serde_json::Value =
                 serde_json::from_slice(&req_body).expect("patch object is json");
             let ingress: Ingress = serde_json::from_value(json).expect("valid service");
+            assert_controller_owner_reference(&kanidm, ingress.meta());
             let response = serde_json::to_vec(&ingress).unwrap();
             // pass through kanidm "patch accepted"
             send.send_response(Response::builder().body(Body::from(response)).unwrap());
@@ -627,7 +1723,41 @@ mod test {
         }
     }
 
+    fn assert_controller_owner_reference(kanidm: &Kanidm, meta: &ObjectMeta) {
+        let owner_references = meta.owner_references.clone().unwrap_or_default();
+        assert_eq!(owner_references.len(), 1);
+        assert_eq!(owner_references[0].kind, "Kanidm");
+        assert_eq!(owner_references[0].name, kanidm.name_any());
+        assert_eq!(owner_references[0].uid, kanidm.uid().unwrap());
+        assert_eq!(owner_references[0].controller, Some(true));
+        assert_eq!(owner_references[0].block_owner_deletion, Some(true));
+    }
+
     pub fn get_test_context() -> (Arc<Context>, ApiServerVerifier) {
+        get_test_context_with_exec_timeout(DEFAULT_EXEC_TIMEOUT)
+    }
+
+    pub fn get_test_context_with_exec_timeout(
+        exec_timeout: Duration,
+    ) -> (Arc<Context>, ApiServerVerifier) {
+        get_test_context_with(exec_timeout, None)
+    }
+
+    /// Test context rolling out pods on changes of the server TLS Secrets in the returned writer.
+    pub fn get_test_context_with_tls_secrets() -> (
+        Arc<Context>,
+        ApiServerVerifier,
+        Writer<PartialObjectMeta<Secret>>,
+    ) {
+        let writer = Writer::default();
+        let (ctx, verifier) = get_test_context_with(DEFAULT_EXEC_TIMEOUT, Some(writer.as_reader()));
+        (ctx, verifier, writer)
+    }
+
+    fn get_test_context_with(
+        exec_timeout: Duration,
+        tls_secret_store: Option<Store<PartialObjectMeta<Secret>>>,
+    ) -> (Arc<Context>, ApiServerVerifier) {
         let (mock_service, handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
         let mock_client = Client::new(mock_service, "default");
         let stores = Stores {
@@ -635,6 +1765,10 @@ mod test {
             service_store: Writer::default().as_reader(),
             ingress_store: Writer::default().as_reader(),
             secret_store: Writer::default().as_reader(),
+            config_map_store: Writer::default().as_reader(),
+            tls_secret_store: tls_secret_store
+                .clone()
+                .unwrap_or_else(|| Writer::default().as_reader()),
         };
         let controller_id = "test";
         let state = State::new(
@@ -642,11 +1776,15 @@ mod test {
             &[controller_id],
             Writer::default().as_reader(),
             Writer::default().as_reader(),
+            Default::default(),
+            Default::default(),
+            MAX_CONCURRENT_KANIDM_REQUESTS,
+        )
+        .with_exec_timeout(exec_timeout);
+        let ctx = Arc::new(
+            Context::new(state.to_context(mock_client, controller_id), stores)
+                .with_tls_secret_rollout(tls_secret_store.is_some()),
         );
-        let ctx = Arc::new(Context::new(
-            state.to_context(mock_client, controller_id),
-            stores,
-        ));
         (ctx, ApiServerVerifier(handle))
     }
 
@@ -661,6 +1799,78 @@ mod test {
         timeout_after_1s(mocksrv).await;
     }
 
+    #[tokio::test]
+    async fn kanidm_config_map_not_managed_is_not_adopted() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test();
+        let mocksrv = fakeserver.run(Scenario::ConfigMapNotManaged(kanidm.clone()));
+        let result = reconcile_kanidm(Arc::new(kanidm), testctx).await;
+        assert!(matches!(result, Err(Error::ConflictError(_))), "{result:?}");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    fn tls_secret(kanidm: &Kanidm, resource_version: &str) -> PartialObjectMeta<Secret> {
+        PartialObjectMeta {
+            metadata: ObjectMeta {
+                name: Some(kanidm.server_tls_secret_name()),
+                namespace: kanidm.namespace(),
+                resource_version: Some(resource_version.to_string()),
+                ..ObjectMeta::default()
+            },
+            ..PartialObjectMeta::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn kanidm_rollout_on_tls_secret_change() {
+        let kanidm = Kanidm::test();
+        for certificate in ["first", "second"] {
+            let (testctx, fakeserver, mut writer) = get_test_context_with_tls_secrets();
+            writer.apply_watcher_event(&watcher::Event::Apply(tls_secret(&kanidm, "1")));
+            let mocksrv = fakeserver.run(Scenario::CreateWithTlsCertificate(
+                kanidm.clone(),
+                certificate.to_string(),
+            ));
+            reconcile_kanidm(Arc::new(kanidm.clone()), testctx)
+                .await
+                .expect("reconciler");
+            timeout_after_1s(mocksrv).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn kanidm_tls_secret_version_only_changes_with_certificate() {
+        let kanidm = Kanidm::test();
+        let (testctx, fakeserver, mut writer) = get_test_context_with_tls_secrets();
+        let mocksrv = fakeserver.run(Scenario::TlsSecretReads(
+            kanidm.clone(),
+            vec![
+                ("1".to_string(), "first".to_string()),
+                ("2".to_string(), "first".to_string()),
+                ("3".to_string(), "second".to_string()),
+            ],
+        ));
+
+        writer.apply_watcher_event(&watcher::Event::Apply(tls_secret(&kanidm, "1")));
+        let first = kanidm.tls_secret_version(&testctx).await.unwrap();
+        assert_eq!(first, Some(certificate_digest(b"first")));
+        // not read again while the resource version is the same
+        assert_eq!(kanidm.tls_secret_version(&testctx).await.unwrap(), first);
+
+        // e.g. a label change
+        writer.apply_watcher_event(&watcher::Event::Apply(tls_secret(&kanidm, "2")));
+        assert_eq!(kanidm.tls_secret_version(&testctx).await.unwrap(), first);
+
+        writer.apply_watcher_event(&watcher::Event::Apply(tls_secret(&kanidm, "3")));
+        assert_eq!(
+            kanidm.tls_secret_version(&testctx).await.unwrap(),
+            Some(certificate_digest(b"second"))
+        );
+
+        drop(testctx);
+        timeout_after_1s(mocksrv).await;
+    }
+
     #[tokio::test]
     async fn kanidm_create_with_two_replicas() {
         let (testctx, fakeserver) = get_test_context();
@@ -693,4 +1903,357 @@ mod test {
             .expect("reconciler");
         timeout_after_1s(mocksrv).await;
     }
+
+    #[tokio::test]
+    async fn kanidm_external() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_external();
+        let mocksrv = fakeserver.run(Scenario::External(kanidm.clone()));
+        reconcile_kanidm(Arc::new(kanidm), testctx)
+            .await
+            .expect("reconciler");
+        // closes the mock apiserver, no workload resources were requested
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_external_status_unchanged() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+
+        let condition = |type_: &str, status: &str, reason: &str, message: &str| Condition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            reason: reason.to_string(),
+            message: message.to_string(),
+            last_transition_time: Time(chrono::Utc::now() - chrono::Duration::hours(1)),
+            observed_generation: None,
+        };
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_external().with_status(KanidmStatus {
+            conditions: Some(vec![
+                condition(
+                    "Available",
+                    "False",
+                    "ExternalUnreachable",
+                    "External Kanidm is not reachable.",
+                ),
+                condition(
+                    "Initialized",
+                    "True",
+                    "ExternalCredentials",
+                    "External Kanidm credentials are provided.",
+                ),
+                condition(
+                    "Reconciling",
+                    "True",
+                    "NotReady",
+                    "Kanidm is not available or initialized yet.",
+                ),
+                condition(
+                    "Stalled",
+                    "False",
+                    "NotStalled",
+                    "Reconcile is not failing repeatedly.",
+                ),
+            ]),
+            replica_column: "0/0".to_string(),
+            secret_name: Some("test-credentials".to_string()),
+            ..KanidmStatus::default()
+        });
+        let mocksrv = fakeserver.run(Scenario::ExternalStatusUnchanged);
+        reconcile_kanidm(Arc::new(kanidm), testctx)
+            .await
+            .expect("reconciler");
+        // closes the mock apiserver, the status was not patched
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_expand_storage() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_storage_size("2Gi");
+        let mocksrv = fakeserver.run(Scenario::ExpandStorage(kanidm.clone()));
+        kanidm
+            .expand_persistent_volume_claims(testctx.clone())
+            .await
+            .expect("expand storage");
+        // the PVC is not read again until the size changes
+        kanidm
+            .expand_persistent_volume_claims(testctx)
+            .await
+            .expect("expand storage");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_expand_storage_with_default_storage_class() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_storage_size("2Gi");
+        let mocksrv = fakeserver.run(Scenario::ExpandStorageDefaultClass(kanidm.clone()));
+        kanidm
+            .expand_persistent_volume_claims(testctx)
+            .await
+            .expect("expand storage");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_shrink_storage_is_ignored() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_storage_size("2Gi");
+        let mocksrv = fakeserver.run(Scenario::ShrinkStorage(kanidm.clone()));
+        kanidm
+            .expand_persistent_volume_claims(testctx)
+            .await
+            .expect("shrink storage is ignored");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    fn replica_status(
+        kanidm: &Kanidm,
+        index: i32,
+        state: KanidmReplicaState,
+    ) -> KanidmReplicaStatus {
+        let statefulset_name = kanidm.statefulset_name(&kanidm.spec.replica_groups[0].name);
+        KanidmReplicaStatus {
+            pod_name: format!("{statefulset_name}-{index}"),
+            statefulset_name,
+            state,
+        }
+    }
+
+    fn pending_replica_status(kanidm: &Kanidm) -> KanidmStatus {
+        KanidmStatus {
+            replica_statuses: vec![
+                replica_status(kanidm, 2, KanidmReplicaState::Pending),
+                replica_status(kanidm, 1, KanidmReplicaState::Pending),
+            ],
+            ..KanidmStatus::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn kanidm_restart_pending_statefulsets() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test().with_replicas(2);
+        let status = pending_replica_status(&kanidm);
+        let replicas_to_update = replicas_to_update(&kanidm, &status, |_| false);
+        // both replicas belong to the same StatefulSet, which is restarted once
+        let mocksrv = fakeserver.run(Scenario::RestartPendingStatefulSet(kanidm.clone()));
+        restart_statefulsets(Arc::new(kanidm), testctx, &replicas_to_update).await;
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_restart_pending_statefulsets_disabled() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test()
+            .with_replicas(2)
+            .without_auto_restart_on_cert_renewal();
+        let status = pending_replica_status(&kanidm);
+        let replicas_to_update = replicas_to_update(&kanidm, &status, |_| false);
+        let mocksrv = fakeserver.run(Scenario::RestartDisabled);
+        restart_statefulsets(Arc::new(kanidm), testctx, &replicas_to_update).await;
+        // closes the mock apiserver, no StatefulSet was restarted
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[test]
+    fn test_pending_replicas_are_stable() {
+        let kanidm = Kanidm::test().with_replicas(5);
+        let status = KanidmStatus {
+            replica_statuses: vec![
+                replica_status(&kanidm, 3, KanidmReplicaState::Pending),
+                replica_status(&kanidm, 0, KanidmReplicaState::Initialized),
+                replica_status(&kanidm, 4, KanidmReplicaState::CertificateExpiring),
+                replica_status(&kanidm, 1, KanidmReplicaState::Pending),
+                replica_status(&kanidm, 2, KanidmReplicaState::Pending),
+            ],
+            ..KanidmStatus::default()
+        };
+        let reversed_status = KanidmStatus {
+            replica_statuses: status.replica_statuses.iter().rev().cloned().collect(),
+            ..KanidmStatus::default()
+        };
+        let existing_secrets = [
+            kanidm.replica_secret_name("test-default-2"),
+            kanidm.replica_secret_name("test-default-4"),
+        ];
+        let secret_exists = |name: &str| existing_secrets.iter().any(|s| s == name);
+        let pod_names = |status: &KanidmStatus| {
+            replicas_to_update(&kanidm, status, secret_exists)
+                .into_iter()
+                .map(|rs| rs.pod_name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let first_reconcile = pod_names(&status);
+        // expiring certificates are renewed even if their secret exists
+        assert_eq!(
+            first_reconcile,
+            vec!["test-default-1", "test-default-3", "test-default-4"]
+        );
+        assert_eq!(pod_names(&status), first_reconcile);
+        assert_eq!(pod_names(&reversed_status), first_reconcile);
+    }
+
+    #[test]
+    fn test_label_keys_override() {
+        let kanidm = Kanidm::test();
+        let label_keys = LabelKeys::new("idm.example.com");
+        let labels = kanidm.generate_resource_labels_with(&label_keys);
+        assert_eq!(
+            labels.get("idm.example.com/cluster"),
+            Some(&"test".to_string())
+        );
+        assert!(!labels.contains_key("kanidm.kaniop.rs/cluster"));
+        assert!(label_keys.is_cluster(&labels, "test"));
+        assert!(!label_keys.is_cluster(&labels, "other"));
+        assert!(!LabelKeys::new(DEFAULT_LABEL_PREFIX).is_cluster(&labels, "test"));
+        assert_eq!(
+            label_keys.cluster_selector("test"),
+            "idm.example.com/cluster=test"
+        );
+        assert_eq!(label_keys.replica_group, "idm.example.com/replica-group");
+    }
+
+    #[test]
+    fn test_select_exec_pod_skips_not_ready_pods() {
+        use k8s_openapi::api::core::v1::{Pod, PodCondition, PodStatus};
+
+        let pod = |name: &str, ready: bool| Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..ObjectMeta::default()
+            },
+            status: Some(PodStatus {
+                conditions: Some(vec![PodCondition {
+                    type_: "Ready".to_string(),
+                    status: if ready { "True" } else { "False" }.to_string(),
+                    ..PodCondition::default()
+                }]),
+                ..PodStatus::default()
+            }),
+            ..Pod::default()
+        };
+        let kanidm = Kanidm::test().with_replicas(3);
+
+        let pods = vec![
+            pod("test-default-2", true),
+            pod("test-default-0", false),
+            pod("test-default-1", true),
+        ];
+        assert_eq!(
+            kanidm.select_exec_pod(&pods),
+            Some("test-default-1".to_string())
+        );
+
+        let pods = vec![pod("test-default-0", false), pod("test-default-1", false)];
+        assert_eq!(kanidm.select_exec_pod(&pods), None);
+        assert_eq!(kanidm.select_exec_pod(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn kanidm_exec_times_out_on_stuck_pod() {
+        let (testctx, fakeserver) = get_test_context_with_exec_timeout(Duration::from_secs(1));
+        let kanidm = Kanidm::test();
+        let mocksrv = fakeserver.run(Scenario::ExecAttachFailsThenHangs(
+            "test-default-0".to_string(),
+        ));
+        let result = kanidm
+            .exec(testctx, "test-default-0", ["kanidmd", "version"])
+            .await;
+        assert!(matches!(result, Err(Error::Timeout(_))), "{result:?}");
+        mocksrv.abort();
+    }
+
+    #[tokio::test]
+    async fn kanidm_exec_does_not_retry_missing_pod() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test();
+        let mocksrv = fakeserver.run(Scenario::ExecPodNotFound("test-default-0".to_string()));
+        let result = kanidm
+            .exec(testctx, "test-default-0", ["kanidmd", "version"])
+            .await;
+        assert!(matches!(result, Err(Error::KubeError(_, _))), "{result:?}");
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_running_version_cached_until_image_changes() {
+        let (testctx, fakeserver) = get_test_context();
+        let kanidm = Kanidm::test();
+        testctx.running_versions.write().await.insert(
+            ObjectRef::from(&kanidm),
+            (
+                (kanidm.spec.image.clone(), vec![(None, None)]),
+                "1.4.5".to_string(),
+            ),
+        );
+        let mocksrv = fakeserver.run(Scenario::RunningVersionRead);
+        assert_eq!(
+            kanidm.running_version(testctx.clone()).await,
+            Some("1.4.5".to_string())
+        );
+
+        let mut upgraded = kanidm.clone();
+        upgraded.spec.image = "kanidm/server:1.5.0".to_string();
+        assert_eq!(upgraded.running_version(testctx.clone()).await, None);
+        assert_eq!(
+            testctx
+                .running_versions
+                .read()
+                .await
+                .get(&ObjectRef::from(&kanidm))
+                .map(|(_, version)| version.as_str()),
+            Some("1.4.5")
+        );
+        drop(testctx);
+        timeout_after_1s(mocksrv).await;
+    }
+
+    #[tokio::test]
+    async fn kanidm_settings_not_applied_when_in_sync_or_unavailable() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+
+        let condition = |type_: &str, status: &str| Condition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            reason: String::new(),
+            message: String::new(),
+            last_transition_time: Time(chrono::Utc::now()),
+            observed_generation: None,
+        };
+        let (testctx, fakeserver) = get_test_context();
+        let mut kanidm = Kanidm::test();
+        kanidm.spec.domain_display_name = Some("Example IdM".to_string());
+        let mocksrv = fakeserver.run(Scenario::SettingsInSync);
+
+        let unavailable = KanidmStatus {
+            conditions: Some(vec![
+                condition("Available", "False"),
+                condition("Initialized", "True"),
+                condition("DomainConfigured", "False"),
+            ]),
+            ..KanidmStatus::default()
+        };
+        let applied = reconcile_settings(&kanidm, testctx.clone(), &Ok(unavailable))
+            .await
+            .unwrap();
+        assert!(!applied);
+
+        let in_sync = KanidmStatus {
+            conditions: Some(vec![
+                condition("Available", "True"),
+                condition("Initialized", "True"),
+                condition("DomainConfigured", "True"),
+            ]),
+            ..KanidmStatus::default()
+        };
+        let applied = reconcile_settings(&kanidm, testctx, &Ok(in_sync))
+            .await
+            .unwrap();
+        assert!(!applied);
+        timeout_after_1s(mocksrv).await;
+    }
 }
@@ -0,0 +1,234 @@
+use super::statefulset::{StatefulSetExt, VOLUME_DATA_NAME};
+
+use crate::error::{Error, Result};
+use crate::kanidm::controller::context::Context;
+use crate::kanidm::crd::Kanidm;
+
+use kaniop_k8s_util::parse_quantity;
+
+use std::sync::Arc;
+
+use futures::future::try_join_all;
+use k8s_openapi::api::core::v1::PersistentVolumeClaim;
+use k8s_openapi::api::storage::v1::StorageClass;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::{Api, ListParams, Patch, PatchParams};
+use kube::runtime::reflector::ObjectRef;
+use kube::ResourceExt;
+use serde_json::json;
+use tracing::{debug, info, trace, warn};
+
+const STORAGE_RESOURCE: &str = "storage";
+/// Annotation of the StorageClass used by the PVCs without `storageClassName`.
+const DEFAULT_STORAGE_CLASS_ANNOTATION: &str = "storageclass.kubernetes.io/is-default-class";
+
+#[derive(Debug, PartialEq)]
+enum StorageResize {
+    Expand,
+    Shrink,
+    Unchanged,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait PersistentVolumeClaimExt {
+    /// Expand the PVCs of every replica whose storage request is lower than the configured size.
+    ///
+    /// StatefulSet volume claim templates cannot be updated, so existing PVCs are patched
+    /// directly when their StorageClass allows volume expansion. Shrinking is not supported by
+    /// Kubernetes, so smaller sizes are ignored. Each PVC is read only once per size.
+    async fn expand_persistent_volume_claims(&self, ctx: Arc<Context>) -> Result<()>;
+}
+
+impl PersistentVolumeClaimExt for Kanidm {
+    async fn expand_persistent_volume_claims(&self, ctx: Arc<Context>) -> Result<()> {
+        let size = match self.storage_size() {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let namespace = self.get_namespace();
+        let pvc_api =
+            Api::<PersistentVolumeClaim>::namespaced(ctx.kaniop_ctx.client.clone(), &namespace);
+        let futures = self
+            .spec
+            .replica_groups
+            .iter()
+            .flat_map(|rg| {
+                let sts_name = self.statefulset_name(&rg.name);
+                (0..rg.replicas).map(move |i| format!("{VOLUME_DATA_NAME}-{sts_name}-{i}"))
+            })
+            .map(|pvc_name| expand_pvc(ctx.clone(), &pvc_api, &namespace, pvc_name, &size))
+            .collect::<Vec<_>>();
+        try_join_all(futures).await?;
+        Ok(())
+    }
+}
+
+impl Kanidm {
+    /// Storage requested for each PVC, if Kanidm uses persistent storage.
+    fn storage_size(&self) -> Option<Quantity> {
+        let storage = self.spec.storage.as_ref()?;
+        if storage.empty_dir.is_some() || storage.ephemeral.is_some() {
+            return None;
+        }
+        storage
+            .claim_template()?
+            .spec?
+            .resources?
+            .requests?
+            .remove(STORAGE_RESOURCE)
+    }
+}
+
+async fn expand_pvc(
+    ctx: Arc<Context>,
+    pvc_api: &Api<PersistentVolumeClaim>,
+    namespace: &str,
+    pvc_name: String,
+    size: &Quantity,
+) -> Result<()> {
+    let pvc_ref = ObjectRef::<PersistentVolumeClaim>::new(&pvc_name).within(namespace);
+    if ctx.pvc_sizes.read().await.get(&pvc_ref) == Some(&size.0) {
+        trace!(
+            msg = "PVC already checked for the storage size",
+            pvc = pvc_name
+        );
+        return Ok(());
+    }
+    let pvc = match pvc_api.get_opt(&pvc_name).await.map_err(|e| {
+        Error::KubeError(format!("failed to get PersistentVolumeClaim {pvc_name}"), e)
+    })? {
+        Some(pvc) => pvc,
+        // not created yet by the StatefulSet
+        None => return Ok(()),
+    };
+    let current = pvc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.resources.as_ref())
+        .and_then(|resources| resources.requests.as_ref())
+        .and_then(|requests| requests.get(STORAGE_RESOURCE));
+    match storage_resize(current, size) {
+        StorageResize::Unchanged => {}
+        StorageResize::Shrink => {
+            warn!(
+                msg = "ignoring storage size lower than the current PVC size, shrinking is not supported",
+                pvc = pvc.name_any(),
+                current = current.map(|q| q.0.as_str()),
+                size = size.0
+            );
+        }
+        StorageResize::Expand => {
+            let storage_class = storage_class(ctx.clone(), &pvc).await?;
+            if storage_class
+                .as_ref()
+                .and_then(|sc| sc.allow_volume_expansion)
+                .unwrap_or(false)
+            {
+                info!(msg = "expanding PVC", pvc = pvc.name_any(), size = size.0);
+                let patch = json!({
+                    "spec": {"resources": {"requests": {STORAGE_RESOURCE: size}}}
+                });
+                pvc_api
+                    .patch(&pvc_name, &PatchParams::default(), &Patch::Merge(&patch))
+                    .await
+                    .map_err(|e| {
+                        Error::KubeError(
+                            format!("failed to expand PersistentVolumeClaim {pvc_name}"),
+                            e,
+                        )
+                    })?;
+            } else {
+                warn!(
+                    msg = "cannot expand PVC, its StorageClass does not allow volume expansion",
+                    pvc = pvc.name_any(),
+                    storage_class = storage_class.map(|sc| sc.name_any())
+                );
+            }
+        }
+    }
+    ctx.pvc_sizes.write().await.insert(pvc_ref, size.0.clone());
+    Ok(())
+}
+
+/// StorageClass of the PVC. PVCs without `storageClassName` use the default StorageClass.
+async fn storage_class(
+    ctx: Arc<Context>,
+    pvc: &PersistentVolumeClaim,
+) -> Result<Option<StorageClass>> {
+    let storage_class_api = Api::<StorageClass>::all(ctx.kaniop_ctx.client.clone());
+    match pvc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.storage_class_name.as_deref())
+    {
+        Some("") => {
+            debug!(msg = "PVC without StorageClass, cannot check volume expansion");
+            Ok(None)
+        }
+        Some(storage_class_name) => {
+            storage_class_api
+                .get_opt(storage_class_name)
+                .await
+                .map_err(|e| {
+                    Error::KubeError(
+                        format!("failed to get StorageClass {storage_class_name}"),
+                        e,
+                    )
+                })
+        }
+        None => {
+            let storage_classes = storage_class_api
+                .list(&ListParams::default())
+                .await
+                .map_err(|e| Error::KubeError("failed to list StorageClasses".to_string(), e))?;
+            Ok(storage_classes
+                .items
+                .into_iter()
+                .find(is_default_storage_class))
+        }
+    }
+}
+
+fn is_default_storage_class(storage_class: &StorageClass) -> bool {
+    storage_class
+        .annotations()
+        .get(DEFAULT_STORAGE_CLASS_ANNOTATION)
+        .is_some_and(|value| value == "true")
+}
+
+fn storage_resize(current: Option<&Quantity>, desired: &Quantity) -> StorageResize {
+    match (current.and_then(parse_quantity), parse_quantity(desired)) {
+        (Some(current), Some(desired)) if desired > current => StorageResize::Expand,
+        (Some(current), Some(desired)) if desired < current => StorageResize::Shrink,
+        _ => StorageResize::Unchanged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{storage_resize, StorageResize};
+
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    #[test]
+    fn test_storage_resize() {
+        let q = |s: &str| Quantity(s.to_string());
+        assert_eq!(
+            storage_resize(Some(&q("1Gi")), &q("2Gi")),
+            StorageResize::Expand
+        );
+        assert_eq!(
+            storage_resize(Some(&q("1Gi")), &q("1024Mi")),
+            StorageResize::Unchanged
+        );
+        assert_eq!(
+            storage_resize(Some(&q("2Gi")), &q("1Gi")),
+            StorageResize::Shrink
+        );
+        assert_eq!(storage_resize(None, &q("1Gi")), StorageResize::Unchanged);
+        assert_eq!(
+            storage_resize(Some(&q("invalid")), &q("1Gi")),
+            StorageResize::Unchanged
+        );
+    }
+}
@@ -2,26 +2,37 @@ use crate::error::{Error, Result};
 use crate::kanidm::controller::context::Context;
 use crate::kanidm::crd::Kanidm;
 
+use kaniop_k8s_util::resources::controller_owner_references;
+
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+use chrono::{DateTime, Duration, Utc};
 use k8s_openapi::api::core::v1::Secret;
-use kube::api::{ObjectMeta, Resource};
+use kube::api::ObjectMeta;
 use kube::ResourceExt;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509;
 use serde_json::Value;
 
+pub const ADMIN_USERNAME_KEY: &str = "ADMIN_USERNAME";
 pub const ADMIN_PASSWORD_KEY: &str = "ADMIN_PASSWORD";
 pub const ADMIN_USER: &str = "admin";
+pub const IDM_ADMIN_USERNAME_KEY: &str = "IDM_ADMIN_USERNAME";
 pub const IDM_ADMIN_PASSWORD_KEY: &str = "IDM_ADMIN_PASSWORD";
 pub const IDM_ADMIN_USER: &str = "idm_admin";
 // decode with `basenc --base64url -d | openssl x509 -noout -text -inform DER`
 pub const REPLICA_SECRET_KEY: &str = "tls.der.b64url";
+pub const DEFAULT_REPLICA_CERT_RENEW_BEFORE_DAYS: u32 = 30;
 
 #[allow(async_fn_in_trait)]
 pub trait SecretExt {
     fn admins_secret_name(&self) -> String;
+    fn operator_credentials_secret_name(&self) -> String;
     fn replica_secret_name(&self, pod_name: &str) -> String;
     async fn generate_admins_secret(&self, ctx: Arc<Context>) -> Result<Secret>;
     async fn generate_replica_secret(&self, ctx: Arc<Context>, pod_name: &str) -> Result<Secret>;
+    async fn renew_replica_secret(&self, ctx: Arc<Context>, pod_name: &str) -> Result<Secret>;
 }
 
 impl SecretExt for Kanidm {
@@ -30,59 +41,54 @@ impl SecretExt for Kanidm {
         format!("{}-admin-passwords", self.name_any())
     }
 
+    /// Secret used to build the Kanidm clients of the operator
+    #[inline]
+    fn operator_credentials_secret_name(&self) -> String {
+        self.spec
+            .external
+            .as_ref()
+            .map(|e| &e.credentials_secret)
+            .or(self.spec.operator_credentials.as_ref())
+            .map(|c| c.name.clone())
+            .unwrap_or_else(|| self.admins_secret_name())
+    }
+
     #[inline]
     fn replica_secret_name(&self, pod_name: &str) -> String {
         format!("{pod_name}-cert")
     }
 
     async fn generate_admins_secret(&self, ctx: Arc<Context>) -> Result<Secret> {
-        let admin_password = self.recover_password(ctx.clone(), ADMIN_USER).await?;
-        let idm_admin_password = self.recover_password(ctx.clone(), IDM_ADMIN_USER).await?;
-
-        let secret = Secret {
-            metadata: ObjectMeta {
-                name: Some(self.admins_secret_name()),
-                namespace: Some(self.namespace().unwrap()),
-                owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
-                annotations: self
-                    .spec
-                    .service
-                    .as_ref()
-                    .and_then(|s| s.annotations.clone()),
-                labels: Some(
-                    self.generate_resource_labels()
-                        .clone()
-                        .into_iter()
-                        .chain(self.labels().clone())
-                        .collect(),
-                ),
-                ..ObjectMeta::default()
-            },
-            string_data: Some(
-                [
-                    ("ADMIN_USERNAME".to_string(), ADMIN_USER.to_string()),
-                    (ADMIN_PASSWORD_KEY.to_string(), admin_password),
-                    ("IDM_ADMIN_USERNAME".to_string(), IDM_ADMIN_USER.to_string()),
-                    (IDM_ADMIN_PASSWORD_KEY.to_string(), idm_admin_password),
-                ]
-                .iter()
-                .cloned()
-                .collect(),
-            ),
-            ..Secret::default()
-        };
-
-        Ok(secret)
+        let (admin_password, idm_admin_password) = self.recover_admin_credentials(ctx).await?;
+        Ok(self.admins_secret(admin_password, idm_admin_password))
     }
 
     async fn generate_replica_secret(&self, ctx: Arc<Context>, pod_name: &str) -> Result<Secret> {
         let cert = self.get_replica_cert(ctx.clone(), pod_name).await?;
 
-        let secret = Secret {
+        Ok(self.generate_secret(
+            self.replica_secret_name(pod_name),
+            [(REPLICA_SECRET_KEY.to_string(), cert)]
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    async fn renew_replica_secret(&self, ctx: Arc<Context>, pod_name: &str) -> Result<Secret> {
+        let renew_certificate_command = vec!["kanidmd", "renew-replication-certificate"];
+        self.exec(ctx.clone(), pod_name, renew_certificate_command)
+            .await?;
+        self.generate_replica_secret(ctx, pod_name).await
+    }
+}
+
+impl Kanidm {
+    fn generate_secret(&self, name: String, string_data: BTreeMap<String, String>) -> Secret {
+        Secret {
             metadata: ObjectMeta {
-                name: Some(self.replica_secret_name(pod_name)),
+                name: Some(name),
                 namespace: Some(self.namespace().unwrap()),
-                owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
+                owner_references: controller_owner_references(self),
                 annotations: self
                     .spec
                     .service
@@ -97,20 +103,43 @@ impl SecretExt for Kanidm {
                 ),
                 ..ObjectMeta::default()
             },
-            string_data: Some(
-                [(REPLICA_SECRET_KEY.to_string(), cert)]
-                    .iter()
-                    .cloned()
-                    .collect(),
-            ),
+            string_data: Some(string_data),
             ..Secret::default()
-        };
+        }
+    }
 
-        Ok(secret)
+    /// Passwords of the `admin` and `idm_admin` users, reset by Kanidm account recovery
+    pub(crate) async fn recover_admin_credentials(
+        &self,
+        ctx: Arc<Context>,
+    ) -> Result<(String, String)> {
+        let admin_password = self.recover_password(ctx.clone(), ADMIN_USER).await?;
+        let idm_admin_password = self.recover_password(ctx, IDM_ADMIN_USER).await?;
+        Ok((admin_password, idm_admin_password))
+    }
+
+    /// Secret holding the credentials of the `admin` and `idm_admin` users
+    pub(crate) fn admins_secret(
+        &self,
+        admin_password: String,
+        idm_admin_password: String,
+    ) -> Secret {
+        self.generate_secret(
+            self.admins_secret_name(),
+            [
+                (ADMIN_USERNAME_KEY.to_string(), ADMIN_USER.to_string()),
+                (ADMIN_PASSWORD_KEY.to_string(), admin_password),
+                (
+                    IDM_ADMIN_USERNAME_KEY.to_string(),
+                    IDM_ADMIN_USER.to_string(),
+                ),
+                (IDM_ADMIN_PASSWORD_KEY.to_string(), idm_admin_password),
+            ]
+            .into_iter()
+            .collect(),
+        )
     }
-}
 
-impl Kanidm {
     async fn recover_password(&self, ctx: Arc<Context>, user: &str) -> Result<String, Error> {
         let recover_command = vec!["kanidmd", "recover-account", "--output", "json"];
         let password_output = self
@@ -175,10 +204,111 @@ fn extract_cert(output: String) -> Result<String, Error> {
     ))
 }
 
+/// Validity period of a replica certificate.
+#[derive(Debug, PartialEq)]
+pub struct CertificateValidity {
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+}
+
+impl CertificateValidity {
+    /// Parse the validity of a certificate encoded as in [`REPLICA_SECRET_KEY`].
+    pub fn from_replica_cert(cert: &[u8]) -> Option<Self> {
+        let cert = std::str::from_utf8(cert)
+            .ok()?
+            .trim()
+            .replace('-', "+")
+            .replace('_', "/");
+        let der = openssl::base64::decode_block(&cert).ok()?;
+        let x509 = X509::from_der(&der).ok()?;
+        Some(Self {
+            not_before: asn1_time_to_datetime(x509.not_before())?,
+            not_after: asn1_time_to_datetime(x509.not_after())?,
+        })
+    }
+
+    /// Whether the certificate expires within `renew_before` from `now`.
+    pub fn is_expiring(&self, renew_before: Duration, now: DateTime<Utc>) -> bool {
+        self.not_after - renew_before <= now
+    }
+}
+
+fn asn1_time_to_datetime(time: &Asn1TimeRef) -> Option<DateTime<Utc>> {
+    let diff = Asn1Time::from_unix(0).ok()?.diff(time).ok()?;
+    DateTime::from_timestamp(i64::from(diff.days) * 86400 + i64::from(diff.secs), 0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::kanidm::crd::{KanidmExternal, SecretRef};
+
+    #[test]
+    fn test_generate_secret_owner_reference() {
+        let kanidm = Kanidm::test();
+        let secret = kanidm.generate_secret(
+            kanidm.replica_secret_name("test-default-0"),
+            [(REPLICA_SECRET_KEY.to_string(), "cert".to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        let owner_references = secret.metadata.owner_references.unwrap();
+        assert_eq!(owner_references.len(), 1);
+        let owner_reference = &owner_references[0];
+        assert_eq!(owner_reference.kind, "Kanidm");
+        assert_eq!(owner_reference.name, kanidm.name_any());
+        assert_eq!(owner_reference.uid, kanidm.uid().unwrap());
+        assert_eq!(owner_reference.controller, Some(true));
+        assert_eq!(owner_reference.block_owner_deletion, Some(true));
+    }
+
+    #[test]
+    fn test_admins_secret() {
+        let kanidm = Kanidm::test();
+        let secret = kanidm.admins_secret("admin-pass".to_string(), "idm-admin-pass".to_string());
+
+        assert_eq!(secret.metadata.name, Some(kanidm.admins_secret_name()));
+        assert_eq!(
+            secret.string_data,
+            Some(BTreeMap::from([
+                (ADMIN_USERNAME_KEY.to_string(), "admin".to_string()),
+                (ADMIN_PASSWORD_KEY.to_string(), "admin-pass".to_string()),
+                (IDM_ADMIN_USERNAME_KEY.to_string(), "idm_admin".to_string()),
+                (
+                    IDM_ADMIN_PASSWORD_KEY.to_string(),
+                    "idm-admin-pass".to_string()
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_operator_credentials_secret_name() {
+        let mut kanidm = Kanidm::test();
+        assert_eq!(
+            kanidm.operator_credentials_secret_name(),
+            kanidm.admins_secret_name()
+        );
+
+        kanidm.spec.operator_credentials = Some(SecretRef {
+            name: "external-credentials".to_string(),
+        });
+        assert_eq!(
+            kanidm.operator_credentials_secret_name(),
+            "external-credentials"
+        );
+
+        kanidm.spec.external = Some(KanidmExternal {
+            url: "https://idm.example.com".to_string(),
+            credentials_secret: SecretRef {
+                name: "idm-credentials".to_string(),
+            },
+        });
+        assert_eq!(kanidm.operator_credentials_secret_name(), "idm-credentials");
+    }
+
     #[test]
     fn test_extract_password() {
         let output = r#"
@@ -202,4 +332,46 @@ mod tests {
         let result = extract_cert(output).unwrap();
         assert_eq!(result, "MIIB_DCCAaGgAwIBAgIBATAKBggqhkjOPQQDAjBMMRswGQYDVQQKDBJLYW5pZG0gUmVwbGljYXRpb24xLTArBgNVBAMMJDJiYTgzMTZhLWViYWEtNGJjMS04NDkzLTVmODZmYWZhZTU5NDAeFw0yNDExMDYxOTEzMjdaFw0yODExMDYxOTEzMjdaMEwxGzAZBgNVBAoMEkthbmlkbSBSZXBsaWNhdGlvbjEtMCsGA1UEAwwkMmJhODMxNmEtZWJhYS00YmMxLTg0OTMtNWY4NmZhZmFlNTk0MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEuXp1hNNZerxDQbCh7rAGW6uM0CPECNd3IvbSh7qH34MkO_plwwDVKFbzcTG8HJE2ouIJlJYN8P4wf6qmrRQMAKN0MHIwDAYDVR0TAQH_BAIwADAOBgNVHQ8BAf8EBAMCBaAwHQYDVR0lBBYwFAYIKwYBBQUHAwEGCCsGAQUFBwMCMB0GA1UdDgQWBBTaOaPuXmtLDTJVv--VYBiQr9gHCTAUBgNVHREEDTALgglsb2NhbGhvc3QwCgYIKoZIzj0EAwIDSQAwRgIhAIZD_J4LyR7D0kg41GRg_TcRxm5mEVhM6WL9BO3XmfUsAiEA7Wpbkvd0b1e-Sg8AS9jP-CpBpmTnC7oEChkyhUYKyFc=");
     }
+
+    const REPLICA_CERT: &str = "MIIB_DCCAaGgAwIBAgIBATAKBggqhkjOPQQDAjBMMRswGQYDVQQKDBJLYW5pZG0gUmVwbGljYXRpb24xLTArBgNVBAMMJDJiYTgzMTZhLWViYWEtNGJjMS04NDkzLTVmODZmYWZhZTU5NDAeFw0yNDExMDYxOTEzMjdaFw0yODExMDYxOTEzMjdaMEwxGzAZBgNVBAoMEkthbmlkbSBSZXBsaWNhdGlvbjEtMCsGA1UEAwwkMmJhODMxNmEtZWJhYS00YmMxLTg0OTMtNWY4NmZhZmFlNTk0MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEuXp1hNNZerxDQbCh7rAGW6uM0CPECNd3IvbSh7qH34MkO_plwwDVKFbzcTG8HJE2ouIJlJYN8P4wf6qmrRQMAKN0MHIwDAYDVR0TAQH_BAIwADAOBgNVHQ8BAf8EBAMCBaAwHQYDVR0lBBYwFAYIKwYBBQUHAwEGCCsGAQUFBwMCMB0GA1UdDgQWBBTaOaPuXmtLDTJVv--VYBiQr9gHCTAUBgNVHREEDTALgglsb2NhbGhvc3QwCgYIKoZIzj0EAwIDSQAwRgIhAIZD_J4LyR7D0kg41GRg_TcRxm5mEVhM6WL9BO3XmfUsAiEA7Wpbkvd0b1e-Sg8AS9jP-CpBpmTnC7oEChkyhUYKyFc=";
+
+    #[test]
+    fn test_certificate_validity_from_replica_cert() {
+        use chrono::TimeZone;
+
+        let validity = CertificateValidity::from_replica_cert(REPLICA_CERT.as_bytes()).unwrap();
+        assert_eq!(
+            validity,
+            CertificateValidity {
+                not_before: Utc.with_ymd_and_hms(2024, 11, 6, 19, 13, 27).unwrap(),
+                not_after: Utc.with_ymd_and_hms(2028, 11, 6, 19, 13, 27).unwrap(),
+            }
+        );
+        assert_eq!(CertificateValidity::from_replica_cert(b"cert"), None);
+    }
+
+    #[test]
+    fn test_certificate_validity_is_expiring() {
+        use chrono::TimeZone;
+
+        let validity = CertificateValidity::from_replica_cert(REPLICA_CERT.as_bytes()).unwrap();
+        let renew_before = Duration::days(30);
+        assert!(!validity.is_expiring(
+            renew_before,
+            Utc.with_ymd_and_hms(2028, 9, 1, 0, 0, 0).unwrap()
+        ));
+        // inside the renew before window
+        assert!(validity.is_expiring(
+            renew_before,
+            Utc.with_ymd_and_hms(2028, 10, 20, 0, 0, 0).unwrap()
+        ));
+        assert!(validity.is_expiring(
+            renew_before,
+            Utc.with_ymd_and_hms(2029, 1, 1, 0, 0, 0).unwrap()
+        ));
+        assert!(!validity.is_expiring(
+            Duration::days(1),
+            Utc.with_ymd_and_hms(2028, 10, 20, 0, 0, 0).unwrap()
+        ));
+    }
 }
@@ -1,14 +1,21 @@
 use crate::kanidm::crd::Kanidm;
 
+use kaniop_k8s_util::resources::controller_owner_references;
+
 use k8s_openapi::api::core::v1::{Service, ServicePort, ServiceSpec};
 use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
-use kube::api::{ObjectMeta, Resource};
+use kube::api::ObjectMeta;
 use kube::ResourceExt;
 
 use super::statefulset::{CONTAINER_REPLICATION_PORT, CONTAINER_REPLICATION_PORT_NAME};
 
+/// Number of the HTTPS port of the Kanidm service, unless overridden in the spec.
+pub const DEFAULT_SERVICE_PORT: i32 = 8443;
+
 pub trait ServiceExt {
     fn service_name(&self) -> String;
+    fn service_port_name(&self) -> String;
+    fn service_port(&self) -> i32;
     fn create_service(&self) -> Service;
     fn create_pod_service(&self, name: &str) -> Service;
 }
@@ -28,10 +35,26 @@ impl ServiceExt for Kanidm {
         self.name_any()
     }
 
+    fn service_port_name(&self) -> String {
+        self.spec
+            .service
+            .as_ref()
+            .and_then(|s| s.port_name.clone())
+            .unwrap_or_else(|| self.spec.port_name.clone())
+    }
+
+    fn service_port(&self) -> i32 {
+        self.spec
+            .service
+            .as_ref()
+            .and_then(|s| s.port)
+            .unwrap_or(DEFAULT_SERVICE_PORT)
+    }
+
     fn create_service(&self) -> Service {
         let ports = std::iter::once(ServicePort {
-            name: Some(self.spec.port_name.clone()),
-            port: 8443,
+            name: Some(self.service_port_name()),
+            port: self.service_port(),
             target_port: Some(IntOrString::String(self.spec.port_name.clone())),
             ..ServicePort::default()
         })
@@ -91,7 +114,7 @@ impl ServiceExtPrivate for Kanidm {
             metadata: ObjectMeta {
                 name: Some(name),
                 namespace: Some(self.namespace().unwrap()),
-                owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
+                owner_references: controller_owner_references(self),
                 annotations: self
                     .spec
                     .service
@@ -110,3 +133,64 @@ impl ServiceExtPrivate for Kanidm {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ServiceExt, DEFAULT_SERVICE_PORT};
+
+    use crate::kanidm::crd::{Kanidm, KanidmService, KanidmSpec};
+
+    use k8s_openapi::api::core::v1::ServicePort;
+    use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+    use kube::api::ObjectMeta;
+
+    fn create_kanidm_with_service(service: Option<KanidmService>) -> Kanidm {
+        Kanidm {
+            metadata: ObjectMeta {
+                name: Some("idm".to_string()),
+                namespace: Some("default".to_string()),
+                ..ObjectMeta::default()
+            },
+            spec: KanidmSpec {
+                domain: "idm.example.com".to_string(),
+                port_name: "https".to_string(),
+                service,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn https_port(kanidm: &Kanidm) -> ServicePort {
+        kanidm.create_service().spec.unwrap().ports.unwrap()[0].clone()
+    }
+
+    #[test]
+    fn test_create_service_default_port() {
+        let port = https_port(&create_kanidm_with_service(None));
+        assert_eq!(port.name, Some("https".to_string()));
+        assert_eq!(port.port, DEFAULT_SERVICE_PORT);
+        assert_eq!(
+            port.target_port,
+            Some(IntOrString::String("https".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_create_service_with_port_overrides() {
+        let kanidm = create_kanidm_with_service(Some(KanidmService {
+            port_name: Some("web".to_string()),
+            port: Some(443),
+            ..KanidmService::default()
+        }));
+        let port = https_port(&kanidm);
+        assert_eq!(port.name, Some("web".to_string()));
+        assert_eq!(port.port, 443);
+        // the service port targets the container port, whose name is not overridden
+        assert_eq!(
+            port.target_port,
+            Some(IntOrString::String("https".to_string()))
+        );
+        assert_eq!(kanidm.client_url(), "https://idm.default.svc:443");
+    }
+}
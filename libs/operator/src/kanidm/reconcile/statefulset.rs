@@ -1,32 +1,53 @@
+use super::config_map::{config_map_key, ConfigMapExt};
 use super::secret::{SecretExt, REPLICA_SECRET_KEY};
 use super::service::ServiceExt;
+use super::LabelKeys;
 
-use crate::kanidm::crd::{Kanidm, KanidmServerRole, ReplicaGroup, ReplicationType};
+use crate::kanidm::crd::{
+    ExternalReplicationNode, Kanidm, KanidmLogLevel, KanidmServerRole, KanidmStorage, ReplicaGroup,
+    ReplicationType,
+};
 
-use kaniop_k8s_util::resources::merge_containers;
+use kaniop_k8s_util::resources::{controller_owner_references, is_subset, merge_containers};
 
 use std::collections::BTreeMap;
 
 use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetSpec};
 use k8s_openapi::api::core::v1::{
-    Container, ContainerPort, EmptyDirVolumeSource, EnvVar, EnvVarSource, HTTPGetAction,
-    ObjectFieldSelector, PersistentVolumeClaim, PodSpec, PodTemplateSpec, Probe, SecretKeySelector,
-    SecretVolumeSource, Volume, VolumeMount,
+    ConfigMapKeySelector, ConfigMapVolumeSource, Container, ContainerPort, EmptyDirVolumeSource,
+    EnvVar, EnvVarSource, HTTPGetAction, ObjectFieldSelector, PersistentVolumeClaim,
+    PersistentVolumeClaimSpec, PodSpec, PodTemplateSpec, Probe, SecretKeySelector,
+    SecretVolumeSource, Toleration, Volume, VolumeMount, VolumeResourceRequirements,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
-use kube::api::{ObjectMeta, Resource};
+use kube::api::ObjectMeta;
 use kube::ResourceExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
-pub const REPLICA_GROUP_LABEL: &str = "kanidm.kaniop.rs/replica-group";
 pub const CONTAINER_REPLICATION_PORT_NAME: &str = "replication";
 pub const CONTAINER_REPLICATION_PORT: i32 = 8444;
+/// Pod template annotation holding a hash of the Kanidm server configuration. It is computed
+/// from `domain`, `logLevel`, `env`, `ldapPortName`, `tlsSecretName`, `extraConfig`,
+/// `externalReplicationNodes` and the replica group fields `role` and `extraConfig`. Any change
+/// to them forces a rollout, even when the rendered pod template would stay the same.
+pub const CONFIG_HASH_ANNOTATION: &str = "kaniop.rs/config-hash";
+/// StatefulSet annotation holding the Kanidm generation it was rendered from. Differences with
+/// a live StatefulSet carrying the current generation are manual changes, not spec updates.
+pub const KANIDM_GENERATION_ANNOTATION: &str = "kaniop.rs/kanidm-generation";
+/// Pod template annotation holding the digest of the server TLS certificate. Renewing the
+/// certificate changes it, so the StatefulSet replaces the pods one by one.
+pub const TLS_SECRET_VERSION_ANNOTATION: &str = "kaniop.rs/tls-secret-version";
 
 // renovate: datasource=docker
 const REPLICATION_CONFIG_IMAGE: &str = "ghcr.io/rash-sh/rash:2.9.0";
 const REPLICATION_CONFIG_SCRIPT: &str = r#"
 - copy:
     content: |
+      {% if env.KANIDM_BASE_CONFIG is defined -%}
+      {{ env.KANIDM_BASE_CONFIG }}
+      {% endif -%}
       [replication]
       origin = "repl://{{ env.POD_NAME }}:{{ env.REPLICATION_PORT }}"
       bindaddress = "0.0.0.0:{{ env.REPLICATION_PORT }}"
@@ -76,18 +97,25 @@ const REPLICATION_CONFIG_SCRIPT: &str = r#"
       {%- endfor -%}
     dest: "{{ env.KANIDM_CONFIG_PATH }}"
 "#;
-const CONTAINER_HTTPS_PORT: i32 = 8443;
-const CONTAINER_LDAP_PORT: i32 = 3636;
+pub const CONTAINER_HTTPS_PORT: i32 = 8443;
+pub const CONTAINER_LDAP_PORT: i32 = 3636;
 // TODO: change to a shared volume
 const KANIDM_CONFIG_PATH: &str = "/data/server.toml";
-const VOLUME_DATA_NAME: &str = "kanidm-data";
-const VOLUME_DATA_PATH: &str = "/data";
+pub const VOLUME_DATA_NAME: &str = "kanidm-data";
+pub const VOLUME_DATA_PATH: &str = "/data";
 const VOLUME_TLS_NAME: &str = "kanidm-certs";
-const VOLUME_TLS_PATH: &str = "/etc/kanidm/tls";
+pub const VOLUME_TLS_PATH: &str = "/etc/kanidm/tls";
+const VOLUME_CONFIG_NAME: &str = "kanidm-config";
 
 pub trait StatefulSetExt {
     fn statefulset_name(&self, rg_name: &str) -> String;
     fn create_statefulset(&self, replica_group: &ReplicaGroup) -> StatefulSet;
+    fn is_statefulset_drifted(&self, replica_group: &ReplicaGroup, live: &StatefulSet) -> bool;
+    fn is_statefulset_selector_changed(
+        &self,
+        replica_group: &ReplicaGroup,
+        live: &StatefulSet,
+    ) -> bool;
 }
 
 trait StatefulSetExtPrivate {
@@ -98,10 +126,11 @@ trait StatefulSetExtPrivate {
     fn generate_pod_labels(&self, replica_group: &ReplicaGroup) -> BTreeMap<String, String>;
     fn generate_labels(&self, pod_labels: &BTreeMap<String, String>) -> BTreeMap<String, String>;
     fn generate_env_vars(&self, replica_group: &ReplicaGroup) -> Vec<EnvVar>;
-    fn generate_volume_mounts(&self) -> Vec<VolumeMount>;
+    fn generate_volume_mounts(&self, replica_group: &ReplicaGroup) -> Vec<VolumeMount>;
     #[allow(clippy::ptr_arg)]
     fn generate_init_containers(
         &self,
+        env: &Vec<EnvVar>,
         volume_mounts: &Vec<VolumeMount>,
         replica_group: &ReplicaGroup,
     ) -> Vec<Container>;
@@ -116,6 +145,11 @@ trait StatefulSetExtPrivate {
         probe: &Probe,
         replica_group: &ReplicaGroup,
     ) -> Vec<Container>;
+    fn generate_node_selector(
+        &self,
+        replica_group: &ReplicaGroup,
+    ) -> Option<BTreeMap<String, String>>;
+    fn generate_tolerations(&self, replica_group: &ReplicaGroup) -> Option<Vec<Toleration>>;
     fn generate_dns_policy(&self) -> Option<String>;
     fn generate_volumes(&self) -> (Vec<Volume>, Option<Vec<PersistentVolumeClaim>>);
     fn generate_metadata(
@@ -123,6 +157,7 @@ trait StatefulSetExtPrivate {
         labels: &BTreeMap<String, String>,
         replica_group_name: &str,
     ) -> ObjectMeta;
+    fn generate_config_hash(&self, replica_group: &ReplicaGroup) -> String;
 }
 
 impl StatefulSetExt for Kanidm {
@@ -135,8 +170,8 @@ impl StatefulSetExt for Kanidm {
         let pod_labels = self.generate_pod_labels(replica_group);
         let labels = self.generate_labels(&pod_labels);
         let env = self.generate_env_vars(replica_group);
-        let volume_mounts = self.generate_volume_mounts();
-        let init_containers = self.generate_init_containers(&volume_mounts, replica_group);
+        let volume_mounts = self.generate_volume_mounts(replica_group);
+        let init_containers = self.generate_init_containers(&env, &volume_mounts, replica_group);
         let ports = self.generate_container_ports();
         let probe = self.generate_probe();
         let containers =
@@ -155,18 +190,24 @@ impl StatefulSetExt for Kanidm {
                 template: PodTemplateSpec {
                     metadata: Some(ObjectMeta {
                         labels: Some(pod_labels),
+                        annotations: Some(BTreeMap::from([(
+                            CONFIG_HASH_ANNOTATION.to_string(),
+                            self.generate_config_hash(replica_group),
+                        )])),
                         ..ObjectMeta::default()
                     }),
                     spec: Some(PodSpec {
                         containers,
                         volumes: Some(volumes),
-                        node_selector: replica_group.node_selector.clone(),
+                        node_selector: self.generate_node_selector(replica_group),
                         affinity: replica_group.affinity.clone(),
-                        tolerations: replica_group.tolerations.clone(),
+                        tolerations: self.generate_tolerations(replica_group),
                         topology_spread_constraints: replica_group
                             .topology_spread_constraints
                             .clone(),
                         security_context: self.spec.security_context.clone(),
+                        priority_class_name: self.spec.priority_class_name.clone(),
+                        runtime_class_name: self.spec.runtime_class_name.clone(),
                         dns_policy,
                         dns_config: self.spec.dns_config.clone(),
                         init_containers: Some(init_containers),
@@ -187,6 +228,38 @@ impl StatefulSetExt for Kanidm {
             ..StatefulSet::default()
         }
     }
+
+    /// Whether the live StatefulSet was modified outside the operator. Only StatefulSets
+    /// already rendered from the current Kanidm generation are compared, and `volumeClaimTemplates`
+    /// are ignored because they are immutable.
+    fn is_statefulset_drifted(&self, replica_group: &ReplicaGroup, live: &StatefulSet) -> bool {
+        let generation = self.metadata.generation.map(|g| g.to_string());
+        if generation.is_none()
+            || live.annotations().get(KANIDM_GENERATION_ANNOTATION) != generation.as_ref()
+        {
+            return false;
+        }
+        let desired_spec =
+            self.create_statefulset(replica_group)
+                .spec
+                .map(|spec| StatefulSetSpec {
+                    volume_claim_templates: None,
+                    ..spec
+                });
+        !is_subset(&desired_spec, &live.spec)
+    }
+
+    /// Whether the selector of the live StatefulSet differs from the generated one, e.g. after
+    /// changing the label prefix. The selector is immutable, so the StatefulSet cannot be patched.
+    fn is_statefulset_selector_changed(
+        &self,
+        replica_group: &ReplicaGroup,
+        live: &StatefulSet,
+    ) -> bool {
+        let desired = self.create_statefulset(replica_group);
+        live.spec.as_ref().map(|spec| &spec.selector)
+            != desired.spec.as_ref().map(|spec| &spec.selector)
+    }
 }
 
 impl StatefulSetExtPrivate for Kanidm {
@@ -194,7 +267,7 @@ impl StatefulSetExtPrivate for Kanidm {
         self.generate_resource_labels()
             .into_iter()
             .chain(std::iter::once((
-                REPLICA_GROUP_LABEL.to_string(),
+                LabelKeys::get().replica_group.clone(),
                 replica_group.name.clone(),
             )))
             .collect()
@@ -208,69 +281,23 @@ impl StatefulSetExtPrivate for Kanidm {
             .collect()
     }
 
+    /// The server configuration is read from the ConfigMap, just the role is set here because
+    /// it depends on the replica group.
     fn generate_env_vars(&self, replica_group: &ReplicaGroup) -> Vec<EnvVar> {
         self.spec
             .env
             .clone()
             .unwrap_or_default()
             .into_iter()
-            .chain(vec![
-                EnvVar {
-                    name: "KANIDM_DOMAIN".to_string(),
-                    value: Some(self.spec.domain.clone()),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_ORIGIN".to_string(),
-                    value: Some(format!("https://{}", self.spec.domain.clone())),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_DB_PATH".to_string(),
-                    value: Some(format!("{VOLUME_DATA_PATH}/kanidm.db")),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_TLS_CHAIN".to_string(),
-                    value: Some(format!("{VOLUME_TLS_PATH}/tls.crt")),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_TLS_KEY".to_string(),
-                    value: Some(format!("{VOLUME_TLS_PATH}/tls.key")),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_BINDADDRESS".to_string(),
-                    value: Some(format!("0.0.0.0:{CONTAINER_HTTPS_PORT}")),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_ROLE".to_string(),
-                    value: Some(serde_plain::to_string(&replica_group.role.clone()).unwrap()),
-                    ..EnvVar::default()
-                },
-                EnvVar {
-                    name: "KANIDM_LOG_LEVEL".to_string(),
-                    value: Some(serde_plain::to_string(&self.spec.log_level.clone()).unwrap()),
-                    ..EnvVar::default()
-                },
-            ])
-            .chain(
-                self.spec
-                    .ldap_port_name
-                    .clone()
-                    .into_iter()
-                    .map(|_| EnvVar {
-                        name: "KANIDM_LDAPBINDADDRESS".to_string(),
-                        value: Some(format!("0.0.0.0:{CONTAINER_LDAP_PORT}")),
-                        ..EnvVar::default()
-                    }),
-            )
+            .chain(std::iter::once(EnvVar {
+                name: "KANIDM_ROLE".to_string(),
+                value: Some(serde_plain::to_string(&replica_group.role.clone()).unwrap()),
+                ..EnvVar::default()
+            }))
             .collect()
     }
 
-    fn generate_volume_mounts(&self) -> Vec<VolumeMount> {
+    fn generate_volume_mounts(&self, replica_group: &ReplicaGroup) -> Vec<VolumeMount> {
         self.spec
             .volume_mounts
             .clone()
@@ -289,15 +316,24 @@ impl StatefulSetExtPrivate for Kanidm {
                     ..VolumeMount::default()
                 },
             ])
+            // with replication, the init container writes the config file merging the ConfigMap
+            .chain((!self.is_replication_enabled()).then(|| VolumeMount {
+                name: VOLUME_CONFIG_NAME.to_string(),
+                mount_path: KANIDM_CONFIG_PATH.to_string(),
+                sub_path: Some(config_map_key(replica_group)),
+                read_only: Some(true),
+                ..VolumeMount::default()
+            }))
             .collect()
     }
 
     fn generate_init_containers(
         &self,
+        env: &Vec<EnvVar>,
         volume_mounts: &Vec<VolumeMount>,
         replica_group: &ReplicaGroup,
     ) -> Vec<Container> {
-        if self.is_replication_enabled() {
+        let replication_config_container = self.is_replication_enabled().then(|| {
             let external_replica_nodes_envs = self
                 .spec
                 .external_replication_nodes
@@ -404,6 +440,18 @@ impl StatefulSetExtPrivate for Kanidm {
                         value: Some(KANIDM_CONFIG_PATH.to_string()),
                         ..EnvVar::default()
                     },
+                    EnvVar {
+                        name: "KANIDM_BASE_CONFIG".to_string(),
+                        value_from: Some(EnvVarSource {
+                            config_map_key_ref: Some(ConfigMapKeySelector {
+                                name: self.config_map_name(),
+                                key: config_map_key(replica_group),
+                                optional: Some(false),
+                            }),
+                            ..EnvVarSource::default()
+                        }),
+                        ..EnvVar::default()
+                    },
                     EnvVar {
                         name: "KANIDM_SERVICE_NAME".to_string(),
                         value: Some(self.service_name()),
@@ -417,7 +465,7 @@ impl StatefulSetExtPrivate for Kanidm {
                 }))
                 .collect::<Vec<EnvVar>>();
 
-            let init_container = Container {
+            Container {
                 name: "kanidm-generate-replication-config".to_string(),
                 image: Some(REPLICATION_CONFIG_IMAGE.to_string()),
                 env: Some(env),
@@ -426,13 +474,34 @@ impl StatefulSetExtPrivate for Kanidm {
                     REPLICATION_CONFIG_SCRIPT.to_string(),
                 ]),
                 volume_mounts: Some(volume_mounts.clone()),
+                security_context: self.spec.container_security_context.clone(),
                 ..Container::default()
-            };
+            }
+        });
+        // runs after the replication config is generated to validate the final configuration
+        let config_test_container = self.spec.validate_config.then(|| Container {
+            name: "kanidm-config-test".to_string(),
+            image: Some(self.spec.image.clone()),
+            image_pull_policy: self.spec.image_pull_policy.clone(),
+            env: Some(env.clone()),
+            command: Some(vec![
+                "kanidmd".to_string(),
+                "configtest".to_string(),
+                "-c".to_string(),
+                KANIDM_CONFIG_PATH.to_string(),
+            ]),
+            volume_mounts: Some(volume_mounts.clone()),
+            security_context: self.spec.container_security_context.clone(),
+            ..Container::default()
+        });
 
-            merge_containers(self.spec.init_containers.clone(), &init_container)
-        } else {
-            self.spec.init_containers.clone().unwrap_or_default()
-        }
+        replication_config_container
+            .into_iter()
+            .chain(config_test_container)
+            .fold(
+                self.spec.init_containers.clone().unwrap_or_default(),
+                |init_containers, container| merge_containers(Some(init_containers), &container),
+            )
     }
 
     fn generate_container_ports(&self) -> Vec<ContainerPort> {
@@ -490,12 +559,40 @@ impl StatefulSetExtPrivate for Kanidm {
             resources: replica_group.resources.clone(),
             readiness_probe: Some(probe.clone()),
             liveness_probe: Some(probe.clone()),
+            security_context: self.spec.container_security_context.clone(),
             ..Container::default()
         };
 
         merge_containers(self.spec.containers.clone(), &kanidm_container)
     }
 
+    fn generate_node_selector(
+        &self,
+        replica_group: &ReplicaGroup,
+    ) -> Option<BTreeMap<String, String>> {
+        match (&self.spec.node_selector, &replica_group.node_selector) {
+            (None, None) => None,
+            (spec, rg) => Some(
+                spec.iter()
+                    .chain(rg.iter())
+                    .flat_map(|node_selector| node_selector.clone())
+                    .collect(),
+            ),
+        }
+    }
+
+    fn generate_tolerations(&self, replica_group: &ReplicaGroup) -> Option<Vec<Toleration>> {
+        match (&self.spec.tolerations, &replica_group.tolerations) {
+            (None, None) => None,
+            (spec, rg) => Some(
+                spec.iter()
+                    .chain(rg.iter())
+                    .flat_map(|tolerations| tolerations.clone())
+                    .collect(),
+            ),
+        }
+    }
+
     fn generate_dns_policy(&self) -> Option<String> {
         match self.spec.host_network {
             Some(true) => Some("ClusterFirstWithHostNet".to_string()),
@@ -504,13 +601,7 @@ impl StatefulSetExtPrivate for Kanidm {
     }
 
     fn generate_volumes(&self) -> (Vec<Volume>, Option<Vec<PersistentVolumeClaim>>) {
-        let secret_name = self.spec.tls_secret_name.clone().unwrap_or_else(|| {
-            self.spec
-                .ingress
-                .as_ref()
-                .and_then(|i| i.tls_secret_name.clone())
-                .unwrap_or_else(|| self.get_tls_secret_name())
-        });
+        let secret_name = self.server_tls_secret_name();
 
         self.expand_storage(
             self.spec
@@ -518,14 +609,24 @@ impl StatefulSetExtPrivate for Kanidm {
                 .clone()
                 .unwrap_or_default()
                 .into_iter()
-                .chain(std::iter::once(Volume {
-                    name: VOLUME_TLS_NAME.to_string(),
-                    secret: Some(SecretVolumeSource {
-                        secret_name: Some(secret_name),
-                        ..SecretVolumeSource::default()
-                    }),
-                    ..Volume::default()
-                }))
+                .chain([
+                    Volume {
+                        name: VOLUME_TLS_NAME.to_string(),
+                        secret: Some(SecretVolumeSource {
+                            secret_name: Some(secret_name),
+                            ..SecretVolumeSource::default()
+                        }),
+                        ..Volume::default()
+                    },
+                    Volume {
+                        name: VOLUME_CONFIG_NAME.to_string(),
+                        config_map: Some(ConfigMapVolumeSource {
+                            name: self.config_map_name(),
+                            ..ConfigMapVolumeSource::default()
+                        }),
+                        ..Volume::default()
+                    },
+                ])
                 .collect(),
         )
     }
@@ -577,7 +678,7 @@ impl StatefulSetExtPrivate for Kanidm {
                             .collect(),
                         None,
                     )
-                } else if let Some(volume_claim_template) = storage.volume_claim_template {
+                } else if let Some(volume_claim_template) = storage.claim_template() {
                     let named_template = PersistentVolumeClaim {
                         metadata: ObjectMeta {
                             name: Some(VOLUME_DATA_NAME.to_string()),
@@ -604,11 +705,113 @@ impl StatefulSetExtPrivate for Kanidm {
             name: Some(self.statefulset_name(replica_group_name)),
             namespace: self.namespace(),
             labels: Some(labels.clone()),
-            owner_references: self.controller_owner_ref(&()).map(|oref| vec![oref]),
-            annotations: Some(self.annotations().to_owned()),
+            owner_references: controller_owner_references(self),
+            annotations: Some(
+                self.annotations()
+                    .clone()
+                    .into_iter()
+                    .chain(self.metadata.generation.map(|generation| {
+                        (
+                            KANIDM_GENERATION_ANNOTATION.to_string(),
+                            generation.to_string(),
+                        )
+                    }))
+                    .collect(),
+            ),
             ..ObjectMeta::default()
         }
     }
+
+    fn generate_config_hash(&self, replica_group: &ReplicaGroup) -> String {
+        let config = KanidmConfig {
+            domain: &self.spec.domain,
+            log_level: &self.spec.log_level,
+            env: &self.spec.env,
+            ldap_port_name: &self.spec.ldap_port_name,
+            tls_secret_name: &self.spec.tls_secret_name,
+            extra_config: &self.spec.extra_config,
+            external_replication_nodes: &self.spec.external_replication_nodes,
+            role: &replica_group.role,
+            replica_group_extra_config: &replica_group.extra_config,
+        };
+        // serializing borrowed spec fields cannot fail
+        let digest = Sha256::digest(serde_json::to_vec(&config).unwrap_or_default());
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+const DEFAULT_ACCESS_MODE: &str = "ReadWriteOnce";
+
+impl KanidmStorage {
+    /// PVC template resulting of merging the `size`, `storageClassName` and `accessModes`
+    /// shorthand into `volumeClaimTemplate`. Values set in `volumeClaimTemplate` win on conflict.
+    pub(super) fn claim_template(&self) -> Option<PersistentVolumeClaim> {
+        if self.size.is_none() && self.storage_class_name.is_none() && self.access_modes.is_none() {
+            return self.volume_claim_template.clone();
+        }
+
+        let template = self.volume_claim_template.clone().unwrap_or_default();
+        let spec = template.spec.clone().unwrap_or_default();
+        let resources = spec.resources.clone().unwrap_or_default();
+        let requests = self
+            .size
+            .clone()
+            .map(|size| ("storage".to_string(), size))
+            .into_iter()
+            .chain(resources.requests.clone().unwrap_or_default())
+            .collect::<BTreeMap<_, _>>();
+        Some(PersistentVolumeClaim {
+            spec: Some(PersistentVolumeClaimSpec {
+                access_modes: spec.access_modes.clone().or_else(|| {
+                    Some(
+                        self.access_modes
+                            .clone()
+                            .unwrap_or_else(|| vec![DEFAULT_ACCESS_MODE.to_string()]),
+                    )
+                }),
+                storage_class_name: spec
+                    .storage_class_name
+                    .clone()
+                    .or_else(|| self.storage_class_name.clone()),
+                resources: Some(VolumeResourceRequirements {
+                    requests: (!requests.is_empty()).then_some(requests),
+                    ..resources
+                }),
+                ..spec
+            }),
+            ..template
+        })
+    }
+}
+
+/// Kanidm spec fields that end up in the server configuration, used to compute
+/// [`CONFIG_HASH_ANNOTATION`].
+#[derive(Serialize)]
+struct KanidmConfig<'a> {
+    domain: &'a str,
+    log_level: &'a KanidmLogLevel,
+    env: &'a Option<Vec<EnvVar>>,
+    ldap_port_name: &'a Option<String>,
+    tls_secret_name: &'a Option<String>,
+    extra_config: &'a Option<String>,
+    external_replication_nodes: &'a Vec<ExternalReplicationNode>,
+    role: &'a KanidmServerRole,
+    replica_group_extra_config: &'a Option<String>,
+}
+
+/// Annotate the pod template of `statefulset` with the server TLS certificate `version`.
+pub fn with_tls_secret_version(mut statefulset: StatefulSet, version: String) -> StatefulSet {
+    if let Some(metadata) = statefulset
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.template.metadata.as_mut())
+    {
+        metadata
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(TLS_SECRET_VERSION_ANNOTATION.to_string(), version);
+    }
+    statefulset
 }
 
 fn replication_type(
@@ -635,12 +838,22 @@ fn replication_type(
 
 #[cfg(test)]
 mod tests {
-    use super::StatefulSetExtPrivate;
+    use super::{
+        LabelKeys, StatefulSetExt, StatefulSetExtPrivate, CONFIG_HASH_ANNOTATION,
+        KANIDM_CONFIG_PATH, KANIDM_GENERATION_ANNOTATION, VOLUME_CONFIG_NAME, VOLUME_DATA_NAME,
+    };
 
-    use crate::kanidm::crd::{Kanidm, KanidmSpec, KanidmStorage};
+    use std::collections::BTreeMap;
+
+    use crate::kanidm::crd::{
+        Kanidm, KanidmLogLevel, KanidmServerRole, KanidmSpec, KanidmStorage, ReplicaGroup,
+    };
     use k8s_openapi::api::core::v1::{
-        EmptyDirVolumeSource, EphemeralVolumeSource, PersistentVolumeClaim, Volume,
+        EmptyDirVolumeSource, EnvVar, EphemeralVolumeSource, PersistentVolumeClaim,
+        PersistentVolumeClaimSpec, PodSecurityContext, SecurityContext, Toleration, Volume,
+        VolumeResourceRequirements,
     };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 
     fn create_kanidm_with_storage(storage: Option<KanidmStorage>) -> Kanidm {
         Kanidm {
@@ -702,6 +915,7 @@ mod tests {
             empty_dir: Some(EmptyDirVolumeSource::default()),
             ephemeral: Some(EphemeralVolumeSource::default()),
             volume_claim_template: Some(PersistentVolumeClaim::default()),
+            ..Default::default()
         });
         let kanidm = create_kanidm_with_storage(storage);
         let (volumes, volume_claim_template) = kanidm.expand_storage(vec![]);
@@ -804,6 +1018,515 @@ mod tests {
             .any(|v| v.name == "kanidm-data" && v.empty_dir.is_some()));
         assert!(volume_claim_template.is_none());
     }
+
+    #[test]
+    fn test_generate_volumes_with_storage_shorthand() {
+        let kanidm = create_kanidm_with_storage(Some(KanidmStorage {
+            size: Some(Quantity("1Gi".to_string())),
+            storage_class_name: Some("fast".to_string()),
+            ..Default::default()
+        }));
+        let (volumes, volume_claim_template) = kanidm.expand_storage(vec![]);
+
+        assert!(volumes.is_empty());
+        let volume_claim_template = volume_claim_template.unwrap();
+        assert_eq!(volume_claim_template.len(), 1);
+        assert_eq!(
+            volume_claim_template[0].metadata.name,
+            Some(VOLUME_DATA_NAME.to_string())
+        );
+        assert_eq!(
+            volume_claim_template[0].spec,
+            Some(PersistentVolumeClaimSpec {
+                access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+                storage_class_name: Some("fast".to_string()),
+                resources: Some(VolumeResourceRequirements {
+                    requests: Some(BTreeMap::from([(
+                        "storage".to_string(),
+                        Quantity("1Gi".to_string())
+                    )])),
+                    ..VolumeResourceRequirements::default()
+                }),
+                ..PersistentVolumeClaimSpec::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_volumes_with_storage_shorthand_and_volumeclaimtemplate() {
+        let kanidm = create_kanidm_with_storage(Some(KanidmStorage {
+            size: Some(Quantity("1Gi".to_string())),
+            storage_class_name: Some("fast".to_string()),
+            access_modes: Some(vec!["ReadWriteOncePod".to_string()]),
+            volume_claim_template: Some(PersistentVolumeClaim {
+                spec: Some(PersistentVolumeClaimSpec {
+                    storage_class_name: Some("slow".to_string()),
+                    resources: Some(VolumeResourceRequirements {
+                        requests: Some(BTreeMap::from([(
+                            "storage".to_string(),
+                            Quantity("5Gi".to_string()),
+                        )])),
+                        ..VolumeResourceRequirements::default()
+                    }),
+                    volume_mode: Some("Filesystem".to_string()),
+                    ..PersistentVolumeClaimSpec::default()
+                }),
+                ..PersistentVolumeClaim::default()
+            }),
+            ..Default::default()
+        }));
+        let (_, volume_claim_template) = kanidm.expand_storage(vec![]);
+
+        assert_eq!(
+            volume_claim_template.unwrap()[0].spec,
+            Some(PersistentVolumeClaimSpec {
+                access_modes: Some(vec!["ReadWriteOncePod".to_string()]),
+                storage_class_name: Some("slow".to_string()),
+                resources: Some(VolumeResourceRequirements {
+                    requests: Some(BTreeMap::from([(
+                        "storage".to_string(),
+                        Quantity("5Gi".to_string())
+                    )])),
+                    ..VolumeResourceRequirements::default()
+                }),
+                volume_mode: Some("Filesystem".to_string()),
+                ..PersistentVolumeClaimSpec::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_volumes_with_emptydir_and_storage_shorthand() {
+        let kanidm = create_kanidm_with_storage(Some(KanidmStorage {
+            empty_dir: Some(EmptyDirVolumeSource::default()),
+            size: Some(Quantity("1Gi".to_string())),
+            ..Default::default()
+        }));
+        let (volumes, volume_claim_template) = kanidm.expand_storage(vec![]);
+
+        assert_eq!(volumes.len(), 1);
+        assert!(volumes[0].empty_dir.is_some());
+        assert!(volume_claim_template.is_none());
+    }
+
+    #[test]
+    fn test_create_statefulset_priority_class_name() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        let pod_spec = |kanidm: &Kanidm| {
+            kanidm
+                .create_statefulset(&kanidm.spec.replica_groups[0])
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+        };
+        assert_eq!(pod_spec(&kanidm).priority_class_name, None);
+
+        kanidm.spec.priority_class_name = Some("system-cluster-critical".to_string());
+        assert_eq!(
+            pod_spec(&kanidm).priority_class_name,
+            Some("system-cluster-critical".to_string())
+        );
+    }
+
+    #[test]
+    fn test_create_statefulset_security_contexts() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        kanidm.spec.replica_groups[0].replicas = 2;
+        let pod_spec = |kanidm: &Kanidm| {
+            kanidm
+                .create_statefulset(&kanidm.spec.replica_groups[0])
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+        };
+
+        let default_pod_spec = pod_spec(&kanidm);
+        assert_eq!(default_pod_spec.runtime_class_name, None);
+        let containers = default_pod_spec
+            .containers
+            .iter()
+            .chain(default_pod_spec.init_containers.iter().flatten())
+            .collect::<Vec<_>>();
+        assert_eq!(containers.len(), 2);
+        assert!(containers.iter().all(|c| c.security_context.is_none()));
+
+        let pod_security_context = PodSecurityContext {
+            run_as_non_root: Some(true),
+            run_as_user: Some(1000),
+            fs_group: Some(1000),
+            ..PodSecurityContext::default()
+        };
+        let container_security_context = SecurityContext {
+            run_as_non_root: Some(true),
+            read_only_root_filesystem: Some(false),
+            ..SecurityContext::default()
+        };
+        kanidm.spec.security_context = Some(pod_security_context.clone());
+        kanidm.spec.container_security_context = Some(container_security_context.clone());
+        kanidm.spec.runtime_class_name = Some("gvisor".to_string());
+
+        let pod_spec = pod_spec(&kanidm);
+        assert_eq!(pod_spec.security_context, Some(pod_security_context));
+        assert_eq!(pod_spec.runtime_class_name, Some("gvisor".to_string()));
+        assert_eq!(
+            pod_spec.containers[0].security_context,
+            Some(container_security_context.clone())
+        );
+        assert_eq!(
+            pod_spec.init_containers.unwrap()[0].security_context,
+            Some(container_security_context)
+        );
+    }
+
+    #[test]
+    fn test_create_statefulset_config_test_init_container() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        kanidm.spec.validate_config = true;
+        let init_containers = |kanidm: &Kanidm| {
+            kanidm
+                .create_statefulset(&kanidm.spec.replica_groups[0])
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+                .init_containers
+                .unwrap()
+        };
+
+        let containers = init_containers(&kanidm);
+        assert_eq!(containers.len(), 1);
+        let config_test = &containers[0];
+        assert_eq!(config_test.name, "kanidm-config-test");
+        assert_eq!(config_test.image, Some(kanidm.spec.image.clone()));
+        assert_eq!(
+            config_test.command,
+            Some(vec![
+                "kanidmd".to_string(),
+                "configtest".to_string(),
+                "-c".to_string(),
+                KANIDM_CONFIG_PATH.to_string(),
+            ])
+        );
+        assert!(config_test
+            .env
+            .iter()
+            .flatten()
+            .any(|env| env.name == "KANIDM_ROLE"));
+
+        // validates the configuration generated for replication
+        kanidm.spec.replica_groups[0].replicas = 2;
+        let containers = init_containers(&kanidm);
+        assert_eq!(
+            containers
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["kanidm-generate-replication-config", "kanidm-config-test"]
+        );
+
+        kanidm.spec.validate_config = false;
+        assert!(init_containers(&kanidm)
+            .iter()
+            .all(|c| c.name != "kanidm-config-test"));
+    }
+
+    #[test]
+    fn test_create_statefulset_config_map_mount() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        kanidm.metadata.name = Some("idm".to_string());
+        let pod_spec = |kanidm: &Kanidm| {
+            kanidm
+                .create_statefulset(&kanidm.spec.replica_groups[0])
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+        };
+
+        let spec = pod_spec(&kanidm);
+        assert!(spec
+            .volumes
+            .unwrap()
+            .iter()
+            .any(|v| v.name == VOLUME_CONFIG_NAME
+                && v.config_map.as_ref().map(|cm| cm.name.as_str()) == Some("idm-config")));
+        let config_mount = spec.containers[0]
+            .volume_mounts
+            .iter()
+            .flatten()
+            .find(|vm| vm.name == VOLUME_CONFIG_NAME)
+            .unwrap();
+        assert_eq!(config_mount.mount_path, KANIDM_CONFIG_PATH);
+        assert_eq!(
+            config_mount.sub_path,
+            Some("server-default.toml".to_string())
+        );
+
+        // with replication the init container merges the ConfigMap into the config file
+        kanidm.spec.replica_groups[0].replicas = 2;
+        let spec = pod_spec(&kanidm);
+        assert!(spec.containers[0]
+            .volume_mounts
+            .iter()
+            .flatten()
+            .all(|vm| vm.name != VOLUME_CONFIG_NAME));
+        let base_config = spec.init_containers.unwrap()[0]
+            .env
+            .iter()
+            .flatten()
+            .find(|env| env.name == "KANIDM_BASE_CONFIG")
+            .and_then(|env| env.value_from.clone())
+            .and_then(|value_from| value_from.config_map_key_ref)
+            .unwrap();
+        assert_eq!(base_config.name, "idm-config");
+        assert_eq!(base_config.key, "server-default.toml");
+    }
+
+    #[test]
+    fn test_create_statefulset_replica_group_config() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        kanidm.metadata.name = Some("idm".to_string());
+        kanidm.spec.replica_groups.push(ReplicaGroup {
+            name: "read".to_string(),
+            replicas: 1,
+            role: KanidmServerRole::ReadOnlyReplica,
+            extra_config: Some("log_level = \"debug\"".to_string()),
+            ..Default::default()
+        });
+
+        for (replica_group, key) in kanidm
+            .spec
+            .replica_groups
+            .iter()
+            .zip(["server-default.toml", "server-read.toml"])
+        {
+            let base_config = kanidm
+                .create_statefulset(replica_group)
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+                .init_containers
+                .unwrap()[0]
+                .env
+                .iter()
+                .flatten()
+                .find(|env| env.name == "KANIDM_BASE_CONFIG")
+                .and_then(|env| env.value_from.clone())
+                .and_then(|value_from| value_from.config_map_key_ref)
+                .unwrap();
+            assert_eq!(base_config.key, key);
+        }
+    }
+
+    #[test]
+    fn test_create_statefulset_node_selector_and_tolerations() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        let pod_spec = |kanidm: &Kanidm| {
+            kanidm
+                .create_statefulset(&kanidm.spec.replica_groups[0])
+                .spec
+                .unwrap()
+                .template
+                .spec
+                .unwrap()
+        };
+        let toleration = |key: &str| Toleration {
+            key: Some(key.to_string()),
+            operator: Some("Exists".to_string()),
+            effect: Some("NoSchedule".to_string()),
+            ..Toleration::default()
+        };
+        assert_eq!(pod_spec(&kanidm).node_selector, None);
+        assert_eq!(pod_spec(&kanidm).tolerations, None);
+
+        kanidm.spec.node_selector = Some(BTreeMap::from([
+            ("dedicated".to_string(), "idm".to_string()),
+            ("zone".to_string(), "a".to_string()),
+        ]));
+        kanidm.spec.tolerations = Some(vec![toleration("dedicated")]);
+        let spec = pod_spec(&kanidm);
+        assert_eq!(spec.node_selector, kanidm.spec.node_selector);
+        assert_eq!(spec.tolerations, Some(vec![toleration("dedicated")]));
+
+        kanidm.spec.replica_groups[0].node_selector =
+            Some(BTreeMap::from([("zone".to_string(), "b".to_string())]));
+        kanidm.spec.replica_groups[0].tolerations = Some(vec![toleration("zone")]);
+        let spec = pod_spec(&kanidm);
+        assert_eq!(
+            spec.node_selector,
+            Some(BTreeMap::from([
+                ("dedicated".to_string(), "idm".to_string()),
+                ("zone".to_string(), "b".to_string()),
+            ]))
+        );
+        assert_eq!(
+            spec.tolerations,
+            Some(vec![toleration("dedicated"), toleration("zone")])
+        );
+    }
+
+    fn config_hash(kanidm: &Kanidm) -> String {
+        let replica_group = &kanidm.spec.replica_groups[0];
+        kanidm
+            .create_statefulset(replica_group)
+            .spec
+            .unwrap()
+            .template
+            .metadata
+            .unwrap()
+            .annotations
+            .unwrap()
+            .get(CONFIG_HASH_ANNOTATION)
+            .unwrap()
+            .clone()
+    }
+
+    fn create_kanidm_with_replica_group() -> Kanidm {
+        Kanidm {
+            spec: KanidmSpec {
+                domain: "idm.example.com".to_string(),
+                replica_groups: vec![ReplicaGroup {
+                    name: "default".to_string(),
+                    replicas: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_config_hash_is_stable() {
+        let kanidm = create_kanidm_with_replica_group();
+        assert_eq!(config_hash(&kanidm), config_hash(&kanidm.clone()));
+        assert_eq!(config_hash(&kanidm).len(), 64);
+    }
+
+    #[test]
+    fn test_config_hash_changes_with_config() {
+        let kanidm = create_kanidm_with_replica_group();
+        let hash = config_hash(&kanidm);
+
+        let mut with_env = kanidm.clone();
+        with_env.spec.env = Some(vec![EnvVar {
+            name: "KANIDM_TRUST_X_FORWARD_FOR".to_string(),
+            value: Some("true".to_string()),
+            ..EnvVar::default()
+        }]);
+        assert_ne!(config_hash(&with_env), hash);
+
+        let mut with_log_level = kanidm.clone();
+        with_log_level.spec.log_level = KanidmLogLevel::Debug;
+        assert_ne!(config_hash(&with_log_level), hash);
+
+        let mut with_ldap = kanidm.clone();
+        with_ldap.spec.ldap_port_name = Some("ldap".to_string());
+        assert_ne!(config_hash(&with_ldap), hash);
+
+        let mut with_role = kanidm.clone();
+        with_role.spec.replica_groups[0].role = KanidmServerRole::ReadOnlyReplica;
+        assert_ne!(config_hash(&with_role), hash);
+
+        let mut with_extra_config = kanidm.clone();
+        with_extra_config.spec.extra_config = Some("trust_x_forward_for = true".to_string());
+        assert_ne!(config_hash(&with_extra_config), hash);
+
+        let mut with_replica_group_extra_config = kanidm.clone();
+        with_replica_group_extra_config.spec.replica_groups[0].extra_config =
+            Some("trust_x_forward_for = true".to_string());
+        assert_ne!(config_hash(&with_replica_group_extra_config), hash);
+    }
+
+    #[test]
+    fn test_config_hash_ignores_non_config_fields() {
+        let kanidm = create_kanidm_with_replica_group();
+        let hash = config_hash(&kanidm);
+
+        let mut scaled = kanidm.clone();
+        scaled.spec.replica_groups[0].replicas = 3;
+        assert_eq!(config_hash(&scaled), hash);
+
+        let mut with_min_ready = kanidm.clone();
+        with_min_ready.spec.min_ready_seconds = Some(10);
+        assert_eq!(config_hash(&with_min_ready), hash);
+
+        let mut with_image = kanidm.clone();
+        with_image.spec.image = "kanidm/server:latest".to_string();
+        assert_eq!(config_hash(&with_image), hash);
+    }
+
+    #[test]
+    fn test_is_statefulset_drifted() {
+        let mut kanidm = create_kanidm_with_replica_group();
+        kanidm.metadata.generation = Some(2);
+        let replica_group = &kanidm.spec.replica_groups[0];
+
+        let mut live = kanidm.create_statefulset(replica_group);
+        assert_eq!(
+            live.metadata
+                .annotations
+                .as_ref()
+                .unwrap()
+                .get(KANIDM_GENERATION_ANNOTATION),
+            Some(&"2".to_string())
+        );
+        // fields defaulted by the API server are not drift
+        let spec = live.spec.as_mut().unwrap();
+        spec.revision_history_limit = Some(10);
+        spec.template.spec.as_mut().unwrap().containers[0].termination_message_path =
+            Some("/dev/termination-log".to_string());
+        assert!(!kanidm.is_statefulset_drifted(replica_group, &live));
+
+        live.spec
+            .as_mut()
+            .unwrap()
+            .template
+            .spec
+            .as_mut()
+            .unwrap()
+            .containers[0]
+            .image = Some("kanidm/server:manual".to_string());
+        assert!(kanidm.is_statefulset_drifted(replica_group, &live));
+
+        // StatefulSets rendered from a previous generation are pending a spec update
+        live.metadata
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert(KANIDM_GENERATION_ANNOTATION.to_string(), "1".to_string());
+        assert!(!kanidm.is_statefulset_drifted(replica_group, &live));
+    }
+
+    #[test]
+    fn test_is_statefulset_selector_changed() {
+        let kanidm = create_kanidm_with_replica_group();
+        let replica_group = &kanidm.spec.replica_groups[0];
+
+        let mut live = kanidm.create_statefulset(replica_group);
+        assert!(!kanidm.is_statefulset_selector_changed(replica_group, &live));
+
+        // StatefulSets created with another label prefix
+        let match_labels = live
+            .spec
+            .as_mut()
+            .unwrap()
+            .selector
+            .match_labels
+            .as_mut()
+            .unwrap();
+        let cluster = match_labels.remove(&LabelKeys::get().cluster).unwrap();
+        match_labels.insert("kanidm.example.com/cluster".to_string(), cluster);
+        assert!(kanidm.is_statefulset_selector_changed(replica_group, &live));
+    }
 }
 
 #[cfg(all(test, feature = "integration-test"))]
@@ -878,6 +1601,22 @@ mod integration_test {
         let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
 
         let test_cases = vec![
+            TestCase {
+                env_vars: vec![
+                    ("KANIDM_CONFIG_PATH", "/tmp/server.toml"),
+                    ("KANIDM_BASE_CONFIG", r#"domain = "idm.example.com""#),
+                    ("REPLICATION_PORT", "8444"),
+                    ("KANIDM_SERVICE_NAME", "kanidm-test"),
+                    ("POD_NAME", "kanidm-test-default-0"),
+                    ("KANIDM_TEST_DEFAULT_0_TYPE", "mutual-pull"),
+                ],
+                expected_result: r#"domain = "idm.example.com"
+[replication]
+origin = "repl://kanidm-test-default-0:8444"
+bindaddress = "0.0.0.0:8444"
+
+"#,
+            },
             TestCase {
                 env_vars: vec![
                     ("KANIDM_CONFIG_PATH", "/tmp/server.toml"),
@@ -1,18 +1,26 @@
-use super::secret::SecretExt;
+use super::domain::DomainExt;
+use super::secret::{CertificateValidity, SecretExt, REPLICA_SECRET_KEY};
 use super::statefulset::StatefulSetExt;
+use super::system_policy::SystemPolicyExt;
 use super::KANIDM_OPERATOR_NAME;
 
+use crate::controller::kanidm::{is_reachable, ClientSettings};
 use crate::error::{Error, Result};
-use crate::kanidm::controller::context::Context;
-use crate::kanidm::crd::{Kanidm, KanidmReplicaState, KanidmReplicaStatus, KanidmStatus};
+use crate::kanidm::controller::context::{Context, RunningVersionKey};
+use crate::kanidm::crd::{
+    Kanidm, KanidmClientPoolStatus, KanidmReplicaState, KanidmReplicaStatus, KanidmStatus,
+};
+
+use kaniop_k8s_util::resources::is_status_unchanged;
 
 use std::sync::Arc;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetStatus};
 use k8s_openapi::api::core::v1::Secret;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use kube::api::{Api, Patch, PatchParams};
+use kube::runtime::events::{Event, EventType};
 use kube::runtime::reflector::ObjectRef;
 use kube::ResourceExt;
 use tracing::{debug, trace};
@@ -25,6 +33,31 @@ const TYPE_PROGRESSING: &str = "Progressing";
 const TYPE_INITIALIZED: &str = "Initialized";
 /// Indicates whether the StatefulSet has failed to create or delete replicas.
 const TYPE_REPLICA_FAILURE: &str = "ReplicaFailure";
+/// Pending replicas wait for the next maintenance window to restart their StatefulSet.
+const TYPE_RESTART_DEFERRED: &str = "RestartDeferred";
+/// Replication is enabled, but only one node accepts writes.
+const TYPE_DEGRADED_REPLICATION: &str = "DegradedReplication";
+/// Replica certificates were renewed, but automatic restarts are disabled.
+const TYPE_RESTART_REQUIRED: &str = "RestartRequired";
+/// A StatefulSet was modified outside the operator and the changes were overwritten.
+const TYPE_DRIFTED: &str = "Drifted";
+/// Domain settings defined in the spec are applied to Kanidm.
+const TYPE_DOMAIN_CONFIGURED: &str = "DomainConfigured";
+/// System policy defined in the spec is applied to Kanidm.
+const TYPE_POLICY_APPLIED: &str = "PolicyApplied";
+/// Kstatus: the operator is working towards the desired state.
+const TYPE_RECONCILING: &str = "Reconciling";
+/// Kstatus: reconciles keep failing and the operator is backing off.
+const TYPE_STALLED: &str = "Stalled";
+
+/// Pod template annotations set when restarting a StatefulSet with kube-rs and kubectl.
+const RESTARTED_AT_ANNOTATIONS: [&str; 2] = [
+    "kube.kubernetes.io/restartedAt",
+    "kubectl.kubernetes.io/restartedAt",
+];
+
+/// Consecutive failed reconciles before a Kanidm is considered stalled.
+const STALLED_RECONCILE_FAILURES: u32 = 3;
 
 const CONDITION_TRUE: &str = "True";
 const CONDITION_FALSE: &str = "False";
@@ -37,6 +70,115 @@ pub trait StatusExt {
 impl StatusExt for Kanidm {
     async fn update_status(&self, ctx: Arc<Context>) -> Result<KanidmStatus> {
         let name = &self.name_any();
+        let namespace = &self.get_namespace();
+        let mut new_status = match self.spec.external.as_ref() {
+            Some(external) => generate_external_status(
+                self.status
+                    .as_ref()
+                    .cloned()
+                    .unwrap_or_default()
+                    .conditions
+                    .unwrap_or_default(),
+                match ClientSettings::new(
+                    Some(self),
+                    namespace,
+                    ctx.kaniop_ctx.client.clone(),
+                    ctx.kaniop_ctx.ca_bundle.clone(),
+                )
+                .await
+                {
+                    Ok(settings) => is_reachable(&external.url, &settings).await,
+                    Err(e) => {
+                        debug!(msg = "failed to get Kanidm client settings", %e);
+                        false
+                    }
+                },
+                external.credentials_secret.name.clone(),
+                self.metadata.generation,
+            ),
+            None => {
+                let mut status = self.generate_workload_status(&ctx);
+                if is_kanidm_available(status.clone()) {
+                    status.version = self.running_version(ctx.clone()).await;
+                }
+                status.version = status
+                    .version
+                    .or_else(|| self.status.as_ref().and_then(|s| s.version.clone()));
+                status
+            }
+        };
+        let mut conditions = new_status.conditions.take().unwrap_or_default();
+        let domain_configured_condition = self
+            .domain_configured_condition(ctx.clone(), &conditions)
+            .await;
+        let policy_applied_condition = self
+            .policy_applied_condition(ctx.clone(), &conditions)
+            .await;
+        conditions.retain(|c| c.type_ != TYPE_DOMAIN_CONFIGURED && c.type_ != TYPE_POLICY_APPLIED);
+        conditions.extend(domain_configured_condition);
+        conditions.extend(policy_applied_condition);
+        new_status.conditions = Some(conditions);
+        new_status.client_pool =
+            client_pool_status(ctx.kaniop_ctx.client_pool_health(namespace, name).await);
+        let reconcile_failures = ctx
+            .kaniop_ctx
+            .reconcile_failures(&ObjectRef::from(self))
+            .await;
+        new_status.conditions = Some(with_kstatus_conditions(
+            new_status.conditions.take().unwrap_or_default(),
+            reconcile_failures,
+            self.metadata.generation,
+        ));
+
+        if is_degraded_replication(new_status.conditions.as_deref())
+            && !is_degraded_replication(self.status.as_ref().and_then(|s| s.conditions.as_deref()))
+        {
+            ctx.kaniop_ctx
+                .publish_event(
+                    self,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "DegradedReplication".to_string(),
+                        note: Some(
+                            "Replication is enabled with a single write replica. Restarts of \
+                            that replica cause downtime, add more write replicas for high \
+                            availability."
+                                .to_string(),
+                        ),
+                        action: "Reconcile".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await?;
+        }
+
+        if is_status_unchanged(self.status.as_ref(), &new_status) {
+            trace!(msg = "status unchanged, skipping patch");
+            return Ok(new_status);
+        }
+        let new_status_patch = Patch::Apply(Kanidm {
+            status: Some(new_status.clone()),
+            ..Kanidm::default()
+        });
+        debug!(msg = "updating Kanidm status");
+        trace!(msg = format!("new status {:?}", new_status_patch));
+        let patch = PatchParams::apply(KANIDM_OPERATOR_NAME).force();
+        let kanidm_api = Api::<Kanidm>::namespaced(ctx.kaniop_ctx.client.clone(), namespace);
+        let _o = kanidm_api
+            .patch_status(name, &patch, &new_status_patch)
+            .await
+            .map_err(|e| {
+                Error::KubeError(
+                    format!("failed to patch Kanidm/status {namespace}/{name}"),
+                    e,
+                )
+            })?;
+        Ok(new_status)
+    }
+}
+
+impl Kanidm {
+    fn generate_workload_status(&self, ctx: &Context) -> KanidmStatus {
         let namespace = &self.get_namespace();
         let statefulsets = self
             .spec
@@ -62,6 +204,8 @@ impl StatusExt for Kanidm {
             .get(&secret_ref)
             .map(|s| s.name_any());
 
+        let now = Utc::now();
+        let renew_before = self.replica_cert_renew_before();
         let replica_infos = statefulsets
             .iter()
             .flat_map(|sts| {
@@ -73,16 +217,47 @@ impl StatusExt for Kanidm {
                     let secret_name = self.replica_secret_name(&pod_name);
                     let secret_ref =
                         ObjectRef::<Secret>::new_with(&secret_name, ()).within(namespace);
+                    let secret = secret_store.get(&secret_ref);
+                    let cert_validity = secret
+                        .as_ref()
+                        .and_then(|s| s.data.as_ref()?.get(REPLICA_SECRET_KEY))
+                        .and_then(|cert| CertificateValidity::from_replica_cert(&cert.0));
                     ReplicaInformation {
                         pod_name,
                         statefulset_name: sts_name.clone(),
-                        replica_secret_exists: secret_store.get(&secret_ref).is_some(),
+                        replica_secret_exists: secret.is_some(),
+                        replica_cert_expiring: cert_validity
+                            .as_ref()
+                            .is_some_and(|v| v.is_expiring(renew_before, now)),
+                        // renewed certificates update the existing secret
+                        replica_secret_updated: secret
+                            .as_ref()
+                            .and_then(|s| s.creation_timestamp())
+                            .map(|t| t.0)
+                            .into_iter()
+                            .chain(cert_validity.map(|v| v.not_before))
+                            .max(),
                     }
                 })
             })
             .collect::<Vec<ReplicaInformation>>();
 
-        let new_status = generate_status(
+        let restart_required_statefulsets = match self.spec.auto_restart_on_cert_renewal {
+            true => Vec::new(),
+            false => {
+                let last_secret_update = replica_infos
+                    .iter()
+                    .filter_map(|ri| ri.replica_secret_updated)
+                    .max();
+                statefulsets
+                    .iter()
+                    .filter(|sts| is_restart_required(sts, last_secret_update))
+                    .map(|sts| sts.name_any())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let status = generate_status(
             self.status
                 .as_ref()
                 .cloned()
@@ -93,34 +268,241 @@ impl StatusExt for Kanidm {
             admin_secret,
             replica_infos,
             self.is_replication_enabled(),
+            self.restart_deferral().is_some() && self.spec.auto_restart_on_cert_renewal,
             self.metadata.generation,
         );
+        let drifted_statefulsets = self
+            .spec
+            .replica_groups
+            .iter()
+            .filter(|rg| self.is_drifted(ctx, rg))
+            .map(|rg| self.statefulset_name(&rg.name))
+            .collect::<Vec<_>>();
+        KanidmStatus {
+            conditions: status.conditions.map(|conditions| {
+                [
+                    self.degraded_replication_condition(),
+                    self.restart_required_condition(&restart_required_statefulsets),
+                    self.drifted_condition(&drifted_statefulsets),
+                ]
+                .iter()
+                .fold(conditions, update_conditions)
+            }),
+            ..status
+        }
+    }
 
-        let new_status_patch = Patch::Apply(Kanidm {
-            status: Some(new_status.clone()),
-            ..Kanidm::default()
-        });
-        debug!(msg = "updating Kanidm status");
-        trace!(msg = format!("new status {:?}", new_status_patch));
-        let patch = PatchParams::apply(KANIDM_OPERATOR_NAME).force();
-        let kanidm_api = Api::<Kanidm>::namespaced(ctx.kaniop_ctx.client.clone(), namespace);
-        let _o = kanidm_api
-            .patch_status(name, &patch, &new_status_patch)
-            .await
-            .map_err(|e| {
-                Error::KubeError(
-                    format!("failed to patch Kanidm/status {namespace}/{name}"),
-                    e,
-                )
-            })?;
-        Ok(new_status)
+    fn restart_required_condition(&self, restart_required_statefulsets: &[String]) -> Condition {
+        let kanidm_generation = self.metadata.generation;
+        match restart_required_statefulsets.is_empty() {
+            false => Condition {
+                type_: TYPE_RESTART_REQUIRED.to_string(),
+                status: CONDITION_TRUE.to_string(),
+                reason: "AutomaticRestartDisabled".to_string(),
+                message: format!(
+                    "StatefulSets must be restarted to load the new replica certificates: {}.",
+                    restart_required_statefulsets.join(", ")
+                ),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+            true => Condition {
+                type_: TYPE_RESTART_REQUIRED.to_string(),
+                status: CONDITION_FALSE.to_string(),
+                reason: "NoRestartRequired".to_string(),
+                message: "Pods run with the current replica certificates.".to_string(),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+        }
+    }
+
+    fn drifted_condition(&self, drifted_statefulsets: &[String]) -> Condition {
+        let kanidm_generation = self.metadata.generation;
+        match drifted_statefulsets.is_empty() {
+            false => Condition {
+                type_: TYPE_DRIFTED.to_string(),
+                status: CONDITION_TRUE.to_string(),
+                reason: "ManualChangesOverwritten".to_string(),
+                message: format!(
+                    "StatefulSets modified outside the operator, overwriting changes: {}.",
+                    drifted_statefulsets.join(", ")
+                ),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+            true => Condition {
+                type_: TYPE_DRIFTED.to_string(),
+                status: CONDITION_FALSE.to_string(),
+                reason: "NoDrift".to_string(),
+                message: "Managed resources match the desired state.".to_string(),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+        }
+    }
+
+    /// `DomainConfigured` condition, checking that Kanidm has the domain settings of the spec,
+    /// which requires Kanidm to be available and initialized. Until then, the previous condition
+    /// is kept. There is no condition when the spec defines no domain settings.
+    async fn domain_configured_condition(
+        &self,
+        ctx: Arc<Context>,
+        conditions: &[Condition],
+    ) -> Option<Condition> {
+        let display_name = self.spec.domain_display_name.as_deref()?;
+        if !is_configurable(conditions) {
+            return previous_condition(conditions, TYPE_DOMAIN_CONFIGURED);
+        }
+        let result = self.is_domain_configured(ctx, display_name).await;
+        if let Err(e) = &result {
+            debug!(msg = "failed to check Kanidm domain", %e);
+        }
+        Some(generate_domain_configured_condition(
+            &result,
+            self.metadata.generation,
+        ))
+    }
+
+    /// `PolicyApplied` condition, checking that Kanidm has the system policy of the spec, which
+    /// requires Kanidm to be available and initialized. Until then, the previous condition is
+    /// kept. There is no condition when the spec defines no system policy.
+    async fn policy_applied_condition(
+        &self,
+        ctx: Arc<Context>,
+        conditions: &[Condition],
+    ) -> Option<Condition> {
+        let policy = self.spec.system_policy.as_ref()?;
+        if !is_configurable(conditions) {
+            return previous_condition(conditions, TYPE_POLICY_APPLIED);
+        }
+        let result = self.is_system_policy_applied(ctx, policy).await;
+        if let Err(e) = &result {
+            debug!(msg = "failed to check Kanidm system policy", %e);
+        }
+        Some(generate_policy_applied_condition(
+            &result,
+            self.metadata.generation,
+        ))
+    }
+
+    /// Version of the Kanidm server running in the pods, as reported by `kanidmd version`. It is
+    /// cached until the image or the StatefulSets change.
+    pub(super) async fn running_version(&self, ctx: Arc<Context>) -> Option<String> {
+        let obj_ref = ObjectRef::from(self);
+        let key = self.running_version_key(&ctx);
+        if let Some((cached_key, version)) = ctx.running_versions.read().await.get(&obj_ref) {
+            if *cached_key == key {
+                return Some(version.clone());
+            }
+        }
+        match self.exec_any(ctx.clone(), ["kanidmd", "version"]).await {
+            Ok(output) => {
+                let version = output.as_deref().and_then(extract_version)?;
+                ctx.running_versions
+                    .write()
+                    .await
+                    .insert(obj_ref, (key, version.clone()));
+                Some(version)
+            }
+            Err(e) => {
+                debug!(msg = "failed to get Kanidm version", %e);
+                None
+            }
+        }
+    }
+
+    /// The pods only change their version when the image or the StatefulSets do. The current
+    /// revision covers the pods that were still rolling out when the version was read.
+    fn running_version_key(&self, ctx: &Context) -> RunningVersionKey {
+        let namespace = &self.get_namespace();
+        let statefulsets = self
+            .spec
+            .replica_groups
+            .iter()
+            .map(|rg| {
+                let sts_name = self.statefulset_name(&rg.name);
+                let sts_ref = ObjectRef::<StatefulSet>::new_with(&sts_name, ()).within(namespace);
+                ctx.stores
+                    .stateful_set_store
+                    .get(&sts_ref)
+                    .map(|sts| {
+                        (
+                            sts.metadata.generation,
+                            sts.status.as_ref().and_then(|s| s.current_revision.clone()),
+                        )
+                    })
+                    .unwrap_or_default()
+            })
+            .collect();
+        (self.spec.image.clone(), statefulsets)
+    }
+
+    fn degraded_replication_condition(&self) -> Condition {
+        let kanidm_generation = self.metadata.generation;
+        match self.is_replication_enabled() && !self.has_write_redundancy() {
+            true => Condition {
+                type_: TYPE_DEGRADED_REPLICATION.to_string(),
+                status: CONDITION_TRUE.to_string(),
+                reason: "SingleWriteReplica".to_string(),
+                message: "Replication is enabled, but only one node accepts writes.".to_string(),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+            false => Condition {
+                type_: TYPE_DEGRADED_REPLICATION.to_string(),
+                status: CONDITION_FALSE.to_string(),
+                reason: "NotDegraded".to_string(),
+                message: "Replication is disabled or more than one node accepts writes."
+                    .to_string(),
+                last_transition_time: Time(Utc::now()),
+                observed_generation: kanidm_generation,
+            },
+        }
     }
 }
 
+/// Whether the StatefulSet was last restarted, or created, before the last replica secret
+/// update, so its pods still run with the previous replica certificates.
+fn is_restart_required(sts: &StatefulSet, last_secret_update: Option<DateTime<Utc>>) -> bool {
+    let Some(last_secret_update) = last_secret_update else {
+        return false;
+    };
+    let last_restart = sts
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.template.metadata.as_ref())
+        .and_then(|metadata| metadata.annotations.as_ref())
+        .into_iter()
+        .flat_map(|annotations| {
+            RESTARTED_AT_ANNOTATIONS
+                .iter()
+                .filter_map(|annotation| annotations.get(*annotation))
+        })
+        .filter_map(|restarted_at| DateTime::parse_from_rfc3339(restarted_at).ok())
+        .map(|restarted_at| restarted_at.with_timezone(&Utc))
+        .chain(sts.creation_timestamp().map(|t| t.0))
+        .max();
+    last_restart.is_some_and(|last_restart| last_restart < last_secret_update)
+}
+
+/// Extract the version from the `kanidmd version` output, e.g. `kanidmd 1.4.5`.
+fn extract_version(output: &str) -> Option<String> {
+    output.lines().rev().find_map(|line| {
+        let (_, version) = line.rsplit_once("kanidmd ")?;
+        let version = version.trim();
+        version
+            .starts_with(|c: char| c.is_ascii_digit())
+            .then(|| version.to_string())
+    })
+}
+
 struct ReplicaInformation {
     pod_name: String,
     statefulset_name: String,
     replica_secret_exists: bool,
+    replica_cert_expiring: bool,
+    replica_secret_updated: Option<DateTime<Utc>>,
 }
 
 pub fn is_kanidm_available(status: KanidmStatus) -> bool {
@@ -139,12 +521,175 @@ pub fn is_kanidm_initialized(status: KanidmStatus) -> bool {
         .any(|c| c.type_ == TYPE_INITIALIZED && c.status == CONDITION_TRUE)
 }
 
+/// Whether the domain settings of Kanidm differ from the spec, so reconcile has to apply them.
+pub fn is_domain_unconfigured(status: KanidmStatus) -> bool {
+    status
+        .conditions
+        .unwrap_or_default()
+        .iter()
+        .any(|c| c.type_ == TYPE_DOMAIN_CONFIGURED && c.status == CONDITION_FALSE)
+}
+
+/// Whether the system policy of Kanidm differs from the spec, so reconcile has to apply it.
+pub fn is_policy_unapplied(status: KanidmStatus) -> bool {
+    status
+        .conditions
+        .unwrap_or_default()
+        .iter()
+        .any(|c| c.type_ == TYPE_POLICY_APPLIED && c.status == CONDITION_FALSE)
+}
+
+/// Status of an external Kanidm, which has no replicas managed by the operator.
+fn generate_external_status(
+    previous_conditions: Vec<Condition>,
+    is_reachable: bool,
+    secret_name: String,
+    kanidm_generation: Option<i64>,
+) -> KanidmStatus {
+    let available_condition = match is_reachable {
+        true => Condition {
+            type_: TYPE_AVAILABLE.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "ExternalReachable".to_string(),
+            message: "External Kanidm is reachable.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        false => Condition {
+            type_: TYPE_AVAILABLE.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "ExternalUnreachable".to_string(),
+            message: "External Kanidm is not reachable.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+    };
+    let initialized_condition = Condition {
+        type_: TYPE_INITIALIZED.to_string(),
+        status: CONDITION_TRUE.to_string(),
+        reason: "ExternalCredentials".to_string(),
+        message: "External Kanidm credentials are provided.".to_string(),
+        last_transition_time: Time(Utc::now()),
+        observed_generation: kanidm_generation,
+    };
+
+    let conditions = [available_condition, initialized_condition]
+        .into_iter()
+        .fold(previous_conditions, |previous_conditions, c| {
+            update_conditions(previous_conditions, &c)
+        });
+    KanidmStatus {
+        ready: is_ready(&conditions),
+        conditions: Some(conditions),
+        replica_column: "0/0".to_string(),
+        secret_name: Some(secret_name),
+        ..KanidmStatus::default()
+    }
+}
+
+/// Whether Kanidm is available and initialized, so its settings can be changed through its API.
+fn is_configurable(conditions: &[Condition]) -> bool {
+    let is_condition = |type_: &str| {
+        conditions
+            .iter()
+            .any(|c| c.type_ == type_ && c.status == CONDITION_TRUE)
+    };
+    is_condition(TYPE_AVAILABLE) && is_condition(TYPE_INITIALIZED)
+}
+
+fn previous_condition(conditions: &[Condition], type_: &str) -> Option<Condition> {
+    conditions.iter().find(|c| c.type_ == type_).cloned()
+}
+
+fn generate_domain_configured_condition(
+    result: &Result<bool>,
+    kanidm_generation: Option<i64>,
+) -> Condition {
+    match result {
+        Ok(true) => Condition {
+            type_: TYPE_DOMAIN_CONFIGURED.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "DomainConfigured".to_string(),
+            message: "Domain settings are applied.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        Ok(false) => Condition {
+            type_: TYPE_DOMAIN_CONFIGURED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "DomainNotConfigured".to_string(),
+            message: "Domain settings differ from the spec.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        Err(e) => Condition {
+            type_: TYPE_DOMAIN_CONFIGURED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "DomainCheckFailed".to_string(),
+            message: format!("Failed to check domain settings: {e}"),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+    }
+}
+
+fn generate_policy_applied_condition(
+    result: &Result<bool>,
+    kanidm_generation: Option<i64>,
+) -> Condition {
+    match result {
+        Ok(true) => Condition {
+            type_: TYPE_POLICY_APPLIED.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "PolicyApplied".to_string(),
+            message: "System policy is applied.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        Ok(false) => Condition {
+            type_: TYPE_POLICY_APPLIED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "PolicyNotApplied".to_string(),
+            message: "System policy differs from the spec.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        Err(e) => Condition {
+            type_: TYPE_POLICY_APPLIED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "PolicyCheckFailed".to_string(),
+            message: format!("Failed to check system policy: {e}"),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+    }
+}
+
+fn is_ready(conditions: &[Condition]) -> bool {
+    let is_condition = |type_: &str, status: &str| {
+        conditions
+            .iter()
+            .any(|c| c.type_ == type_ && c.status == status)
+    };
+    is_condition(TYPE_AVAILABLE, CONDITION_TRUE)
+        && is_condition(TYPE_INITIALIZED, CONDITION_TRUE)
+        && !is_condition(TYPE_REPLICA_FAILURE, CONDITION_TRUE)
+}
+
+fn client_pool_status((healthy, total): (usize, usize)) -> Option<KanidmClientPoolStatus> {
+    (total > 0).then_some(KanidmClientPoolStatus {
+        healthy: healthy as u32,
+        total: total as u32,
+    })
+}
+
 fn generate_status(
     previous_conditions: Vec<Condition>,
     statefulset_statuses: &[Option<StatefulSetStatus>],
     secret_name: Option<String>,
     replica_infos: Vec<ReplicaInformation>,
     is_replication_enabled: bool,
+    is_restart_deferred: bool,
     kanidm_generation: Option<i64>,
 ) -> KanidmStatus {
     let available_replicas = statefulset_statuses
@@ -164,7 +709,11 @@ fn generate_status(
         .map(|ri| KanidmReplicaStatus {
             pod_name: ri.pod_name.clone(),
             statefulset_name: ri.statefulset_name.clone(),
-            state: if ri.replica_secret_exists || !is_replication_enabled {
+            state: if !is_replication_enabled {
+                KanidmReplicaState::Initialized
+            } else if ri.replica_cert_expiring {
+                KanidmReplicaState::CertificateExpiring
+            } else if ri.replica_secret_exists {
                 KanidmReplicaState::Initialized
             } else {
                 KanidmReplicaState::Pending
@@ -177,11 +726,13 @@ fn generate_status(
         statefulset_statuses,
         secret_name.is_some(),
         &replica_statuses,
+        is_restart_deferred,
         kanidm_generation,
     );
 
     let replica_column = format!("{available_replicas}/{replicas}");
     KanidmStatus {
+        ready: is_ready(&new_conditions),
         conditions: Some(new_conditions),
         available_replicas,
         replicas,
@@ -194,6 +745,8 @@ fn generate_status(
         replica_statuses,
         replica_column,
         secret_name,
+        client_pool: None,
+        version: None,
     }
 }
 
@@ -203,6 +756,7 @@ fn generate_status_conditions(
     statefulset_statuses: &[Option<StatefulSetStatus>],
     secret_exists: bool,
     replica_statuses: &[KanidmReplicaStatus],
+    is_restart_deferred: bool,
     kanidm_generation: Option<i64>,
 ) -> Vec<Condition> {
     let sts_statuses = statefulset_statuses.iter().filter_map(|sts| sts.as_ref());
@@ -329,11 +883,37 @@ fn generate_status_conditions(
         }
     };
 
+    let restart_deferred_condition = match is_restart_deferred
+        && replica_statuses
+            .iter()
+            .any(|rs| rs.state != KanidmReplicaState::Initialized)
+    {
+        true => Condition {
+            type_: TYPE_RESTART_DEFERRED.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "OutsideMaintenanceWindow".to_string(),
+            message: "Pending replicas and expiring replica certificates are waiting for the \
+                next maintenance window."
+                .to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        false => Condition {
+            type_: TYPE_RESTART_DEFERRED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "NoRestartDeferred".to_string(),
+            message: "No restarts are deferred.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+    };
+
     [
         available_condition,
         progressing_condition,
         initialized_condition,
         replicate_failure_condition,
+        restart_deferred_condition,
     ]
     .into_iter()
     .fold(previous_conditions, |previous_conditions, c| {
@@ -341,6 +921,83 @@ fn generate_status_conditions(
     })
 }
 
+fn is_degraded_replication(conditions: Option<&[Condition]>) -> bool {
+    conditions.is_some_and(|conditions| {
+        conditions
+            .iter()
+            .any(|c| c.type_ == TYPE_DEGRADED_REPLICATION && c.status == CONDITION_TRUE)
+    })
+}
+
+/// Add the Kstatus `Reconciling` and `Stalled` conditions, so `kubectl wait` can be used to wait
+/// for rollouts.
+fn with_kstatus_conditions(
+    conditions: Vec<Condition>,
+    reconcile_failures: u32,
+    kanidm_generation: Option<i64>,
+) -> Vec<Condition> {
+    let is_condition = |type_: &str, status: &str| {
+        conditions
+            .iter()
+            .any(|c| c.type_ == type_ && c.status == status)
+    };
+    let reconciling_condition = if is_condition(TYPE_PROGRESSING, CONDITION_TRUE) {
+        Condition {
+            type_: TYPE_RECONCILING.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "Progressing".to_string(),
+            message: "Replicas are being rolled out.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        }
+    } else if !is_condition(TYPE_AVAILABLE, CONDITION_TRUE)
+        || !is_condition(TYPE_INITIALIZED, CONDITION_TRUE)
+    {
+        Condition {
+            type_: TYPE_RECONCILING.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "NotReady".to_string(),
+            message: "Kanidm is not available or initialized yet.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        }
+    } else {
+        Condition {
+            type_: TYPE_RECONCILING.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "Reconciled".to_string(),
+            message: "Kanidm matches the desired state.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        }
+    };
+
+    let stalled_condition = match reconcile_failures >= STALLED_RECONCILE_FAILURES {
+        true => Condition {
+            type_: TYPE_STALLED.to_string(),
+            status: CONDITION_TRUE.to_string(),
+            reason: "ReconcileFailing".to_string(),
+            message: format!("Reconcile failed {reconcile_failures} consecutive times."),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+        false => Condition {
+            type_: TYPE_STALLED.to_string(),
+            status: CONDITION_FALSE.to_string(),
+            reason: "NotStalled".to_string(),
+            message: "Reconcile is not failing repeatedly.".to_string(),
+            last_transition_time: Time(Utc::now()),
+            observed_generation: kanidm_generation,
+        },
+    };
+
+    [reconciling_condition, stalled_condition]
+        .into_iter()
+        .fold(conditions, |conditions, c| {
+            update_conditions(conditions, &c)
+        })
+}
+
 /// Update conditions based on the current status and previous conditions in the Kanidm
 fn update_conditions(
     previous_conditions: Vec<Condition>,
@@ -368,6 +1025,9 @@ fn update_conditions(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::kanidm::crd::{
+        ExternalReplicationNode, KanidmServerRole, KanidmSpec, ReplicaGroup, ReplicationType,
+    };
     use chrono::Utc;
 
     fn create_condition(type_: &str, status: &str) -> Condition {
@@ -428,4 +1088,407 @@ mod test {
             .iter()
             .any(|c| c.type_ == TYPE_AVAILABLE && c.status == CONDITION_TRUE));
     }
+
+    fn create_replica_status(state: KanidmReplicaState) -> KanidmReplicaStatus {
+        KanidmReplicaStatus {
+            pod_name: "test-default-0".to_string(),
+            statefulset_name: "test-default".to_string(),
+            state,
+        }
+    }
+
+    fn is_restart_deferred(conditions: &[Condition]) -> bool {
+        conditions
+            .iter()
+            .any(|c| c.type_ == TYPE_RESTART_DEFERRED && c.status == CONDITION_TRUE)
+    }
+
+    #[test]
+    fn test_generate_status_conditions_restart_deferred() {
+        let conditions = generate_status_conditions(
+            vec![],
+            &[],
+            true,
+            &[create_replica_status(KanidmReplicaState::Pending)],
+            true,
+            None,
+        );
+
+        assert!(is_restart_deferred(&conditions));
+    }
+
+    #[test]
+    fn test_generate_status_conditions_restart_not_deferred_in_window() {
+        let conditions = generate_status_conditions(
+            vec![],
+            &[],
+            true,
+            &[create_replica_status(KanidmReplicaState::Pending)],
+            false,
+            None,
+        );
+
+        assert!(!is_restart_deferred(&conditions));
+    }
+
+    #[test]
+    fn test_generate_status_conditions_restart_not_deferred_without_pending_replicas() {
+        let conditions = generate_status_conditions(
+            vec![],
+            &[],
+            true,
+            &[create_replica_status(KanidmReplicaState::Initialized)],
+            true,
+            None,
+        );
+
+        assert!(!is_restart_deferred(&conditions));
+    }
+
+    #[test]
+    fn test_generate_external_status() {
+        let status = generate_external_status(
+            vec![create_condition(TYPE_AVAILABLE, CONDITION_FALSE)],
+            true,
+            "idm-credentials".to_string(),
+            Some(1),
+        );
+        assert!(is_kanidm_available(status.clone()));
+        assert!(is_kanidm_initialized(status.clone()));
+        assert!(status.ready);
+        assert_eq!(status.conditions.unwrap().len(), 2);
+        assert_eq!(status.replicas, 0);
+        assert!(status.replica_statuses.is_empty());
+        assert_eq!(status.secret_name, Some("idm-credentials".to_string()));
+    }
+
+    #[test]
+    fn test_generate_external_status_unreachable() {
+        let status =
+            generate_external_status(vec![], false, "idm-credentials".to_string(), Some(1));
+        assert!(!status.ready);
+        assert!(!is_kanidm_available(status));
+    }
+
+    #[test]
+    fn test_client_pool_status() {
+        assert_eq!(client_pool_status((0, 0)), None);
+        assert_eq!(
+            client_pool_status((1, 2)),
+            Some(KanidmClientPoolStatus {
+                healthy: 1,
+                total: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_is_ready() {
+        let available = create_condition(TYPE_AVAILABLE, CONDITION_TRUE);
+        let initialized = create_condition(TYPE_INITIALIZED, CONDITION_TRUE);
+        assert!(is_ready(&[available.clone(), initialized.clone()]));
+        assert!(!is_ready(std::slice::from_ref(&available)));
+        assert!(!is_ready(&[
+            create_condition(TYPE_AVAILABLE, CONDITION_FALSE),
+            initialized.clone()
+        ]));
+        assert!(!is_ready(&[
+            available.clone(),
+            initialized.clone(),
+            create_condition(TYPE_REPLICA_FAILURE, CONDITION_TRUE)
+        ]));
+        assert!(is_ready(&[
+            available,
+            initialized,
+            create_condition(TYPE_REPLICA_FAILURE, CONDITION_FALSE)
+        ]));
+    }
+
+    fn condition_status<'a>(conditions: &'a [Condition], type_: &str) -> &'a str {
+        conditions
+            .iter()
+            .find(|c| c.type_ == type_)
+            .map(|c| c.status.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_kstatus_conditions_on_success() {
+        let conditions = with_kstatus_conditions(
+            vec![
+                create_condition(TYPE_AVAILABLE, CONDITION_TRUE),
+                create_condition(TYPE_INITIALIZED, CONDITION_TRUE),
+                create_condition(TYPE_PROGRESSING, CONDITION_FALSE),
+            ],
+            0,
+            Some(1),
+        );
+        assert_eq!(
+            condition_status(&conditions, TYPE_RECONCILING),
+            CONDITION_FALSE
+        );
+        assert_eq!(condition_status(&conditions, TYPE_STALLED), CONDITION_FALSE);
+    }
+
+    #[test]
+    fn test_kstatus_conditions_while_progressing() {
+        let conditions = with_kstatus_conditions(
+            vec![
+                create_condition(TYPE_AVAILABLE, CONDITION_TRUE),
+                create_condition(TYPE_INITIALIZED, CONDITION_TRUE),
+                create_condition(TYPE_PROGRESSING, CONDITION_TRUE),
+            ],
+            0,
+            Some(1),
+        );
+        assert_eq!(
+            condition_status(&conditions, TYPE_RECONCILING),
+            CONDITION_TRUE
+        );
+
+        let conditions = with_kstatus_conditions(
+            vec![create_condition(TYPE_AVAILABLE, CONDITION_FALSE)],
+            0,
+            Some(1),
+        );
+        assert_eq!(
+            condition_status(&conditions, TYPE_RECONCILING),
+            CONDITION_TRUE
+        );
+    }
+
+    #[test]
+    fn test_kstatus_conditions_on_failure() {
+        let previous = vec![
+            create_condition(TYPE_AVAILABLE, CONDITION_TRUE),
+            create_condition(TYPE_INITIALIZED, CONDITION_TRUE),
+        ];
+        let conditions =
+            with_kstatus_conditions(previous.clone(), STALLED_RECONCILE_FAILURES - 1, Some(1));
+        assert_eq!(condition_status(&conditions, TYPE_STALLED), CONDITION_FALSE);
+
+        let conditions = with_kstatus_conditions(previous, STALLED_RECONCILE_FAILURES, Some(1));
+        assert_eq!(condition_status(&conditions, TYPE_STALLED), CONDITION_TRUE);
+        let stalled = conditions.iter().find(|c| c.type_ == TYPE_STALLED).unwrap();
+        assert_eq!(stalled.reason, "ReconcileFailing");
+
+        // recovers once the reconcile succeeds
+        let conditions = with_kstatus_conditions(conditions, 0, Some(1));
+        assert_eq!(condition_status(&conditions, TYPE_STALLED), CONDITION_FALSE);
+        assert_eq!(
+            conditions
+                .iter()
+                .filter(|c| c.type_ == TYPE_STALLED)
+                .count(),
+            1
+        );
+    }
+
+    fn create_kanidm(
+        replica_groups: Vec<(KanidmServerRole, i32)>,
+        external_replication_nodes: Vec<ReplicationType>,
+    ) -> Kanidm {
+        Kanidm {
+            spec: KanidmSpec {
+                replica_groups: replica_groups
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (role, replicas))| ReplicaGroup {
+                        name: format!("rg{i}"),
+                        replicas,
+                        role,
+                        ..Default::default()
+                    })
+                    .collect(),
+                external_replication_nodes: external_replication_nodes
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, _type)| ExternalReplicationNode {
+                        name: format!("ern{i}"),
+                        _type,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_degraded_replication_condition() {
+        let cases = [
+            (
+                create_kanidm(vec![(KanidmServerRole::WriteReplica, 1)], vec![]),
+                false,
+            ),
+            (
+                create_kanidm(vec![(KanidmServerRole::WriteReplica, 3)], vec![]),
+                false,
+            ),
+            (
+                create_kanidm(
+                    vec![
+                        (KanidmServerRole::WriteReplica, 1),
+                        (KanidmServerRole::ReadOnlyReplica, 2),
+                    ],
+                    vec![],
+                ),
+                true,
+            ),
+            (
+                create_kanidm(
+                    vec![(KanidmServerRole::WriteReplicaNoUI, 1)],
+                    vec![ReplicationType::AllowPull],
+                ),
+                true,
+            ),
+            (
+                create_kanidm(
+                    vec![(KanidmServerRole::WriteReplica, 1)],
+                    vec![ReplicationType::MutualPull],
+                ),
+                false,
+            ),
+            (
+                create_kanidm(
+                    vec![
+                        (KanidmServerRole::WriteReplica, 1),
+                        (KanidmServerRole::WriteReplicaNoUI, 1),
+                    ],
+                    vec![],
+                ),
+                false,
+            ),
+        ];
+
+        for (kanidm, expected) in cases {
+            let condition = kanidm.degraded_replication_condition();
+            assert_eq!(condition.type_, TYPE_DEGRADED_REPLICATION);
+            assert_eq!(
+                is_degraded_replication(Some(&[condition])),
+                expected,
+                "unexpected condition for {:?}",
+                kanidm.spec.replica_groups
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_restart_required() {
+        use kube::api::ObjectMeta;
+        use std::collections::BTreeMap;
+
+        use chrono::TimeZone;
+
+        let time = |hour: u32| Utc.with_ymd_and_hms(2024, 11, 6, hour, 0, 0).unwrap();
+        let statefulset = |restarted_at: Option<(&str, u32)>| StatefulSet {
+            metadata: ObjectMeta {
+                creation_timestamp: Some(Time(time(10))),
+                ..ObjectMeta::default()
+            },
+            spec: Some(k8s_openapi::api::apps::v1::StatefulSetSpec {
+                template: k8s_openapi::api::core::v1::PodTemplateSpec {
+                    metadata: Some(ObjectMeta {
+                        annotations: restarted_at.map(|(annotation, hour)| {
+                            BTreeMap::from([(annotation.to_string(), time(hour).to_rfc3339())])
+                        }),
+                        ..ObjectMeta::default()
+                    }),
+                    spec: None,
+                },
+                ..Default::default()
+            }),
+            status: None,
+        };
+
+        assert!(!is_restart_required(&statefulset(None), None));
+        assert!(!is_restart_required(&statefulset(None), Some(time(9))));
+        assert!(is_restart_required(&statefulset(None), Some(time(11))));
+        assert!(is_restart_required(
+            &statefulset(Some(("kube.kubernetes.io/restartedAt", 11))),
+            Some(time(12))
+        ));
+        assert!(!is_restart_required(
+            &statefulset(Some(("kubectl.kubernetes.io/restartedAt", 13))),
+            Some(time(12))
+        ));
+    }
+
+    #[test]
+    fn test_extract_version() {
+        let output = r#"
+        00000000-0000-0000-0000-000000000000 WARN     🚧 [warn]: This is running as uid == 0 (root) which may be a security risk.
+        kanidmd 1.4.5"#;
+        assert_eq!(extract_version(output), Some("1.4.5".to_string()));
+        assert_eq!(
+            extract_version("kanidmd 1.5.0-dev\n"),
+            Some("1.5.0-dev".to_string())
+        );
+        assert_eq!(extract_version("error: unrecognized subcommand"), None);
+        assert_eq!(extract_version("kanidmd version"), None);
+    }
+
+    #[test]
+    fn test_generate_domain_configured_condition() {
+        let condition = generate_domain_configured_condition(&Ok(true), Some(2));
+        assert_eq!(condition.type_, TYPE_DOMAIN_CONFIGURED);
+        assert_eq!(condition.status, CONDITION_TRUE);
+        assert_eq!(condition.observed_generation, Some(2));
+
+        let condition = generate_domain_configured_condition(&Ok(false), Some(2));
+        assert_eq!(condition.status, CONDITION_FALSE);
+        assert_eq!(condition.reason, "DomainNotConfigured");
+
+        let condition = generate_domain_configured_condition(
+            &Err(Error::KanidmClientError(
+                "failed to get Kanidm domain".to_string(),
+                Box::new(kanidm_client::ClientError::EmptyResponse),
+            )),
+            Some(2),
+        );
+        assert_eq!(condition.status, CONDITION_FALSE);
+        assert_eq!(condition.reason, "DomainCheckFailed");
+        assert!(condition.message.contains("failed to get Kanidm domain"));
+    }
+
+    #[test]
+    fn test_is_configurable() {
+        assert!(is_configurable(&[
+            create_condition(TYPE_AVAILABLE, CONDITION_TRUE),
+            create_condition(TYPE_INITIALIZED, CONDITION_TRUE),
+        ]));
+        assert!(!is_configurable(&[
+            create_condition(TYPE_AVAILABLE, CONDITION_TRUE),
+            create_condition(TYPE_INITIALIZED, CONDITION_FALSE),
+        ]));
+        assert!(!is_configurable(&[create_condition(
+            TYPE_INITIALIZED,
+            CONDITION_TRUE
+        )]));
+    }
+
+    #[test]
+    fn test_generate_policy_applied_condition() {
+        let condition = generate_policy_applied_condition(&Ok(true), Some(3));
+        assert_eq!(condition.type_, TYPE_POLICY_APPLIED);
+        assert_eq!(condition.status, CONDITION_TRUE);
+        assert_eq!(condition.observed_generation, Some(3));
+
+        let condition = generate_policy_applied_condition(&Ok(false), Some(3));
+        assert_eq!(condition.status, CONDITION_FALSE);
+        assert_eq!(condition.reason, "PolicyNotApplied");
+
+        let condition = generate_policy_applied_condition(
+            &Err(Error::MissingData(
+                "idm_all_persons group not found".to_string(),
+            )),
+            Some(3),
+        );
+        assert_eq!(condition.status, CONDITION_FALSE);
+        assert_eq!(condition.reason, "PolicyCheckFailed");
+        assert!(condition
+            .message
+            .contains("idm_all_persons group not found"));
+    }
 }
@@ -0,0 +1,254 @@
+use crate::controller::context::SystemClientContext;
+use crate::error::{Error, Result};
+use crate::kanidm::controller::context::Context;
+use crate::kanidm::crd::{Kanidm, KanidmSystemPolicy};
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::{ATTR_AUTH_PASSWORD_MINIMUM_LENGTH, ATTR_CLASS};
+use kanidm_proto::v1::Entry;
+use tracing::{debug, trace};
+
+/// Group holding the account policy applied to every person.
+const ALL_PERSONS_GROUP: &str = "idm_all_persons";
+const ACCOUNT_POLICY_CLASS: &str = "account_policy";
+
+#[allow(async_fn_in_trait)]
+pub trait SystemPolicyExt {
+    /// Whether the system policy of Kanidm matches the given one. It only reads from Kanidm.
+    async fn is_system_policy_applied(
+        &self,
+        ctx: Arc<Context>,
+        policy: &KanidmSystemPolicy,
+    ) -> Result<bool>;
+
+    /// Apply the system policy to Kanidm, changing only the values that differ from the current
+    /// ones.
+    async fn apply_system_policy(
+        &self,
+        ctx: Arc<Context>,
+        policy: &KanidmSystemPolicy,
+    ) -> Result<()>;
+}
+
+impl SystemPolicyExt for Kanidm {
+    async fn is_system_policy_applied(
+        &self,
+        ctx: Arc<Context>,
+        policy: &KanidmSystemPolicy,
+    ) -> Result<bool> {
+        let kanidm_client = ctx.kaniop_ctx.get_system_client(self).await?;
+        if let Some(length) = policy.password_minimum_length {
+            let group = get_all_persons_group(&kanidm_client).await?;
+            if !is_password_minimum_length_updated(&group, length) {
+                return Ok(false);
+            }
+        }
+        if let Some(badlist) = &policy.password_badlist {
+            let current = get_password_badlist(&kanidm_client).await?;
+            let (to_append, to_remove) = password_badlist_diff(&current, badlist);
+            if !to_append.is_empty() || !to_remove.is_empty() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn apply_system_policy(
+        &self,
+        ctx: Arc<Context>,
+        policy: &KanidmSystemPolicy,
+    ) -> Result<()> {
+        let kanidm_client = ctx.kaniop_ctx.get_system_client(self).await?;
+        if let Some(length) = policy.password_minimum_length {
+            apply_password_minimum_length(&kanidm_client, length).await?;
+        }
+        if let Some(badlist) = &policy.password_badlist {
+            apply_password_badlist(&kanidm_client, badlist).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn get_all_persons_group(kanidm_client: &KanidmClient) -> Result<Entry> {
+    kanidm_client
+        .idm_group_get(ALL_PERSONS_GROUP)
+        .await
+        .map_err(|e| {
+            Error::KanidmClientError(
+                format!("failed to get {ALL_PERSONS_GROUP} group"),
+                Box::new(e),
+            )
+        })?
+        .ok_or_else(|| Error::MissingData(format!("{ALL_PERSONS_GROUP} group not found")))
+}
+
+async fn get_password_badlist(kanidm_client: &KanidmClient) -> Result<Vec<String>> {
+    kanidm_client
+        .system_password_badlist_get()
+        .await
+        .map_err(|e| {
+            Error::KanidmClientError("failed to get password badlist".to_string(), Box::new(e))
+        })
+}
+
+async fn apply_password_minimum_length(kanidm_client: &KanidmClient, length: u32) -> Result<()> {
+    let group = get_all_persons_group(kanidm_client).await?;
+    if is_password_minimum_length_updated(&group, length) {
+        trace!(msg = "password minimum length unchanged");
+        return Ok(());
+    }
+    if !has_account_policy(&group) {
+        debug!(msg = format!("enabling account policy on {ALL_PERSONS_GROUP} group"));
+        kanidm_client
+            .group_account_policy_enable(ALL_PERSONS_GROUP)
+            .await
+            .map_err(|e| {
+                Error::KanidmClientError(
+                    format!("failed to enable account policy on {ALL_PERSONS_GROUP} group"),
+                    Box::new(e),
+                )
+            })?;
+    }
+    debug!(msg = "updating password minimum length", length);
+    kanidm_client
+        .group_account_policy_password_minimum_length_set(ALL_PERSONS_GROUP, length)
+        .await
+        .map_err(|e| {
+            Error::KanidmClientError(
+                "failed to set password minimum length".to_string(),
+                Box::new(e),
+            )
+        })
+}
+
+async fn apply_password_badlist(kanidm_client: &KanidmClient, badlist: &[String]) -> Result<()> {
+    let current = get_password_badlist(kanidm_client).await?;
+    let (to_append, to_remove) = password_badlist_diff(&current, badlist);
+    if !to_append.is_empty() {
+        debug!(
+            msg = "appending passwords to badlist",
+            count = to_append.len()
+        );
+        kanidm_client
+            .system_password_badlist_append(to_append)
+            .await
+            .map_err(|e| {
+                Error::KanidmClientError(
+                    "failed to append passwords to badlist".to_string(),
+                    Box::new(e),
+                )
+            })?;
+    }
+    if !to_remove.is_empty() {
+        debug!(
+            msg = "removing passwords from badlist",
+            count = to_remove.len()
+        );
+        kanidm_client
+            .system_password_badlist_remove(to_remove)
+            .await
+            .map_err(|e| {
+                Error::KanidmClientError(
+                    "failed to remove passwords from badlist".to_string(),
+                    Box::new(e),
+                )
+            })?;
+    }
+    Ok(())
+}
+
+fn has_account_policy(group: &Entry) -> bool {
+    group
+        .attrs
+        .get(ATTR_CLASS)
+        .is_some_and(|classes| classes.iter().any(|c| c == ACCOUNT_POLICY_CLASS))
+}
+
+fn is_password_minimum_length_updated(group: &Entry, length: u32) -> bool {
+    group
+        .attrs
+        .get(ATTR_AUTH_PASSWORD_MINIMUM_LENGTH)
+        .and_then(|values| values.first())
+        .and_then(|value| value.parse::<u32>().ok())
+        == Some(length)
+}
+
+/// Passwords to append to and to remove from the `current` badlist to match the `desired` one.
+/// Kanidm stores them in lowercase.
+fn password_badlist_diff(current: &[String], desired: &[String]) -> (Vec<String>, Vec<String>) {
+    let current = current
+        .iter()
+        .map(|p| p.to_lowercase())
+        .collect::<BTreeSet<_>>();
+    let desired = desired
+        .iter()
+        .map(|p| p.to_lowercase())
+        .collect::<BTreeSet<_>>();
+    (
+        desired.difference(&current).cloned().collect(),
+        current.difference(&desired).cloned().collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn group(attrs: &[(&str, &[&str])]) -> Entry {
+        Entry {
+            attrs: attrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), strings(v)))
+                .collect::<BTreeMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_password_badlist_diff() {
+        let (to_append, to_remove) = password_badlist_diff(
+            &strings(&["password", "123456"]),
+            &strings(&["Password", "qwerty"]),
+        );
+        assert_eq!(to_append, strings(&["qwerty"]));
+        assert_eq!(to_remove, strings(&["123456"]));
+    }
+
+    #[test]
+    fn test_password_badlist_diff_unchanged() {
+        let (to_append, to_remove) =
+            password_badlist_diff(&strings(&["password"]), &strings(&["PASSWORD", "password"]));
+        assert!(to_append.is_empty());
+        assert!(to_remove.is_empty());
+    }
+
+    #[test]
+    fn test_password_badlist_diff_clear() {
+        let (to_append, to_remove) = password_badlist_diff(&strings(&["password"]), &[]);
+        assert!(to_append.is_empty());
+        assert_eq!(to_remove, strings(&["password"]));
+    }
+
+    #[test]
+    fn test_is_password_minimum_length_updated() {
+        let policy_group = group(&[
+            (ATTR_CLASS, &["group", ACCOUNT_POLICY_CLASS]),
+            (ATTR_AUTH_PASSWORD_MINIMUM_LENGTH, &["12"]),
+        ]);
+        assert!(has_account_policy(&policy_group));
+        assert!(is_password_minimum_length_updated(&policy_group, 12));
+        assert!(!is_password_minimum_length_updated(&policy_group, 16));
+
+        let plain_group = group(&[(ATTR_CLASS, &["group"])]);
+        assert!(!has_account_policy(&plain_group));
+        assert!(!is_password_minimum_length_updated(&plain_group, 12));
+    }
+}
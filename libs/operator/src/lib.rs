@@ -1,6 +1,9 @@
+#[cfg(feature = "client")]
 pub mod controller;
 pub mod crd;
 pub mod error;
 pub mod kanidm;
+#[cfg(feature = "client")]
 pub mod metrics;
+#[cfg(feature = "client")]
 pub mod telemetry;
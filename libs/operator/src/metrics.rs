@@ -1,13 +1,15 @@
 use crate::controller::ControllerId;
 use crate::error::Error;
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
 
 use opentelemetry::trace::TraceId;
-use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
 use prometheus_client::metrics::{
     counter::Counter, exemplar::HistogramWithExemplars, family::Family, gauge::Gauge,
+    histogram::Histogram,
 };
 use prometheus_client::registry::{Registry, Unit};
 use tokio::time::Instant;
@@ -37,7 +39,7 @@ impl Metrics {
     }
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct ControllerMetrics {
     controller: String,
     pub reconcile: ReconcileMetrics,
@@ -45,7 +47,47 @@ pub struct ControllerMetrics {
     pub status_update_errors: Family<ControllerLabels, Counter>,
     pub triggered: Family<TriggeredLabels, Counter>,
     pub watch_operations_failed: Family<ControllerLabels, Counter>,
+    pub reload_triggers_coalesced: Family<ControllerLabels, Counter>,
+    pub reload_triggers_dropped: Family<ControllerLabels, Counter>,
+    pub store_objects: Family<StoreLabels, Gauge>,
+    pub finalizer_cleanup_failures: Family<StoreLabels, Counter>,
     pub ready: Family<ControllerLabels, Gauge>,
+    pub kanidm_request_wait_duration: Family<ControllerLabels, Histogram>,
+    pub drift_corrected: Family<ControllerLabels, Counter>,
+    pub kanidm_cache_hits: Family<ControllerLabels, Counter>,
+    pub kanidm_cache_misses: Family<ControllerLabels, Counter>,
+    pub objects: Family<ObjectStateLabels, Gauge>,
+    pub reconcile_queue_depth: Family<ControllerLabels, Gauge>,
+    /// Objects triggered for reconcile whose reconcile has not started, keyed by namespace and name
+    queued: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl Default for ControllerMetrics {
+    fn default() -> Self {
+        Self {
+            controller: Default::default(),
+            reconcile: Default::default(),
+            spec_replicas: Default::default(),
+            status_update_errors: Default::default(),
+            triggered: Default::default(),
+            watch_operations_failed: Default::default(),
+            reload_triggers_coalesced: Default::default(),
+            reload_triggers_dropped: Default::default(),
+            store_objects: Default::default(),
+            finalizer_cleanup_failures: Default::default(),
+            ready: Default::default(),
+            kanidm_request_wait_duration:
+                Family::<ControllerLabels, Histogram>::new_with_constructor(|| {
+                    Histogram::new([0.001, 0.01, 0.1, 0.5, 1., 5.].into_iter())
+                }),
+            drift_corrected: Default::default(),
+            kanidm_cache_hits: Default::default(),
+            kanidm_cache_misses: Default::default(),
+            objects: Default::default(),
+            reconcile_queue_depth: Default::default(),
+            queued: Default::default(),
+        }
+    }
 }
 
 impl ControllerMetrics {
@@ -99,11 +141,62 @@ impl ControllerMetrics {
             "Total number of watch operations that failed",
             self.watch_operations_failed.clone(),
         );
+        r.register(
+            "reload_triggers_coalesced",
+            "Number of reconcile triggers coalesced with pending ones because the reload channel was full",
+            self.reload_triggers_coalesced.clone(),
+        );
+        r.register(
+            "reload_triggers_dropped",
+            "Number of reconcile triggers dropped because the reload channel was closed",
+            self.reload_triggers_dropped.clone(),
+        );
+        r.register(
+            "store_objects",
+            "Number of objects in the reflector store of a watched resource kind",
+            self.store_objects.clone(),
+        );
+        r.register(
+            "finalizer_cleanup_failures",
+            "Number of errors that occurred during finalizer cleanups, blocking objects deletion",
+            self.finalizer_cleanup_failures.clone(),
+        );
         r.register(
             "ready",
             "1 when the controller is ready to reconcile resources, 0 otherwise",
             self.ready.clone(),
         );
+        r.register_with_unit(
+            "kanidm_request_wait_duration",
+            "Time Kanidm requests wait for a free slot of the concurrent requests limit",
+            Unit::Seconds,
+            self.kanidm_request_wait_duration.clone(),
+        );
+        r.register(
+            "drift_corrected",
+            "Number of times the operator overwrote manual changes to a managed resource",
+            self.drift_corrected.clone(),
+        );
+        r.register(
+            "kanidm_cache_hits",
+            "Number of Kanidm reads served from the cache of recently read objects",
+            self.kanidm_cache_hits.clone(),
+        );
+        r.register(
+            "kanidm_cache_misses",
+            "Number of Kanidm reads not found in the cache of recently read objects",
+            self.kanidm_cache_misses.clone(),
+        );
+        r.register(
+            "objects",
+            "Number of objects per kind and sync state, based on their status",
+            self.objects.clone(),
+        );
+        r.register(
+            "reconcile_queue_depth",
+            "Number of objects triggered for reconcile whose reconcile has not started yet",
+            self.reconcile_queue_depth.clone(),
+        );
         self
     }
 
@@ -183,12 +276,128 @@ impl ControllerMetrics {
             .inc();
     }
 
+    pub fn reload_triggers_coalesced_inc(&self) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.reload_triggers_coalesced
+            .get_or_create(&controller_labels)
+            .inc();
+    }
+
+    pub fn reload_triggers_dropped_inc(&self) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.reload_triggers_dropped
+            .get_or_create(&controller_labels)
+            .inc();
+    }
+
+    pub fn store_objects_set(&self, kind: &str, objects: usize) {
+        let store_labels = StoreLabels {
+            controller: self.controller.clone(),
+            kind: kind.to_string(),
+        };
+        self.store_objects
+            .get_or_create(&store_labels)
+            .set(objects as i64);
+    }
+
+    pub fn finalizer_cleanup_failures_inc(&self, kind: &str) {
+        let store_labels = StoreLabels {
+            controller: self.controller.clone(),
+            kind: kind.to_string(),
+        };
+        self.finalizer_cleanup_failures
+            .get_or_create(&store_labels)
+            .inc();
+    }
+
     pub fn ready_set(&self, status: i64) {
         let controller_labels = ControllerLabels {
             controller: self.controller.clone(),
         };
         self.ready.get_or_create(&controller_labels).set(status);
     }
+
+    pub fn kanidm_request_wait_duration_observe(&self, seconds: f64) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.kanidm_request_wait_duration
+            .get_or_create(&controller_labels)
+            .observe(seconds);
+    }
+
+    pub fn drift_corrected_inc(&self) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.drift_corrected.get_or_create(&controller_labels).inc();
+    }
+
+    pub fn kanidm_cache_hits_inc(&self) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.kanidm_cache_hits
+            .get_or_create(&controller_labels)
+            .inc();
+    }
+
+    pub fn kanidm_cache_misses_inc(&self) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.kanidm_cache_misses
+            .get_or_create(&controller_labels)
+            .inc();
+    }
+
+    /// Track the object as waiting for the reconcile triggered by a watch event.
+    pub fn reconcile_queued(&self, namespace: &str, name: &str) {
+        // safe unwrap: the lock is never held while panicking
+        let mut queued = self.queued.lock().unwrap();
+        queued.insert((namespace.to_string(), name.to_string()));
+        self.reconcile_queue_depth_set(queued.len());
+    }
+
+    /// Stop tracking the object as waiting, because its reconcile has started.
+    pub fn reconcile_dequeued(&self, namespace: &str, name: &str) {
+        // safe unwrap: the lock is never held while panicking
+        let mut queued = self.queued.lock().unwrap();
+        queued.remove(&(namespace.to_string(), name.to_string()));
+        self.reconcile_queue_depth_set(queued.len());
+    }
+
+    fn reconcile_queue_depth_set(&self, depth: usize) {
+        let controller_labels = ControllerLabels {
+            controller: self.controller.clone(),
+        };
+        self.reconcile_queue_depth
+            .get_or_create(&controller_labels)
+            .set(depth as i64);
+    }
+
+    /// Set the number of objects of the kind per sync state, from the state of each object.
+    pub fn objects_set<I>(&self, kind: &str, object_states: I)
+    where
+        I: IntoIterator<Item = ObjectState>,
+    {
+        let object_states = object_states.into_iter().collect::<Vec<_>>();
+        for state in [ObjectState::Synced, ObjectState::Error] {
+            let objects = object_states.iter().filter(|s| **s == state).count();
+            let object_state_labels = ObjectStateLabels {
+                controller: self.controller.clone(),
+                kind: kind.to_string(),
+                state,
+            };
+            self.objects
+                .get_or_create(&object_state_labels)
+                .set(objects as i64);
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -259,6 +468,45 @@ pub struct ResourceLabels {
     pub name: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StoreLabels {
+    pub controller: String,
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ObjectStateLabels {
+    pub controller: String,
+    pub kind: String,
+    pub state: ObjectState,
+}
+
+/// Sync state of an object: synced when its status is ready, error otherwise.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ObjectState {
+    Synced,
+    Error,
+}
+
+impl From<bool> for ObjectState {
+    fn from(ready: bool) -> Self {
+        if ready {
+            ObjectState::Synced
+        } else {
+            ObjectState::Error
+        }
+    }
+}
+
+impl EncodeLabelValue for ObjectState {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        match self {
+            ObjectState::Synced => encoder.write_str("synced"),
+            ObjectState::Error => encoder.write_str("error"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct TriggeredLabels {
     pub controller: String,
@@ -271,3 +519,112 @@ pub enum Action {
     Apply,
     Delete,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(registry: &Registry) -> String {
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, registry).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_store_objects_set() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["kanidm"]);
+        let controller_metrics = metrics.controllers.get("kanidm").unwrap();
+
+        controller_metrics.store_objects_set("Secret", 3);
+        assert!(encode(&metrics.registry)
+            .contains(r#"kaniop_store_objects{controller="kanidm",kind="Secret"} 3"#));
+
+        controller_metrics.store_objects_set("Secret", 1);
+        assert!(encode(&metrics.registry)
+            .contains(r#"kaniop_store_objects{controller="kanidm",kind="Secret"} 1"#));
+    }
+
+    #[test]
+    fn test_finalizer_cleanup_failures_inc() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["group"]);
+        let controller_metrics = metrics.controllers.get("group").unwrap();
+
+        controller_metrics.finalizer_cleanup_failures_inc("KanidmGroup");
+        controller_metrics.finalizer_cleanup_failures_inc("KanidmGroup");
+        assert!(encode(&metrics.registry).contains(
+            r#"kaniop_finalizer_cleanup_failures_total{controller="group",kind="KanidmGroup"} 2"#
+        ));
+    }
+
+    #[test]
+    fn test_objects_set() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["oauth2"]);
+        let controller_metrics = metrics.controllers.get("oauth2").unwrap();
+        let objects = |state: &str, count: usize| {
+            format!(
+                r#"kaniop_objects{{controller="oauth2",kind="KanidmOAuth2Client",state="{state}"}} {count}"#
+            )
+        };
+
+        controller_metrics.objects_set(
+            "KanidmOAuth2Client",
+            [ObjectState::Synced, ObjectState::Error],
+        );
+        let encoded = encode(&metrics.registry);
+        assert!(encoded.contains(&objects("synced", 1)));
+        assert!(encoded.contains(&objects("error", 1)));
+
+        controller_metrics.objects_set(
+            "KanidmOAuth2Client",
+            [ObjectState::Synced, ObjectState::Synced],
+        );
+        let encoded = encode(&metrics.registry);
+        assert!(encoded.contains(&objects("synced", 2)));
+        assert!(encoded.contains(&objects("error", 0)));
+
+        controller_metrics.objects_set("KanidmOAuth2Client", []);
+        assert!(encode(&metrics.registry).contains(&objects("synced", 0)));
+    }
+
+    #[test]
+    fn test_reconcile_queue_depth() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["group"]);
+        let controller_metrics = metrics.controllers.get("group").unwrap();
+        let depth =
+            |depth: usize| format!(r#"kaniop_reconcile_queue_depth{{controller="group"}} {depth}"#);
+
+        controller_metrics.reconcile_queued("default", "admins");
+        controller_metrics.reconcile_queued("default", "users");
+        controller_metrics.reconcile_queued("default", "users");
+        assert!(encode(&metrics.registry).contains(&depth(2)));
+
+        controller_metrics.reconcile_dequeued("default", "users");
+        assert!(encode(&metrics.registry).contains(&depth(1)));
+
+        controller_metrics.reconcile_dequeued("default", "unknown");
+        assert!(encode(&metrics.registry).contains(&depth(1)));
+    }
+
+    #[test]
+    fn test_drift_corrected_inc() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["kanidm"]);
+        let controller_metrics = metrics.controllers.get("kanidm").unwrap();
+
+        controller_metrics.drift_corrected_inc();
+        assert!(encode(&metrics.registry)
+            .contains(r#"kaniop_drift_corrected_total{controller="kanidm"} 1"#));
+    }
+
+    #[test]
+    fn test_kanidm_cache_inc() {
+        let metrics = Metrics::new(Registry::with_prefix("kaniop"), &["oauth2"]);
+        let controller_metrics = metrics.controllers.get("oauth2").unwrap();
+
+        controller_metrics.kanidm_cache_misses_inc();
+        controller_metrics.kanidm_cache_hits_inc();
+        controller_metrics.kanidm_cache_hits_inc();
+        let encoded = encode(&metrics.registry);
+        assert!(encoded.contains(r#"kaniop_kanidm_cache_hits_total{controller="oauth2"} 2"#));
+        assert!(encoded.contains(r#"kaniop_kanidm_cache_misses_total{controller="oauth2"} 1"#));
+    }
+}
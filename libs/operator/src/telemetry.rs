@@ -1,16 +1,21 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use opentelemetry::trace::{TraceError, TraceId, TracerProvider as _};
-use opentelemetry::KeyValue;
+use opentelemetry::trace::{
+    Link, SamplingResult, SpanKind, TraceError, TraceId, TracerProvider as _,
+};
+use opentelemetry::{Context, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::trace::{RandomIdGenerator, Sampler, TracerProvider};
+use opentelemetry_sdk::trace::{RandomIdGenerator, Sampler, ShouldSample, TracerProvider};
 use opentelemetry_sdk::Resource;
 use serde::Serialize;
 use thiserror::Error;
 use tracing::dispatcher::SetGlobalDefaultError;
 use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::ParseError;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::{EnvFilter, Registry};
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
 /// An error type representing various issues that can occur during tracing initialization.
 #[derive(Error, Debug)]
@@ -22,6 +27,113 @@ pub enum Error {
     /// Error encountered when setting the global tracing subscriber.
     #[error("SetGlobalDefaultError: {0}")]
     SetGlobalDefaultError(#[source] SetGlobalDefaultError),
+
+    /// Error encountered when parsing a logging filter directive.
+    #[error("InvalidLogFilter: {0}")]
+    InvalidLogFilter(#[source] ParseError),
+
+    /// Error encountered when replacing the logging filter of the tracing subscriber.
+    #[error("ReloadError: {0}")]
+    ReloadError(#[source] reload::Error),
+
+    /// Error encountered when setting a sampling ratio out of the `0.0..=1.0` range.
+    #[error("InvalidSampleRatio: {0} is not between 0.0 and 1.0")]
+    InvalidSampleRatio(f64),
+}
+
+/// Handles to change the telemetry configuration at runtime, returned by [`init`].
+#[derive(Clone, Debug)]
+pub struct TelemetryHandles {
+    pub log_filter: LogFilterHandle,
+    pub sample_ratio: SampleRatioHandle,
+}
+
+/// Handle to change the logging filter at runtime, returned by [`init`].
+#[derive(Clone, Debug)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Current logging filter directives.
+    pub fn get(&self) -> Result<String, Error> {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .map_err(Error::ReloadError)
+    }
+
+    /// Replace the logging filter with the given directives, e.g. "info,kube=debug". Directives
+    /// are validated before applying them, keeping the current filter if any is invalid.
+    pub fn set(&self, log_filter: &str) -> Result<(), Error> {
+        let filter = log_filter_directives(log_filter)?;
+        self.0.reload(filter).map_err(Error::ReloadError)
+    }
+}
+
+/// Handle to change the sampling ratio of traces at runtime. Shared with the sampler installed
+/// in the tracer provider, so changes apply to the next sampled spans.
+#[derive(Clone, Debug)]
+pub struct SampleRatioHandle(Arc<AtomicU64>);
+
+impl SampleRatioHandle {
+    fn new(ratio: f64) -> Self {
+        Self(Arc::new(AtomicU64::new(ratio.to_bits())))
+    }
+
+    /// Current sampling ratio.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Replace the sampling ratio, which has to be between `0.0` and `1.0`.
+    pub fn set(&self, ratio: f64) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(Error::InvalidSampleRatio(ratio));
+        }
+        self.0.store(ratio.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Trace ID ratio based sampler reading its ratio from a [`SampleRatioHandle`].
+#[derive(Clone, Debug)]
+struct ReloadableRatioSampler(SampleRatioHandle);
+
+impl ShouldSample for ReloadableRatioSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        Sampler::TraceIdRatioBased(self.0.get()).should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+        )
+    }
+}
+
+fn log_filter_directives(log_filter: &str) -> Result<EnvFilter, Error> {
+    EnvFilter::try_new(log_filter)
+        .map(with_default_directives)
+        .map_err(Error::InvalidLogFilter)
+}
+
+fn with_default_directives(filter: EnvFilter) -> EnvFilter {
+    // Safe unwrap: kanidm_client=error is a valid filter directive
+    filter.add_directive("kanidm_client=error".parse().unwrap())
+}
+
+/// Reloadable logging filter layer, with the handle to change it. Invalid directives are
+/// ignored, so a typo in the startup filter does not keep the operator from running.
+fn log_filter_layer(log_filter: &str) -> (reload::Layer<EnvFilter, Registry>, LogFilterHandle) {
+    let (layer, handle) = reload::Layer::new(with_default_directives(EnvFilter::new(log_filter)));
+    (layer, LogFilterHandle(handle))
 }
 
 /// Fetches the current `opentelemetry::trace::TraceId` as a hexadecimal string.
@@ -101,23 +213,24 @@ pub enum LogFormat {
 /// The function sets a global tracing subscriber using the combination of
 /// the [`tracing_subscriber`] logger and optionally the OpenTelemetry tracer if enabled.
 ///
-/// If the tracing subsystem is successfully configured, the function returns
-/// `Ok(())`, otherwise an appropriate error is returned.
+/// If the tracing subsystem is successfully configured, the function returns the
+/// [`TelemetryHandles`] to change the logging filter and the sampling ratio at runtime, otherwise
+/// an appropriate error is returned.
 pub async fn init(
     log_filter: &str,
     log_format: LogFormat,
     tracing_url: Option<&str>,
     trace_ratio: f64,
-) -> Result<(), Error> {
+) -> Result<TelemetryHandles, Error> {
     let logger = match log_format {
         LogFormat::Json => tracing_subscriber::fmt::layer().json().compact().boxed(),
         LogFormat::Text => tracing_subscriber::fmt::layer().compact().boxed(),
     };
 
-    // Safe unwrap: log_filter is a valid filter directive
-    let filter = EnvFilter::new(log_filter).add_directive("kanidm_client=error".parse().unwrap());
+    let (filter, log_filter_handle) = log_filter_layer(log_filter);
+    let sample_ratio_handle = SampleRatioHandle::new(trace_ratio);
 
-    let collector = Registry::default().with(logger).with(filter);
+    let collector = Registry::default().with(filter).with(logger);
 
     if let Some(url) = tracing_url {
         let exporter = opentelemetry_otlp::SpanExporter::builder()
@@ -128,7 +241,7 @@ pub async fn init(
             .map_err(Error::TraceError)?;
 
         let provider = TracerProvider::builder()
-            .with_sampler(Sampler::TraceIdRatioBased(trace_ratio))
+            .with_sampler(ReloadableRatioSampler(sample_ratio_handle.clone()))
             .with_id_generator(RandomIdGenerator::default())
             .with_max_events_per_span(64)
             .with_max_attributes_per_span(16)
@@ -140,14 +253,99 @@ pub async fn init(
 
         let telemetry = OpenTelemetryLayer::new(tracer);
         tracing::subscriber::set_global_default(collector.with(telemetry))
-            .map_err(Error::SetGlobalDefaultError)
+            .map_err(Error::SetGlobalDefaultError)?;
     } else {
-        tracing::subscriber::set_global_default(collector).map_err(Error::SetGlobalDefaultError)
+        tracing::subscriber::set_global_default(collector).map_err(Error::SetGlobalDefaultError)?;
     }
+    Ok(TelemetryHandles {
+        log_filter: log_filter_handle,
+        sample_ratio: sample_ratio_handle,
+    })
 }
 
-#[cfg(all(test, feature = "integration-test"))]
+#[cfg(test)]
 mod test {
+    use super::*;
+
+    use opentelemetry::trace::SamplingDecision;
+    use tracing::Level;
+
+    #[test]
+    fn test_log_filter_handle_set() {
+        let (filter, handle) = log_filter_layer("info");
+        let subscriber = Registry::default().with(filter);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(target: "kube", Level::INFO));
+            assert!(!tracing::enabled!(target: "kube", Level::DEBUG));
+
+            handle.set("info,kube=debug").unwrap();
+            assert!(tracing::enabled!(target: "kube", Level::DEBUG));
+            assert!(!tracing::enabled!(target: "kaniop", Level::DEBUG));
+            assert!(!tracing::enabled!(target: "kanidm_client", Level::WARN));
+            assert!(handle.get().unwrap().contains("kube=debug"));
+        });
+    }
+
+    #[test]
+    fn test_sample_ratio_handle_set() {
+        let handle = SampleRatioHandle::new(0.0);
+        let sampler = ReloadableRatioSampler(handle.clone());
+        let is_sampled = |sampler: &ReloadableRatioSampler| {
+            let result = sampler.should_sample(
+                None,
+                TraceId::from_bytes((u128::MAX / 2).to_be_bytes()),
+                "test",
+                &SpanKind::Internal,
+                &[],
+                &[],
+            );
+            result.decision == SamplingDecision::RecordAndSample
+        };
+        assert!(!is_sampled(&sampler));
+
+        handle.set(1.0).unwrap();
+        assert_eq!(handle.get(), 1.0);
+        assert!(is_sampled(&sampler));
+    }
+
+    #[test]
+    fn test_sample_ratio_handle_set_invalid() {
+        let handle = SampleRatioHandle::new(0.1);
+        assert!(matches!(handle.set(1.5), Err(Error::InvalidSampleRatio(_))));
+        assert!(matches!(
+            handle.set(-0.1),
+            Err(Error::InvalidSampleRatio(_))
+        ));
+        assert!(matches!(
+            handle.set(f64::NAN),
+            Err(Error::InvalidSampleRatio(_))
+        ));
+        assert_eq!(handle.get(), 0.1);
+    }
+
+    #[test]
+    fn test_log_filter_handle_set_invalid() {
+        let (_filter, handle) = log_filter_layer("info,kube=debug");
+        assert!(matches!(
+            handle.set("kube=notalevel"),
+            Err(Error::InvalidLogFilter(_))
+        ));
+        assert!(handle.get().unwrap().contains("kube=debug"));
+    }
+
+    #[test]
+    fn test_log_filter_layer_ignores_invalid_directives() {
+        let (filter, _handle) = log_filter_layer("info,kube=notalevel");
+        let subscriber = Registry::default().with(filter);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(target: "kube", Level::INFO));
+            assert!(!tracing::enabled!(target: "kube", Level::DEBUG));
+        });
+    }
+}
+
+#[cfg(all(test, feature = "integration-test"))]
+mod integration_test {
     // This test only works when telemetry is initialized fully
     // and requires OPENTELEMETRY_ENDPOINT_URL pointing to a valid server
     #[tokio::test]
@@ -6,7 +6,8 @@ use kaniop_operator::backoff_reconciler;
 use kaniop_operator::controller::{
     check_api_queryable,
     context::{BackoffContext, Context as KaniopContext, IdmClientContext},
-    ControllerId, State, DEFAULT_RECONCILE_INTERVAL,
+    reconcile_once, reconcile_watcher, ControllerId, ReconcileErrors, State,
+    DEFAULT_RECONCILE_INTERVAL,
 };
 use kaniop_operator::error::{Error, Result};
 use kaniop_operator::metrics::ControllerMetrics;
@@ -15,10 +16,10 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use futures::StreamExt;
+use kube::api::Api;
 use kube::client::Client;
 use kube::runtime::controller::{self, Controller};
-use kube::runtime::reflector::ObjectRef;
-use kube::runtime::watcher;
+use kube::runtime::reflector::{self, ObjectRef};
 use time::OffsetDateTime;
 use tokio::sync::RwLock;
 use tokio::time::Duration;
@@ -54,6 +55,10 @@ impl BackoffContext<KanidmPersonAccount> for Context {
     async fn reset_backoff(&self, obj_ref: ObjectRef<KanidmPersonAccount>) {
         self.kaniop_ctx.reset_backoff(obj_ref).await
     }
+
+    async fn publish_terminal_error(&self, obj: &KanidmPersonAccount, error: &Error) {
+        self.kaniop_ctx.publish_terminal_error(obj, error).await
+    }
 }
 
 impl IdmClientContext<KanidmPersonAccount> for Context {
@@ -83,7 +88,9 @@ pub async fn run(state: State, client: Client) {
     info!(msg = format!("starting {CONTROLLER_ID} controller"));
     // TODO: watcher::Config::default().streaming_lists() when stabilized in K8s
     // https://kubernetes.io/docs/reference/using-api/api-concepts/#streaming-lists
-    let person_controller = Controller::new(person, watcher::Config::default().any_semantic())
+    let (person_store, person_writer) = reflector::store();
+    let person_watcher = reconcile_watcher(person, person_writer, ctx.kaniop_ctx.metrics.clone());
+    let person_controller = Controller::for_stream(person_watcher, person_store)
         // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
         .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
         .shutdown_on_signal()
@@ -101,3 +108,14 @@ pub async fn run(state: State, client: Client) {
         _ = cleanup_expired_tokens(ctx.clone()) => {},
     }
 }
+
+/// Reconcile every person account once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let ctx = Arc::new(Context::new(
+        state.to_context(client.clone(), CONTROLLER_ID),
+    ));
+    reconcile_once(&Api::<KanidmPersonAccount>::all(client), |person| {
+        reconcile_person_account(person, ctx.clone())
+    })
+    .await
+}
@@ -1,6 +1,5 @@
 use kaniop_k8s_util::types::{get_first_cloned, parse_time};
-use kaniop_operator::controller::kanidm::KanidmResource;
-use kaniop_operator::crd::{KanidmPersonPosixAttributes, KanidmRef};
+use kaniop_operator::crd::{KanidmPersonPosixAttributes, KanidmRef, KanidmResource, ReadyStatus};
 
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use kanidm_proto::constants::{
@@ -27,6 +26,7 @@ use serde::{Deserialize, Serialize};
     plural = "kanidmpersonsaccounts",
     singular = "kanidmpersonaccount",
     shortname = "person",
+    category = "kaniop",
     namespaced,
     status = "KanidmPersonAccountStatus",
     doc = r#"The Kanidm person account custom resource definition (CRD) defines a person account in Kanidm.
@@ -45,6 +45,23 @@ pub struct KanidmPersonAccountSpec {
     /// If omitted, the operator retains the attributes in the database but ceases to manage them.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub posix_attributes: Option<KanidmPersonPosixAttributes>,
+
+    /// Name or SPN of the groups that this person is a direct member of. Only applied when
+    /// `manageGroups` is enabled.
+    ///
+    /// Group membership can also be managed from the `members` field of a `KanidmGroup`. Do not
+    /// manage the same group from both sides: each reconciliation overwrites the other one, so the
+    /// last writer wins. The operator emits a warning event when it detects this situation, for
+    /// `KanidmGroup` resources of any namespace that reference the same Kanidm.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+
+    /// Reconcile the group membership of the person with the `groups` field, adding the person to
+    /// the listed groups and removing it from any other group it is a direct member of. Without
+    /// `groups`, the person is removed from all of them. Dynamic groups, like `idm_all_persons`,
+    /// are never modified.
+    #[serde(default)]
+    pub manage_groups: bool,
 }
 
 impl KanidmResource for KanidmPersonAccount {
@@ -59,6 +76,13 @@ impl KanidmResource for KanidmPersonAccount {
     }
 }
 
+impl ReadyStatus for KanidmPersonAccount {
+    #[inline]
+    fn ready(&self) -> Option<bool> {
+        self.status.as_ref().map(|status| status.ready)
+    }
+}
+
 /// Attributes that personally identify a person account.
 ///
 /// The attributes defined here are set by the operator. If you want to manage those attributes
@@ -72,7 +96,7 @@ pub struct KanidmPersonAttributes {
     pub displayname: String,
 
     /// Set the mail address, can be set multiple times for multiple addresses. The first listed
-    /// mail address is the 'primary'.
+    /// mail address is the 'primary'. Setting an empty list will clear the mail attribute.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mail: Option<Vec<String>>,
 
@@ -96,9 +120,9 @@ pub struct KanidmPersonAttributes {
 impl PartialEq for KanidmPersonAttributes {
     /// Compare attributes defined in the first object with the second object values.
     /// If the second object has more attributes defined, they will be ignored.
+    /// Mail addresses are not compared: they are tracked by their own condition.
     fn eq(&self, other: &Self) -> bool {
         self.displayname == other.displayname
-            && (self.mail.is_none() || self.mail == other.mail)
             && (self.legalname.is_none() || self.legalname == other.legalname)
             && (self.account_valid_from.is_none()
                 || self.account_valid_from == other.account_valid_from)
@@ -128,6 +152,8 @@ pub struct KanidmPersonAccountStatus {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub conditions: Option<Vec<Condition>>,
 
+    /// The person exists and all its conditions, except `PosixInitialized`, `Credential` and
+    /// `Valid`, are true.
     pub ready: bool,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
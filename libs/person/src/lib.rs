@@ -1,4 +1,6 @@
+#[cfg(feature = "client")]
 pub mod controller;
 #[rustfmt::skip]
 pub mod crd;
+#[cfg(feature = "client")]
 pub mod reconcile;
@@ -1,32 +1,40 @@
 use crate::controller::Context;
 use crate::crd::{KanidmPersonAccount, KanidmPersonAccountStatus, KanidmPersonAttributes};
 
+use kaniop_group::crd::KanidmGroup;
+use kaniop_k8s_util::resources::is_status_unchanged;
+use kaniop_k8s_util::types::{diff_set, normalize_spn};
 use kaniop_operator::controller::kanidm::KanidmResource;
-use kaniop_operator::controller::{context::IdmClientContext, DEFAULT_RECONCILE_INTERVAL};
+use kaniop_operator::controller::{
+    context::{out_of_sync_conditions, IdmClientContext},
+    DEFAULT_RECONCILE_INTERVAL,
+};
 use kaniop_operator::crd::KanidmPersonPosixAttributes;
 use kaniop_operator::error::{Error, Result};
 use kaniop_operator::telemetry;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Not;
 use std::sync::Arc;
 use std::time::Duration;
 
 use futures::TryFutureExt;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
-use k8s_openapi::chrono::Utc;
+use k8s_openapi::chrono::{DateTime, Utc};
 use kanidm_client::{ClientError, KanidmClient};
-use kanidm_proto::constants::{ATTR_ACCOUNT_EXPIRE, ATTR_ACCOUNT_VALID_FROM};
+use kanidm_proto::constants::{
+    ATTR_ACCOUNT_EXPIRE, ATTR_ACCOUNT_VALID_FROM, ATTR_DIRECTMEMBEROF, ATTR_MAIL,
+};
 use kanidm_proto::v1::Entry;
-use kube::api::{Api, Patch, PatchParams};
+use kube::api::{Api, ListParams, Patch, PatchParams};
 use kube::runtime::controller::Action;
 use kube::runtime::events::{Event, EventType};
 use kube::runtime::finalizer::{finalizer, Event as Finalizer};
 use kube::runtime::reflector::ObjectRef;
-use kube::{Resource, ResourceExt};
+use kube::ResourceExt;
 use time::format_description::well_known::Rfc3339;
 use time::{OffsetDateTime, UtcOffset};
-use tracing::{debug, field, info, instrument, trace, warn, Span};
+use tracing::{debug, field, info, instrument, trace, Span};
 
 pub static PERSON_OPERATOR_NAME: &str = "kanidmpersonsaccounts.kaniop.rs";
 pub static PERSON_FINALIZER: &str = "kanidms.kaniop.rs/person";
@@ -35,6 +43,8 @@ const DEFAULT_RESET_TOKEN_TTL: u32 = 3600;
 const TYPE_CREDENTIAL: &str = "Credential";
 const TYPE_EXISTS: &str = "Exists";
 const TYPE_UPDATED: &str = "Updated";
+const TYPE_MAIL_UPDATED: &str = "MailUpdated";
+const TYPE_GROUPS_UPDATED: &str = "GroupsUpdated";
 const TYPE_POSIX_INITIALIZED: &str = "PosixInitialized";
 const TYPE_POSIX_UPDATED: &str = "PosixUpdated";
 const TYPE_VALIDITY: &str = "Valid";
@@ -42,6 +52,17 @@ const REASON_ATTRIBUTES_MATCH: &str = "AttributesMatch";
 const REASON_ATTRIBUTES_NOT_MATCH: &str = "AttributesNotMatch";
 const CONDITION_TRUE: &str = "True";
 const CONDITION_FALSE: &str = "False";
+/// Groups whose members are computed by Kanidm, membership cannot be modified.
+const DYNAMIC_GROUPS: [&str; 2] = ["idm_all_persons", "idm_all_accounts"];
+
+/// Conditions that reconcile fixes when they are false
+const SYNC_CONDITIONS: [&str; 5] = [
+    TYPE_EXISTS,
+    TYPE_UPDATED,
+    TYPE_MAIL_UPDATED,
+    TYPE_POSIX_UPDATED,
+    TYPE_GROUPS_UPDATED,
+];
 
 #[instrument(skip(ctx, person))]
 pub async fn reconcile_person_account(
@@ -57,21 +78,36 @@ pub async fn reconcile_person_account(
     info!(msg = "reconciling person account");
 
     let namespace = person.get_namespace();
-    let kanidm_client = ctx.get_idm_client(&person).await?;
-    let status = person
-        .update_status(kanidm_client.clone(), ctx.clone())
-        .await
-        .map_err(|e| {
-            debug!(msg = "failed to reconcile status", %e);
-            ctx.kaniop_ctx.metrics.status_update_errors_inc();
-            e
-        })?;
     let persons_api: Api<KanidmPersonAccount> =
         Api::namespaced(ctx.kaniop_ctx.client.clone(), &namespace);
+    let (kanidm_client, status) = match person.client_and_status(ctx.clone()).await {
+        Ok(client_and_status) => client_and_status,
+        // a deleted or unreachable Kanidm must not block the deletion forever
+        Err(e) if person.metadata.deletion_timestamp.is_some() => {
+            return ctx
+                .kaniop_ctx
+                .cleanup_unavailable(&persons_api, PERSON_FINALIZER, person, e)
+                .await
+                .map_err(|e| {
+                    Error::FinalizerError(
+                        "failed on person account finalizer".to_string(),
+                        Box::new(e),
+                    )
+                });
+        }
+        Err(e) => return Err(e),
+    };
     finalizer(&persons_api, PERSON_FINALIZER, person, |event| async {
         match event {
             Finalizer::Apply(p) => p.reconcile(kanidm_client, status, ctx).await,
-            Finalizer::Cleanup(p) => p.cleanup(kanidm_client, status, ctx).await,
+            Finalizer::Cleanup(p) => {
+                let result = ctx
+                    .kaniop_ctx
+                    .kanidm_api_limiter(&p)
+                    .run(p.cleanup(kanidm_client, status, ctx.clone()))
+                    .await;
+                ctx.kaniop_ctx.cleanup_with_grace(&p, result).await
+            }
         }
     })
     .await
@@ -90,6 +126,22 @@ impl KanidmPersonAccount {
         self.namespace().unwrap()
     }
 
+    async fn client_and_status(
+        &self,
+        ctx: Arc<Context>,
+    ) -> Result<(Arc<KanidmClient>, KanidmPersonAccountStatus)> {
+        let kanidm_client = ctx.get_idm_client(self).await?;
+        let status = self
+            .update_status(kanidm_client.clone(), ctx.clone())
+            .await
+            .map_err(|e| {
+                debug!(msg = "failed to reconcile status", %e);
+                ctx.kaniop_ctx.metrics.status_update_errors_inc();
+                e
+            })?;
+        Ok((kanidm_client, status))
+    }
+
     #[inline]
     async fn reconcile(
         &self,
@@ -105,22 +157,17 @@ impl KanidmPersonAccount {
             Err(e) => match e {
                 Error::KanidmClientError(_, _) => {
                     ctx.kaniop_ctx
-                        .recorder
-                        .publish(
-                            &Event {
+                        .publish_event(
+                            self,
+                            Event {
                                 type_: EventType::Warning,
                                 reason: "KanidmError".to_string(),
                                 note: Some(format!("{e:?}")),
                                 action: "KanidmRequest".to_string(),
                                 secondary: None,
                             },
-                            &self.object_ref(&()),
                         )
-                        .await
-                        .map_err(|e| {
-                            warn!(msg = "failed to publish KanidmError event", %e);
-                            Error::KubeError("failed to publish event".to_string(), e)
-                        })?;
+                        .await?;
                     Err(e)
                 }
                 _ => Err(e),
@@ -135,14 +182,21 @@ impl KanidmPersonAccount {
         ctx: Arc<Context>,
     ) -> Result<Action> {
         let name = &self.name_any();
+        // stages send their requests to Kanidm one at a time
+        let limiter = ctx.kaniop_ctx.kanidm_api_limiter(self);
 
         let mut require_status_update = false;
         if is_person_false(TYPE_EXISTS, status.clone()) {
-            self.create(&kanidm_client, name).await?;
+            limiter.run(self.create(&kanidm_client, name)).await?;
             require_status_update = true;
         }
         if is_person_false(TYPE_UPDATED, status.clone()) {
-            self.update(&kanidm_client, name).await?;
+            limiter.run(self.update(&kanidm_client, name)).await?;
+            require_status_update = true;
+        }
+
+        if is_person_false(TYPE_MAIL_UPDATED, status.clone()) {
+            limiter.run(self.update_mail(&kanidm_client, name)).await?;
             require_status_update = true;
         }
 
@@ -150,7 +204,16 @@ impl KanidmPersonAccount {
             || (is_person_false(TYPE_POSIX_INITIALIZED, status.clone())
                 && is_person(TYPE_POSIX_UPDATED, status.clone()))
         {
-            self.update_posix_attributes(&kanidm_client, name).await?;
+            limiter
+                .run(self.update_posix_attributes(&kanidm_client, name))
+                .await?;
+            require_status_update = true;
+        }
+
+        if is_person_false(TYPE_GROUPS_UPDATED, status.clone()) {
+            limiter
+                .run(self.update_groups(&kanidm_client, name, ctx.clone()))
+                .await?;
             require_status_update = true;
         }
 
@@ -163,7 +226,9 @@ impl KanidmPersonAccount {
                 _ => true,
             };
             if create_token {
-                self.create_reset_token(&kanidm_client, name, ctx).await?;
+                limiter
+                    .run(self.create_reset_token(&kanidm_client, name, ctx))
+                    .await?;
             };
         };
 
@@ -202,7 +267,7 @@ impl KanidmPersonAccount {
                 None,
                 Some(&self.spec.person_attributes.displayname),
                 self.spec.person_attributes.legalname.as_deref(),
-                self.spec.person_attributes.mail.as_deref(),
+                None,
             )
             .await
             .map_err(|e| {
@@ -215,22 +280,7 @@ impl KanidmPersonAccount {
                     Box::new(e),
                 )
             })?;
-        let mut update_entry = Entry {
-            attrs: BTreeMap::new(),
-        };
-        if let Some(account_expire) = self.spec.person_attributes.account_expire.as_ref() {
-            update_entry.attrs.insert(
-                ATTR_ACCOUNT_EXPIRE.to_string(),
-                vec![account_expire.0.to_rfc3339()],
-            );
-        }
-        if let Some(account_valid_from) = self.spec.person_attributes.account_valid_from.as_ref() {
-            update_entry.attrs.insert(
-                ATTR_ACCOUNT_VALID_FROM.to_string(),
-                vec![account_valid_from.0.to_rfc3339()],
-            );
-        }
-
+        let update_entry = validity_entry(&self.spec.person_attributes);
         if update_entry.attrs.is_empty().not() {
             let _: Entry = kanidm_client
                 .perform_patch_request(&format!("/v1/person/{}", name), update_entry)
@@ -249,6 +299,48 @@ impl KanidmPersonAccount {
         Ok(())
     }
 
+    async fn update_mail(&self, kanidm_client: &KanidmClient, name: &str) -> Result<()> {
+        debug!(msg = format!("update {ATTR_MAIL} attribute"));
+        let mail = self
+            .spec
+            .person_attributes
+            .mail
+            .as_ref()
+            .ok_or_else(|| Error::MissingData("person mail is not defined".to_string()))?;
+
+        if mail.is_empty() {
+            kanidm_client
+                .idm_person_account_purge_attr(name, ATTR_MAIL)
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to purge {ATTR_MAIL} for {name} from {namespace}/{kanidm}",
+                            namespace = self.kanidm_namespace(),
+                            kanidm = self.kanidm_name(),
+                        ),
+                        Box::new(e),
+                    )
+                })?;
+        } else {
+            let mail = mail.iter().map(String::as_str).collect::<Vec<_>>();
+            kanidm_client
+                .idm_person_account_set_attr(name, ATTR_MAIL, &mail)
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to update {ATTR_MAIL} for {name} from {namespace}/{kanidm}",
+                            namespace = self.kanidm_namespace(),
+                            kanidm = self.kanidm_name(),
+                        ),
+                        Box::new(e),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
     async fn update_posix_attributes(
         &self,
         kanidm_client: &KanidmClient,
@@ -282,6 +374,112 @@ impl KanidmPersonAccount {
         Ok(())
     }
 
+    async fn update_groups(
+        &self,
+        kanidm_client: &KanidmClient,
+        name: &str,
+        ctx: Arc<Context>,
+    ) -> Result<()> {
+        debug!(msg = "update groups");
+        let groups = self.spec.groups.clone().unwrap_or_default();
+
+        let groups_api = Api::<KanidmGroup>::all(ctx.kaniop_ctx.client.clone());
+        let kanidm_groups = groups_api
+            .list(&ListParams::default())
+            .await
+            .map_err(|e| Error::KubeError("failed to list KanidmGroups".to_string(), e))?;
+        let conflicting_groups = self.conflicting_groups(&kanidm_groups.items);
+        if !conflicting_groups.is_empty() {
+            ctx.kaniop_ctx
+                .publish_event(
+                    self,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "GroupMembershipConflict".to_string(),
+                        note: Some(format!(
+                            "members of groups {} are also managed by KanidmGroup resources",
+                            conflicting_groups.join(", ")
+                        )),
+                        action: "UpdateGroups".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await?;
+        }
+
+        let current_groups = kanidm_client
+            .idm_person_account_get(name)
+            .await
+            .map_err(|e| {
+                Error::KanidmClientError(
+                    format!(
+                        "failed to get {name} from {namespace}/{kanidm}",
+                        namespace = self.kanidm_namespace(),
+                        kanidm = self.kanidm_name(),
+                    ),
+                    Box::new(e),
+                )
+            })?
+            .and_then(|p| p.attrs.get(ATTR_DIRECTMEMBEROF).cloned())
+            .unwrap_or_default();
+
+        let (add_groups, remove_groups) = groups_diff(&groups, &current_groups);
+        trace!(msg = format!("add to groups {add_groups:?}, remove from groups {remove_groups:?}"));
+        for group in add_groups.iter() {
+            kanidm_client
+                .idm_group_add_members(group, &[name])
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to add {name} to group {group} from {namespace}/{kanidm}",
+                            namespace = self.kanidm_namespace(),
+                            kanidm = self.kanidm_name(),
+                        ),
+                        Box::new(e),
+                    )
+                })?;
+        }
+        for group in remove_groups.iter() {
+            kanidm_client
+                .idm_group_remove_members(group, &[name])
+                .await
+                .map_err(|e| {
+                    Error::KanidmClientError(
+                        format!(
+                            "failed to remove {name} from group {group} from {namespace}/{kanidm}",
+                            namespace = self.kanidm_namespace(),
+                            kanidm = self.kanidm_name(),
+                        ),
+                        Box::new(e),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Groups declared in the person that also have their members managed by a `KanidmGroup` of
+    /// the same Kanidm cluster, in any namespace.
+    fn conflicting_groups(&self, kanidm_groups: &[KanidmGroup]) -> Vec<String> {
+        let groups = self
+            .spec
+            .groups
+            .iter()
+            .flatten()
+            .map(|g| normalize_spn(g))
+            .collect::<BTreeSet<_>>();
+        kanidm_groups
+            .iter()
+            .filter(|g| {
+                g.kanidm_name() == self.kanidm_name()
+                    && g.kanidm_namespace() == self.kanidm_namespace()
+                    && g.spec.members.is_some()
+            })
+            .map(|g| normalize_spn(&g.name_any()))
+            .filter(|g| groups.contains(g))
+            .collect()
+    }
+
     async fn create_reset_token(
         &self,
         kanidm_client: &KanidmClient,
@@ -324,22 +522,17 @@ impl KanidmPersonAccount {
                 .expect("Failed to format date time!!!")
         );
         ctx.kaniop_ctx
-            .recorder
-            .publish(
-                &Event {
+            .publish_event(
+                self,
+                Event {
                     type_: EventType::Normal,
                     reason: "TokenCreated".to_string(),
                     note: Some(msg),
                     action: "CreateUpdateCredentialsToken".into(),
                     secondary: None,
                 },
-                &self.object_ref(&()),
             )
-            .await
-            .map_err(|e| {
-                warn!(msg = "failed to publish TokenCreated event", %e);
-                Error::KubeError("failed to publish event".to_string(), e)
-            })?;
+            .await?;
         ctx.internal_cache
             .write()
             .await
@@ -387,8 +580,9 @@ impl KanidmPersonAccount {
         // safe unwrap: person is namespaced scoped
         let namespace = self.get_namespace();
         let name = self.name_any();
-        let current_person = kanidm_client
-            .idm_person_account_get(&name)
+        let limiter = ctx.kaniop_ctx.kanidm_api_limiter(self);
+        let current_person = limiter
+            .run(kanidm_client.idm_person_account_get(&name))
             .map_err(|e| {
                 Error::KanidmClientError(
                     format!(
@@ -400,8 +594,8 @@ impl KanidmPersonAccount {
                 )
             })
             .await?;
-        let credential_present = match kanidm_client
-            .idm_person_account_get_credential_status(&name)
+        let credential_present = match limiter
+            .run(kanidm_client.idm_person_account_get_credential_status(&name))
             .await
         {
             Ok(cs) => Some(cs.creds.is_empty().not()),
@@ -409,7 +603,21 @@ impl KanidmPersonAccount {
             Err(_) => None,
         };
 
-        let status = self.generate_status(current_person, credential_present)?;
+        let mut status = self.generate_status(current_person, credential_present)?;
+        if let Some(stalled) = ctx
+            .kaniop_ctx
+            .stalled_condition(
+                self,
+                out_of_sync_conditions(status.conditions.as_deref(), &SYNC_CONDITIONS),
+            )
+            .await
+        {
+            status.conditions.get_or_insert_with(Vec::new).push(stalled);
+        }
+        if is_status_unchanged(self.status.as_ref(), &status) {
+            trace!(msg = "status unchanged, skipping patch");
+            return Ok(status);
+        }
         let status_patch = Patch::Apply(KanidmPersonAccount {
             status: Some(status.clone()),
             ..KanidmPersonAccount::default()
@@ -470,6 +678,57 @@ impl KanidmPersonAccount {
                     }
                 };
 
+                let mail_condition = self.spec.person_attributes.mail.as_ref().map(|mail| {
+                    if is_mail_updated(mail, p.attrs.get(ATTR_MAIL).unwrap_or(&Vec::new())) {
+                        Condition {
+                            type_: TYPE_MAIL_UPDATED.to_string(),
+                            status: CONDITION_TRUE.to_string(),
+                            reason: REASON_ATTRIBUTES_MATCH.to_string(),
+                            message: format!("Person exists with desired {ATTR_MAIL} attribute."),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    } else {
+                        Condition {
+                            type_: TYPE_MAIL_UPDATED.to_string(),
+                            status: CONDITION_FALSE.to_string(),
+                            reason: REASON_ATTRIBUTES_NOT_MATCH.to_string(),
+                            message: format!("Person exists with different {ATTR_MAIL} attribute."),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    }
+                });
+
+                let groups_condition = self.spec.manage_groups.then(|| {
+                    let groups = self.spec.groups.as_deref().unwrap_or_default();
+                    let current_groups = p
+                        .attrs
+                        .get(ATTR_DIRECTMEMBEROF)
+                        .cloned()
+                        .unwrap_or_default();
+                    let (add_groups, remove_groups) = groups_diff(groups, &current_groups);
+                    if add_groups.is_empty() && remove_groups.is_empty() {
+                        Condition {
+                            type_: TYPE_GROUPS_UPDATED.to_string(),
+                            status: CONDITION_TRUE.to_string(),
+                            reason: REASON_ATTRIBUTES_MATCH.to_string(),
+                            message: "Person is member of the desired groups.".to_string(),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    } else {
+                        Condition {
+                            type_: TYPE_GROUPS_UPDATED.to_string(),
+                            status: CONDITION_FALSE.to_string(),
+                            reason: REASON_ATTRIBUTES_NOT_MATCH.to_string(),
+                            message: "Person is member of different groups.".to_string(),
+                            last_transition_time: Time(now),
+                            observed_generation: self.metadata.generation,
+                        }
+                    }
+                });
+
                 let current_person_posix = KanidmPersonPosixAttributes::from(p);
                 let posix_initialized_condition = if current_person_posix.gidnumber.is_some() {
                     Condition {
@@ -536,19 +795,7 @@ impl KanidmPersonAccount {
                 });
 
                 let validity_condition = {
-                    let valid = if let Some(valid_from) =
-                        current_person_attributes.account_valid_from.as_ref()
-                    {
-                        now > valid_from.0
-                    } else {
-                        true
-                    } && if let Some(expire) =
-                        current_person_attributes.account_expire.as_ref()
-                    {
-                        now < expire.0
-                    } else {
-                        true
-                    };
+                    let valid = is_account_valid(&current_person_attributes, now);
 
                     if valid {
                         Condition {
@@ -578,7 +825,9 @@ impl KanidmPersonAccount {
                 ]
                 .into_iter()
                 .chain(credentials_condition)
+                .chain(mail_condition)
                 .chain(posix_updated_condition)
+                .chain(groups_condition)
                 .collect::<Vec<_>>();
                 let status = conditions
                     .iter()
@@ -615,6 +864,65 @@ impl KanidmPersonAccount {
     }
 }
 
+/// Returns the groups the person has to be added to and removed from, respectively, to match the
+/// desired groups. Dynamic groups are ignored.
+fn groups_diff(desired: &[String], current: &[String]) -> (BTreeSet<String>, BTreeSet<String>) {
+    let desired = desired
+        .iter()
+        .map(|g| normalize_spn(g))
+        .filter(|g| !DYNAMIC_GROUPS.contains(&g.as_str()))
+        .collect::<BTreeSet<_>>();
+    let current = current
+        .iter()
+        .map(|g| normalize_spn(g))
+        .filter(|g| !DYNAMIC_GROUPS.contains(&g.as_str()))
+        .collect::<BTreeSet<_>>();
+    let (to_add, to_remove) = diff_set(&current, &desired);
+    (
+        to_add.into_iter().cloned().collect(),
+        to_remove.into_iter().cloned().collect(),
+    )
+}
+
+/// Entry patching the account validity attributes defined in the person attributes.
+fn validity_entry(attributes: &KanidmPersonAttributes) -> Entry {
+    let mut entry = Entry {
+        attrs: BTreeMap::new(),
+    };
+    if let Some(account_expire) = attributes.account_expire.as_ref() {
+        entry.attrs.insert(
+            ATTR_ACCOUNT_EXPIRE.to_string(),
+            vec![account_expire.0.to_rfc3339()],
+        );
+    }
+    if let Some(account_valid_from) = attributes.account_valid_from.as_ref() {
+        entry.attrs.insert(
+            ATTR_ACCOUNT_VALID_FROM.to_string(),
+            vec![account_valid_from.0.to_rfc3339()],
+        );
+    }
+    entry
+}
+
+/// Whether the account is valid at `now`. Accounts without validity attributes are always valid.
+fn is_account_valid(attributes: &KanidmPersonAttributes, now: DateTime<Utc>) -> bool {
+    attributes
+        .account_valid_from
+        .as_ref()
+        .map_or(true, |valid_from| now > valid_from.0)
+        && attributes
+            .account_expire
+            .as_ref()
+            .map_or(true, |expire| now < expire.0)
+}
+
+/// Whether the current mail addresses match the desired ones. The first address is the primary
+/// one and has to match, but Kanidm does not keep the order of the remaining addresses.
+fn is_mail_updated(desired: &[String], current: &[String]) -> bool {
+    desired.first() == current.first()
+        && desired.iter().collect::<BTreeSet<_>>() == current.iter().collect::<BTreeSet<_>>()
+}
+
 pub fn is_person(type_: &str, status: KanidmPersonAccountStatus) -> bool {
     status
         .conditions
@@ -630,3 +938,251 @@ pub fn is_person_false(type_: &str, status: KanidmPersonAccountStatus) -> bool {
         .iter()
         .any(|c| c.type_ == type_ && c.status == CONDITION_FALSE)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        groups_diff, is_account_valid, is_mail_updated, is_person_false, validity_entry,
+        KanidmPersonAccount, TYPE_GROUPS_UPDATED,
+    };
+
+    use crate::crd::{KanidmPersonAccountSpec, KanidmPersonAttributes};
+
+    use kaniop_group::crd::{KanidmGroup, KanidmGroupSpec};
+    use kaniop_operator::crd::KanidmRef;
+
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::{DateTime, Utc};
+    use kanidm_proto::constants::{
+        ATTR_ACCOUNT_EXPIRE, ATTR_ACCOUNT_VALID_FROM, ATTR_DIRECTMEMBEROF,
+    };
+    use kanidm_proto::v1::Entry;
+    use kube::api::ObjectMeta;
+
+    fn kanidm_ref() -> KanidmRef {
+        KanidmRef {
+            name: "my-idm".to_string(),
+            namespace: None,
+        }
+    }
+
+    fn person(groups: &[&str]) -> KanidmPersonAccount {
+        KanidmPersonAccount {
+            metadata: ObjectMeta {
+                name: Some("me".to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            spec: KanidmPersonAccountSpec {
+                kanidm_ref: kanidm_ref(),
+                groups: Some(groups.iter().map(|g| g.to_string()).collect()),
+                manage_groups: true,
+                ..Default::default()
+            },
+            status: None,
+        }
+    }
+
+    fn group(name: &str, members: Option<Vec<String>>) -> KanidmGroup {
+        KanidmGroup {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            spec: KanidmGroupSpec {
+                kanidm_ref: kanidm_ref(),
+                members,
+                ..Default::default()
+            },
+            status: None,
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_groups_diff_add() {
+        let (add, remove) = groups_diff(
+            &strings(&["admins", "users"]),
+            &strings(&["users@idm.example.com", "idm_all_persons@idm.example.com"]),
+        );
+        assert_eq!(add, BTreeSet::from(["admins".to_string()]));
+        assert!(remove.is_empty());
+    }
+
+    #[test]
+    fn test_groups_diff_remove() {
+        let (add, remove) = groups_diff(
+            &strings(&["users"]),
+            &strings(&[
+                "users@idm.example.com",
+                "admins@idm.example.com",
+                "idm_all_accounts@idm.example.com",
+            ]),
+        );
+        assert!(add.is_empty());
+        assert_eq!(remove, BTreeSet::from(["admins".to_string()]));
+    }
+
+    #[test]
+    fn test_groups_diff_ignores_dynamic_groups() {
+        let (add, remove) = groups_diff(
+            &strings(&["idm_all_persons"]),
+            &strings(&["idm_all_accounts@idm.example.com"]),
+        );
+        assert!(add.is_empty());
+        assert!(remove.is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_groups() {
+        let person = person(&["admins", "users@idm.example.com", "ops"]);
+        let mut other_kanidm_group = group("ops", Some(strings(&["me"])));
+        other_kanidm_group.spec.kanidm_ref.name = "other-idm".to_string();
+        let groups = vec![
+            group("admins", Some(strings(&["me"]))),
+            group("users", Some(Vec::new())),
+            group("devs", Some(strings(&["me"]))),
+            other_kanidm_group,
+        ];
+        assert_eq!(
+            person.conflicting_groups(&groups),
+            strings(&["admins", "users"])
+        );
+    }
+
+    #[test]
+    fn test_conflicting_groups_without_group_members() {
+        let person = person(&["admins"]);
+        let groups = vec![group("admins", None)];
+        assert!(person.conflicting_groups(&groups).is_empty());
+    }
+
+    #[test]
+    fn test_groups_updated_without_groups_removes_all_memberships() {
+        let mut person = person(&[]);
+        person.spec.groups = None;
+        let entry = Entry {
+            attrs: BTreeMap::from([(
+                ATTR_DIRECTMEMBEROF.to_string(),
+                strings(&["admins@idm.example.com", "idm_all_persons@idm.example.com"]),
+            )]),
+        };
+        let status = person.generate_status(Some(entry), None).unwrap();
+        assert!(is_person_false(TYPE_GROUPS_UPDATED, status));
+
+        person.spec.manage_groups = false;
+        let entry = Entry {
+            attrs: BTreeMap::from([(
+                ATTR_DIRECTMEMBEROF.to_string(),
+                strings(&["admins@idm.example.com"]),
+            )]),
+        };
+        let status = person.generate_status(Some(entry), None).unwrap();
+        assert!(!is_person_false(TYPE_GROUPS_UPDATED, status));
+    }
+
+    fn mail(addresses: &[&str]) -> Vec<String> {
+        addresses.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_mail_updated_primary_order() {
+        let desired = mail(&["me@example.com", "alias@example.com", "other@example.com"]);
+
+        assert!(is_mail_updated(&desired, &desired));
+        assert!(is_mail_updated(
+            &desired,
+            &mail(&["me@example.com", "other@example.com", "alias@example.com"])
+        ));
+        assert!(!is_mail_updated(
+            &desired,
+            &mail(&["alias@example.com", "me@example.com", "other@example.com"])
+        ));
+        assert!(!is_mail_updated(
+            &desired,
+            &mail(&["me@example.com", "alias@example.com"])
+        ));
+    }
+
+    #[test]
+    fn test_is_mail_updated_clear() {
+        assert!(is_mail_updated(&[], &[]));
+        assert!(!is_mail_updated(&[], &mail(&["me@example.com"])));
+        assert!(!is_mail_updated(&mail(&["me@example.com"]), &[]));
+    }
+
+    fn time(rfc3339: &str) -> Time {
+        Time(
+            DateTime::parse_from_rfc3339(rfc3339)
+                .unwrap()
+                .with_timezone(&Utc),
+        )
+    }
+
+    #[test]
+    fn test_validity_entry() {
+        let attributes = KanidmPersonAttributes {
+            displayname: "Me".to_string(),
+            account_valid_from: Some(time("2024-01-01T00:00:00Z")),
+            account_expire: Some(time("2025-01-01T00:00:00Z")),
+            ..KanidmPersonAttributes::default()
+        };
+
+        let entry = validity_entry(&attributes);
+
+        assert_eq!(
+            entry.attrs.get(ATTR_ACCOUNT_VALID_FROM),
+            Some(&vec!["2024-01-01T00:00:00+00:00".to_string()])
+        );
+        assert_eq!(
+            entry.attrs.get(ATTR_ACCOUNT_EXPIRE),
+            Some(&vec!["2025-01-01T00:00:00+00:00".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_validity_entry_unset() {
+        let attributes = KanidmPersonAttributes {
+            displayname: "Me".to_string(),
+            ..KanidmPersonAttributes::default()
+        };
+
+        assert!(validity_entry(&attributes).attrs.is_empty());
+    }
+
+    #[test]
+    fn test_is_account_valid() {
+        let now = time("2024-06-01T00:00:00Z").0;
+        let attributes = |valid_from: Option<&str>, expire: Option<&str>| KanidmPersonAttributes {
+            displayname: "Me".to_string(),
+            account_valid_from: valid_from.map(time),
+            account_expire: expire.map(time),
+            ..KanidmPersonAttributes::default()
+        };
+
+        assert!(is_account_valid(&attributes(None, None), now));
+        assert!(is_account_valid(
+            &attributes(Some("2024-01-01T00:00:00Z"), Some("2025-01-01T00:00:00Z")),
+            now
+        ));
+        assert!(!is_account_valid(
+            &attributes(Some("2024-07-01T00:00:00Z"), None),
+            now
+        ));
+        assert!(!is_account_valid(
+            &attributes(None, Some("2024-05-01T00:00:00Z")),
+            now
+        ));
+        // expiry cleared in Kanidm
+        assert!(is_account_valid(
+            &attributes(Some("2024-01-01T00:00:00Z"), None),
+            now
+        ));
+    }
+}
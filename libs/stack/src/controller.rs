@@ -0,0 +1,68 @@
+use crate::crd::KanidmStack;
+use crate::reconcile::{reconcile_stack, STACK_LABEL};
+
+use kaniop_group::crd::KanidmGroup;
+use kaniop_oauth2::crd::KanidmOAuth2Client;
+use kaniop_operator::backoff_reconciler;
+use kaniop_operator::controller::{
+    check_api_queryable, error_policy, reconcile_once, ControllerId, ReconcileErrors, State,
+};
+use kaniop_operator::error::Result;
+use kaniop_operator::kanidm::crd::Kanidm;
+use kaniop_person::crd::KanidmPersonAccount;
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+use kube::api::Api;
+use kube::client::Client;
+use kube::runtime::controller::{self, Controller};
+use kube::runtime::watcher;
+use tokio::time::Duration;
+use tracing::info;
+
+pub const CONTROLLER_ID: ControllerId = "stack";
+
+/// Initialize Kanidm stack controller and shared state
+pub async fn run(state: State, client: Client) {
+    let stack = check_api_queryable::<KanidmStack>(client.clone()).await;
+
+    let ctx = Arc::new(state.to_context(client.clone(), CONTROLLER_ID));
+    // children are labeled with the name of their stack
+    let children_config = watcher::Config::default().labels(STACK_LABEL);
+
+    info!(msg = format!("starting {CONTROLLER_ID} controller"));
+    let stack_controller = Controller::new(stack, watcher::Config::default().any_semantic())
+        .owns(Api::<Kanidm>::all(client.clone()), children_config.clone())
+        .owns(
+            Api::<KanidmGroup>::all(client.clone()),
+            children_config.clone(),
+        )
+        .owns(
+            Api::<KanidmPersonAccount>::all(client.clone()),
+            children_config.clone(),
+        )
+        .owns(Api::<KanidmOAuth2Client>::all(client), children_config)
+        // debounce to filter out reconcile calls that happen quick succession (only taking the latest)
+        .with_config(controller::Config::default().debounce(Duration::from_millis(500)))
+        .shutdown_on_signal()
+        .run(
+            backoff_reconciler!(reconcile_stack),
+            error_policy,
+            ctx.clone(),
+        )
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    ctx.metrics.ready_set(1);
+    tokio::join!(stack_controller);
+}
+
+/// Reconcile every stack once and return the errors of the failed ones.
+pub async fn run_once(state: State, client: Client) -> Result<ReconcileErrors> {
+    let ctx = Arc::new(state.to_context(client.clone(), CONTROLLER_ID));
+    reconcile_once(&Api::<KanidmStack>::all(client), |stack| {
+        reconcile_stack(stack, ctx.clone())
+    })
+    .await
+}
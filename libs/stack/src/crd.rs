@@ -0,0 +1,141 @@
+use kaniop_group::crd::KanidmGroupSpec;
+use kaniop_oauth2::crd::KanidmOAuth2ClientSpec;
+use kaniop_operator::kanidm::crd::KanidmSpec;
+use kaniop_person::crd::KanidmPersonAccountSpec;
+
+use std::marker::PhantomData;
+
+use kube::CustomResource;
+#[cfg(feature = "schemars")]
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{ArrayValidation, InstanceType, ObjectValidation, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+
+/// A stack is a Kanidm cluster together with the groups, person accounts and OAuth2 clients
+/// defined in it. The operator creates and owns every resource of the stack, removing the ones
+/// that are no longer listed.
+/// More info:
+/// https://github.com/kubernetes/community/blob/master/contributors/devel/sig-architecture/api-conventions.md#spec-and-status
+#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[kube(
+    group = "kaniop.rs",
+    version = "v1beta1",
+    kind = "KanidmStack",
+    plural = "kanidmstacks",
+    singular = "kanidmstack",
+    shortname = "kstack",
+    category = "kaniop",
+    namespaced,
+    doc = r#"The Kanidm stack custom resource definition (CRD) defines a Kanidm cluster and the
+    resources managed in it. Every resource is created in the namespace of the stack, named as
+    defined in the stack, and with its `kanidmRef` pointing to the stack Kanidm."#,
+    printcolumn = r#"{"name":"Domain","type":"string","jsonPath":".spec.kanidm.domain"}"#,
+    derive = "Default"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmStackSpec {
+    /// Kanidm cluster of the stack. It is named as the stack.
+    pub kanidm: KanidmSpec,
+
+    /// Groups defined in the stack Kanidm.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "schemars", schemars(schema_with = "groups_schema"))]
+    pub groups: Vec<KanidmStackResource<KanidmGroupSpec>>,
+
+    /// Person accounts defined in the stack Kanidm.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "schemars", schemars(schema_with = "person_accounts_schema"))]
+    pub person_accounts: Vec<KanidmStackResource<KanidmPersonAccountSpec>>,
+
+    /// OAuth2 clients defined in the stack Kanidm.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "schemars", schemars(schema_with = "oauth2_clients_schema"))]
+    pub oauth2_clients: Vec<KanidmStackResource<KanidmOAuth2ClientSpec>>,
+}
+
+/// Resource of the stack, with the spec of its kind without `kanidmRef`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KanidmStackResource<S> {
+    /// Name of the resource. It has to be unique for its kind in the namespace.
+    pub name: String,
+
+    /// Spec of the resource. `kanidmRef` is set by the operator to the stack Kanidm.
+    pub spec: serde_json::Value,
+
+    #[serde(skip)]
+    pub spec_type: PhantomData<S>,
+}
+
+impl<S> KanidmStackResource<S> {
+    pub fn new(name: &str, spec: serde_json::Value) -> Self {
+        Self {
+            name: name.to_string(),
+            spec,
+            spec_type: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "schemars")]
+fn groups_schema(gen: &mut SchemaGenerator) -> Schema {
+    resources_schema::<KanidmGroupSpec>(gen)
+}
+
+#[cfg(feature = "schemars")]
+fn person_accounts_schema(gen: &mut SchemaGenerator) -> Schema {
+    resources_schema::<KanidmPersonAccountSpec>(gen)
+}
+
+#[cfg(feature = "schemars")]
+fn oauth2_clients_schema(gen: &mut SchemaGenerator) -> Schema {
+    resources_schema::<KanidmOAuth2ClientSpec>(gen)
+}
+
+/// Schema of a list of [`KanidmStackResource`] with the child spec `S` without `kanidmRef`.
+#[cfg(feature = "schemars")]
+fn resources_schema<S: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut spec = S::json_schema(gen);
+    if let Schema::Object(spec_object) = &mut spec {
+        if let Some(object) = spec_object.object.as_mut() {
+            object.properties.remove("kanidmRef");
+            object.required.remove("kanidmRef");
+        }
+        spec_object.metadata().description = Some(
+            "Spec of the resource. `kanidmRef` is set by the operator to the stack Kanidm."
+                .to_string(),
+        );
+    }
+    let mut name = gen.subschema_for::<String>().into_object();
+    name.metadata().description = Some(
+        "Name of the resource. It has to be unique for its kind in the namespace.".to_string(),
+    );
+    let resource = SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        object: Some(Box::new(ObjectValidation {
+            properties: [
+                ("name".to_string(), Schema::Object(name)),
+                ("spec".to_string(), spec),
+            ]
+            .into_iter()
+            .collect(),
+            required: ["name".to_string(), "spec".to_string()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    Schema::Object(SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::Object(resource).into()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
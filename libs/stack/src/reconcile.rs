@@ -0,0 +1,301 @@
+use crate::crd::{KanidmStack, KanidmStackResource};
+
+use kaniop_group::crd::KanidmGroup;
+use kaniop_k8s_util::resources::controller_owner_references;
+use kaniop_k8s_util::types::short_type_name;
+use kaniop_oauth2::crd::KanidmOAuth2Client;
+use kaniop_operator::controller::{context::Context, DEFAULT_RECONCILE_INTERVAL};
+use kaniop_operator::error::{Error, Result};
+use kaniop_operator::kanidm::crd::Kanidm;
+use kaniop_operator::telemetry;
+use kaniop_person::crd::KanidmPersonAccount;
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use futures::future::try_join_all;
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::{Api, DeleteParams, ListParams, ObjectMeta, Patch, PatchParams};
+use kube::client::Client;
+use kube::runtime::controller::Action;
+use kube::{Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, field, info, instrument, Span};
+
+pub static STACK_OPERATOR_NAME: &str = "kanidmstacks.kaniop.rs";
+/// Label with the name of the stack that owns the resource.
+pub const STACK_LABEL: &str = "kaniop.rs/stack";
+
+#[instrument(skip(ctx, stack))]
+pub async fn reconcile_stack(
+    stack: Arc<KanidmStack>,
+    ctx: Arc<Context<KanidmStack>>,
+) -> Result<Action> {
+    let trace_id = telemetry::get_trace_id();
+    Span::current().record("trace_id", field::display(&trace_id));
+    let _timer = ctx.metrics.reconcile_count_and_measure(&trace_id);
+    info!(msg = "reconciling stack");
+
+    // build every child before applying any of them, so an invalid spec changes nothing
+    let kanidm = stack.kanidm();
+    let groups = stack.children::<KanidmGroup, _>(&stack.spec.groups)?;
+    let person_accounts = stack.children::<KanidmPersonAccount, _>(&stack.spec.person_accounts)?;
+    let oauth2_clients = stack.children::<KanidmOAuth2Client, _>(&stack.spec.oauth2_clients)?;
+
+    stack.sync(ctx.client.clone(), vec![kanidm]).await?;
+    stack.sync(ctx.client.clone(), groups).await?;
+    stack.sync(ctx.client.clone(), person_accounts).await?;
+    stack.sync(ctx.client.clone(), oauth2_clients).await?;
+    Ok(Action::requeue(DEFAULT_RECONCILE_INTERVAL))
+}
+
+impl KanidmStack {
+    #[inline]
+    fn get_namespace(&self) -> String {
+        // safe unwrap: stack is namespaced scoped
+        self.namespace().unwrap()
+    }
+
+    #[inline]
+    pub fn stack_selector(&self) -> String {
+        format!("{STACK_LABEL}={}", self.name_any())
+    }
+
+    fn child_metadata(&self, name: &str) -> ObjectMeta {
+        ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(self.get_namespace()),
+            labels: Some([(STACK_LABEL.to_string(), self.name_any())].into()),
+            owner_references: controller_owner_references(self),
+            ..ObjectMeta::default()
+        }
+    }
+
+    /// Kanidm of the stack, named as the stack.
+    pub fn kanidm(&self) -> Kanidm {
+        Kanidm {
+            metadata: self.child_metadata(&self.name_any()),
+            spec: self.spec.kanidm.clone(),
+            status: None,
+        }
+    }
+
+    /// Resources of kind `K` defined in the stack, with `kanidmRef` pointing to the stack Kanidm.
+    pub fn children<K, S>(&self, resources: &[KanidmStackResource<S>]) -> Result<Vec<K>>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        resources
+            .iter()
+            .map(|resource| {
+                let mut spec = resource.spec.clone();
+                if let Some(spec) = spec.as_object_mut() {
+                    spec.insert("kanidmRef".to_string(), json!({"name": self.name_any()}));
+                }
+                serde_json::from_value(json!({
+                    "apiVersion": K::api_version(&()),
+                    "kind": K::kind(&()),
+                    "metadata": self.child_metadata(&resource.name),
+                    "spec": spec,
+                }))
+                .map_err(|e| {
+                    Error::ValidationError(format!(
+                        "invalid {} {} in stack: {e}",
+                        K::kind(&()),
+                        resource.name
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// Apply the `desired` resources of kind `K` and delete the ones of the stack that are not
+    /// desired anymore.
+    async fn sync<K>(&self, client: Client, desired: Vec<K>) -> Result<()>
+    where
+        K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+            + Clone
+            + Debug
+            + DeserializeOwned
+            + Serialize,
+    {
+        let namespace = self.get_namespace();
+        let api = Api::<K>::namespaced(client, &namespace);
+        let api_ref = &api;
+        let namespace_ref = &namespace;
+        try_join_all(desired.iter().map(|obj| async move {
+            let name = obj.name_any();
+            debug!(msg = format!("applying {}", short_type_name::<K>()), %name);
+            api_ref
+                .patch(
+                    &name,
+                    &PatchParams::apply(STACK_OPERATOR_NAME).force(),
+                    &Patch::Apply(obj),
+                )
+                .await
+                .map_err(|e| {
+                    Error::KubeError(
+                        format!(
+                            "failed to patch {} {namespace_ref}/{name}",
+                            short_type_name::<K>()
+                        ),
+                        e,
+                    )
+                })
+        }))
+        .await?;
+
+        let current = api
+            .list(&ListParams::default().labels(&self.stack_selector()))
+            .await
+            .map_err(|e| {
+                Error::KubeError(
+                    format!("failed to list {} in {namespace}", short_type_name::<K>()),
+                    e,
+                )
+            })?;
+        try_join_all(
+            orphan_names(&desired, &current.items)
+                .into_iter()
+                .map(|name| async move {
+                    info!(msg = format!("deleting {}", short_type_name::<K>()), %name);
+                    api_ref
+                        .delete(&name, &DeleteParams::default())
+                        .await
+                        .map_err(|e| {
+                            Error::KubeError(
+                                format!(
+                                    "failed to delete {} {namespace_ref}/{name}",
+                                    short_type_name::<K>()
+                                ),
+                                e,
+                            )
+                        })
+                }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Names of the `current` resources that are not `desired` anymore.
+pub fn orphan_names<K: Resource>(desired: &[K], current: &[K]) -> Vec<String> {
+    let desired_names = desired
+        .iter()
+        .map(|o| o.name_any())
+        .collect::<BTreeSet<_>>();
+    current
+        .iter()
+        .map(|o| o.name_any())
+        .filter(|name| !desired_names.contains(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{orphan_names, STACK_LABEL};
+
+    use crate::crd::{KanidmStack, KanidmStackResource, KanidmStackSpec};
+
+    use kaniop_group::crd::KanidmGroup;
+    use kaniop_oauth2::crd::KanidmOAuth2Client;
+    use kaniop_operator::kanidm::crd::KanidmSpec;
+
+    use kube::api::ObjectMeta;
+    use kube::ResourceExt;
+    use serde_json::json;
+
+    fn stack() -> KanidmStack {
+        KanidmStack {
+            metadata: ObjectMeta {
+                name: Some("idm".to_string()),
+                namespace: Some("default".to_string()),
+                uid: Some("e9f4f0f6-8b5f-4f36-9d8a-4a9d3c4a0f5e".to_string()),
+                ..ObjectMeta::default()
+            },
+            spec: KanidmStackSpec {
+                kanidm: KanidmSpec {
+                    domain: "idm.example.com".to_string(),
+                    ..KanidmSpec::default()
+                },
+                groups: vec![
+                    KanidmStackResource::new("admins", json!({"members": ["alice"]})),
+                    KanidmStackResource::new("users", json!({})),
+                ],
+                oauth2_clients: vec![KanidmStackResource::new(
+                    "grafana",
+                    json!({
+                        "displayname": "Grafana",
+                        "origin": "https://grafana.example.com",
+                        "redirectUrl": ["https://grafana.example.com/login/generic_oauth"],
+                    }),
+                )],
+                ..KanidmStackSpec::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_kanidm_owned_by_stack() {
+        let stack = stack();
+        let kanidm = stack.kanidm();
+        assert_eq!(kanidm.name_any(), "idm");
+        assert_eq!(kanidm.namespace(), Some("default".to_string()));
+        assert_eq!(kanidm.spec.domain, "idm.example.com");
+        assert_eq!(kanidm.labels()[STACK_LABEL], "idm");
+        let owner = &kanidm.owner_references()[0];
+        assert_eq!(owner.kind, "KanidmStack");
+        assert_eq!(owner.controller, Some(true));
+    }
+
+    #[test]
+    fn test_children_reference_stack_kanidm() {
+        let stack = stack();
+        let groups = stack
+            .children::<KanidmGroup, _>(&stack.spec.groups)
+            .unwrap();
+        assert_eq!(
+            groups.iter().map(|g| g.name_any()).collect::<Vec<_>>(),
+            vec!["admins", "users"]
+        );
+        assert!(groups.iter().all(|g| g.spec.kanidm_ref.name == "idm"));
+        assert!(groups.iter().all(|g| g.labels()[STACK_LABEL] == "idm"));
+        assert_eq!(groups[0].spec.members, Some(vec!["alice".to_string()]));
+
+        let oauth2_clients = stack
+            .children::<KanidmOAuth2Client, _>(&stack.spec.oauth2_clients)
+            .unwrap();
+        assert_eq!(oauth2_clients[0].name_any(), "grafana");
+        assert_eq!(oauth2_clients[0].spec.kanidm_ref.name, "idm");
+        assert_eq!(oauth2_clients[0].owner_references()[0].name, "idm");
+    }
+
+    #[test]
+    fn test_children_invalid_spec() {
+        let mut stack = stack();
+        stack.spec.oauth2_clients = vec![KanidmStackResource::new(
+            "grafana",
+            json!({"displayname": "Grafana"}),
+        )];
+        assert!(stack
+            .children::<KanidmOAuth2Client, _>(&stack.spec.oauth2_clients)
+            .is_err());
+    }
+
+    #[test]
+    fn test_orphan_names() {
+        let stack = stack();
+        let desired = stack
+            .children::<KanidmGroup, _>(&stack.spec.groups[..1])
+            .unwrap();
+        let current = stack
+            .children::<KanidmGroup, _>(&stack.spec.groups)
+            .unwrap();
+        assert_eq!(orphan_names(&desired, &current), vec!["users"]);
+        assert!(orphan_names(&current, &desired).is_empty());
+        assert_eq!(orphan_names(&[], &current), vec!["admins", "users"]);
+    }
+}
@@ -0,0 +1,213 @@
+use super::{setup_kanidm_connection, wait_for};
+
+use kaniop_account_policy::crd::KanidmAccountPolicy;
+use kaniop_group::crd::KanidmGroup;
+
+use std::collections::BTreeMap;
+
+use kube::api::{DeleteParams, Patch, PatchParams, PostParams};
+use kube::runtime::{conditions, wait::Condition};
+use kube::{Api, ResourceExt};
+use serde_json::json;
+
+const KANIDM_NAME: &str = "test-account-policy";
+
+fn check_account_policy_condition(
+    cond: &str,
+    status: String,
+) -> impl Condition<KanidmAccountPolicy> + '_ {
+    move |obj: Option<&KanidmAccountPolicy>| {
+        obj.and_then(|policy| policy.status.as_ref())
+            .and_then(|status| status.conditions.as_ref())
+            .map_or(false, |conditions| {
+                conditions
+                    .iter()
+                    .any(|c| c.type_ == cond && c.status == status)
+            })
+    }
+}
+
+fn is_account_policy(cond: &str) -> impl Condition<KanidmAccountPolicy> + '_ {
+    check_account_policy_condition(cond, "True".to_string())
+}
+
+fn is_account_policy_false(cond: &str) -> impl Condition<KanidmAccountPolicy> + '_ {
+    check_account_policy_condition(cond, "False".to_string())
+}
+
+fn is_account_policy_ready() -> impl Condition<KanidmAccountPolicy> {
+    move |obj: Option<&KanidmAccountPolicy>| {
+        obj.and_then(|policy| policy.status.as_ref())
+            .map_or(false, |status| status.ready)
+    }
+}
+
+fn first_attr(attrs: &BTreeMap<String, Vec<String>>, attr: &str) -> Option<String> {
+    attrs.get(attr).and_then(|values| values.first()).cloned()
+}
+
+#[tokio::test]
+async fn account_policy_lifecycle() {
+    let name = "test-account-policy-lifecycle";
+    let group_name = "test-account-policy-lifecycle-group";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+
+    let group_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+    });
+    let group = KanidmGroup::new(group_name, serde_json::from_value(group_spec).unwrap());
+    let group_api = Api::<KanidmGroup>::namespaced(s.client.clone(), "default");
+    group_api
+        .create(&PostParams::default(), &group)
+        .await
+        .unwrap();
+
+    let policy_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "group": group_name,
+        "authSessionExpiry": 3600,
+        "passwordMinimumLength": 12,
+        "credentialTypeMinimum": "mfa",
+    });
+    let mut policy = KanidmAccountPolicy::new(name, serde_json::from_value(policy_spec).unwrap());
+    let policy_api = Api::<KanidmAccountPolicy>::namespaced(s.client.clone(), "default");
+    policy_api
+        .create(&PostParams::default(), &policy)
+        .await
+        .unwrap();
+
+    wait_for(policy_api.clone(), name, is_account_policy("GroupExists")).await;
+    wait_for(policy_api.clone(), name, is_account_policy("Enabled")).await;
+    wait_for(policy_api.clone(), name, is_account_policy_ready()).await;
+
+    let policy_group = s
+        .kanidm_client
+        .idm_group_get(group_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(policy_group
+        .attrs
+        .get("class")
+        .unwrap()
+        .contains(&"account_policy".to_string()));
+    assert_eq!(
+        first_attr(&policy_group.attrs, "authsession_expiry"),
+        Some("3600".to_string())
+    );
+    assert_eq!(
+        first_attr(&policy_group.attrs, "auth_password_minimum_length"),
+        Some("12".to_string())
+    );
+    assert_eq!(
+        first_attr(&policy_group.attrs, "credential_type_minimum"),
+        Some("mfa".to_string())
+    );
+
+    // Update the policy
+    policy.spec.password_minimum_length = Some(16);
+    policy.spec.privilege_expiry = Some(600);
+    policy_api
+        .patch(
+            name,
+            &PatchParams::apply("e2e-test").force(),
+            &Patch::Apply(&policy),
+        )
+        .await
+        .unwrap();
+
+    wait_for(
+        policy_api.clone(),
+        name,
+        is_account_policy_false("PasswordMinimumLengthUpdated"),
+    )
+    .await;
+    wait_for(
+        policy_api.clone(),
+        name,
+        is_account_policy("PasswordMinimumLengthUpdated"),
+    )
+    .await;
+    wait_for(policy_api.clone(), name, is_account_policy_ready()).await;
+
+    let updated_group = s
+        .kanidm_client
+        .idm_group_get(group_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        first_attr(&updated_group.attrs, "auth_password_minimum_length"),
+        Some("16".to_string())
+    );
+    assert_eq!(
+        first_attr(&updated_group.attrs, "privilege_expiry"),
+        Some("600".to_string())
+    );
+
+    // Delete the policy
+    let policy_uid = policy_api.get(name).await.unwrap().uid().unwrap();
+    policy_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .unwrap();
+    wait_for(
+        policy_api.clone(),
+        name,
+        conditions::is_deleted(&policy_uid),
+    )
+    .await;
+
+    let cleaned_group = s
+        .kanidm_client
+        .idm_group_get(group_name)
+        .await
+        .unwrap()
+        .unwrap();
+    for attr in [
+        "authsession_expiry",
+        "privilege_expiry",
+        "auth_password_minimum_length",
+        "credential_type_minimum",
+    ] {
+        assert_eq!(first_attr(&cleaned_group.attrs, attr), None);
+    }
+}
+
+#[tokio::test]
+async fn account_policy_group_not_exists() {
+    let name = "test-account-policy-group-not-exists";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+
+    let policy_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "group": "test-account-policy-missing-group",
+        "passwordMinimumLength": 12,
+    });
+    let policy = KanidmAccountPolicy::new(name, serde_json::from_value(policy_spec).unwrap());
+    let policy_api = Api::<KanidmAccountPolicy>::namespaced(s.client.clone(), "default");
+    policy_api
+        .create(&PostParams::default(), &policy)
+        .await
+        .unwrap();
+
+    wait_for(
+        policy_api.clone(),
+        name,
+        is_account_policy_false("GroupExists"),
+    )
+    .await;
+
+    let policy_uid = policy_api.get(name).await.unwrap().uid().unwrap();
+    policy_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .unwrap();
+    wait_for(policy_api, name, conditions::is_deleted(&policy_uid)).await;
+}
@@ -593,3 +593,49 @@ async fn group_posix_attributes_collision() {
         .unwrap()
         .contains("duplicate value detected"));
 }
+
+#[tokio::test]
+async fn group_external_source_requires_one_source() {
+    let name = "test-group-external-source-requires-one-source";
+    let client = Client::try_default().await.unwrap();
+    let group_spec = |external_source: serde_json::Value| {
+        serde_json::from_value(json!({
+            "kanidmRef": {
+                "name": "test",
+            },
+            "externalSource": external_source,
+        }))
+        .unwrap()
+    };
+    let group_api = Api::<KanidmGroup>::namespaced(client.clone(), "default");
+
+    for external_source in [
+        json!({}),
+        json!({
+            "configMapRef": {"name": "members", "key": "members"},
+            "url": "https://scim.example.com/Groups/admins",
+        }),
+    ] {
+        let group = KanidmGroup::new(name, group_spec(external_source));
+        let result = group_api.create(&PostParams::default(), &group).await;
+
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Exactly one of configMapRef or url has to be defined in externalSource."));
+    }
+
+    let group = KanidmGroup::new(
+        name,
+        group_spec(json!({"configMapRef": {"name": "members", "key": "members"}})),
+    );
+    group_api
+        .create(&PostParams::default(), &group)
+        .await
+        .unwrap();
+    group_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .unwrap();
+}
@@ -19,6 +19,7 @@ use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Pod, Secret};
 use k8s_openapi::ByteString;
 use kube::api::{Api, LogParams, ObjectMeta, Patch, PatchParams, PostParams};
 use kube::client::Client;
+use kube::core::Request;
 use kube::runtime::wait::{conditions, Condition};
 use kube::ResourceExt;
 use serde_json::json;
@@ -32,6 +33,8 @@ static KANIDM_DEFAULT_SPEC_JSON: LazyLock<serde_json::Value> = LazyLock::new(||
         "domain": "idm.example.com",
         "image": format!("kanidm/server:{}", get_dependency_version().unwrap()),
         "replicaGroups": [{"name": DEFAULT_REPLICA_GROUP_NAME, "replicas": 1}],
+        // test certificate is not valid for the service hostname
+        "insecureSkipVerify": true,
     })
 });
 
@@ -446,6 +449,88 @@ async fn kanidm_change_domain() {
         .contains("Domain cannot be changed."));
 }
 
+#[tokio::test]
+async fn kanidm_domain_display_name() {
+    let name = "test-domain-display-name";
+    let s = setup(name, Some(json!({"domainDisplayName": "E2E IdM"}))).await;
+
+    wait_for(s.kanidm_api.clone(), name, is_kanidm("DomainConfigured")).await;
+}
+
+#[tokio::test]
+async fn kanidm_system_policy() {
+    let name = "test-system-policy";
+    let s = setup(
+        name,
+        Some(json!({"systemPolicy": {"passwordMinimumLength": 12}})),
+    )
+    .await;
+
+    wait_for(s.kanidm_api.clone(), name, is_kanidm("PolicyApplied")).await;
+
+    let kanidm_patch = json!({
+        "spec": {
+            "systemPolicy": {
+                "passwordMinimumLength": 16,
+                "passwordBadlist": ["e2e-bad-password"],
+            },
+        },
+    });
+    let kanidm = s
+        .kanidm_api
+        .patch(name, &PatchParams::default(), &Patch::Merge(&kanidm_patch))
+        .await
+        .unwrap();
+    let generation = kanidm.metadata.generation;
+    let is_policy_updated = move |obj: Option<&Kanidm>| {
+        obj.and_then(|kanidm| kanidm.status.as_ref())
+            .and_then(|status| status.conditions.as_ref())
+            .is_some_and(|conditions| {
+                conditions.iter().any(|c| {
+                    c.type_ == "PolicyApplied"
+                        && c.status == "True"
+                        && c.observed_generation == generation
+                })
+            })
+    };
+    wait_for(s.kanidm_api.clone(), name, is_policy_updated).await;
+}
+
+#[tokio::test]
+async fn kanidm_deprecated_ingress_class_annotation_warning() {
+    let client = Client::try_default().await.unwrap();
+    let mut kanidm_spec_json = KANIDM_DEFAULT_SPEC_JSON.clone();
+    let patch_ingress = json!({
+        "ingress": {"annotations": {"kubernetes.io/ingress.class": "nginx"}},
+    });
+    merge(&mut kanidm_spec_json, &patch_ingress);
+
+    let kanidm = Kanidm::new(
+        "test-deprecated-ingress-class",
+        serde_json::from_value(kanidm_spec_json).unwrap(),
+    );
+    let kanidm_api = Api::<Kanidm>::namespaced(client.clone(), "default");
+    let request = Request::new(kanidm_api.resource_url())
+        .create(
+            &PostParams {
+                dry_run: true,
+                ..PostParams::default()
+            },
+            serde_json::to_vec(&kanidm).unwrap(),
+        )
+        .unwrap();
+    let response = client
+        .send(request.map(kube::client::Body::from))
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success());
+    assert!(response.headers().get_all("warning").iter().any(|w| w
+        .to_str()
+        .unwrap()
+        .contains("Ingress annotation 'kubernetes.io/ingress.class' is deprecated")));
+}
+
 #[tokio::test]
 async fn kanidm_donwscale_to_zero() {
     let name = "test-downscale-to-zero";
@@ -320,6 +320,55 @@ async fn kanidm_delete_replica_group() {
     assert!(check_secret.is_err());
 }
 
+#[tokio::test]
+async fn kanidm_delete_last_write_replica_group() {
+    let name = "test-delete-last-write-replica-group";
+    let mut kanidm_path = json!({
+        "replicaGroups": [
+            {"name": "default", "replicas": 1},
+            {"name": "read-replica", "replicas": 1, "role": "read_only_replica"},
+        ],
+    });
+    let patch_storage = STORAGE_VOLUME_CLAIM_TEMPLATE_JSON.clone();
+
+    merge(&mut kanidm_path, &patch_storage);
+    let s = setup(name, Some(kanidm_path.clone())).await;
+    let mut kanidm = s.kanidm_api.get(name).await.unwrap();
+    kanidm.spec.replica_groups.remove(0);
+    kanidm.metadata.managed_fields = None;
+    let result = s
+        .kanidm_api
+        .patch(
+            name,
+            &PatchParams::apply("e2e-test").force(),
+            &Patch::Apply(&kanidm),
+        )
+        .await;
+
+    dbg!(&result);
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("At least one write replica group is required."));
+
+    kanidm.annotations_mut().insert(
+        "kaniop.rs/allow-write-replica-groups-removal".to_string(),
+        "true".to_string(),
+    );
+    let result = s
+        .kanidm_api
+        .patch(
+            name,
+            &PatchParams::apply("e2e-test").force(),
+            &Patch::Apply(&kanidm),
+        )
+        .await;
+
+    dbg!(&result);
+    assert!(result.is_ok());
+}
+
 #[tokio::test]
 async fn kanidm_replica_groups_one_primary_and_external_node_automatic_refresh() {
     let client = Client::try_default().await.unwrap();
@@ -1,7 +1,9 @@
+mod account_policy;
 mod group;
 mod kanidm;
 mod oauth2;
 mod person;
+mod stack;
 
 use std::ops::Not;
 use std::sync::Arc;
@@ -45,7 +47,7 @@ where
     .unwrap_or_else(|_| {
         eprintln!(
             "timeout waiting for {}/{name} to match condition",
-            short_type_name::<K>().unwrap_or("Unknown resource")
+            short_type_name::<K>()
         );
         panic!()
     })
@@ -9,7 +9,7 @@ use std::{collections::BTreeSet, ops::Not};
 use chrono::Utc;
 use k8s_openapi::api::core::v1::{Event, Secret};
 use kube::{
-    api::{ListParams, Patch, PatchParams, PostParams},
+    api::{DeleteParams, ListParams, Patch, PatchParams, PostParams},
     runtime::{conditions, wait::Condition},
     Api, Client, ResourceExt,
 };
@@ -118,6 +118,31 @@ async fn oauth2_create_no_idm() {
     assert!(oauth2_result.status.is_none());
 }
 
+#[tokio::test]
+async fn oauth2_invalid_origin() {
+    let client = Client::try_default().await.unwrap();
+
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Test OAuth2 Client",
+        "redirectUrl": [],
+        "origin": "not a url",
+    });
+    let oauth2 = KanidmOAuth2Client::new(
+        "test-invalid-origin",
+        serde_json::from_value(oauth2_spec).unwrap(),
+    );
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(client.clone(), "default");
+    let result = oauth2_api.create(&PostParams::default(), &oauth2).await;
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Origin must be a valid URL."));
+}
+
 #[tokio::test]
 async fn oauth2_update() {
     let name = "test-oauth2-update";
@@ -218,6 +243,67 @@ async fn oauth2_update() {
     );
 }
 
+#[tokio::test]
+async fn oauth2_adopt_existing() {
+    let name = "test-oauth2-adopt-existing";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    s.kanidm_client
+        .idm_oauth2_rs_basic_create(
+            name,
+            "Oauth2 Preexisting",
+            &format!("https://{name}.preexisting.com"),
+        )
+        .await
+        .unwrap();
+    let oauth2_preexisting = s.kanidm_client.idm_oauth2_rs_get(name).await.unwrap();
+    let uuid = oauth2_preexisting.unwrap().attrs.get("uuid").cloned();
+
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Adopt Existing",
+        "redirectUrl": [],
+        "origin": format!("https://{name}.example.com"),
+    });
+    let oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+
+    wait_for(oauth2_api.clone(), name, is_oauth2("Exists")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2("Updated")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+
+    let oauth2_adopted = s
+        .kanidm_client
+        .idm_oauth2_rs_get(name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(oauth2_adopted.attrs.get("uuid").cloned(), uuid);
+    assert_eq!(
+        oauth2_adopted
+            .attrs
+            .get("displayname")
+            .unwrap()
+            .first()
+            .unwrap(),
+        "Oauth2 Adopt Existing"
+    );
+    assert_eq!(
+        oauth2_adopted
+            .attrs
+            .get("oauth2_rs_origin_landing")
+            .unwrap()
+            .first()
+            .unwrap(),
+        &format!("https://{name}.example.com/")
+    );
+}
+
 #[tokio::test]
 async fn oauth2_secret() {
     let name = "test-secret";
@@ -249,7 +335,173 @@ async fn oauth2_secret() {
         .get(&format!("{name}-kanidm-oauth2-credentials"))
         .await
         .unwrap();
-    assert_eq!(secret.data.clone().unwrap().len(), 2);
+    let data = secret.data.unwrap();
+    assert_eq!(data.len(), 4);
+    let issuer = String::from_utf8(data["ISSUER"].0.clone()).unwrap();
+    assert!(issuer.ends_with(&format!("/oauth2/openid/{name}")));
+    assert_eq!(
+        data["DISCOVERY_URL"].0,
+        format!("{issuer}/.well-known/openid-configuration").as_bytes()
+    );
+    assert_eq!(
+        secret
+            .metadata
+            .annotations
+            .unwrap()
+            .get("kaniop.rs/oauth2-issuer"),
+        Some(&issuer)
+    );
+    let oauth2 = oauth2_api.get(name).await.unwrap();
+    assert_eq!(oauth2.status.unwrap().client_secret, None);
+}
+
+#[tokio::test]
+async fn oauth2_expose_secret_in_status() {
+    let name = "test-expose-secret-in-status";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Expose Secret",
+        "redirectUrl": [],
+        "origin": format!("https://{name}.example.com"),
+        "exposeSecretInStatus": true,
+    });
+    let oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+
+    wait_for(oauth2_api.clone(), name, is_oauth2("SecretInitialized")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+
+    let client_secret = s
+        .kanidm_client
+        .idm_oauth2_rs_get_basic_secret(name)
+        .await
+        .unwrap();
+    assert!(client_secret.is_some());
+    let oauth2 = oauth2_api.get(name).await.unwrap();
+    assert_eq!(oauth2.status.unwrap().client_secret, client_secret);
+}
+
+#[tokio::test]
+async fn oauth2_secret_type() {
+    let name = "test-secret-type";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Secret Type",
+        "redirectUrl": [],
+        "origin": format!("https://{name}.example.com"),
+        "secretType": "kubernetes.io/basic-auth",
+    });
+    let mut oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+
+    wait_for(oauth2_api.clone(), name, is_oauth2("SecretInitialized")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+
+    let secret_api = Api::<Secret>::namespaced(s.client.clone(), "default");
+    let secret = secret_api
+        .get(&format!("{name}-kanidm-oauth2-credentials"))
+        .await
+        .unwrap();
+    assert_eq!(secret.type_.as_deref(), Some("kubernetes.io/basic-auth"));
+    let data = secret.data.unwrap();
+    assert_eq!(data.len(), 6);
+    assert_eq!(data["username"].0, name.as_bytes());
+    assert_eq!(data["password"], data["CLIENT_SECRET"]);
+
+    oauth2.spec.secret_type = Some("example.com/oauth2".to_string());
+    let result = oauth2_api
+        .patch(
+            name,
+            &PatchParams::apply("e2e-test").force(),
+            &Patch::Apply(&oauth2),
+        )
+        .await;
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Secret type cannot be changed."));
+}
+
+#[tokio::test]
+async fn oauth2_secret_format() {
+    let name = "test-secret-format";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Secret Format",
+        "redirectUrl": [],
+        "origin": format!("https://{name}.example.com"),
+        "secretFormat": "json",
+    });
+    let oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+
+    wait_for(oauth2_api.clone(), name, is_oauth2("SecretInitialized")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+
+    let secret_api = Api::<Secret>::namespaced(s.client.clone(), "default");
+    let secret = secret_api
+        .get(&format!("{name}-kanidm-oauth2-credentials"))
+        .await
+        .unwrap();
+    let data = secret.data.unwrap();
+    assert_eq!(data.len(), 1);
+    let credentials: serde_json::Value =
+        serde_json::from_slice(&data["credentials.json"].0).unwrap();
+    assert_eq!(credentials["client_id"], name);
+    assert!(credentials["client_secret"]
+        .as_str()
+        .is_some_and(|secret| !secret.is_empty()));
+    assert!(credentials["issuer"]
+        .as_str()
+        .unwrap()
+        .ends_with(&format!("/oauth2/openid/{name}")));
+    assert!(credentials["discovery_url"]
+        .as_str()
+        .unwrap()
+        .ends_with(&format!(
+            "/oauth2/openid/{name}/.well-known/openid-configuration"
+        )));
+}
+
+#[tokio::test]
+async fn oauth2_invalid_secret_type() {
+    let name = "test-invalid-secret-type";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Invalid Secret Type",
+        "redirectUrl": [],
+        "origin": format!("https://{name}.example.com"),
+        "secretType": "kubernetes.io/tls",
+    });
+    let oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    let result = oauth2_api.create(&PostParams::default(), &oauth2).await;
+    assert!(result.is_err());
 }
 
 #[tokio::test]
@@ -380,6 +632,7 @@ async fn oauth2_scope_map() {
         },
         "displayname": "Oauth2 Update",
         "redirectUrl": [],
+        "requireOpenid": false,
         "scopeMap": [{
             "group": group_1,
             "scopes": ["scope1", "scope2"],
@@ -549,6 +802,7 @@ async fn oauth2_scope_map_group_by_uuid_not_allowed() {
             "redirectUrl": [],
             "displayname": "Test OAuth2 Client",
             "origin": "https://example.com",
+            "requireOpenid": false,
             "scopeMap": [{
                 "group": "00000000-0000-0000-0000-000000000000",
                 "scopes": ["scope1", "scope2"],
@@ -579,6 +833,7 @@ async fn oauth2_scope_map_unique_groups() {
             "redirectUrl": [],
             "displayname": "Test OAuth2 Client",
             "origin": "https://example.com",
+            "requireOpenid": false,
             "scopeMap": [{
                 "group": "group1",
                 "scopes": ["scope1", "scope2"],
@@ -600,6 +855,111 @@ async fn oauth2_scope_map_unique_groups() {
         .contains("Groups must be unique in Scope Maps."));
 }
 
+#[tokio::test]
+async fn oauth2_scope_map_requires_openid() {
+    let client = Client::try_default().await.unwrap();
+    let name = "test-oauth2-scope-map-requires-openid";
+    let oauth2_spec = |scopes: &[&str]| {
+        serde_json::from_value(json!({
+            "kanidmRef": {
+                "name": "test",
+            },
+            "redirectUrl": [],
+            "displayname": "Test OAuth2 Client",
+            "origin": "https://example.com",
+            "scopeMap": [{
+                "group": "group1",
+                "scopes": scopes,
+            }],
+        }))
+        .unwrap()
+    };
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(client.clone(), "default");
+
+    let oauth2 = KanidmOAuth2Client::new(name, oauth2_spec(&["profile", "email"]));
+    let result = oauth2_api.create(&PostParams::default(), &oauth2).await;
+
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Scope Maps must include the 'openid' scope."));
+
+    let oauth2 = KanidmOAuth2Client::new(name, oauth2_spec(&["openid", "profile"]));
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+    oauth2_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn oauth2_scope_map_requires_openid_ratchets_on_update() {
+    let client = Client::try_default().await.unwrap();
+    let name = "test-oauth2-scope-map-requires-openid-ratchets";
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": "test",
+        },
+        "redirectUrl": [],
+        "displayname": "Test OAuth2 Client",
+        "origin": "https://example.com",
+        "requireOpenid": false,
+        "scopeMap": [{
+            "group": "group1",
+            "scopes": ["profile", "email"],
+        }],
+    });
+    let oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(client.clone(), "default");
+    oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap();
+
+    // clients created before the rule keep working while their scope map does not change
+    oauth2_api
+        .patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({"spec": {"requireOpenid": null}})),
+        )
+        .await
+        .unwrap();
+    oauth2_api
+        .patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({"metadata": {"labels": {"test": "ratchet"}}})),
+        )
+        .await
+        .unwrap();
+
+    let result = oauth2_api
+        .patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({"spec": {"scopeMap": [{
+                "group": "group1",
+                "scopes": ["profile"],
+            }]}})),
+        )
+        .await;
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Scope Maps must include the 'openid' scope."));
+
+    oauth2_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .unwrap();
+}
+
 #[tokio::test]
 async fn oauth2_sup_scope_map() {
     let name = "test-oauth2-sup-scope-map";
@@ -789,6 +1149,123 @@ async fn oauth2_sup_scope_map() {
         .not(),);
 }
 
+#[tokio::test]
+async fn oauth2_sup_scope_map_missing_group() {
+    let name = "test-oauth2-sup-scope-map-missing-group";
+    let s = setup_kanidm_connection(KANIDM_NAME).await;
+    let group_1 = "test-oauth2-sup-scope-map-missing-group-1";
+    let group_2 = "test-oauth2-sup-scope-map-missing-group-2";
+    create_group(group_1, s.client.clone()).await;
+
+    let oauth2_spec = json!({
+        "kanidmRef": {
+            "name": KANIDM_NAME,
+        },
+        "displayname": "Oauth2 Missing Group",
+        "redirectUrl": [],
+        "supScopeMap": [{
+            "group": group_1,
+            "scopes": ["scope1", "scope2"],
+        }, {
+            "group": group_2,
+            "scopes": ["scope3", "scope4"],
+        }],
+        "origin": format!("https://{name}.example.com"),
+    });
+    let mut oauth2 = KanidmOAuth2Client::new(name, serde_json::from_value(oauth2_spec).unwrap());
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(s.client.clone(), "default");
+    let oauth2_uid = oauth2_api
+        .create(&PostParams::default(), &oauth2)
+        .await
+        .unwrap()
+        .uid()
+        .unwrap();
+
+    // the sup scope map of the missing group is skipped with a warning
+    let opts = ListParams::default().fields(&format!(
+        "involvedObject.kind=KanidmOAuth2Client,involvedObject.apiVersion=kaniop.rs/v1beta1,involvedObject.uid={oauth2_uid},reason=SupScopeMapGroupNotFound"
+    ));
+    let event_api = Api::<Event>::namespaced(s.client.clone(), "default");
+    check_event_with_timeout(&event_api, &opts).await;
+    let event_list = event_api.list(&opts).await.unwrap();
+    assert!(event_list
+        .items
+        .iter()
+        .any(|e| e.message.as_ref().is_some_and(|m| m.contains(group_2))));
+    wait_for(
+        oauth2_api.clone(),
+        name,
+        is_oauth2_false("SupScopeMapUpdated"),
+    )
+    .await;
+
+    let oauth2_with_missing_group = s.kanidm_client.idm_oauth2_rs_get(name).await.unwrap();
+    assert_eq!(
+        oauth2_with_missing_group
+            .unwrap()
+            .attrs
+            .get("oauth2_rs_sup_scope_map")
+            .unwrap(),
+        &[format!(
+            r#"{group_1}@{KANIDM_NAME}.localhost: {{"scope1", "scope2"}}"#
+        )]
+    );
+
+    // once the group is created, its sup scope map is applied
+    create_group(group_2, s.client.clone()).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2("SupScopeMapUpdated")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+    let oauth2_group_created = s.kanidm_client.idm_oauth2_rs_get(name).await.unwrap();
+    assert_eq!(
+        oauth2_group_created
+            .unwrap()
+            .attrs
+            .get("oauth2_rs_sup_scope_map")
+            .unwrap()
+            .iter()
+            .collect::<BTreeSet<_>>(),
+        BTreeSet::from([
+            &format!(r#"{group_1}@{KANIDM_NAME}.localhost: {{"scope1", "scope2"}}"#),
+            &format!(r#"{group_2}@{KANIDM_NAME}.localhost: {{"scope3", "scope4"}}"#)
+        ])
+    );
+
+    // deleting the group and its sup scope map keeps the client ready
+    let group_api = Api::<KanidmGroup>::namespaced(s.client.clone(), "default");
+    let group_2_uid = group_api.get(group_2).await.unwrap().uid().unwrap();
+    group_api
+        .delete(group_2, &DeleteParams::default())
+        .await
+        .unwrap();
+    wait_for(group_api, group_2, conditions::is_deleted(&group_2_uid)).await;
+    oauth2.spec.sup_scope_map = serde_json::from_value(json!([{
+        "group": group_1,
+        "scopes": ["scope1", "scope2"],
+    }]))
+    .unwrap();
+    oauth2_api
+        .patch(
+            name,
+            &PatchParams::apply("e2e-test").force(),
+            &Patch::Apply(&oauth2),
+        )
+        .await
+        .unwrap();
+    wait_for(oauth2_api.clone(), name, is_oauth2("SupScopeMapUpdated")).await;
+    wait_for(oauth2_api.clone(), name, is_oauth2_ready()).await;
+    let oauth2_group_deleted = s.kanidm_client.idm_oauth2_rs_get(name).await.unwrap();
+    assert_eq!(
+        oauth2_group_deleted
+            .unwrap()
+            .attrs
+            .get("oauth2_rs_sup_scope_map")
+            .unwrap(),
+        &[format!(
+            r#"{group_1}@{KANIDM_NAME}.localhost: {{"scope1", "scope2"}}"#
+        )]
+    );
+}
+
 #[tokio::test]
 async fn oauth2_sup_scope_map_group_by_uuid_not_allowed() {
     let client = Client::try_default().await.unwrap();
@@ -1500,6 +1977,34 @@ async fn oauth2_non_public_client_allow_localhost_redirect() {
         .contains("Just public clients can allow localhost redirect."));
 }
 
+#[tokio::test]
+async fn oauth2_non_public_client_device_flow() {
+    let client = Client::try_default().await.unwrap();
+
+    let oauth2 = KanidmOAuth2Client::new(
+        "test-non-public-client-device-flow",
+        serde_json::from_value(json!({
+            "kanidmRef": {
+                "name": "test",
+            },
+            "redirectUrl": [],
+            "displayname": "Test OAuth2 Client",
+            "origin": "https://example.com",
+            "public": false,
+            "deviceFlowEnable": true,
+        }))
+        .unwrap(),
+    );
+    let oauth2_api = Api::<KanidmOAuth2Client>::namespaced(client.clone(), "default");
+    let result = oauth2_api.create(&PostParams::default(), &oauth2).await;
+
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Just public clients can enable device flow."));
+}
+
 #[tokio::test]
 async fn oauth2_allow_localhost_redirect() {
     let name = "test-allow-localhost-redirect";
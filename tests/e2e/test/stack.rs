@@ -0,0 +1,100 @@
+use super::wait_for;
+
+use kaniop_group::crd::KanidmGroup;
+use kaniop_operator::kanidm::crd::Kanidm;
+use kaniop_stack::crd::KanidmStack;
+use kaniop_stack::reconcile::STACK_LABEL;
+
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams, PostParams};
+use kube::runtime::wait::{conditions, Condition};
+use kube::{Api, Client, ResourceExt};
+use serde_json::json;
+
+fn exists<K>() -> impl Condition<K> {
+    |obj: Option<&K>| obj.is_some()
+}
+
+#[tokio::test]
+async fn stack_children_lifecycle() {
+    let name = "test-stack-lifecycle";
+    let client = Client::try_default().await.unwrap();
+
+    let stack_spec = json!({
+        "kanidm": {
+            "domain": format!("{name}.localhost"),
+            "replicaGroups": [{"name": "default", "replicas": 1}],
+        },
+        "groups": [
+            {"name": "test-stack-admins", "spec": {"members": ["admin"]}},
+            {"name": "test-stack-users", "spec": {}},
+        ],
+    });
+    let stack = KanidmStack::new(name, serde_json::from_value(stack_spec).unwrap());
+    let stack_api = Api::<KanidmStack>::namespaced(client.clone(), "default");
+    stack_api
+        .create(&PostParams::default(), &stack)
+        .await
+        .unwrap();
+
+    let kanidm_api = Api::<Kanidm>::namespaced(client.clone(), "default");
+    wait_for(kanidm_api.clone(), name, exists()).await;
+    let kanidm = kanidm_api.get(name).await.unwrap();
+    assert_eq!(kanidm.spec.domain, format!("{name}.localhost"));
+    assert_eq!(kanidm.owner_references()[0].kind, "KanidmStack");
+
+    let group_api = Api::<KanidmGroup>::namespaced(client.clone(), "default");
+    wait_for(group_api.clone(), "test-stack-admins", exists()).await;
+    wait_for(group_api.clone(), "test-stack-users", exists()).await;
+    let group = group_api.get("test-stack-admins").await.unwrap();
+    assert_eq!(group.spec.kanidm_ref.name, name);
+    assert_eq!(group.spec.members, Some(vec!["admin".to_string()]));
+
+    let users_uid = group_api
+        .get("test-stack-users")
+        .await
+        .unwrap()
+        .uid()
+        .unwrap();
+    // the Kanidm never gets ready without its TLS secret, so groups are deleted without cleanup
+    let stack_patch = json!({
+        "spec": {
+            "groups": [{"name": "test-stack-admins", "spec": {"members": ["admin"]}}],
+        },
+    });
+    stack_api
+        .patch(name, &PatchParams::default(), &Patch::Merge(&stack_patch))
+        .await
+        .unwrap();
+    wait_for(
+        group_api.clone(),
+        "test-stack-users",
+        conditions::is_deleted(&users_uid),
+    )
+    .await;
+    let groups = group_api
+        .list(&ListParams::default().labels(&format!("{STACK_LABEL}={name}")))
+        .await
+        .unwrap();
+    assert_eq!(
+        groups.iter().map(|g| g.name_any()).collect::<Vec<_>>(),
+        vec!["test-stack-admins"]
+    );
+
+    let admins_uid = group.uid().unwrap();
+    stack_api
+        .delete(name, &DeleteParams::foreground())
+        .await
+        .unwrap();
+    wait_for(
+        group_api,
+        "test-stack-admins",
+        conditions::is_deleted(&admins_uid),
+    )
+    .await;
+    wait_for(
+        kanidm_api,
+        name,
+        conditions::is_deleted(&kanidm.uid().unwrap()),
+    )
+    .await;
+}